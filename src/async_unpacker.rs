@@ -0,0 +1,672 @@
+use std::{
+    cmp,
+    future::Future,
+    io::{self, Read},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use anyhow::bail;
+use thiserror::Error;
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt},
+    sync::{mpsc, Semaphore},
+    task,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::cache::FileCache;
+use crate::compression;
+use crate::hash::{self, HashingReader};
+use crate::limits::{LimitError, LimitedReader, Limits};
+use crate::progress::{Event, NoProgress, Observer};
+use crate::referrers;
+use crate::report::{LayerReport, PendingManifest, SkippedEntry, UnpackReport};
+use crate::spec::descriptor::Descriptor;
+use crate::spec::digest::{Algorithm, Digest};
+use crate::spec::index::{Index, INDEX_FILE_NAME};
+use crate::spec::manifest::Manifest;
+use crate::spec::media_types::MediaType;
+use crate::store::DirectoryStore;
+use crate::unpacker::{check_descriptor_size, Applier, Timestamps};
+
+// Blobs are read and decompressed in chunks of this size, and up to this
+// many chunks are buffered per layer.
+const CHUNK_SIZE: usize = 64 * 1024;
+const BUFFERED_CHUNKS: usize = 16;
+
+/// A blob opened for reading asynchronously.
+pub type AsyncBlob = Box<dyn AsyncRead + Send + Unpin>;
+
+/// AsyncBlobStore gives async access to the content of an OCI image layout.
+pub trait AsyncBlobStore: Send + Sync {
+    /// Opens the blob with the given digest for reading.
+    fn open(&self, digest: &Digest) -> impl Future<Output = io::Result<AsyncBlob>> + Send;
+
+    /// Opens the `index.json` of the layout.
+    fn open_index(&self) -> impl Future<Output = io::Result<AsyncBlob>> + Send;
+}
+
+impl AsyncBlobStore for DirectoryStore {
+    async fn open(&self, digest: &Digest) -> io::Result<AsyncBlob> {
+        Ok(Box::new(File::open(self.blob_path(digest)).await?))
+    }
+
+    async fn open_index(&self) -> io::Result<AsyncBlob> {
+        Ok(Box::new(
+            File::open(self.root().join(INDEX_FILE_NAME)).await?,
+        ))
+    }
+}
+
+/// The error of an unpack stopped through its cancellation token.
+#[derive(Debug, Error)]
+#[error("unpack cancelled")]
+pub struct Cancelled;
+
+/// AsyncUnpacker unpacks an image like `Unpacker`, reading and decompressing
+/// blobs on tokio. Layers are applied in order on a blocking thread, while
+/// the following ones are read ahead.
+#[derive(Debug)]
+pub struct AsyncUnpacker<S: AsyncBlobStore = DirectoryStore> {
+    store: S,
+    destination: String,
+    limits: Limits,
+    cache: Option<FileCache>,
+    restore_dir_mtimes: bool,
+    timestamps: Timestamps,
+    observer: Arc<dyn Observer + Send + Sync>,
+    prefetch: usize,
+}
+
+impl AsyncUnpacker {
+    pub fn new(image_name: String, destination: String) -> Self {
+        AsyncUnpacker::with_store(DirectoryStore::new(image_name), destination)
+    }
+}
+
+impl<S: AsyncBlobStore> AsyncUnpacker<S> {
+    /// Creates an unpacker reading the image from any async blob store.
+    pub fn with_store(store: S, destination: String) -> Self {
+        AsyncUnpacker {
+            store,
+            destination,
+            limits: Limits::default(),
+            cache: None,
+            restore_dir_mtimes: false,
+            timestamps: Timestamps::Preserve,
+            observer: Arc::new(NoProgress),
+            prefetch: 1,
+        }
+    }
+
+    /// Overrides the default resource limits.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Creates regular files from a cache shared across unpacks.
+    pub fn cache(mut self, cache: FileCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Sets the mtimes and modes of directories once everything is unpacked.
+    pub fn restore_dir_mtimes(mut self, restore: bool) -> Self {
+        self.restore_dir_mtimes = restore;
+        self
+    }
+
+    /// Clamps or overrides mtimes.
+    pub fn timestamps(mut self, timestamps: Timestamps) -> Self {
+        self.timestamps = timestamps;
+        self
+    }
+
+    /// Reports the progress of the unpack to `observer`, which may be called
+    /// from another thread.
+    pub fn observer(mut self, observer: impl Observer + Send + Sync + 'static) -> Self {
+        self.observer = Arc::new(observer);
+        self
+    }
+
+    /// How many layers are read and decompressed ahead of the one being
+    /// applied, 1 by default.
+    pub fn prefetch(mut self, layers: usize) -> Self {
+        self.prefetch = layers;
+        self
+    }
+
+    /// Unpacks the image, failing with `Cancelled` once `token` is cancelled.
+    pub async fn unpack(&self, token: CancellationToken) -> anyhow::Result<UnpackReport> {
+        match self.run(&token).await {
+            Err(_) if token.is_cancelled() => Err(Cancelled.into()),
+            result => result,
+        }
+    }
+
+    async fn run(&self, token: &CancellationToken) -> anyhow::Result<UnpackReport> {
+        let start = Instant::now();
+        let _lock = match &self.cache {
+            Some(cache) => {
+                let cache = cache.clone();
+                Some(task::spawn_blocking(move || cache.lock()).await??)
+            }
+            None => None,
+        };
+
+        let bytes = read_limited(
+            self.store.open_index().await?,
+            self.limits.max_manifest_size,
+            LimitError::DocumentTooLarge {
+                what: String::from("index.json"),
+                limit: self.limits.max_manifest_size,
+            },
+        )
+        .await?;
+        let index_digest = hash::digest_bytes(&Algorithm::Sha256, &bytes)?;
+        let index: Index = serde_json::from_slice(&bytes)?;
+        tokio::fs::create_dir(&self.destination).await?;
+
+        let mut manifests = Vec::new();
+        let mut layers = Vec::new();
+        for descriptor in referrers::images(&index) {
+            if !self.is_image(descriptor).await? {
+                continue;
+            }
+            let (manifest, pending) = self.read_manifest(descriptor).await?;
+            manifests.push((pending, manifest.layers.len()));
+            layers.extend(manifest.layers);
+        }
+
+        // Layers are read by tasks and decompressed on blocking threads
+        // feeding the blocking applier, which stops them by cancelling
+        // `inner` if it fails.
+        let inner = token.child_token();
+        let mut feeds = Vec::new();
+        let mut streams = Vec::new();
+        for layer in layers {
+            let (sender, receiver) = mpsc::channel(BUFFERED_CHUNKS);
+            let compressed = Arc::new(AtomicU64::new(0));
+            feeds.push((layer.clone(), sender, compressed.clone()));
+            streams.push(LayerStream {
+                layer,
+                receiver,
+                compressed,
+            });
+        }
+
+        let apply = {
+            let destination = self.destination.clone();
+            let limits = self.limits;
+            let cache = self.cache.clone();
+            let (restore_dir_mtimes, timestamps) = (self.restore_dir_mtimes, self.timestamps);
+            let observer = self.observer.clone();
+            let inner = inner.clone();
+            task::spawn_blocking(move || {
+                let mut applier = Applier::new(destination, limits);
+                applier.cache = cache.as_ref();
+                applier.restore_dir_mtimes = restore_dir_mtimes;
+                applier.timestamps = timestamps;
+                applier.observer = observer.as_ref();
+                let result = apply_layers(&applier, streams, &inner);
+                if result.is_err() {
+                    inner.cancel();
+                }
+                result
+            })
+        };
+
+        let read = async {
+            let semaphore = Arc::new(Semaphore::new(self.prefetch + 1));
+            // Decompressed bytes of all the layers, against the total limit.
+            let decompressed = Arc::new(AtomicU64::new(0));
+            for (layer, sender, compressed) in feeds {
+                let permit = tokio::select! {
+                    _ = inner.cancelled() => return Ok(()),
+                    permit = semaphore.clone().acquire_owned() => permit?,
+                };
+                let blob = match self.store.open(&layer.digest).await {
+                    Ok(blob) => blob,
+                    Err(e) => {
+                        let _ = sender.send(Err(e)).await;
+                        return Ok(());
+                    }
+                };
+                let (raw_sender, raw_receiver) = mpsc::channel(BUFFERED_CHUNKS);
+                let token = inner.clone();
+                task::spawn(async move {
+                    tokio::select! {
+                        _ = token.cancelled() => {}
+                        _ = forward(blob, &raw_sender, &compressed) => {}
+                    }
+                });
+
+                let raw = ChannelReader {
+                    receiver: raw_receiver,
+                    token: inner.clone(),
+                    chunk: vec![],
+                    position: 0,
+                };
+                let limits = self.limits;
+                let decompressed = decompressed.clone();
+                task::spawn_blocking(move || {
+                    if let Err(e) = decode(raw, &layer, &sender, &limits, &decompressed) {
+                        let _ = sender.blocking_send(Err(e));
+                    }
+                    drop(permit);
+                });
+            }
+            Ok::<(), anyhow::Error>(())
+        };
+
+        let (applied, read) = tokio::join!(apply, read);
+        if read.is_err() {
+            inner.cancel();
+        }
+        read?;
+        let (layers, skipped, mut warnings) = applied??;
+
+        let mut layers = layers.into_iter();
+        let manifests = manifests
+            .into_iter()
+            .map(|(mut pending, count)| {
+                for layer in layers.by_ref().take(count) {
+                    pending.add_layer(layer, &mut warnings);
+                }
+                pending.finish()
+            })
+            .collect();
+
+        Ok(UnpackReport {
+            index: index_digest,
+            manifests,
+            skipped,
+            warnings,
+            signatures: vec![],
+            dry_run: None,
+            elapsed: start.elapsed(),
+        })
+    }
+
+    async fn read_manifest(
+        &self,
+        descriptor: &Descriptor,
+    ) -> anyhow::Result<(Manifest, PendingManifest)> {
+        self.observer.event(&Event::ManifestSelected {
+            digest: descriptor.digest.clone(),
+        });
+        let limits = &self.limits;
+        let bytes = self
+            .read_document(descriptor, "manifest", limits.max_manifest_size)
+            .await?;
+        let manifest: Manifest = serde_json::from_slice(&bytes)?;
+        let config = self
+            .read_document(&manifest.config, "config", limits.max_config_size)
+            .await?;
+        let pending = PendingManifest::new(descriptor, &manifest.config, &config);
+
+        Ok((manifest, pending))
+    }
+
+    // Tells whether an entry of the index is an image rather than an
+    // artifact, as `referrers::select_images` does.
+    async fn is_image(&self, descriptor: &Descriptor) -> anyhow::Result<bool> {
+        if descriptor.media_type != MediaType::ImageManifest
+            || descriptor.size > self.limits.max_manifest_size
+        {
+            return Ok(true);
+        }
+        let bytes = read_limited(
+            self.store.open(&descriptor.digest).await?,
+            descriptor.size,
+            LimitError::BlobSizeExceeded {
+                digest: descriptor.digest.clone(),
+                size: descriptor.size,
+            },
+        )
+        .await?;
+        let actual = hash::digest_bytes(&descriptor.digest.algorithm, &bytes)?;
+        if actual != descriptor.digest {
+            bail!("manifest {} has digest {}", descriptor.digest, actual);
+        }
+
+        Ok(!referrers::is_artifact(&serde_json::from_slice(&bytes)?))
+    }
+
+    async fn read_document(
+        &self,
+        descriptor: &Descriptor,
+        what: &str,
+        limit: u64,
+    ) -> anyhow::Result<Vec<u8>> {
+        let digest = &descriptor.digest;
+        check_descriptor_size(descriptor, what, limit)?;
+
+        let bytes = read_limited(
+            self.store.open(digest).await?,
+            descriptor.size,
+            LimitError::BlobSizeExceeded {
+                digest: digest.clone(),
+                size: descriptor.size,
+            },
+        )
+        .await?;
+        let actual = hash::digest_bytes(&digest.algorithm, &bytes)?;
+        if &actual != digest {
+            bail!("{} {} has digest {}", what, digest, actual);
+        }
+        self.observer.event(&Event::DigestVerified {
+            digest: digest.clone(),
+        });
+
+        Ok(bytes)
+    }
+}
+
+// The decompressed stream of a layer, with how much of its blob was read.
+struct LayerStream {
+    layer: Descriptor,
+    receiver: mpsc::Receiver<io::Result<Vec<u8>>>,
+    compressed: Arc<AtomicU64>,
+}
+
+// Applies the layers in order. Decoding errors, such as a digest mismatch,
+// come at the end of each stream.
+fn apply_layers(
+    applier: &Applier,
+    streams: Vec<LayerStream>,
+    token: &CancellationToken,
+) -> anyhow::Result<(Vec<LayerReport>, Vec<SkippedEntry>, Vec<String>)> {
+    let mut reports = Vec::new();
+    for stream in streams {
+        if token.is_cancelled() {
+            bail!(Cancelled);
+        }
+        applier.observer.event(&Event::LayerStarted {
+            digest: stream.layer.digest.clone(),
+            size: stream.layer.size,
+        });
+        let mut reader = ChannelReader {
+            receiver: stream.receiver,
+            token: token.clone(),
+            chunk: vec![],
+            position: 0,
+        };
+        let compressed = || stream.compressed.load(Ordering::Relaxed);
+        let mut report = applier.apply_layer(&stream.layer, &mut reader, &compressed)?;
+
+        io::copy(&mut reader, &mut io::sink()).map_err(LimitError::from_io)?;
+        applier.observer.event(&Event::DigestVerified {
+            digest: stream.layer.digest,
+        });
+        report.compressed_bytes = compressed();
+        reports.push(report);
+    }
+
+    let (skipped, warnings) = applier.finish()?;
+    Ok((reports, skipped, warnings))
+}
+
+// Reads a blob into `sender` as it comes, for `decode` to process.
+async fn forward(
+    mut blob: AsyncBlob,
+    sender: &mpsc::Sender<io::Result<Vec<u8>>>,
+    compressed: &AtomicU64,
+) {
+    let mut size = 0;
+    loop {
+        let mut buffer = vec![0; CHUNK_SIZE];
+        let n = match blob.read(&mut buffer).await {
+            Ok(0) => return,
+            Ok(n) => n,
+            Err(e) => {
+                let _ = sender.send(Err(e)).await;
+                return;
+            }
+        };
+        size += n as u64;
+        compressed.store(size, Ordering::Relaxed);
+        buffer.truncate(n);
+        // The decoder is gone if the receiver is, it has its own error.
+        if sender.send(Ok(buffer)).await.is_err() {
+            return;
+        }
+    }
+}
+
+// Hashes and decompresses a blob into `sender`, a chunk at a time so a
+// small blob can't expand past the limits in memory. `decompressed` is
+// shared by the layers, against the total size limit.
+fn decode(
+    raw: ChannelReader,
+    layer: &Descriptor,
+    sender: &mpsc::Sender<io::Result<Vec<u8>>>,
+    limits: &Limits,
+    decompressed: &AtomicU64,
+) -> io::Result<()> {
+    let hasher = hash::hasher(&layer.digest.algorithm).map_err(io::Error::other)?;
+    let mut blob = HashingReader::new(
+        LimitedReader::for_blob(raw, &layer.digest, layer.size),
+        hasher,
+    );
+    let decoder =
+        compression::decompress(&layer.media_type, &mut blob).map_err(io::Error::other)?;
+    let mut decoder = LimitedReader::new(
+        decoder,
+        limits.max_layer_size,
+        LimitError::LayerTooLarge {
+            digest: layer.digest.clone(),
+            limit: limits.max_layer_size,
+        },
+    );
+
+    loop {
+        let mut chunk = vec![0; CHUNK_SIZE];
+        let n = decoder.read(&mut chunk)?;
+        if n == 0 {
+            break;
+        }
+        if decompressed.fetch_add(n as u64, Ordering::Relaxed) + n as u64 > limits.max_total_size {
+            return Err(io::Error::other(LimitError::TotalSizeExceeded {
+                limit: limits.max_total_size,
+            }));
+        }
+        chunk.truncate(n);
+        // The applier is gone if the receiver is, it has its own error.
+        if sender.blocking_send(Ok(chunk)).is_err() {
+            return Ok(());
+        }
+    }
+
+    drop(decoder);
+    io::copy(&mut blob, &mut io::sink())?;
+    let (digest, _) = blob.finish();
+    if digest != layer.digest {
+        return Err(io::Error::other(format!(
+            "layer {} has digest {}",
+            layer.digest, digest
+        )));
+    }
+
+    Ok(())
+}
+
+async fn read_limited(
+    mut reader: AsyncBlob,
+    limit: u64,
+    error: LimitError,
+) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    (&mut reader)
+        .take(limit + 1)
+        .read_to_end(&mut bytes)
+        .await?;
+    if bytes.len() as u64 > limit {
+        bail!(error);
+    }
+
+    Ok(bytes)
+}
+
+// ChannelReader reads the chunks of a blob or of a decompressed layer on a
+// blocking thread.
+struct ChannelReader {
+    receiver: mpsc::Receiver<io::Result<Vec<u8>>>,
+    token: CancellationToken,
+    chunk: Vec<u8>,
+    position: usize,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.chunk.len() {
+            match self.receiver.blocking_recv() {
+                Some(chunk) => {
+                    self.chunk = chunk?;
+                    self.position = 0;
+                }
+                // A cancelled decoder stops without an error of its own.
+                None if self.token.is_cancelled() => return Err(io::Error::other(Cancelled)),
+                None => return Ok(0),
+            }
+        }
+
+        let n = cmp::min(buf.len(), self.chunk.len() - self.position);
+        buf[..n].copy_from_slice(&self.chunk[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::mpsc};
+
+    use tokio_util::sync::CancellationToken;
+
+    use crate::{
+        async_unpacker::{AsyncUnpacker, Cancelled},
+        limits::{LimitError, Limits},
+        progress::Event,
+        store::DirectoryStore,
+        test_utils::{gzip, sha256, tar, TestEntry, TestLayout},
+    };
+
+    #[tokio::test]
+    async fn test_async_unpack() {
+        let layout = TestLayout::new();
+        let base = tar(&[TestEntry::File("etc/hostname", b"localhost")]);
+        let top = tar(&[TestEntry::File("etc/motd", b"hello")]);
+        layout.add_image(&[base.clone(), top.clone()], None);
+
+        let (sender, receiver) = mpsc::channel();
+        let destination = layout.dir.path().join("rootfs");
+        let report = AsyncUnpacker::new(layout.path(), destination.to_str().unwrap().to_owned())
+            .observer(sender)
+            .unpack(CancellationToken::new())
+            .await
+            .unwrap();
+        let layers = &report.manifests[0].layers;
+        assert_eq!(layers[0].diff_id, sha256(&base));
+        assert_eq!(layers[1].compressed_bytes, gzip(&top).len() as u64);
+        assert_eq!(
+            fs::read(destination.join("etc/hostname")).unwrap(),
+            b"localhost"
+        );
+        assert_eq!(fs::read(destination.join("etc/motd")).unwrap(), b"hello");
+        let verified = receiver
+            .try_iter()
+            .filter(|e| matches!(e, Event::DigestVerified { .. }))
+            .count();
+        assert_eq!(verified, 4);
+
+        // A corrupted layer fails once it was read entirely.
+        let store = DirectoryStore::new(layout.path());
+        fs::write(
+            store.blob_path(&sha256(&gzip(&base))),
+            gzip(&tar(&[TestEntry::File("etc/hostname", b"evil")])),
+        )
+        .unwrap();
+        let destination = layout.dir.path().join("corrupted");
+        let err = AsyncUnpacker::new(layout.path(), destination.to_str().unwrap().to_owned())
+            .unpack(CancellationToken::new())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("has digest"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_async_unpack_refuses_zip_bomb() {
+        let zeroes = vec![0; 1 << 20];
+        let layer = tar(&[TestEntry::File("zeroes", &zeroes)]);
+        let layout = TestLayout::new();
+        layout.add_image(&[layer.clone(), layer], None);
+
+        let limits = Limits {
+            max_layer_size: 1 << 16,
+            ..Limits::default()
+        };
+        assert!(matches!(
+            unpack_with_limits(&layout, "layer", limits).await,
+            LimitError::LayerTooLarge { limit, .. } if limit == 1 << 16
+        ));
+
+        let limits = Limits {
+            max_total_size: (1 << 20) + (1 << 16),
+            ..Limits::default()
+        };
+        assert_eq!(
+            unpack_with_limits(&layout, "total", limits).await,
+            LimitError::TotalSizeExceeded {
+                limit: (1 << 20) + (1 << 16)
+            }
+        );
+    }
+
+    async fn unpack_with_limits(layout: &TestLayout, name: &str, limits: Limits) -> LimitError {
+        let destination = layout.dir.path().join(name);
+        AsyncUnpacker::new(layout.path(), destination.to_str().unwrap().to_owned())
+            .limits(limits)
+            .prefetch(2)
+            .unpack(CancellationToken::new())
+            .await
+            .unwrap_err()
+            .downcast::<LimitError>()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_async_unpack_cancelled() {
+        let layout = TestLayout::new();
+        layout.add_image(
+            &[
+                tar(&[TestEntry::File("a", b"a")]),
+                tar(&[TestEntry::File("b", b"b")]),
+            ],
+            None,
+        );
+
+        // Cancelled as soon as the first layer is applied.
+        let token = CancellationToken::new();
+        let cancel = token.clone();
+        let destination = layout.dir.path().join("rootfs");
+        let err = AsyncUnpacker::new(layout.path(), destination.to_str().unwrap().to_owned())
+            .observer(move |event: &Event| {
+                if let Event::LayerFinished { .. } = event {
+                    cancel.cancel();
+                }
+            })
+            .unpack(token)
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<Cancelled>().is_some(), "{}", err);
+        assert!(destination.join("a").exists());
+        assert!(!destination.join("b").exists());
+    }
+}
@@ -0,0 +1,340 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::SystemTime,
+};
+
+use anyhow::bail;
+use filetime::FileTime;
+
+use crate::hash::{self, HashingWriter};
+use crate::lock::LayoutLock;
+use crate::spec::digest::{Algorithm, Digest};
+use crate::store::temp_path;
+
+// Files up to this size are hashed in memory, and only written on a miss.
+const IN_MEMORY_SIZE: usize = 1024 * 1024;
+
+/// How files are created from the cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Clone the cached file, sharing its blocks until either is modified.
+    /// Falls back to copying on filesystems without reflinks.
+    Reflink,
+    /// Hardlink a read-only cached file. Unpacked trees must not be modified
+    /// in place, and files sharing content also share their mode and mtime.
+    Hardlink,
+    Copy,
+}
+
+impl FromStr for CacheMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reflink" => Ok(CacheMode::Reflink),
+            "hardlink" => Ok(CacheMode::Hardlink),
+            "copy" => Ok(CacheMode::Copy),
+            _ => bail!(
+                "unknown cache mode {:?}, expected reflink, hardlink or copy",
+                s
+            ),
+        }
+    }
+}
+
+/// The number of files and bytes in a cache, or removed from it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/// FileCache stores the content of regular files by sha256 digest, under
+/// `sha256/<encoded>`, so unpacks sharing files don't write them again.
+/// Hardlinked files are separate read-only copies, one per mode and mtime.
+#[derive(Debug, Clone)]
+pub struct FileCache {
+    root: PathBuf,
+    mode: CacheMode,
+}
+
+impl FileCache {
+    /// Opens the cache at `root`, creating it if needed.
+    pub fn open<P: Into<PathBuf>>(root: P) -> io::Result<Self> {
+        let root = root.into();
+        fs::create_dir_all(root.join(Algorithm::Sha256.to_string()))?;
+        Ok(FileCache {
+            root,
+            mode: CacheMode::Reflink,
+        })
+    }
+
+    pub fn mode(mut self, mode: CacheMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Takes a shared lock on the cache, held while using it so `prune`
+    /// doesn't remove files being linked.
+    pub fn lock(&self) -> io::Result<LayoutLock> {
+        LayoutLock::shared(&self.root)
+    }
+
+    /// Adds the content read from `reader` to the cache, returning its digest.
+    pub fn insert(&self, reader: &mut dyn Read) -> io::Result<Digest> {
+        let mut bytes = Vec::new();
+        reader
+            .take(IN_MEMORY_SIZE as u64 + 1)
+            .read_to_end(&mut bytes)?;
+        if bytes.len() <= IN_MEMORY_SIZE {
+            let digest = hash::digest_bytes(&Algorithm::Sha256, &bytes).unwrap();
+            if !self.path(&digest).exists() {
+                let temp_path = temp_path(&self.root);
+                fs::write(&temp_path, &bytes)?;
+                self.commit(&temp_path, &digest)?;
+            }
+            return Ok(digest);
+        }
+
+        let temp_path = temp_path(&self.root);
+        let hasher = hash::hasher(&Algorithm::Sha256).unwrap();
+        let mut writer = HashingWriter::new(File::create(&temp_path)?, hasher);
+        writer.write_all(&bytes)?;
+        io::copy(reader, &mut writer)?;
+        let (_, digest, _) = writer.finish();
+        if self.path(&digest).exists() {
+            fs::remove_file(&temp_path)?;
+        } else {
+            self.commit(&temp_path, &digest)?;
+        }
+
+        Ok(digest)
+    }
+
+    /// Creates `destination` from the cached file with the given digest,
+    /// with the permission bits of `mode` and the mtime `mtime`.
+    pub fn materialize(
+        &self,
+        digest: &Digest,
+        destination: &Path,
+        mode: u32,
+        mtime: u64,
+    ) -> io::Result<()> {
+        let source = self.path(digest);
+        // Marks the entry as recently used for pruning.
+        filetime::set_file_mtime(&source, FileTime::now())?;
+        let mtime = FileTime::from_unix_time(mtime as i64, 0);
+
+        if self.mode == CacheMode::Hardlink {
+            let mode = mode & 0o777 & !0o222;
+            let linked = self.root.join(Algorithm::Sha256.to_string()).join(format!(
+                "{}-{:o}-{}",
+                digest.encoded,
+                mode,
+                mtime.unix_seconds()
+            ));
+            if !linked.exists() {
+                let temp_path = temp_path(&self.root);
+                clone_file(&source, &temp_path)?;
+                set_metadata(&temp_path, mode, mtime)?;
+                fs::rename(&temp_path, &linked)?;
+            }
+            match fs::hard_link(&linked, destination) {
+                Err(e) if matches!(e.raw_os_error(), Some(libc::EXDEV | libc::EMLINK)) => {}
+                result => return result,
+            }
+        }
+
+        if self.mode == CacheMode::Copy {
+            fs::copy(&source, destination)?;
+        } else {
+            clone_file(&source, destination)?;
+        }
+        set_metadata(destination, mode & 0o777, mtime)
+    }
+
+    /// Counts the files and bytes in the cache.
+    pub fn stats(&self) -> io::Result<CacheStats> {
+        let mut stats = CacheStats::default();
+        for (_, entry) in self.entries()? {
+            stats.files += entry.files;
+            stats.bytes += entry.bytes;
+        }
+
+        Ok(stats)
+    }
+
+    /// Removes the least recently used content until the cache holds at most
+    /// `max_bytes`, returning what was removed.
+    pub fn prune(&self, max_bytes: u64) -> io::Result<CacheStats> {
+        let _lock = LayoutLock::exclusive(&self.root)?;
+        let mut entries: Vec<(String, CacheEntry)> = self.entries()?.into_iter().collect();
+        entries.sort_by(|(a_name, a), (b_name, b)| (a.used, a_name).cmp(&(b.used, b_name)));
+
+        let mut total: u64 = entries.iter().map(|(_, e)| e.bytes).sum();
+        let mut removed = CacheStats::default();
+        for (_, entry) in entries {
+            if total <= max_bytes {
+                break;
+            }
+            for path in &entry.paths {
+                fs::remove_file(path)?;
+            }
+            total -= entry.bytes;
+            removed.files += entry.files;
+            removed.bytes += entry.bytes;
+        }
+
+        Ok(removed)
+    }
+
+    fn path(&self, digest: &Digest) -> PathBuf {
+        self.root
+            .join(Algorithm::Sha256.to_string())
+            .join(&digest.encoded)
+    }
+
+    // Cached files are read-only, so they can't be modified through a
+    // hardlink by mistake.
+    fn commit(&self, temp_path: &Path, digest: &Digest) -> io::Result<()> {
+        fs::set_permissions(temp_path, fs::Permissions::from_mode(0o444))?;
+        fs::rename(temp_path, self.path(digest))
+    }
+
+    // Groups the cached files by content.
+    fn entries(&self) -> io::Result<BTreeMap<String, CacheEntry>> {
+        let mut entries: BTreeMap<String, CacheEntry> = BTreeMap::new();
+        for file in fs::read_dir(self.root.join(Algorithm::Sha256.to_string()))? {
+            let file = file?;
+            let metadata = file.metadata()?;
+            let name = file.file_name().to_string_lossy().into_owned();
+            if !metadata.is_file() || name.starts_with('.') {
+                continue;
+            }
+
+            let encoded = name.split('-').next().unwrap_or_default().to_owned();
+            let entry = entries.entry(encoded.clone()).or_default();
+            entry.files += 1;
+            entry.bytes += metadata.len();
+            entry.paths.push(file.path());
+            if name == encoded {
+                entry.used = metadata.modified()?;
+            }
+        }
+
+        Ok(entries)
+    }
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    files: u64,
+    bytes: u64,
+    // The last time the content was materialized.
+    used: SystemTime,
+    paths: Vec<PathBuf>,
+}
+
+impl Default for CacheEntry {
+    fn default() -> Self {
+        CacheEntry {
+            files: 0,
+            bytes: 0,
+            used: SystemTime::UNIX_EPOCH,
+            paths: vec![],
+        }
+    }
+}
+
+// Creates `destination` as a reflink of `source`, or a copy where the
+// filesystem can't share blocks.
+fn clone_file(source: &Path, destination: &Path) -> io::Result<()> {
+    let mut source = File::open(source)?;
+    let mut destination = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(destination)?;
+
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+
+        // SAFETY: both descriptors are open for the duration of the call.
+        let result =
+            unsafe { libc::ioctl(destination.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) };
+        if result == 0 {
+            return Ok(());
+        }
+    }
+
+    io::copy(&mut source, &mut destination)?;
+    Ok(())
+}
+
+fn set_metadata(path: &Path, mode: u32, mtime: FileTime) -> io::Result<()> {
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    filetime::set_file_times(path, mtime, mtime)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::MetadataExt, time::Duration};
+
+    use filetime::FileTime;
+    use tempfile::TempDir;
+
+    use crate::cache::{CacheMode, CacheStats, FileCache};
+
+    #[test]
+    fn test_cache() {
+        let dir = TempDir::new().unwrap();
+        let cache = FileCache::open(dir.path().join("cache")).unwrap();
+        let digest = cache.insert(&mut &b"hello"[..]).unwrap();
+        assert_eq!(cache.insert(&mut &b"hello"[..]).unwrap(), digest);
+        let big = vec![7; (1 << 20) + 1];
+        let big_digest = cache.insert(&mut big.as_slice()).unwrap();
+        assert_eq!(
+            cache.stats().unwrap(),
+            CacheStats {
+                files: 2,
+                bytes: big.len() as u64 + 5
+            }
+        );
+
+        let copy = dir.path().join("copy");
+        cache.materialize(&digest, &copy, 0o100755, 1).unwrap();
+        assert_eq!(fs::read(&copy).unwrap(), b"hello");
+        let metadata = fs::metadata(&copy).unwrap();
+        assert_eq!((metadata.mode() & 0o7777, metadata.mtime()), (0o755, 1));
+
+        // Hardlinks share a read-only file per mode and mtime.
+        let cache = cache.mode(CacheMode::Hardlink);
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        cache.materialize(&big_digest, &a, 0o644, 1).unwrap();
+        cache.materialize(&big_digest, &b, 0o644, 1).unwrap();
+        let (a, b) = (fs::metadata(a).unwrap(), fs::metadata(b).unwrap());
+        assert_eq!((a.ino(), a.nlink(), a.mode() & 0o777), (b.ino(), 3, 0o444));
+
+        // The least recently used content goes first.
+        let old =
+            FileTime::from_system_time(std::time::SystemTime::now() - Duration::from_secs(60));
+        filetime::set_file_mtime(cache.path(&big_digest), old).unwrap();
+        assert_eq!(
+            cache.prune(5).unwrap(),
+            CacheStats {
+                files: 2,
+                bytes: 2 * big.len() as u64
+            }
+        );
+        assert_eq!(cache.stats().unwrap(), CacheStats { files: 1, bytes: 5 });
+    }
+}
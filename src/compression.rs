@@ -0,0 +1,129 @@
+use std::{
+    io::{self, Read, Write},
+    str::FromStr,
+};
+
+use anyhow::bail;
+use flate2::{read::GzDecoder, write::GzEncoder};
+
+use crate::spec::media_types::MediaType;
+
+/// Wraps a layer blob in the decoder matching its media type, yielding the
+/// uncompressed tar stream.
+pub fn decompress<'a, R: Read + 'a>(
+    media_type: &MediaType,
+    reader: R,
+) -> anyhow::Result<Box<dyn Read + 'a>> {
+    Ok(match media_type {
+        MediaType::ImageLayerTar | MediaType::ImageLayerNondistributableTar => Box::new(reader),
+        MediaType::ImageLayerTarGzip | MediaType::ImageLayerNondistributableTarGzip => {
+            Box::new(GzDecoder::new(reader))
+        }
+        MediaType::ImageLayerZstd | MediaType::ImageLayerNonDistributableZstd => {
+            Box::new(zstd::Decoder::new(reader)?)
+        }
+        media_type => bail!("{} is not a layer media type", media_type),
+    })
+}
+
+/// The compression applied to a tar stream being written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// The media type of a layer compressed this way.
+    pub fn layer_media_type(&self) -> MediaType {
+        match self {
+            Compression::None => MediaType::ImageLayerTar,
+            Compression::Gzip => MediaType::ImageLayerTarGzip,
+            Compression::Zstd => MediaType::ImageLayerZstd,
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            _ => bail!("unknown compression {:?}, expected none, gzip or zstd", s),
+        }
+    }
+}
+
+/// Compressor compresses what is written to it, `finish` must be called to
+/// flush the end of the stream.
+pub enum Compressor<W: Write> {
+    None(W),
+    Gzip(GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> Compressor<W> {
+    pub fn new(compression: Compression, writer: W) -> io::Result<Self> {
+        Ok(match compression {
+            Compression::None => Compressor::None(writer),
+            Compression::Gzip => {
+                Compressor::Gzip(GzEncoder::new(writer, flate2::Compression::default()))
+            }
+            Compression::Zstd => Compressor::Zstd(zstd::Encoder::new(writer, 0)?),
+        })
+    }
+
+    /// Ends the compressed stream and returns the inner writer.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Compressor::None(writer) => Ok(writer),
+            Compressor::Gzip(encoder) => encoder.finish(),
+            Compressor::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Compressor<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Compressor::None(writer) => writer.write(buf),
+            Compressor::Gzip(encoder) => encoder.write(buf),
+            Compressor::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Compressor::None(writer) => writer.flush(),
+            Compressor::Gzip(encoder) => encoder.flush(),
+            Compressor::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use crate::compression::{decompress, Compression, Compressor};
+
+    #[test]
+    fn test_compression_round_trip() {
+        for compression in [Compression::None, Compression::Gzip, Compression::Zstd] {
+            let mut compressor = Compressor::new(compression, Vec::new()).unwrap();
+            compressor.write_all(b"hello").unwrap();
+            let compressed = compressor.finish().unwrap();
+
+            let mut decompressed = Vec::new();
+            decompress(&compression.layer_media_type(), compressed.as_slice())
+                .unwrap()
+                .read_to_end(&mut decompressed)
+                .unwrap();
+            assert_eq!(decompressed, b"hello");
+        }
+    }
+}
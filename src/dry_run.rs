@@ -0,0 +1,308 @@
+use std::{
+    collections::BTreeMap,
+    io::Read,
+    path::{Component, Path, PathBuf},
+};
+
+use serde::Serialize;
+use tar::{Entry, EntryType};
+
+use crate::merge::{normalize, remove_lower, Whiteout};
+use crate::report::SkippedEntry;
+use crate::spec::digest::Digest;
+
+// Like the kernel, give up on resolving paths after this many symlinks.
+const MAX_SYMLINKS: usize = 40;
+
+/// The kind of an entry of the unpacked tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+    Hardlink,
+    Device,
+    Fifo,
+}
+
+impl EntryKind {
+    fn of(entry_type: EntryType) -> Self {
+        match entry_type {
+            EntryType::Directory => EntryKind::Directory,
+            EntryType::Symlink => EntryKind::Symlink,
+            EntryType::Link => EntryKind::Hardlink,
+            EntryType::Char | EntryType::Block => EntryKind::Device,
+            EntryType::Fifo => EntryKind::Fifo,
+            _ => EntryKind::File,
+        }
+    }
+}
+
+/// A path whose kind changes from one layer to a later one, e.g. a file
+/// replaced by a directory.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Conflict {
+    pub path: PathBuf,
+    /// The layer replacing the entry.
+    pub layer: Digest,
+    pub lower: EntryKind,
+    pub upper: EntryKind,
+}
+
+/// What unpacking an image would result in.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DryRunReport {
+    /// The number of entries of the final tree.
+    pub files: u64,
+    /// The size of the regular files of the final tree.
+    pub bytes: u64,
+    /// Entries which wouldn't be unpacked as they are in the layer.
+    pub refused: Vec<SkippedEntry>,
+    pub conflicts: Vec<Conflict>,
+}
+
+/// What simulating an entry did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Simulated {
+    Written,
+    WhiteoutApplied,
+    Refused,
+}
+
+#[derive(Debug)]
+struct Node {
+    kind: EntryKind,
+    size: u64,
+    layer: usize,
+    // The target of symlinks.
+    target: Option<PathBuf>,
+}
+
+/// Simulation applies layers to an in-memory tree, with the whiteout
+/// semantics of the image spec, to report what an unpack would do.
+#[derive(Debug, Default)]
+pub(crate) struct Simulation {
+    tree: BTreeMap<PathBuf, Node>,
+    // The number and digest of the layer being applied.
+    layer: usize,
+    digest: Option<Digest>,
+    refused: Vec<SkippedEntry>,
+    conflicts: Vec<Conflict>,
+}
+
+impl Simulation {
+    pub(crate) fn start_layer(&mut self, digest: &Digest) {
+        if self.digest.is_some() {
+            self.layer += 1;
+        }
+        self.digest = Some(digest.clone());
+    }
+
+    pub(crate) fn entry<R: Read>(&mut self, entry: &Entry<R>) -> anyhow::Result<Simulated> {
+        let header = entry.header();
+        let raw_path = entry.path()?;
+        if raw_path.components().any(|c| c == Component::ParentDir) {
+            return Ok(self.refuse(&raw_path, "outside of the destination"));
+        }
+        let path = normalize(&raw_path);
+        let (parent, name) = match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => (parent.to_path_buf(), name.to_owned()),
+            // The root itself.
+            _ => return Ok(Simulated::Written),
+        };
+
+        if let Some(whiteout) = Whiteout::of(&path) {
+            let layer = self.layer;
+            whiteout.apply(&mut self.tree, |node| node.layer < layer);
+            return Ok(Simulated::WhiteoutApplied);
+        }
+
+        // Entries under symlinks to directories land in their targets.
+        let path = match self.resolve(&parent, &mut 0) {
+            Some(parent) => parent.join(&name),
+            None => return Ok(self.refuse(&path, "escapes through a symlink")),
+        };
+        // Parents are created as needed, replacing what isn't a
+        // directory, even for entries refused below.
+        for ancestor in path
+            .ancestors()
+            .skip(1)
+            .filter(|a| !a.as_os_str().is_empty())
+        {
+            if self.tree.get(ancestor).map(|n| n.kind) != Some(EntryKind::Directory) {
+                self.insert(ancestor, EntryKind::Directory, 0, None);
+            }
+        }
+
+        let kind = EntryKind::of(header.entry_type());
+        let target = entry.link_name()?.map(|t| t.into_owned());
+        match kind {
+            EntryKind::Device => {
+                return Ok(self.refuse(&path, "device nodes need privileges"));
+            }
+            EntryKind::Hardlink => {
+                let target = target.as_deref().map(normalize).unwrap_or_default();
+                if self.resolve(&target, &mut 0).is_none() {
+                    return Ok(self.refuse(&path, "hardlink outside of the destination"));
+                }
+            }
+            _ => {}
+        }
+        if header.mode()? & 0o6000 != 0 {
+            self.refuse(&path, "setuid and setgid bits are dropped");
+        }
+
+        let size = match kind {
+            EntryKind::File => header.size()?,
+            _ => 0,
+        };
+        self.insert(&path, kind, size, target);
+
+        Ok(Simulated::Written)
+    }
+
+    pub(crate) fn report(&self) -> DryRunReport {
+        DryRunReport {
+            files: self.tree.len() as u64,
+            bytes: self.tree.values().map(|n| n.size).sum(),
+            refused: self.refused.clone(),
+            conflicts: self.conflicts.clone(),
+        }
+    }
+
+    fn refuse(&mut self, path: &Path, reason: &str) -> Simulated {
+        self.refused.push(SkippedEntry {
+            path: path.to_path_buf(),
+            reason: String::from(reason),
+        });
+        Simulated::Refused
+    }
+
+    fn insert(&mut self, path: &Path, kind: EntryKind, size: u64, target: Option<PathBuf>) {
+        if let Some(lower) = self.tree.get(path) {
+            if lower.layer < self.layer && lower.kind != kind {
+                self.conflicts.push(Conflict {
+                    path: path.to_path_buf(),
+                    layer: self.digest.clone().unwrap(),
+                    lower: lower.kind,
+                    upper: kind,
+                });
+            }
+        }
+        if kind != EntryKind::Directory {
+            // A file replacing a directory replaces its content too.
+            let layer = self.layer;
+            remove_lower(&mut self.tree, path, false, |node| node.layer < layer);
+        }
+        self.tree.insert(
+            path.to_path_buf(),
+            Node {
+                kind,
+                size,
+                layer: self.layer,
+                target,
+            },
+        );
+    }
+
+    // Follows the symlinks of the tree in `path`, returning None if it leaves
+    // the root. Absolute targets point outside of the destination when
+    // unpacking.
+    fn resolve(&self, path: &Path, followed: &mut usize) -> Option<PathBuf> {
+        let mut resolved = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => resolved.push(name),
+                Component::CurDir => continue,
+                Component::ParentDir if resolved.pop() => continue,
+                _ => return None,
+            }
+            if let Some(Node {
+                kind: EntryKind::Symlink,
+                target: Some(target),
+                ..
+            }) = self.tree.get(&resolved)
+            {
+                *followed += 1;
+                if *followed > MAX_SYMLINKS || target.is_absolute() {
+                    return None;
+                }
+                let link = resolved
+                    .parent()
+                    .unwrap_or_else(|| Path::new(""))
+                    .join(target);
+                resolved = self.resolve(&link, followed)?;
+            }
+        }
+
+        Some(resolved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::{
+        dry_run::{Conflict, EntryKind},
+        report::SkippedEntry,
+        test_utils::{gzip, sha256, tar, TestEntry, TestLayout},
+        unpacker::Unpacker,
+    };
+
+    #[test]
+    fn test_dry_run() {
+        let layout = TestLayout::new();
+        let top = tar(&[
+            TestEntry::Dir("etc/hostname/"),
+            TestEntry::File(".wh.tmp", b""),
+            TestEntry::File("lib/escape", b""),
+        ]);
+        layout.add_image(
+            &[
+                tar(&[
+                    TestEntry::File("etc/hostname", b"localhost"),
+                    TestEntry::File("tmp/a/b", b"b"),
+                    TestEntry::Symlink("lib", "../.."),
+                    TestEntry::CharDevice("dev/null", 1, 3),
+                ]),
+                top.clone(),
+            ],
+            None,
+        );
+
+        let destination = layout.dir.path().join("rootfs");
+        let report = Unpacker::new(layout.path(), destination.to_str().unwrap().to_owned())
+            .dry_run(true)
+            .unpack()
+            .unwrap();
+        assert!(!destination.exists());
+        let dry_run = report.dry_run.unwrap();
+        // etc, etc/hostname, dev and lib.
+        assert_eq!((dry_run.files, dry_run.bytes), (4, 0));
+        assert_eq!(
+            dry_run.refused,
+            [
+                SkippedEntry {
+                    path: PathBuf::from("dev/null"),
+                    reason: String::from("device nodes need privileges"),
+                },
+                SkippedEntry {
+                    path: PathBuf::from("lib/escape"),
+                    reason: String::from("escapes through a symlink"),
+                },
+            ]
+        );
+        assert_eq!(
+            dry_run.conflicts,
+            [Conflict {
+                path: PathBuf::from("etc/hostname"),
+                layer: sha256(&gzip(&top)),
+                lower: EntryKind::File,
+                upper: EntryKind::Directory,
+            }]
+        );
+        assert_eq!(report.manifests[0].layers[1].whiteouts_applied, 1);
+    }
+}
@@ -0,0 +1,210 @@
+use std::{
+    cmp,
+    io::{Seek, Write},
+};
+
+use tar::Builder;
+
+use crate::compression::{Compression, Compressor};
+use crate::merge::{read_verified, select_manifest, MergedLayers};
+use crate::pusher::split_image_tag;
+use crate::spec::config::Image;
+use crate::spec::index::Index;
+use crate::spec::manifest::Manifest;
+use crate::squashfs::SquashFsWriter;
+use crate::store::{BlobStore, DirectoryStore};
+
+/// Exporter writes the merged root filesystem of an image as a single tar
+/// stream, e.g. for `machinectl import-tar` or WSL. Nothing is written to
+/// disk, so no privileges are needed to preserve ownership or device nodes.
+#[derive(Debug)]
+pub struct Exporter<S: BlobStore = DirectoryStore> {
+    store: S,
+    tag: Option<String>,
+    compression: Compression,
+}
+
+impl Exporter {
+    /// `image` is `<layout>[:tag]`, the tag selects a manifest in `index.json`.
+    pub fn new(image: String) -> Self {
+        let (image_path, tag) = split_image_tag(&image);
+        Exporter::with_store(DirectoryStore::new(image_path)).tag(tag)
+    }
+}
+
+impl<S: BlobStore> Exporter<S> {
+    /// Creates an exporter reading the image from any blob store.
+    pub fn with_store(store: S) -> Self {
+        Exporter {
+            store,
+            tag: None,
+            compression: Compression::None,
+        }
+    }
+
+    /// Selects the manifest to export, required if the index has several.
+    pub fn tag(mut self, tag: Option<String>) -> Self {
+        self.tag = tag;
+        self
+    }
+
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Writes the tar stream to `writer`, and returns it.
+    pub fn export<W: Write>(&self, writer: W) -> anyhow::Result<W> {
+        let _lock = self.store.lock_shared()?;
+        let manifest = self.manifest()?;
+        let merged = MergedLayers::new(&self.store, &manifest.layers, false)?;
+        let mut builder = Builder::new(Compressor::new(self.compression, writer)?);
+        merged.write(&mut builder)?;
+
+        Ok(builder.into_inner()?.finish()?)
+    }
+
+    /// Writes a SquashFS image to `writer`, and returns it. Blocks are
+    /// compressed with the exporter's compression. The image's creation
+    /// time is used as the filesystem's, so the output is reproducible.
+    pub fn export_squashfs<W: Write + Seek>(&self, writer: W) -> anyhow::Result<W> {
+        let _lock = self.store.lock_shared()?;
+        let manifest = self.manifest()?;
+        let config: Image = serde_json::from_slice(&read_verified(&self.store, &manifest.config)?)?;
+        let mtime = config.created.map_or(0, |c| {
+            cmp::min(c.timestamp().max(0), u32::MAX as i64) as u32
+        });
+
+        let merged = MergedLayers::new(&self.store, &manifest.layers, false)?;
+        let mut squashfs = SquashFsWriter::new(writer, self.compression, mtime)?;
+        merged.walk(|entry| squashfs.add(entry))?;
+
+        squashfs.finish()
+    }
+
+    fn manifest(&self) -> anyhow::Result<Manifest> {
+        let index: Index = serde_json::from_reader(self.store.open_index()?)?;
+        let descriptor = select_manifest(&index, self.tag.as_deref(), "the layout")?;
+        Ok(serde_json::from_slice(&read_verified(
+            &self.store,
+            descriptor,
+        )?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, io::Read};
+
+    use tar::{Archive, EntryType, Header};
+
+    use crate::{
+        compression::{decompress, Compression},
+        export::Exporter,
+        test_utils::{tar, TestEntry, TestLayout},
+    };
+
+    struct Exported {
+        entry_type: EntryType,
+        uid: u64,
+        link: Option<String>,
+        contents: Vec<u8>,
+        xattrs: Vec<(String, Vec<u8>)>,
+    }
+
+    fn entries(tar: &[u8]) -> HashMap<String, Exported> {
+        let mut archive = Archive::new(tar);
+        archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let xattrs = match entry.pax_extensions().unwrap() {
+                    Some(extensions) => extensions
+                        .map(|e| e.unwrap())
+                        .map(|e| (e.key().unwrap().to_owned(), e.value_bytes().to_owned()))
+                        .collect(),
+                    None => vec![],
+                };
+                let mut contents = Vec::new();
+                entry.read_to_end(&mut contents).unwrap();
+                let exported = Exported {
+                    entry_type: entry.header().entry_type(),
+                    uid: entry.header().uid().unwrap(),
+                    link: entry
+                        .link_name()
+                        .unwrap()
+                        .map(|l| l.to_str().unwrap().to_owned()),
+                    contents,
+                    xattrs,
+                };
+                (entry.path().unwrap().to_str().unwrap().to_owned(), exported)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_export() {
+        // A binary owned by uid 1000 with a capability xattr.
+        let mut builder = tar::Builder::new(Vec::new());
+        builder
+            .append_pax_extensions([("SCHILY.xattr.security.capability", &b"cap"[..])])
+            .unwrap();
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Regular);
+        header.set_mode(0o755);
+        header.set_uid(1000);
+        header.set_size(4);
+        builder
+            .append_data(&mut header, "usr/bin/ping", &b"ping"[..])
+            .unwrap();
+        let base = builder.into_inner().unwrap();
+
+        let layout = TestLayout::new();
+        layout.add_image(
+            &[
+                base,
+                tar(&[
+                    TestEntry::File("etc/shadow", b"secret"),
+                    TestEntry::Link("etc/shadow-", "etc/shadow"),
+                    TestEntry::Link("etc/shadow.bak", "etc/shadow"),
+                ]),
+                tar(&[TestEntry::File("etc/.wh.shadow", b"")]),
+            ],
+            None,
+        );
+
+        let exported = Exporter::new(layout.path())
+            .compression(Compression::Zstd)
+            .export(Vec::new())
+            .unwrap();
+        let mut tar = Vec::new();
+        decompress(&Compression::Zstd.layer_media_type(), exported.as_slice())
+            .unwrap()
+            .read_to_end(&mut tar)
+            .unwrap();
+        let entries = entries(&tar);
+
+        let mut paths: Vec<&str> = entries.keys().map(String::as_str).collect();
+        paths.sort_unstable();
+        assert_eq!(paths, ["etc/shadow-", "etc/shadow.bak", "usr/bin/ping"]);
+
+        let ping = &entries["usr/bin/ping"];
+        assert_eq!((ping.uid, ping.contents.as_slice()), (1000, &b"ping"[..]));
+        assert_eq!(
+            ping.xattrs,
+            [(
+                String::from("SCHILY.xattr.security.capability"),
+                b"cap".to_vec()
+            )]
+        );
+
+        // The whited out file's data moves to the remaining hardlinks.
+        let shadow = &entries["etc/shadow-"];
+        assert_eq!(shadow.entry_type, EntryType::Regular);
+        assert_eq!(shadow.contents, b"secret");
+        let backup = &entries["etc/shadow.bak"];
+        assert_eq!(backup.entry_type, EntryType::Link);
+        assert_eq!(backup.link.as_deref(), Some("etc/shadow-"));
+    }
+}
@@ -0,0 +1,521 @@
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, Read},
+};
+
+use serde::Serialize;
+
+use crate::compression::decompress;
+use crate::gc::{find_referrers, Document};
+use crate::hash::{self, HashingReader};
+use crate::spec::config::Image;
+use crate::spec::descriptor::Descriptor;
+use crate::spec::digest::Digest;
+use crate::spec::index::{Index, INDEX_FILE_NAME};
+use crate::spec::layout::{ImageLayout, IMAGE_LAYOUT};
+use crate::spec::manifest::Manifest;
+use crate::spec::media_types::MediaType;
+use crate::spec::validation::{
+    validate_config, validate_index, validate_layout, validate_manifest, Violation,
+};
+use crate::store::{BlobStore, DirectoryStore};
+
+/// A problem found while checking a layout.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Problem {
+    /// `oci-layout` or `index.json` is missing or invalid.
+    InvalidLayout {
+        message: String,
+    },
+    /// A descriptor points at a blob which is not in the layout.
+    DanglingReference {
+        digest: Digest,
+        referenced_by: Option<Digest>,
+    },
+    SizeMismatch {
+        digest: Digest,
+        expected: u64,
+        actual: u64,
+    },
+    DigestMismatch {
+        digest: Digest,
+        actual: Digest,
+    },
+    DiffIdMismatch {
+        layer: Digest,
+        expected: Digest,
+        actual: Digest,
+    },
+    /// A document couldn't be parsed as its media type says it should.
+    InvalidDocument {
+        digest: Digest,
+        message: String,
+    },
+    SchemaViolation {
+        digest: Digest,
+        message: String,
+    },
+    /// The blob uses a digest algorithm or a media type which can't be checked.
+    Unsupported {
+        digest: Digest,
+        message: String,
+    },
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct FsckReport {
+    pub verified_blobs: usize,
+    pub problems: Vec<Problem>,
+    /// Blobs not reachable from `index.json`, these don't fail the check.
+    pub orphaned_blobs: Vec<Digest>,
+}
+
+impl FsckReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Fsck checks the integrity of a whole image layout: every blob reachable
+/// from `index.json` must exist with the expected size and digest, every
+/// document must parse, and every layer must decompress to its DiffID.
+#[derive(Debug)]
+pub struct Fsck {
+    store: DirectoryStore,
+}
+
+impl Fsck {
+    pub fn new(image_path: String) -> Self {
+        Fsck {
+            store: DirectoryStore::new(image_path),
+        }
+    }
+
+    pub fn check(&self) -> anyhow::Result<FsckReport> {
+        let mut report = FsckReport::default();
+        let mut visited = HashSet::new();
+        let _lock = self.store.lock_shared()?;
+
+        self.check_layout_file(&mut report);
+
+        let index = fs::read(self.store.root().join(INDEX_FILE_NAME))
+            .map_err(anyhow::Error::from)
+            .and_then(|bytes| Ok(serde_json::from_slice::<Index>(&bytes)?));
+        match index {
+            Ok(index) => {
+                for violation in validate_index(&index) {
+                    report.problems.push(Problem::InvalidLayout {
+                        message: format!("{}: {}", INDEX_FILE_NAME, violation),
+                    });
+                }
+                for manifest in &index.manifests {
+                    self.check_descriptor(manifest, None, &mut visited, &mut report)?;
+                }
+            }
+            Err(e) => report.problems.push(Problem::InvalidLayout {
+                message: format!("{}: {}", INDEX_FILE_NAME, e),
+            }),
+        }
+
+        // Referrers are reachable through their subject, as gc keeps them.
+        let blobs = self.store.blobs()?;
+        loop {
+            let referrers = find_referrers(&self.store, &blobs, &visited)?;
+            if referrers.is_empty() {
+                break;
+            }
+            for referrer in &referrers {
+                self.check_descriptor(referrer, None, &mut visited, &mut report)?;
+            }
+        }
+
+        report.orphaned_blobs = blobs
+            .into_iter()
+            .map(|(digest, _)| digest)
+            .filter(|d| !visited.contains(d))
+            .collect();
+
+        Ok(report)
+    }
+
+    fn check_layout_file(&self, report: &mut FsckReport) {
+        let layout = fs::read(self.store.root().join(IMAGE_LAYOUT))
+            .map_err(anyhow::Error::from)
+            .and_then(|bytes| Ok(serde_json::from_slice::<ImageLayout>(&bytes)?));
+        match layout {
+            Ok(layout) => {
+                for violation in validate_layout(&layout) {
+                    report.problems.push(Problem::InvalidLayout {
+                        message: format!("{}: {}", IMAGE_LAYOUT, violation),
+                    });
+                }
+            }
+            Err(e) => report.problems.push(Problem::InvalidLayout {
+                message: format!("{}: {}", IMAGE_LAYOUT, e),
+            }),
+        }
+    }
+
+    fn check_descriptor(
+        &self,
+        descriptor: &Descriptor,
+        parent: Option<&Digest>,
+        visited: &mut HashSet<Digest>,
+        report: &mut FsckReport,
+    ) -> anyhow::Result<()> {
+        if !visited.insert(descriptor.digest.clone()) {
+            return Ok(());
+        }
+
+        let bytes = match self.read_verified(descriptor, parent, report)? {
+            Some(bytes) => bytes,
+            None => return Ok(()),
+        };
+        let digest = &descriptor.digest;
+        // Docker documents share the OCI fields, but not the image-spec's rules.
+        let is_oci = matches!(
+            descriptor.media_type,
+            MediaType::ImageIndex | MediaType::ImageManifest
+        );
+
+        match Document::of(&descriptor.media_type) {
+            Some(Document::Index) => match serde_json::from_slice::<Index>(&bytes) {
+                Ok(index) => {
+                    if is_oci {
+                        schema_violations(digest, validate_index(&index), report);
+                    }
+                    for manifest in &index.manifests {
+                        self.check_descriptor(manifest, Some(digest), visited, report)?;
+                    }
+                }
+                Err(e) => report.problems.push(invalid_document(digest, e)),
+            },
+            Some(Document::Manifest) => match serde_json::from_slice::<Manifest>(&bytes) {
+                Ok(manifest) => {
+                    if is_oci {
+                        schema_violations(digest, validate_manifest(&manifest), report);
+                    }
+                    self.check_manifest(digest, &manifest, visited, report)?;
+                }
+                Err(e) => report.problems.push(invalid_document(digest, e)),
+            },
+            // What it references can't be told, as gc refuses to collect.
+            None => report.problems.push(Problem::Unsupported {
+                digest: digest.clone(),
+                message: format!("unsupported media type {}", descriptor.media_type),
+            }),
+        }
+
+        Ok(())
+    }
+
+    fn check_manifest(
+        &self,
+        digest: &Digest,
+        manifest: &Manifest,
+        visited: &mut HashSet<Digest>,
+        report: &mut FsckReport,
+    ) -> anyhow::Result<()> {
+        let config = &manifest.config;
+        let mut diff_ids = None;
+        visited.insert(config.digest.clone());
+        match self.read_verified(config, Some(digest), report)? {
+            // Artifacts have configs of their own types, if any.
+            Some(bytes) if config.media_type == MediaType::ImageConfig => {
+                match serde_json::from_slice::<Image>(&bytes) {
+                    Ok(image) => {
+                        schema_violations(
+                            &config.digest,
+                            validate_config(&image, manifest),
+                            report,
+                        );
+                        diff_ids = Some(image.rootfs.diff_ids);
+                    }
+                    Err(e) => report.problems.push(invalid_document(&config.digest, e)),
+                }
+            }
+            _ => {}
+        }
+
+        for (i, layer) in manifest.layers.iter().enumerate() {
+            if !visited.insert(layer.digest.clone()) {
+                continue;
+            }
+            let diff_id = diff_ids.as_ref().and_then(|d| d.get(i));
+            self.check_layer(layer, digest, diff_id, report)?;
+        }
+
+        Ok(())
+    }
+
+    // Reads a layer once, verifying both the compressed blob and its DiffID.
+    fn check_layer(
+        &self,
+        layer: &Descriptor,
+        parent: &Digest,
+        diff_id: Option<&Digest>,
+        report: &mut FsckReport,
+    ) -> anyhow::Result<()> {
+        let mut reader = match self.open_verifiable(layer, Some(parent), report)? {
+            Some(reader) => reader,
+            None => return Ok(()),
+        };
+
+        if let Some(diff_id) = diff_id {
+            match hash::hasher(&diff_id.algorithm) {
+                Ok(mut hasher) => match decompress(&layer.media_type, &mut reader) {
+                    Ok(mut tar) => {
+                        io::copy(&mut tar, &mut hasher)?;
+                        let actual = hasher.finish();
+                        if &actual != diff_id {
+                            report.problems.push(Problem::DiffIdMismatch {
+                                layer: layer.digest.clone(),
+                                expected: diff_id.clone(),
+                                actual,
+                            });
+                        }
+                    }
+                    Err(e) => report.problems.push(Problem::Unsupported {
+                        digest: layer.digest.clone(),
+                        message: e.to_string(),
+                    }),
+                },
+                Err(e) => report.problems.push(Problem::Unsupported {
+                    digest: diff_id.clone(),
+                    message: e.to_string(),
+                }),
+            }
+        }
+
+        // Compressed streams may carry trailing bytes the decoder didn't need.
+        io::copy(&mut reader, &mut io::sink())?;
+        self.finish_verification(layer, reader, report);
+
+        Ok(())
+    }
+
+    fn read_verified(
+        &self,
+        descriptor: &Descriptor,
+        parent: Option<&Digest>,
+        report: &mut FsckReport,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let mut reader = match self.open_verifiable(descriptor, parent, report)? {
+            Some(reader) => reader,
+            None => return Ok(None),
+        };
+
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        if self.finish_verification(descriptor, reader, report) {
+            Ok(Some(bytes))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn open_verifiable(
+        &self,
+        descriptor: &Descriptor,
+        parent: Option<&Digest>,
+        report: &mut FsckReport,
+    ) -> anyhow::Result<Option<HashingReader<Box<dyn Read + '_>>>> {
+        let digest = &descriptor.digest;
+        let hasher = match hash::hasher(&digest.algorithm) {
+            Ok(hasher) => hasher,
+            Err(e) => {
+                report.problems.push(Problem::Unsupported {
+                    digest: digest.clone(),
+                    message: e.to_string(),
+                });
+                return Ok(None);
+            }
+        };
+
+        let inner = match self.store.open(digest) {
+            Ok(inner) => inner,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                report.problems.push(Problem::DanglingReference {
+                    digest: digest.clone(),
+                    referenced_by: parent.cloned(),
+                });
+                return Ok(None);
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Some(HashingReader::new(inner, hasher)))
+    }
+
+    fn finish_verification<R: Read>(
+        &self,
+        descriptor: &Descriptor,
+        reader: HashingReader<R>,
+        report: &mut FsckReport,
+    ) -> bool {
+        report.verified_blobs += 1;
+        let mut ok = true;
+        let (actual, size) = reader.finish();
+
+        if size != descriptor.size {
+            report.problems.push(Problem::SizeMismatch {
+                digest: descriptor.digest.clone(),
+                expected: descriptor.size,
+                actual: size,
+            });
+            ok = false;
+        }
+
+        if actual != descriptor.digest {
+            report.problems.push(Problem::DigestMismatch {
+                digest: descriptor.digest.clone(),
+                actual,
+            });
+            ok = false;
+        }
+
+        ok
+    }
+}
+
+fn schema_violations(digest: &Digest, violations: Vec<Violation>, report: &mut FsckReport) {
+    for violation in violations {
+        report.problems.push(Problem::SchemaViolation {
+            digest: digest.clone(),
+            message: violation.to_string(),
+        });
+    }
+}
+
+fn invalid_document(digest: &Digest, e: serde_json::Error) -> Problem {
+    Problem::InvalidDocument {
+        digest: digest.clone(),
+        message: e.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::{
+        fsck::{Fsck, Problem},
+        gc::GarbageCollector,
+        spec::{layout::IMAGE_LAYOUT, media_types::MediaType},
+        store::DirectoryStore,
+        test_utils::{descriptor, gzip, sha256, tar, TestEntry, TestLayout},
+    };
+
+    #[test]
+    fn test_fsck_valid_layout() {
+        let layout = TestLayout::new();
+        layout.add_image(&[tar(&[TestEntry::File("a", b"a")])], Some("latest"));
+
+        let report = Fsck::new(layout.path()).check().unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        // manifest, config and layer
+        assert_eq!(report.verified_blobs, 3);
+        assert!(report.orphaned_blobs.is_empty());
+    }
+
+    #[test]
+    fn test_fsck_problems() {
+        let layout = TestLayout::new();
+        let layer = tar(&[TestEntry::File("a", b"a")]);
+        let manifest = layout.add_image(std::slice::from_ref(&layer), Some("latest"));
+        let orphan = layout.write_blob(MediaType::ImageLayerTar, b"orphan");
+        let store = DirectoryStore::new(layout.path());
+        let layer_digest = sha256(&gzip(&layer));
+
+        // Corrupt the layer, and reference a blob that doesn't exist.
+        fs::write(store.blob_path(&layer_digest), gzip(b"corrupt")).unwrap();
+        let missing = descriptor(MediaType::ImageManifest, b"missing");
+        layout.add_to_index(missing.clone(), None);
+        fs::remove_file(layout.dir.path().join(IMAGE_LAYOUT)).unwrap();
+
+        let report = Fsck::new(layout.path()).check().unwrap();
+        assert!(!report.is_ok());
+        assert!(matches!(report.problems[0], Problem::InvalidLayout { .. }));
+        assert!(report.problems.iter().any(|p| matches!(
+            p,
+            Problem::DigestMismatch { digest, .. } if digest == &layer_digest
+        )));
+        assert!(report.problems.iter().any(|p| matches!(
+            p,
+            Problem::DiffIdMismatch { layer, .. } if layer == &layer_digest
+        )));
+        assert!(report.problems.contains(&Problem::DanglingReference {
+            digest: missing.digest,
+            referenced_by: None,
+        }));
+        assert!(!report.problems.iter().any(
+            |p| matches!(p, Problem::DanglingReference { digest, .. } if digest == &manifest.digest)
+        ));
+        assert_eq!(report.orphaned_blobs, vec![orphan.digest]);
+    }
+
+    #[test]
+    fn test_fsck_docker_manifests_and_referrers() {
+        let layout = TestLayout::new();
+        let layer = layout.write_blob(
+            MediaType::Other(String::from("application/vnd.docker.image.rootfs.diff.tar")),
+            &tar(&[TestEntry::File("hello", b"world")]),
+        );
+        let config = layout.write_blob(
+            MediaType::Other(String::from(
+                "application/vnd.docker.container.image.v1+json",
+            )),
+            b"{}",
+        );
+        let manifest = layout.write_blob(
+            MediaType::Other(String::from(
+                "application/vnd.docker.distribution.manifest.v2+json",
+            )),
+            serde_json::to_string(&serde_json::json!({
+                "schemaVersion": 2,
+                "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+                "config": config,
+                "layers": [layer],
+            }))
+            .unwrap()
+            .as_bytes(),
+        );
+        layout.add_to_index(manifest.clone(), None);
+        let image = layout.add_image(&[tar(&[TestEntry::File("a", b"a")])], None);
+        // Only reachable through its subject, like a signature.
+        let empty = layout.write_blob(MediaType::EmptyJson, b"{}");
+        let referrer = layout.write_blob(
+            MediaType::ImageManifest,
+            serde_json::to_string(&serde_json::json!({
+                "schemaVersion": 2,
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "artifactType": "application/vnd.example.sbom",
+                "config": empty,
+                "layers": [empty],
+                "subject": image,
+            }))
+            .unwrap()
+            .as_bytes(),
+        );
+
+        let report = Fsck::new(layout.path()).check().unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        // the Docker manifest, config and layer, the image's three blobs, the
+        // referrer and the empty blob
+        assert_eq!(report.verified_blobs, 8);
+        assert!(
+            report.orphaned_blobs.is_empty(),
+            "{:?}",
+            report.orphaned_blobs
+        );
+
+        let gc = GarbageCollector::new(layout.path())
+            .dry_run(true)
+            .collect()
+            .unwrap();
+        assert!(gc.collected.is_empty());
+        assert!(!report.orphaned_blobs.contains(&referrer.digest));
+    }
+}
@@ -0,0 +1,338 @@
+use std::{
+    collections::HashSet,
+    fs,
+    io::{BufReader, Read},
+};
+
+use anyhow::{bail, Context};
+use serde::Deserialize;
+
+use crate::lock::LayoutLock;
+use crate::spec::descriptor::Descriptor;
+use crate::spec::digest::Digest;
+use crate::spec::index::Index;
+use crate::spec::manifest::Manifest;
+use crate::spec::media_types::MediaType;
+use crate::store::{BlobStat, BlobStore, DirectoryStore};
+
+// Blobs larger than this are never considered as referrer manifests.
+const MAX_REFERRER_SIZE: u64 = 4 * 1024 * 1024;
+// Docker's manifest list and manifest, with the same fields as the OCI
+// index and manifest.
+const DOCKER_MANIFEST_LIST: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+const DOCKER_MANIFEST: &str = "application/vnd.docker.distribution.manifest.v2+json";
+
+// Just enough of a manifest or an index to tell whether it refers to
+// another manifest through its `subject`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ReferrerProbe {
+    media_type: Option<MediaType>,
+    subject: Option<Descriptor>,
+    manifests: Option<serde_json::Value>,
+}
+
+/// The documents whose references are followed, OCI or Docker ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Document {
+    Index,
+    Manifest,
+}
+
+impl Document {
+    /// Returns the kind of document of a media type, or `None` if what it
+    /// references is unknown.
+    pub(crate) fn of(media_type: &MediaType) -> Option<Document> {
+        match media_type {
+            MediaType::ImageIndex => Some(Document::Index),
+            MediaType::ImageManifest => Some(Document::Manifest),
+            MediaType::Other(media_type) if media_type == DOCKER_MANIFEST_LIST => {
+                Some(Document::Index)
+            }
+            MediaType::Other(media_type) if media_type == DOCKER_MANIFEST => {
+                Some(Document::Manifest)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A blob removed, or that would be removed, by garbage collection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CollectedBlob {
+    pub digest: Digest,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GcReport {
+    pub collected: Vec<CollectedBlob>,
+    pub reclaimed_bytes: u64,
+}
+
+/// GarbageCollector deletes blobs of an image layout which can't be reached
+/// from its `index.json`.
+#[derive(Debug)]
+pub struct GarbageCollector {
+    store: DirectoryStore,
+    dry_run: bool,
+}
+
+impl GarbageCollector {
+    pub fn new(image_path: String) -> Self {
+        GarbageCollector {
+            store: DirectoryStore::new(image_path),
+            dry_run: false,
+        }
+    }
+
+    /// Only report what would be deleted.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    pub fn collect(&self) -> anyhow::Result<GcReport> {
+        let _lock = LayoutLock::exclusive(self.store.root())?;
+
+        let blobs = self.store.blobs()?;
+        let mut reachable = reachable(&self.store)?;
+        // Manifests that refer to a reachable manifest through their `subject`
+        // (signatures, SBOMs...) are kept along with everything they reference.
+        loop {
+            let referrers = find_referrers(&self.store, &blobs, &reachable)?;
+            if referrers.is_empty() {
+                break;
+            }
+            for referrer in &referrers {
+                mark(&self.store, referrer, &mut reachable)?;
+            }
+        }
+
+        let mut report = GcReport::default();
+        for (digest, stat) in blobs {
+            if reachable.contains(&digest) {
+                continue;
+            }
+
+            if !self.dry_run {
+                fs::remove_file(self.store.blob_path(&digest))?;
+            }
+            report.reclaimed_bytes += stat.size;
+            report.collected.push(CollectedBlob {
+                digest,
+                size: stat.size,
+            });
+        }
+
+        Ok(report)
+    }
+}
+
+/// Returns descriptors of the manifests and indexes among `blobs` which
+/// aren't reachable, but refer to a reachable manifest through their
+/// `subject`. Referrers of referrers are found by calling it again.
+pub(crate) fn find_referrers<S: BlobStore>(
+    store: &S,
+    blobs: &[(Digest, BlobStat)],
+    reachable: &HashSet<Digest>,
+) -> anyhow::Result<Vec<Descriptor>> {
+    let mut referrers = Vec::new();
+    for (digest, stat) in blobs {
+        if reachable.contains(digest) || stat.size > MAX_REFERRER_SIZE {
+            continue;
+        }
+
+        let mut bytes = Vec::new();
+        store.open(digest)?.read_to_end(&mut bytes)?;
+        let probe: ReferrerProbe = match serde_json::from_slice(&bytes) {
+            Ok(probe) => probe,
+            Err(_) => continue,
+        };
+        match probe.subject {
+            Some(subject) if reachable.contains(&subject.digest) => {}
+            _ => continue,
+        }
+
+        // Documents without a media type are told apart by their fields.
+        let media_type = match (probe.media_type, probe.manifests) {
+            (Some(media_type), _) => media_type,
+            (None, Some(_)) => MediaType::ImageIndex,
+            (None, None) => MediaType::ImageManifest,
+        };
+        referrers.push(Descriptor {
+            media_type,
+            digest: digest.clone(),
+            size: stat.size,
+            urls: None,
+            annotations: None,
+            platform: None,
+            data: None,
+            artifact_type: None,
+        });
+    }
+
+    Ok(referrers)
+}
+
+/// Returns the digests of every blob reachable from the `index.json` of the
+/// store, following nested indexes down to configs and layers.
+pub fn reachable<S: BlobStore>(store: &S) -> anyhow::Result<HashSet<Digest>> {
+    let index: Index = serde_json::from_reader(BufReader::new(store.open_index()?))?;
+    let mut reachable = HashSet::new();
+    for manifest in &index.manifests {
+        mark(store, manifest, &mut reachable)?;
+    }
+
+    Ok(reachable)
+}
+
+fn mark<S: BlobStore>(
+    store: &S,
+    descriptor: &Descriptor,
+    reachable: &mut HashSet<Digest>,
+) -> anyhow::Result<()> {
+    if !reachable.insert(descriptor.digest.clone()) {
+        return Ok(());
+    }
+
+    // A missing index or manifest hides what it references, so collecting
+    // anything would be unsafe. So does one of an unknown media type.
+    let document = match Document::of(&descriptor.media_type) {
+        Some(document) => document,
+        None => bail!(
+            "{} has unsupported media type {}, its references are unknown",
+            descriptor.digest,
+            descriptor.media_type
+        ),
+    };
+    if document == Document::Index {
+        let reader = store
+            .open(&descriptor.digest)
+            .with_context(|| format!("index {} is missing", descriptor.digest))?;
+        let index: Index = serde_json::from_reader(BufReader::new(reader))?;
+        for manifest in &index.manifests {
+            mark(store, manifest, reachable)?;
+        }
+    } else {
+        let reader = store
+            .open(&descriptor.digest)
+            .with_context(|| format!("manifest {} is missing", descriptor.digest))?;
+        let manifest: Manifest = serde_json::from_reader(BufReader::new(reader))?;
+        reachable.insert(manifest.config.digest);
+        for layer in manifest.layers {
+            reachable.insert(layer.digest);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        gc::GarbageCollector,
+        spec::media_types::MediaType,
+        store::{BlobStore, DirectoryStore},
+        test_utils::{tar, TestEntry, TestLayout},
+    };
+
+    #[test]
+    fn test_gc() {
+        let layout = TestLayout::new();
+        let shared = tar(&[TestEntry::File("shared", b"shared")]);
+        let kept = layout.add_image(std::slice::from_ref(&shared), Some("kept"));
+        let dropped = layout.write_image(&[shared, tar(&[TestEntry::File("gone", b"gone")])]);
+        let orphan = layout.write_blob(MediaType::ImageLayerTar, b"orphan");
+        let referrer = layout.write_blob(
+            MediaType::ImageManifest,
+            format!(
+                r#"{{"schemaVersion":2,"config":{{"mediaType":"application/vnd.oci.image.config.v1+json","size":2,"digest":"{}"}},"layers":[],"subject":{{"mediaType":"application/vnd.oci.image.manifest.v1+json","size":{},"digest":"{}"}}}}"#,
+                layout.write_blob(MediaType::ImageConfig, b"{}").digest,
+                kept.size,
+                kept.digest
+            )
+            .as_bytes(),
+        );
+        let store = DirectoryStore::new(layout.path());
+
+        let report = GarbageCollector::new(layout.path())
+            .dry_run(true)
+            .collect()
+            .unwrap();
+        // the dropped manifest, its config, its own layer and the orphan
+        assert_eq!(report.collected.len(), 4);
+        assert!(report.reclaimed_bytes > dropped.size + orphan.size);
+        assert!(store.exists(&dropped.digest));
+
+        let collected = GarbageCollector::new(layout.path()).collect().unwrap();
+        assert_eq!(collected, report);
+        assert!(!store.exists(&dropped.digest));
+        assert!(!store.exists(&orphan.digest));
+        assert!(store.exists(&kept.digest));
+        assert!(store.exists(&referrer.digest));
+
+        let report = GarbageCollector::new(layout.path()).collect().unwrap();
+        assert!(report.collected.is_empty());
+    }
+
+    #[test]
+    fn test_gc_docker_manifests() {
+        let layout = TestLayout::new();
+        let layer = layout.write_blob(
+            MediaType::Other(String::from("application/vnd.docker.image.rootfs.diff.tar")),
+            &tar(&[TestEntry::File("hello", b"world")]),
+        );
+        let config = layout.write_blob(
+            MediaType::Other(String::from(
+                "application/vnd.docker.container.image.v1+json",
+            )),
+            b"{}",
+        );
+        let manifest = layout.write_blob(
+            MediaType::Other(String::from(
+                "application/vnd.docker.distribution.manifest.v2+json",
+            )),
+            serde_json::to_string(&serde_json::json!({
+                "schemaVersion": 2,
+                "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+                "config": config,
+                "layers": [layer],
+            }))
+            .unwrap()
+            .as_bytes(),
+        );
+        let list = layout.write_blob(
+            MediaType::Other(String::from(
+                "application/vnd.docker.distribution.manifest.list.v2+json",
+            )),
+            serde_json::to_string(&serde_json::json!({
+                "schemaVersion": 2,
+                "mediaType": "application/vnd.docker.distribution.manifest.list.v2+json",
+                "manifests": [manifest],
+            }))
+            .unwrap()
+            .as_bytes(),
+        );
+        layout.add_to_index(list, None);
+
+        let report = GarbageCollector::new(layout.path()).collect().unwrap();
+        assert!(report.collected.is_empty());
+        let store = DirectoryStore::new(layout.path());
+        assert!(store.exists(&layer.digest));
+
+        // What an unknown manifest references can't be told.
+        let unknown = layout.write_blob(
+            MediaType::Other(String::from("application/vnd.example.manifest+json")),
+            br#"{"blobs":[]}"#,
+        );
+        layout.add_to_index(unknown, None);
+        let err = GarbageCollector::new(layout.path()).collect().unwrap_err();
+        assert!(
+            err.to_string().contains("unsupported media type"),
+            "{}",
+            err
+        );
+        assert!(store.exists(&layer.digest));
+    }
+}
@@ -0,0 +1,320 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, Read, Write},
+    sync::{OnceLock, RwLock},
+};
+
+use sha2::{Digest as _, Sha256, Sha512};
+use thiserror::Error;
+
+use crate::spec::digest::{Algorithm, Digest};
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum HashError {
+    #[error("unsupported digest algorithm {0}")]
+    Unsupported(Algorithm),
+}
+
+/// A streaming hasher for one digest algorithm. Data is fed through `Write`.
+pub trait DigestHasher: Write + Send {
+    fn algorithm(&self) -> Algorithm;
+
+    /// Consumes the hasher, returning the digest of everything written.
+    fn finish(self: Box<Self>) -> Digest;
+}
+
+/// Creates a new hasher for an algorithm.
+pub type HasherFactory = fn() -> Box<dyn DigestHasher>;
+
+macro_rules! rust_crypto_hasher {
+    ($name:ident, $inner:ty, $algorithm:expr) => {
+        #[derive(Default)]
+        struct $name($inner);
+
+        impl Write for $name {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.update(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        impl DigestHasher for $name {
+            fn algorithm(&self) -> Algorithm {
+                $algorithm
+            }
+
+            fn finish(self: Box<Self>) -> Digest {
+                Digest::new($algorithm, format!("{:x}", self.0.finalize()))
+            }
+        }
+    };
+}
+
+rust_crypto_hasher!(Sha256Hasher, Sha256, Algorithm::Sha256);
+rust_crypto_hasher!(Sha512Hasher, Sha512, Algorithm::Sha512);
+
+#[cfg(feature = "blake3")]
+#[derive(Default)]
+struct Blake3Hasher(blake3::Hasher);
+
+#[cfg(feature = "blake3")]
+impl Write for Blake3Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "blake3")]
+impl DigestHasher for Blake3Hasher {
+    fn algorithm(&self) -> Algorithm {
+        Algorithm::Blake3
+    }
+
+    fn finish(self: Box<Self>) -> Digest {
+        Digest::new(
+            Algorithm::Blake3,
+            self.0.finalize().to_hex().as_str().to_owned(),
+        )
+    }
+}
+
+/// HasherRegistry maps digest algorithms to hasher implementations.
+/// The default registry knows sha256 and sha512, and blake3 when the
+/// `blake3` feature is enabled; more can be registered.
+#[derive(Clone)]
+pub struct HasherRegistry {
+    factories: HashMap<Algorithm, HasherFactory>,
+}
+
+impl HasherRegistry {
+    /// Creates a registry without any algorithm.
+    pub fn empty() -> Self {
+        HasherRegistry {
+            factories: HashMap::new(),
+        }
+    }
+
+    pub fn register(&mut self, algorithm: Algorithm, factory: HasherFactory) {
+        self.factories.insert(algorithm, factory);
+    }
+
+    pub fn supports(&self, algorithm: &Algorithm) -> bool {
+        self.factories.contains_key(algorithm)
+    }
+
+    pub fn hasher(&self, algorithm: &Algorithm) -> Result<Box<dyn DigestHasher>, HashError> {
+        self.factories
+            .get(algorithm)
+            .map(|factory| factory())
+            .ok_or_else(|| HashError::Unsupported(algorithm.clone()))
+    }
+}
+
+impl Default for HasherRegistry {
+    fn default() -> Self {
+        let mut registry = HasherRegistry::empty();
+        registry.register(Algorithm::Sha256, || Box::new(Sha256Hasher::default()));
+        registry.register(Algorithm::Sha512, || Box::new(Sha512Hasher::default()));
+        #[cfg(feature = "blake3")]
+        registry.register(Algorithm::Blake3, || Box::new(Blake3Hasher::default()));
+        registry
+    }
+}
+
+impl fmt::Debug for HasherRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.factories.keys()).finish()
+    }
+}
+
+// The registry behind `hasher`, starting as the default one.
+static REGISTRY: OnceLock<RwLock<HasherRegistry>> = OnceLock::new();
+
+fn registry() -> &'static RwLock<HasherRegistry> {
+    REGISTRY.get_or_init(|| RwLock::new(HasherRegistry::default()))
+}
+
+/// Registers a hasher for the whole process, so digests of `algorithm` can
+/// be verified everywhere blobs are read.
+pub fn register(algorithm: Algorithm, factory: HasherFactory) {
+    registry().write().unwrap().register(algorithm, factory);
+}
+
+/// Returns a hasher from the process-wide registry: the default one, plus
+/// what was added with [`register`].
+pub fn hasher(algorithm: &Algorithm) -> Result<Box<dyn DigestHasher>, HashError> {
+    registry().read().unwrap().hasher(algorithm)
+}
+
+/// Computes the digest of a byte slice.
+pub fn digest_bytes(algorithm: &Algorithm, bytes: &[u8]) -> Result<Digest, HashError> {
+    let mut hasher = hasher(algorithm)?;
+    // Writing to a hasher never fails.
+    hasher.write_all(bytes).unwrap();
+    Ok(hasher.finish())
+}
+
+/// HashingReader hashes and counts everything read through it.
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Box<dyn DigestHasher>,
+    size: u64,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R, hasher: Box<dyn DigestHasher>) -> Self {
+        HashingReader {
+            inner,
+            hasher,
+            size: 0,
+        }
+    }
+
+    /// The number of bytes read so far.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns the digest and the size of everything read.
+    pub fn finish(self) -> (Digest, u64) {
+        (self.hasher.finish(), self.size)
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.write_all(&buf[..n])?;
+        self.size += n as u64;
+        Ok(n)
+    }
+}
+
+/// HashingWriter hashes and counts everything written through it.
+pub struct HashingWriter<W> {
+    inner: W,
+    hasher: Box<dyn DigestHasher>,
+    size: u64,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W, hasher: Box<dyn DigestHasher>) -> Self {
+        HashingWriter {
+            inner,
+            hasher,
+            size: 0,
+        }
+    }
+
+    /// Returns the inner writer, and the digest and size of everything
+    /// written.
+    pub fn finish(self) -> (W, Digest, u64) {
+        (self.inner, self.hasher.finish(), self.size)
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.write_all(&buf[..n])?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read, Write};
+
+    use sha2::{Digest as _, Sha256};
+
+    use crate::{
+        hash::{
+            digest_bytes, hasher, register, DigestHasher, HashError, HasherRegistry, HashingReader,
+            HashingWriter,
+        },
+        spec::digest::{Algorithm, Digest},
+    };
+
+    #[test]
+    fn test_hashers() {
+        assert_eq!(
+            digest_bytes(&Algorithm::Sha256, b"").unwrap().to_string(),
+            "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            digest_bytes(&Algorithm::Sha512, b"").unwrap().encoded,
+            "cf83e1357eefb8bdf1542850d66d8007d620e4050b5715dc83f4a921d36ce9ce\
+             47d0d13c5d85f2b0ff8318d2877eec2f63b931bd47417a81a538327af927da3e"
+        );
+
+        let unregistered = Algorithm::Unregistered(String::from("md5"));
+        assert_eq!(
+            hasher(&unregistered).err(),
+            Some(HashError::Unsupported(unregistered.clone()))
+        );
+        assert!(!HasherRegistry::empty().supports(&Algorithm::Sha256));
+    }
+
+    #[test]
+    fn test_register() {
+        rust_crypto_hasher!(
+            Sha256Alias,
+            Sha256,
+            Algorithm::Unregistered(String::from("sha256-alias"))
+        );
+
+        let alias = Algorithm::Unregistered(String::from("sha256-alias"));
+        assert!(digest_bytes(&alias, b"").is_err());
+        register(alias.clone(), || Box::new(Sha256Alias::default()));
+        assert_eq!(
+            digest_bytes(&alias, b"").unwrap().to_string(),
+            "sha256-alias:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    fn test_hashing_reader() {
+        let mut reader = HashingReader::new(&b"hello"[..], hasher(&Algorithm::Sha256).unwrap());
+        io::copy(&mut reader.by_ref(), &mut io::sink()).unwrap();
+        assert_eq!(reader.size(), 5);
+        let (digest, size) = reader.finish();
+        assert_eq!(digest, digest_bytes(&Algorithm::Sha256, b"hello").unwrap());
+        assert_eq!(size, 5);
+
+        let mut writer = HashingWriter::new(Vec::new(), hasher(&Algorithm::Sha256).unwrap());
+        writer.write_all(b"hello").unwrap();
+        let (inner, written, size) = writer.finish();
+        assert_eq!((inner.as_slice(), size), (&b"hello"[..], 5));
+        assert_eq!(written, digest);
+    }
+
+    #[cfg(feature = "blake3")]
+    #[test]
+    fn test_blake3() {
+        assert_eq!(
+            digest_bytes(&Algorithm::Blake3, b"").unwrap().to_string(),
+            "blake3:af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+        );
+    }
+
+    #[cfg(not(feature = "blake3"))]
+    #[test]
+    fn test_blake3_unsupported() {
+        assert!(hasher(&Algorithm::Blake3).is_err());
+    }
+}
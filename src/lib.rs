@@ -1,2 +1,29 @@
+#[cfg(feature = "async")]
+pub mod async_unpacker;
+pub mod cache;
+pub mod compression;
+pub mod dry_run;
+pub mod export;
+pub mod fsck;
+pub mod gc;
+pub mod hash;
+pub mod limits;
+pub mod lock;
+pub mod merge;
+pub mod process;
+pub mod progress;
+pub mod pusher;
+pub mod referrers;
+pub mod registry;
+pub mod report;
+pub mod signature;
+pub mod signer;
 pub mod spec;
+pub mod squash;
+pub mod squashfs;
+pub mod store;
 pub mod unpacker;
+pub mod validate;
+
+#[cfg(test)]
+mod test_utils;
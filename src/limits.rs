@@ -0,0 +1,413 @@
+use std::{
+    cmp,
+    collections::HashSet,
+    error::Error as StdError,
+    fs,
+    io::{self, Read},
+    path::{Component, Path, PathBuf},
+};
+
+use thiserror::Error;
+
+use crate::spec::digest::Digest;
+
+const KIB: u64 = 1024;
+const MIB: u64 = 1024 * KIB;
+const GIB: u64 = 1024 * MIB;
+
+/// Limits bound the resources an image may consume while being unpacked,
+/// so that hostile images (zip bombs, huge manifests...) are refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Maximum size of `index.json`, indexes and manifests.
+    pub max_manifest_size: u64,
+    /// Maximum size of an image config.
+    pub max_config_size: u64,
+    /// Maximum number of bytes a single layer may decompress to.
+    pub max_layer_size: u64,
+    /// Maximum number of bytes all layers together may decompress to.
+    pub max_total_size: u64,
+    /// Maximum number of tar entries across all layers.
+    pub max_entries: u64,
+    /// Maximum length of an entry path, in bytes.
+    pub max_path_length: usize,
+    /// Maximum number of components of an entry path.
+    pub max_path_depth: usize,
+    /// Maximum number of symlinks followed when resolving a symlink.
+    pub max_symlink_chain: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_manifest_size: 4 * MIB,
+            max_config_size: 8 * MIB,
+            max_layer_size: 32 * GIB,
+            max_total_size: 128 * GIB,
+            max_entries: 4_000_000,
+            max_path_length: 4096,
+            max_path_depth: 256,
+            // Same as the kernel's MAXSYMLINKS
+            max_symlink_chain: 40,
+        }
+    }
+}
+
+impl Limits {
+    /// Limits that never trigger.
+    pub fn unlimited() -> Self {
+        Limits {
+            max_manifest_size: u64::MAX,
+            max_config_size: u64::MAX,
+            max_layer_size: u64::MAX,
+            max_total_size: u64::MAX,
+            max_entries: u64::MAX,
+            max_path_length: usize::MAX,
+            max_path_depth: usize::MAX,
+            max_symlink_chain: usize::MAX,
+        }
+    }
+
+    pub fn check_path(&self, path: &Path) -> Result<(), LimitError> {
+        let length = path.as_os_str().len();
+        if length > self.max_path_length {
+            return Err(LimitError::PathTooLong {
+                path: path.to_owned(),
+                limit: self.max_path_length,
+            });
+        }
+
+        let depth = path
+            .components()
+            .filter(|c| matches!(c, Component::Normal(_)))
+            .count();
+        if depth > self.max_path_depth {
+            return Err(LimitError::PathTooDeep {
+                path: path.to_owned(),
+                limit: self.max_path_depth,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Follows the symlink at `path`, relative to `root`, failing if the
+    /// chain is longer than allowed or loops. Targets are resolved inside
+    /// `root`.
+    pub fn check_symlink_chain(&self, root: &Path, path: &Path) -> Result<(), LimitError> {
+        resolve_in_root(root, path, self.max_symlink_chain).map(|_| ())
+    }
+}
+
+// What is left to resolve: a path, or the end of the target of a link.
+enum Pending {
+    Path(PathBuf),
+    Link(PathBuf),
+}
+
+/// Follows the symlinks in `path` component by component as if `root` were
+/// `/`, so neither links in `root` nor the host's lead outside of it.
+/// Fails after `max_symlinks` links, or on a link whose target goes through
+/// itself.
+pub(crate) fn resolve_in_root(
+    root: &Path,
+    path: &Path,
+    max_symlinks: usize,
+) -> Result<PathBuf, LimitError> {
+    let mut pending = vec![Pending::Path(path.to_path_buf())];
+    // The links whose targets are being resolved.
+    let mut following = HashSet::new();
+    let mut resolved = PathBuf::new();
+    let mut followed = 0;
+
+    while let Some(next) = pending.pop() {
+        let next = match next {
+            Pending::Path(next) => next,
+            Pending::Link(link) => {
+                following.remove(&link);
+                continue;
+            }
+        };
+        let mut components = next.components();
+        while let Some(component) = components.next() {
+            match component {
+                Component::Normal(name) => resolved.push(name),
+                Component::ParentDir => {
+                    resolved.pop();
+                    continue;
+                }
+                Component::RootDir => {
+                    resolved = PathBuf::new();
+                    continue;
+                }
+                _ => continue,
+            }
+            // Anything but a symlink fails to be read as one.
+            let target = match fs::read_link(root.join(&resolved)) {
+                Ok(target) => target,
+                Err(_) => continue,
+            };
+            followed += 1;
+            if followed > max_symlinks {
+                return Err(LimitError::SymlinkChainTooLong {
+                    path: path.to_owned(),
+                    limit: max_symlinks,
+                });
+            }
+            if !following.insert(resolved.clone()) {
+                return Err(LimitError::SymlinkLoop {
+                    path: path.to_owned(),
+                });
+            }
+            // Resolve the target from the link's directory, then what was
+            // left of the path.
+            pending.push(Pending::Path(components.as_path().to_path_buf()));
+            pending.push(Pending::Link(resolved.clone()));
+            pending.push(Pending::Path(target));
+            resolved.pop();
+            break;
+        }
+    }
+
+    Ok(root.join(resolved))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum LimitError {
+    #[error("blob {digest} is larger than its descriptor size of {size} bytes")]
+    BlobSizeExceeded { digest: Digest, size: u64 },
+
+    #[error("{what} is larger than the limit of {limit} bytes")]
+    DocumentTooLarge { what: String, limit: u64 },
+
+    #[error("layer {digest} decompresses to more than {limit} bytes")]
+    LayerTooLarge { digest: Digest, limit: u64 },
+
+    #[error("layers decompress to more than {limit} bytes in total")]
+    TotalSizeExceeded { limit: u64 },
+
+    #[error("image has more than {limit} entries")]
+    TooManyEntries { limit: u64 },
+
+    #[error("path {path:?} is longer than {limit} bytes")]
+    PathTooLong { path: PathBuf, limit: usize },
+
+    #[error("path {path:?} is deeper than {limit} components")]
+    PathTooDeep { path: PathBuf, limit: usize },
+
+    #[error("symlink {path:?} resolves through more than {limit} links")]
+    SymlinkChainTooLong { path: PathBuf, limit: usize },
+
+    #[error("symlink {path:?} resolves through a loop")]
+    SymlinkLoop { path: PathBuf },
+}
+
+impl LimitError {
+    /// Recovers a limit error that was carried through an `io::Error`,
+    /// e.g. by a `LimitedReader` wrapped in a decoder.
+    pub fn from_io(e: io::Error) -> anyhow::Error {
+        // The tar crate wraps errors again, so walk the whole chain.
+        let mut source = e.get_ref().map(|inner| inner as &(dyn StdError + 'static));
+        while let Some(inner) = source {
+            if let Some(limit) = inner.downcast_ref::<LimitError>() {
+                return limit.clone().into();
+            }
+            source = match inner.downcast_ref::<io::Error>() {
+                Some(io) => io.get_ref().map(|inner| inner as &(dyn StdError + 'static)),
+                None => inner.source(),
+            };
+        }
+
+        e.into()
+    }
+}
+
+/// LimitedReader fails with the given error instead of yielding more than
+/// `limit` bytes.
+#[derive(Debug)]
+pub struct LimitedReader<R> {
+    inner: R,
+    remaining: u64,
+    consumed: u64,
+    error: LimitError,
+}
+
+impl<R: Read> LimitedReader<R> {
+    pub fn new(inner: R, limit: u64, error: LimitError) -> Self {
+        LimitedReader {
+            inner,
+            remaining: limit,
+            consumed: 0,
+            error,
+        }
+    }
+
+    /// Refuses to read past the size of the blob's descriptor.
+    pub fn for_blob(inner: R, digest: &Digest, size: u64) -> Self {
+        LimitedReader::new(
+            inner,
+            size,
+            LimitError::BlobSizeExceeded {
+                digest: digest.clone(),
+                size,
+            },
+        )
+    }
+
+    /// The number of bytes read so far.
+    pub fn consumed(&self) -> u64 {
+        self.consumed
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        if self.remaining == 0 {
+            // Probe for data past the limit.
+            let mut probe = [0; 1];
+            return match self.inner.read(&mut probe)? {
+                0 => Ok(0),
+                _ => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    self.error.clone(),
+                )),
+            };
+        }
+
+        let max = cmp::min(buf.len() as u64, self.remaining) as usize;
+        let n = self.inner.read(&mut buf[..max])?;
+        self.remaining -= n as u64;
+        self.consumed += n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::Read,
+        os::unix::fs::symlink,
+        path::{Path, PathBuf},
+    };
+
+    use tempfile::TempDir;
+
+    use crate::{
+        limits::{resolve_in_root, LimitError, LimitedReader, Limits},
+        test_utils::sha256,
+    };
+
+    #[test]
+    fn test_limited_reader() {
+        let digest = sha256(b"hello");
+        let mut buf = Vec::new();
+        let mut reader = LimitedReader::for_blob(&b"hello"[..], &digest, 5);
+        reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"hello");
+        assert_eq!(reader.consumed(), 5);
+
+        let mut reader = LimitedReader::for_blob(&b"hello"[..], &digest, 4);
+        let err = reader.read_to_end(&mut buf).unwrap_err();
+        assert_eq!(
+            LimitError::from_io(err).downcast::<LimitError>().unwrap(),
+            LimitError::BlobSizeExceeded { digest, size: 4 }
+        );
+    }
+
+    #[test]
+    fn test_check_path() {
+        let limits = Limits {
+            max_path_length: 10,
+            max_path_depth: 2,
+            ..Limits::default()
+        };
+        assert!(limits.check_path(Path::new("a/b")).is_ok());
+        assert!(matches!(
+            limits.check_path(Path::new("a/b/c")),
+            Err(LimitError::PathTooDeep { .. })
+        ));
+        assert!(matches!(
+            limits.check_path(Path::new("abcdefghijk")),
+            Err(LimitError::PathTooLong { .. })
+        ));
+    }
+
+    #[test]
+    fn test_check_symlink_chain() {
+        let root = TempDir::new().unwrap();
+        symlink("b", root.path().join("a")).unwrap();
+        symlink("/c", root.path().join("b")).unwrap();
+        symlink("../../d", root.path().join("c")).unwrap();
+        symlink("a", root.path().join("loop")).unwrap();
+        symlink("loop", root.path().join("a2")).unwrap();
+
+        let limits = Limits {
+            max_symlink_chain: 3,
+            ..Limits::default()
+        };
+        assert!(limits
+            .check_symlink_chain(root.path(), Path::new("a"))
+            .is_ok());
+
+        let limits = Limits {
+            max_symlink_chain: 2,
+            ..Limits::default()
+        };
+        assert!(matches!(
+            limits.check_symlink_chain(root.path(), Path::new("a")),
+            Err(LimitError::SymlinkChainTooLong { .. })
+        ));
+
+        // Loops are refused without a limit, through intermediate
+        // components too.
+        symlink("pong", root.path().join("ping")).unwrap();
+        symlink("ping", root.path().join("pong")).unwrap();
+        assert_eq!(
+            Limits::unlimited().check_symlink_chain(root.path(), Path::new("ping")),
+            Err(LimitError::SymlinkLoop {
+                path: PathBuf::from("ping")
+            })
+        );
+        symlink("self/x", root.path().join("self")).unwrap();
+        assert_eq!(
+            Limits::unlimited().check_symlink_chain(root.path(), Path::new("self")),
+            Err(LimitError::SymlinkLoop {
+                path: PathBuf::from("self")
+            })
+        );
+    }
+
+    #[test]
+    fn test_resolve_in_root() {
+        let root = TempDir::new().unwrap();
+        let outside = TempDir::new().unwrap();
+        fs::create_dir(root.path().join("dir")).unwrap();
+        symlink("../..", root.path().join("dir/up")).unwrap();
+        symlink(outside.path(), root.path().join("host")).unwrap();
+        symlink("dir/up/dir", root.path().join("again")).unwrap();
+
+        assert_eq!(
+            resolve_in_root(root.path(), Path::new("dir/up/etc"), 40).unwrap(),
+            root.path().join("etc")
+        );
+        assert_eq!(
+            resolve_in_root(root.path(), Path::new("host/file"), 40).unwrap(),
+            root.path()
+                .join(outside.path().strip_prefix("/").unwrap().join("file"))
+        );
+        // The same link twice in a row is not a loop.
+        assert_eq!(
+            resolve_in_root(root.path(), Path::new("again/up/again"), 40).unwrap(),
+            root.path().join("dir")
+        );
+    }
+}
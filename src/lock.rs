@@ -0,0 +1,83 @@
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    path::Path,
+};
+
+use fs2::FileExt;
+
+/// The lock file taken by commands that modify an image layout.
+pub const LOCK_FILE: &str = ".lock";
+
+/// LayoutLock is an advisory lock on an image layout directory, released when
+/// dropped. Writers take it exclusively, so they can't race with each other
+/// or with garbage collection.
+#[derive(Debug)]
+pub struct LayoutLock {
+    file: Option<File>,
+}
+
+impl LayoutLock {
+    /// Blocks until the layout can be locked exclusively.
+    pub fn exclusive(root: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(root.join(LOCK_FILE))?;
+        FileExt::lock_exclusive(&file)?;
+        Ok(LayoutLock { file: Some(file) })
+    }
+
+    /// Blocks until the layout can be locked for reading. The lock file is
+    /// opened read-only; a layout without one which it can't be created in,
+    /// e.g. a read-only mirror, is read without locking.
+    pub fn shared(root: &Path) -> io::Result<Self> {
+        let path = root.join(LOCK_FILE);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                match OpenOptions::new().create_new(true).write(true).open(&path) {
+                    Ok(file) => file,
+                    Err(e) if e.kind() == io::ErrorKind::AlreadyExists => File::open(&path)?,
+                    Err(_) => return Ok(LayoutLock { file: None }),
+                }
+            }
+            Err(e) => return Err(e),
+        };
+        FileExt::lock_shared(&file)?;
+        Ok(LayoutLock { file: Some(file) })
+    }
+}
+
+impl Drop for LayoutLock {
+    fn drop(&mut self) {
+        if let Some(file) = &self.file {
+            let _ = FileExt::unlock(file);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    use tempfile::TempDir;
+
+    use crate::lock::{LayoutLock, LOCK_FILE};
+
+    #[test]
+    fn test_shared_lock() {
+        let dir = TempDir::new().unwrap();
+        let lock_file = dir.path().join(LOCK_FILE);
+        fs::write(&lock_file, b"").unwrap();
+        fs::set_permissions(&lock_file, fs::Permissions::from_mode(0o444)).unwrap();
+        let lock = LayoutLock::shared(dir.path()).unwrap();
+        assert!(lock.file.is_some());
+        drop(lock);
+
+        // A layout the lock file can't be created in is read unlocked.
+        let lock = LayoutLock::shared(&dir.path().join("missing")).unwrap();
+        assert!(lock.file.is_none());
+    }
+}
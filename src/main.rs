@@ -1,5 +1,33 @@
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, BufWriter, IsTerminal, Read, Write},
+    path::Path,
+    process,
+    str::FromStr,
+};
+
+use anyhow::{bail, Context};
 use clap::Parser;
-use oci_extractor::unpacker::Unpacker;
+use oci_extractor::cache::{CacheMode, FileCache};
+use oci_extractor::compression::Compression;
+use oci_extractor::export::Exporter;
+use oci_extractor::fsck;
+use oci_extractor::gc::GarbageCollector;
+use oci_extractor::limits::{LimitError, LimitedReader, Limits};
+use oci_extractor::lock::LayoutLock;
+use oci_extractor::merge::select_manifest;
+use oci_extractor::progress::{Event, Observer, ProgressBar};
+use oci_extractor::pusher::{split_image_tag, BlobPush, Pusher};
+use oci_extractor::referrers;
+use oci_extractor::registry::reference::Reference;
+use oci_extractor::signature::{self, PrivateKey, PublicKey, VerifiedSignature};
+use oci_extractor::signer::{Attachment, Signer};
+use oci_extractor::spec::index::Index;
+use oci_extractor::squash::{parse_layer_range, Squasher};
+use oci_extractor::store::{BlobStore, DirectoryStore, TarStore};
+use oci_extractor::unpacker::{Timestamps, Unpacker};
+use oci_extractor::validate::Validator;
 
 #[derive(Parser)]
 struct Opts {
@@ -10,6 +38,17 @@ struct Opts {
 #[derive(Parser)]
 enum SubCommand {
     Unpack(Unpack),
+    Push(Push),
+    Gc(Gc),
+    #[clap(alias = "verify")]
+    Fsck(Fsck),
+    Squash(Squash),
+    Export(Export),
+    Cache(Cache),
+    Referrers(Referrers),
+    VerifySignature(VerifySignature),
+    Sign(Sign),
+    Validate(Validate),
 }
 
 #[derive(Parser)]
@@ -17,14 +56,465 @@ struct Unpack {
     #[clap(long)]
     image: String,
     destination: String,
+    /// Refuse indexes and manifests larger than this many bytes
+    #[clap(long)]
+    max_manifest_size: Option<u64>,
+    /// Refuse layers decompressing to more than this many bytes
+    #[clap(long)]
+    max_layer_size: Option<u64>,
+    /// Refuse images decompressing to more than this many bytes
+    #[clap(long)]
+    max_total_size: Option<u64>,
+    /// Refuse images with more than this many entries
+    #[clap(long)]
+    max_entries: Option<u64>,
+    /// Share regular files with other unpacks through this cache directory
+    #[clap(long)]
+    cache: Option<String>,
+    /// Create files from the cache with `reflink`, `hardlink` or `copy`
+    #[clap(long, default_value = "reflink")]
+    cache_mode: CacheMode,
+    /// Set directory mtimes from their entries once all children are unpacked
+    #[clap(long)]
+    restore_dir_mtimes: bool,
+    /// Clamp mtimes to this many seconds since the epoch, defaults to `$SOURCE_DATE_EPOCH`
+    #[clap(long)]
+    source_date_epoch: Option<u64>,
+    /// Set every mtime to the source date epoch instead of clamping
+    #[clap(long)]
+    override_mtimes: bool,
+    /// Don't report progress on stderr
+    #[clap(long)]
+    quiet: bool,
+    /// Write a JSON report of what was unpacked to this file
+    #[clap(long)]
+    report: Option<String>,
+    /// Print what the unpacked tree would be, without writing anything
+    #[clap(long)]
+    dry_run: bool,
+    /// Refuse images without a cosign signature verifying with this PEM public key
+    #[clap(long)]
+    verify_key: Option<String>,
+    /// Refuse indexes, manifests and configs breaking a rule of the image-spec
+    #[clap(long)]
+    strict: bool,
+}
+
+impl Unpack {
+    fn limits(&self) -> Limits {
+        let defaults = Limits::default();
+        Limits {
+            max_manifest_size: self.max_manifest_size.unwrap_or(defaults.max_manifest_size),
+            max_layer_size: self.max_layer_size.unwrap_or(defaults.max_layer_size),
+            max_total_size: self.max_total_size.unwrap_or(defaults.max_total_size),
+            max_entries: self.max_entries.unwrap_or(defaults.max_entries),
+            ..defaults
+        }
+    }
+
+    fn timestamps(&self) -> anyhow::Result<Timestamps> {
+        let epoch = match (self.source_date_epoch, env::var("SOURCE_DATE_EPOCH")) {
+            (Some(epoch), _) => Some(epoch),
+            (None, Ok(epoch)) => Some(
+                epoch
+                    .parse()
+                    .with_context(|| format!("invalid SOURCE_DATE_EPOCH {:?}", epoch))?,
+            ),
+            (None, Err(_)) => None,
+        };
+        Ok(match epoch {
+            Some(epoch) if self.override_mtimes => Timestamps::Override(epoch),
+            Some(epoch) => Timestamps::Clamp(epoch),
+            None => Timestamps::Preserve,
+        })
+    }
+}
+
+/// Push a local OCI layout to a distribution registry
+#[derive(Parser)]
+struct Push {
+    /// The layout to push, as `<layout>[:tag]`
+    image: String,
+    /// The destination, e.g. `localhost:5000/app:latest`
+    reference: String,
+    /// Use plain HTTP to talk to the registry
+    #[clap(long)]
+    insecure: bool,
+    /// Upload blobs larger than this many bytes in chunks
+    #[clap(long)]
+    chunk_size: Option<usize>,
+    /// Repository to try cross-repository mounts from, may be repeated
+    #[clap(long)]
+    mount_from: Vec<String>,
+}
+
+/// Delete blobs which can't be reached from the layout's index.json
+#[derive(Parser)]
+struct Gc {
+    /// The layout to collect
+    image: String,
+    /// Only report what would be deleted
+    #[clap(long)]
+    dry_run: bool,
+}
+
+/// Verify the integrity of a whole layout, printing a JSON report
+#[derive(Parser)]
+struct Fsck {
+    /// The layout to check
+    image: String,
+}
+
+/// Squash the layers of an image into a single layer
+#[derive(Parser)]
+struct Squash {
+    /// The image to squash, as `<layout>[:tag]`
+    image: String,
+    /// Write the squashed image to `<layout>[:tag]` instead of replacing the original
+    #[clap(long)]
+    output: Option<String>,
+    /// Only squash the layers N..M, counted from the bottom and starting at 0
+    #[clap(long)]
+    layers: Option<String>,
+}
+
+/// Write the merged root filesystem of an image as a single tar or a SquashFS image
+#[derive(Parser)]
+struct Export {
+    /// The image to export, as `<layout>[:tag]` or an `oci-archive` tarball
+    #[clap(long)]
+    image: String,
+    /// The file to write, `-` for stdout
+    output: String,
+    /// Compress the tar, or the SquashFS blocks, with `gzip` or `zstd`
+    #[clap(long, default_value = "none")]
+    compression: Compression,
+    /// Write a `tar` stream or a `squashfs` image, which cannot go to stdout
+    #[clap(long, default_value = "tar")]
+    format: String,
+}
+
+/// Show the size of an unpack cache, and prune it
+#[derive(Parser)]
+struct Cache {
+    /// The cache directory
+    cache: String,
+    /// Remove the least recently used files until the cache holds at most this many bytes
+    #[clap(long)]
+    prune: Option<u64>,
+}
+
+/// List the artifacts attached to an image, such as signatures and SBOMs
+#[derive(Parser)]
+struct Referrers {
+    /// The image, as `<layout>[:tag]`
+    image: String,
+}
+
+/// Verify the cosign signatures of the images of a layout against a public key, offline
+#[derive(Parser)]
+struct VerifySignature {
+    /// The layout or an `oci-archive` tarball, as `<path>[:tag]` to verify a single image
+    image: String,
+    /// The PEM public key, e.g. `cosign.pub`
+    #[clap(long)]
+    key: String,
+    /// Refuse indexes and manifests larger than this many bytes
+    #[clap(long)]
+    max_manifest_size: Option<u64>,
+}
+
+impl VerifySignature {
+    fn limits(&self) -> Limits {
+        let defaults = Limits::default();
+        Limits {
+            max_manifest_size: self.max_manifest_size.unwrap_or(defaults.max_manifest_size),
+            ..defaults
+        }
+    }
+}
+
+/// Sign an image of a layout with cosign's simple signing, writing the signature into the layout
+#[derive(Parser)]
+struct Sign {
+    /// The image, as `<layout>[:tag]`
+    image: String,
+    /// The PEM PKCS#8 private key, ECDSA P-256 or Ed25519
+    #[clap(long)]
+    key: String,
+    /// Attach the signature with a `sha256-<hex>.sig` tag or through its `subject`
+    #[clap(long, default_value = "tag")]
+    attach: Attachment,
+    /// The reference the image is signed as, e.g. `registry.example.com/app`
+    #[clap(long)]
+    reference: String,
+}
+
+/// Check the documents of a layout against the rules of the image-spec, exiting non-zero on violations
+#[derive(Parser)]
+struct Validate {
+    /// The layout, as `<layout>[:tag]` to validate a single image
+    image: String,
 }
 
 fn main() {
     let opts: Opts = Opts::parse();
+    if let Err(e) = run(opts) {
+        eprintln!("{:#}", e);
+        process::exit(1);
+    }
+}
+
+fn run(opts: Opts) -> anyhow::Result<()> {
     match opts.subcmd {
         SubCommand::Unpack(u) => {
-            let unpacker = Unpacker::new(u.image, u.destination);
-            unpacker.unpack();
+            // An `oci-archive` tarball is read in place, without extracting it first.
+            if Path::new(&u.image).is_file() {
+                let store = TarStore::open(&u.image)?;
+                unpack(Unpacker::with_store(store, u.destination.clone()), &u)?;
+            } else {
+                unpack(Unpacker::new(u.image.clone(), u.destination.clone()), &u)?;
+            }
+        }
+        SubCommand::Push(p) => {
+            let reference = Reference::from_str(&p.reference)?;
+            let mut pusher = Pusher::new(p.image, reference, p.insecure).mount_from(p.mount_from);
+            if let Some(chunk_size) = p.chunk_size {
+                pusher = pusher.chunk_size(chunk_size);
+            }
+            let report = pusher.push()?;
+            for blob in &report.blobs {
+                match &blob.outcome {
+                    BlobPush::Existing => println!("blob exists: {}", blob.digest),
+                    BlobPush::Mounted(from) => {
+                        println!("mounted blob: {} from {}", blob.digest, from)
+                    }
+                    BlobPush::Uploaded => println!("pushed blob: {}", blob.digest),
+                }
+            }
+            for manifest in &report.manifests {
+                println!("pushed manifest: {}", manifest);
+            }
+        }
+        SubCommand::Gc(g) => {
+            let report = GarbageCollector::new(g.image)
+                .dry_run(g.dry_run)
+                .collect()?;
+            for blob in &report.collected {
+                println!("{} ({} bytes)", blob.digest, blob.size);
+            }
+            if g.dry_run {
+                println!("reclaimable: {} bytes", report.reclaimed_bytes);
+            } else {
+                println!("reclaimed: {} bytes", report.reclaimed_bytes);
+            }
+        }
+        SubCommand::Validate(v) => {
+            let report = Validator::new(v.image).validate()?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            if !report.is_ok() {
+                process::exit(1);
+            }
+        }
+        SubCommand::Fsck(f) => {
+            let report = fsck::Fsck::new(f.image).check()?;
+            println!("{}", serde_json::to_string_pretty(&report).unwrap());
+            if !report.is_ok() {
+                process::exit(1);
+            }
+        }
+        SubCommand::Squash(s) => {
+            let mut squasher = Squasher::new(s.image);
+            if let Some(output) = s.output {
+                squasher = squasher.output(output);
+            }
+            if let Some(layers) = s.layers {
+                squasher = squasher.layers(parse_layer_range(&layers)?);
+            }
+            let report = squasher.squash()?;
+            println!(
+                "squashed {} layers into {}",
+                report.squashed_layers, report.layer.digest
+            );
+            println!("manifest: {}", report.manifest.digest);
+        }
+        SubCommand::Export(e) => {
+            let (image_path, tag) = split_image_tag(&e.image);
+            if Path::new(&image_path).is_file() {
+                let store = TarStore::open(&image_path)?;
+                let exporter = Exporter::with_store(store).tag(tag);
+                export(exporter.compression(e.compression), &e.output, &e.format)?;
+            } else {
+                let exporter = Exporter::new(e.image).compression(e.compression);
+                export(exporter, &e.output, &e.format)?;
+            }
+        }
+        SubCommand::Cache(c) => {
+            let cache = open_cache(&c.cache)?;
+            if let Some(max_bytes) = c.prune {
+                let removed = cache.prune(max_bytes)?;
+                println!("pruned: {} files, {} bytes", removed.files, removed.bytes);
+            }
+            let stats = cache.stats()?;
+            println!("cached: {} files, {} bytes", stats.files, stats.bytes);
+        }
+        SubCommand::VerifySignature(v) => {
+            for s in verify_signatures(&v)? {
+                println!(
+                    "{} signed as {} by {}",
+                    s.image, s.docker_reference, s.signature
+                );
+            }
+        }
+        SubCommand::Sign(s) => {
+            let pem =
+                fs::read_to_string(&s.key).with_context(|| format!("cannot read key {}", s.key))?;
+            let key = PrivateKey::from_pem(&pem)?;
+            let signer = Signer::new(s.image, s.reference, key).attachment(s.attach);
+            println!("{}", signer.sign()?.digest);
+        }
+        SubCommand::Referrers(r) => {
+            for referrer in referrers::Referrers::new(r.image).list()? {
+                let artifact_type = referrer.artifact_type.as_ref();
+                println!(
+                    "{} {}",
+                    artifact_type.map_or("-", |t| t.as_str()),
+                    referrer.digest
+                );
+                let mut annotations: Vec<_> = referrer.annotations.iter().flatten().collect();
+                annotations.sort();
+                for (key, value) in annotations {
+                    println!("  {}={}", key, value);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_public_key(path: &str) -> anyhow::Result<PublicKey> {
+    let pem = fs::read_to_string(path).with_context(|| format!("cannot read key {}", path))?;
+    Ok(PublicKey::from_pem(&pem)?)
+}
+
+fn verify_signatures(v: &VerifySignature) -> anyhow::Result<Vec<VerifiedSignature>> {
+    let key = read_public_key(&v.key)?;
+    let limits = v.limits();
+    let (path, tag) = split_image_tag(&v.image);
+    if Path::new(&path).is_file() {
+        let store = TarStore::open(&path)?;
+        return verify_store(&store, tag.as_deref(), &key, &limits);
+    }
+
+    let store = DirectoryStore::new(path);
+    let _lock = LayoutLock::shared(store.root())?;
+    verify_store(&store, tag.as_deref(), &key, &limits)
+}
+
+// Verifies the image tagged `tag`, or else every image an unpack would
+// unpack.
+fn verify_store<S: BlobStore>(
+    store: &S,
+    tag: Option<&str>,
+    key: &PublicKey,
+    limits: &Limits,
+) -> anyhow::Result<Vec<VerifiedSignature>> {
+    let mut bytes = Vec::new();
+    LimitedReader::new(
+        store.open_index()?,
+        limits.max_manifest_size,
+        LimitError::DocumentTooLarge {
+            what: String::from("index.json"),
+            limit: limits.max_manifest_size,
+        },
+    )
+    .read_to_end(&mut bytes)
+    .map_err(LimitError::from_io)?;
+    let index: Index = serde_json::from_slice(&bytes)?;
+
+    match tag {
+        Some(tag) => {
+            let image = select_manifest(&index, Some(tag), "the layout")?;
+            Ok(vec![signature::verify_image(
+                store,
+                &index,
+                &image.digest,
+                key,
+            )?])
+        }
+        None => signature::verify_images(store, &index, key, limits),
+    }
+}
+
+// A progress bar on terminals, a line per layer otherwise.
+fn progress() -> Box<dyn Observer> {
+    if io::stderr().is_terminal() {
+        return Box::new(ProgressBar::new(io::stderr()));
+    }
+    Box::new(|event: &Event| {
+        if let Event::LayerStarted { digest, size } = event {
+            eprintln!("unpacking layer: {} ({} bytes)", digest, size);
+        }
+    })
+}
+
+fn open_cache(path: &str) -> anyhow::Result<FileCache> {
+    FileCache::open(path).with_context(|| format!("cannot open cache {}", path))
+}
+
+fn unpack<S: BlobStore>(unpacker: Unpacker<S>, u: &Unpack) -> anyhow::Result<()> {
+    let mut unpacker = unpacker
+        .limits(u.limits())
+        .restore_dir_mtimes(u.restore_dir_mtimes)
+        .timestamps(u.timestamps()?)
+        .dry_run(u.dry_run)
+        .strict(u.strict);
+    if let Some(cache) = &u.cache {
+        unpacker = unpacker.cache(open_cache(cache)?.mode(u.cache_mode));
+    }
+    if let Some(key) = &u.verify_key {
+        unpacker = unpacker.verify_signatures(read_public_key(key)?);
+    }
+    if !u.quiet && !u.dry_run {
+        let progress = progress();
+        unpacker = unpacker.observer(move |e: &Event| progress.event(e));
+    }
+
+    let report = unpacker.unpack()?;
+    if let Some(dry_run) = &report.dry_run {
+        println!("{}", serde_json::to_string_pretty(dry_run).unwrap());
+    }
+    if let Some(path) = &u.report {
+        let mut file = BufWriter::new(create(path)?);
+        serde_json::to_writer_pretty(&mut file, &report)?;
+        file.flush()
+            .with_context(|| format!("cannot write {}", path))?;
+    }
+    Ok(())
+}
+
+fn create(path: &str) -> anyhow::Result<File> {
+    File::create(path).with_context(|| format!("cannot create {}", path))
+}
+
+fn export<S: BlobStore>(exporter: Exporter<S>, output: &str, format: &str) -> anyhow::Result<()> {
+    if format == "squashfs" {
+        if output == "-" {
+            bail!("a squashfs image cannot be written to stdout");
         }
+        let writer = BufWriter::new(create(output)?);
+        let mut writer = exporter.export_squashfs(writer)?;
+        writer.flush()?;
+        return Ok(());
+    } else if format != "tar" {
+        bail!("unknown format {}, expected tar or squashfs", format);
     }
+
+    let writer: Box<dyn Write> = match output {
+        "-" => Box::new(io::stdout()),
+        path => Box::new(create(path)?),
+    };
+    let mut writer = exporter.export(BufWriter::new(writer))?;
+    writer.flush()?;
+    Ok(())
 }
@@ -0,0 +1,373 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    io::{self, Read, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail};
+use tar::{Archive, Builder, Entry, EntryType, Header};
+
+use crate::compression;
+use crate::hash::{self, HashingReader};
+use crate::limits::{LimitError, LimitedReader};
+use crate::referrers;
+use crate::spec::descriptor::Descriptor;
+use crate::spec::index::Index;
+use crate::store::BlobStore;
+
+const WHITEOUT_PREFIX: &str = ".wh.";
+const WHITEOUT_OPAQUE: &str = ".wh..wh..opq";
+
+/// What a whiteout entry hides from the layers below its own, as defined
+/// by the image-spec. The same layer's entries are never hidden.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Whiteout {
+    /// `.wh.<name>` hides the path and everything under it.
+    Path(PathBuf),
+    /// `.wh..wh..opq` hides the content of its directory, but not the
+    /// directory itself.
+    Children(PathBuf),
+}
+
+impl Whiteout {
+    /// The whiteout a normalized entry path stands for, if it is one.
+    pub(crate) fn of(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?;
+        let parent = path.parent().unwrap_or_else(|| Path::new(""));
+        if name == WHITEOUT_OPAQUE {
+            return Some(Whiteout::Children(parent.to_path_buf()));
+        }
+        match name.strip_prefix(WHITEOUT_PREFIX)? {
+            "" | "." | ".." => None,
+            hidden => Some(Whiteout::Path(parent.join(hidden))),
+        }
+    }
+
+    /// The path hidden, or whose content is.
+    pub(crate) fn path(&self) -> &Path {
+        match self {
+            Whiteout::Path(path) | Whiteout::Children(path) => path,
+        }
+    }
+
+    /// Removes what is hidden from a tree of the merged layers, `is_lower`
+    /// telling whether a node comes from a layer below the whiteout's.
+    pub(crate) fn apply<T>(&self, tree: &mut BTreeMap<PathBuf, T>, is_lower: impl Fn(&T) -> bool) {
+        let including_path = matches!(self, Whiteout::Path(_));
+        remove_lower(tree, self.path(), including_path, is_lower);
+    }
+}
+
+// The layer and position within the layer of a tar entry.
+type EntryId = (usize, usize);
+
+// Where the entry for a path comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Origin {
+    id: EntryId,
+    is_dir: bool,
+    // For hardlinks, the target path and the entry holding the data.
+    link: Option<(PathBuf, EntryId)>,
+}
+
+impl Origin {
+    fn data(&self) -> EntryId {
+        self.link.as_ref().map_or(self.id, |(_, data)| *data)
+    }
+}
+
+/// Selects the manifest tagged `tag`, or the only image of the index,
+/// ignoring signatures and other artifacts.
+pub fn select_manifest<'a>(
+    index: &'a Index,
+    tag: Option<&str>,
+    layout: impl fmt::Display,
+) -> anyhow::Result<&'a Descriptor> {
+    if let Some(tag) = tag {
+        return index
+            .find_tag(tag)
+            .ok_or_else(|| anyhow!("tag {} not found in {}", tag, layout));
+    }
+    match referrers::images(index).collect::<Vec<_>>()[..] {
+        [image] => Ok(image),
+        _ => bail!("{} has several manifests, select one with a tag", layout),
+    }
+}
+
+/// Reads a whole blob, checking it against its descriptor.
+pub fn read_verified<S: BlobStore>(store: &S, descriptor: &Descriptor) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    LimitedReader::for_blob(
+        store.open(&descriptor.digest)?,
+        &descriptor.digest,
+        descriptor.size,
+    )
+    .read_to_end(&mut bytes)
+    .map_err(LimitError::from_io)?;
+    let actual = hash::digest_bytes(&descriptor.digest.algorithm, &bytes)?;
+    if actual != descriptor.digest {
+        bail!("blob {} has digest {}", descriptor.digest, actual);
+    }
+
+    Ok(bytes)
+}
+
+/// An entry of the tree resulting from merging layers.
+pub struct MergedEntry<'e> {
+    pub path: PathBuf,
+    pub header: Header,
+    /// The target of symlinks and hardlinks, hardlinks target merged paths.
+    pub link_name: Option<PathBuf>,
+    /// PAX extensions such as `SCHILY.xattr.*` records.
+    pub pax_extensions: Vec<(String, Vec<u8>)>,
+    pub data: &'e mut dyn Read,
+}
+
+/// MergedLayers applies layers on top of each other without extracting
+/// them, and walks the resulting tree or writes it as a single tar stream.
+/// Ownership, modes, PAX extensions such as xattrs, and hardlinks are
+/// preserved.
+pub struct MergedLayers<'a, S: BlobStore> {
+    store: &'a S,
+    layers: &'a [Descriptor],
+    origins: BTreeMap<PathBuf, Origin>,
+    // Hardlinks whose target was replaced or removed by a later layer, by
+    // the entry holding their data.
+    detached: HashMap<EntryId, Vec<PathBuf>>,
+}
+
+impl<'a, S: BlobStore> MergedLayers<'a, S> {
+    /// Walks the layers, bottom-most first. Whiteouts are resolved, and kept
+    /// in the output if `keep_whiteouts` is set because the result will sit
+    /// on top of other layers.
+    pub fn new(
+        store: &'a S,
+        layers: &'a [Descriptor],
+        keep_whiteouts: bool,
+    ) -> anyhow::Result<Self> {
+        let mut origins = BTreeMap::new();
+
+        for (layer_index, layer) in layers.iter().enumerate() {
+            with_layer(store, layer, |reader| {
+                for (entry_index, entry) in Archive::new(reader).entries()?.enumerate() {
+                    let entry = entry.map_err(LimitError::from_io)?;
+                    let path = normalize(&entry.path()?);
+                    // The root itself has nothing to merge.
+                    if path.as_os_str().is_empty() {
+                        continue;
+                    }
+                    let mut origin = Origin {
+                        id: (layer_index, entry_index),
+                        is_dir: entry.header().entry_type().is_dir(),
+                        link: None,
+                    };
+
+                    let is_lower = |o: &Origin| o.id.0 < layer_index;
+                    if let Some(whiteout) = Whiteout::of(&path) {
+                        whiteout.apply(&mut origins, is_lower);
+                        if keep_whiteouts {
+                            origins.insert(path, origin);
+                        }
+                        continue;
+                    }
+
+                    if entry.header().entry_type() == EntryType::Link {
+                        if let Some(target) = entry.link_name()? {
+                            let target = normalize(&target);
+                            origin.link = origins.get(&target).map(|o| (target, o.data()));
+                        }
+                    }
+                    if !origin.is_dir {
+                        // A file replacing a directory replaces its content too.
+                        remove_lower(&mut origins, &path, false, is_lower);
+                    }
+                    origins.insert(path, origin);
+                }
+
+                Ok(())
+            })?;
+        }
+
+        // Hardlinks can only point to their target if it is still there.
+        let detached_links: Vec<(PathBuf, EntryId)> = origins
+            .iter()
+            .filter_map(|(path, origin)| {
+                let (target, data) = origin.link.as_ref()?;
+                match origins.get(target) {
+                    Some(target) if target.data() == *data => None,
+                    _ => Some((path.to_owned(), *data)),
+                }
+            })
+            .collect();
+        let mut detached: HashMap<EntryId, Vec<PathBuf>> = HashMap::new();
+        for (path, data) in detached_links {
+            origins.remove(&path);
+            detached.entry(data).or_default().push(path);
+        }
+
+        Ok(MergedLayers {
+            store,
+            layers,
+            origins,
+            detached,
+        })
+    }
+
+    /// Calls `f` on every entry of the merged tree, in layer order.
+    pub fn walk(&self, mut f: impl FnMut(MergedEntry) -> anyhow::Result<()>) -> anyhow::Result<()> {
+        for (layer_index, layer) in self.layers.iter().enumerate() {
+            with_layer(self.store, layer, |reader| {
+                for (entry_index, entry) in Archive::new(reader).entries()?.enumerate() {
+                    let mut entry = entry.map_err(LimitError::from_io)?;
+                    let id = (layer_index, entry_index);
+                    let pax_extensions = pax_extensions(&mut entry)?;
+
+                    // The first detached hardlink takes over the data, the
+                    // others link to it.
+                    if let Some(paths) = self.detached.get(&id) {
+                        let mut header = entry.header().clone();
+                        header.set_entry_type(EntryType::Regular);
+                        f(MergedEntry {
+                            path: paths[0].clone(),
+                            header: header.clone(),
+                            link_name: None,
+                            pax_extensions: pax_extensions.clone(),
+                            data: &mut entry,
+                        })?;
+                        header.set_entry_type(EntryType::Link);
+                        header.set_size(0);
+                        for path in &paths[1..] {
+                            f(MergedEntry {
+                                path: path.clone(),
+                                header: header.clone(),
+                                link_name: Some(paths[0].clone()),
+                                pax_extensions: pax_extensions.clone(),
+                                data: &mut io::empty(),
+                            })?;
+                        }
+                        continue;
+                    }
+
+                    let path = normalize(&entry.path()?);
+                    let origin = match self.origins.get(&path) {
+                        Some(origin) if origin.id == id => origin,
+                        _ => continue,
+                    };
+                    let header = entry.header().clone();
+                    let link_name = match (&origin.link, header.entry_type()) {
+                        (Some((target, _)), _) => Some(target.clone()),
+                        (None, EntryType::Symlink | EntryType::Link) => Some(
+                            entry
+                                .link_name()?
+                                .ok_or_else(|| anyhow!("link {:?} has no target", path))?
+                                .into_owned(),
+                        ),
+                        _ => None,
+                    };
+                    f(MergedEntry {
+                        path,
+                        header,
+                        link_name,
+                        pax_extensions,
+                        data: &mut entry,
+                    })?;
+                }
+
+                Ok(())
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes every entry of the merged tree to a tar stream.
+    pub fn write<W: Write>(&self, builder: &mut Builder<W>) -> anyhow::Result<()> {
+        self.walk(|mut entry| {
+            if !entry.pax_extensions.is_empty() {
+                builder.append_pax_extensions(
+                    entry
+                        .pax_extensions
+                        .iter()
+                        .map(|(k, v)| (k.as_str(), v.as_slice())),
+                )?;
+            }
+
+            // Paths and link names are set again so long ones get their GNU
+            // extension headers.
+            match &entry.link_name {
+                Some(target) => builder.append_link(&mut entry.header, &entry.path, target)?,
+                None => builder.append_data(&mut entry.header, &entry.path, entry.data)?,
+            }
+
+            Ok(())
+        })
+    }
+}
+
+// Opens a layer, calling `f` on its decompressed stream and then checking
+// the layer's digest.
+fn with_layer<S: BlobStore>(
+    store: &S,
+    layer: &Descriptor,
+    f: impl FnOnce(&mut dyn Read) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let hasher = hash::hasher(&layer.digest.algorithm)?;
+    let mut blob = HashingReader::new(
+        LimitedReader::for_blob(store.open(&layer.digest)?, &layer.digest, layer.size),
+        hasher,
+    );
+    f(&mut compression::decompress(&layer.media_type, &mut blob)?)?;
+
+    io::copy(&mut blob, &mut io::sink()).map_err(LimitError::from_io)?;
+    let (digest, _) = blob.finish();
+    if digest != layer.digest {
+        bail!("layer {} has digest {}", layer.digest, digest);
+    }
+
+    Ok(())
+}
+
+// Returns the PAX extensions of an entry, except those describing the
+// path, link name and size which are rewritten anyway.
+fn pax_extensions<R: Read>(entry: &mut Entry<R>) -> anyhow::Result<Vec<(String, Vec<u8>)>> {
+    let mut kept = Vec::new();
+    if let Some(extensions) = entry.pax_extensions()? {
+        for extension in extensions {
+            let extension = extension?;
+            let key = extension.key()?;
+            if !matches!(key, "path" | "linkpath" | "size") {
+                kept.push((key.to_owned(), extension.value_bytes().to_owned()));
+            }
+        }
+    }
+
+    Ok(kept)
+}
+
+/// Drops `./` and leading `/` so the same path is always spelled the same.
+pub(crate) fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect()
+}
+
+/// Removes the nodes of lower layers under `path` from a tree of the merged
+/// layers, and `path` itself when `including_path` is set.
+pub(crate) fn remove_lower<T>(
+    tree: &mut BTreeMap<PathBuf, T>,
+    path: &Path,
+    including_path: bool,
+    is_lower: impl Fn(&T) -> bool,
+) {
+    // Paths compare component by component, so descendants directly follow.
+    let lower: Vec<PathBuf> = tree
+        .range(path.to_path_buf()..)
+        .take_while(|(p, _)| p.starts_with(path))
+        .filter(|(p, node)| is_lower(node) && (including_path || p.as_path() != path))
+        .map(|(p, _)| p.to_owned())
+        .collect();
+    for path in lower {
+        tree.remove(&path);
+    }
+}
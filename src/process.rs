@@ -0,0 +1,368 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::limits::resolve_in_root;
+use crate::spec::config::ImageConfig;
+
+// The PATH runtimes set when the image doesn't.
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+// Like the kernel, give up on resolving paths after this many symlinks.
+const MAX_SYMLINKS: usize = 40;
+
+#[derive(Debug, Error)]
+pub enum ProcessError {
+    #[error("the image has neither an entrypoint nor a command")]
+    NoCommand,
+
+    #[error("invalid environment variable {0:?}, expected NAME=VALUE")]
+    InvalidEnv(String),
+
+    #[error("user {0:?} not found in /etc/passwd")]
+    UnknownUser(String),
+
+    #[error("group {0:?} not found in /etc/group")]
+    UnknownGroup(String),
+
+    #[error("invalid user {0:?}, expected user, uid, user:group or uid:gid")]
+    InvalidUser(String),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// Overrides of the image config, as given to `docker run`.
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    /// Replaces the entrypoint, and the command of the image with it.
+    pub entrypoint: Option<Vec<String>>,
+    pub cmd: Option<Vec<String>>,
+    /// `NAME=VALUE` entries replacing or adding to the image's.
+    pub env: Vec<String>,
+    pub user: Option<String>,
+    pub working_dir: Option<String>,
+}
+
+/// The user a process runs as.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct User {
+    pub uid: u32,
+    pub gid: u32,
+    /// The groups listing the user by name in `/etc/group`.
+    pub additional_gids: Vec<u32>,
+}
+
+/// The process to run for an image, with its config and overrides applied.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Process {
+    pub args: Vec<String>,
+    pub env: BTreeMap<String, String>,
+    pub user: User,
+    pub working_dir: PathBuf,
+}
+
+impl Process {
+    /// Resolves the process of `config`, looking users and groups up in the
+    /// unpacked image at `rootfs`. `PATH` and `HOME` get defaults if unset.
+    pub fn resolve(
+        config: &ImageConfig,
+        rootfs: &Path,
+        overrides: &Overrides,
+    ) -> Result<Process, ProcessError> {
+        // A new entrypoint drops the image's command, like `docker run`.
+        let args: Vec<String> = match &overrides.entrypoint {
+            Some(entrypoint) => entrypoint
+                .iter()
+                .chain(overrides.cmd.iter().flatten())
+                .cloned()
+                .collect(),
+            None => config
+                .entrypoint
+                .iter()
+                .flatten()
+                .chain(
+                    overrides
+                        .cmd
+                        .as_ref()
+                        .or(config.cmd.as_ref())
+                        .into_iter()
+                        .flatten(),
+                )
+                .cloned()
+                .collect(),
+        };
+        if args.is_empty() {
+            return Err(ProcessError::NoCommand);
+        }
+
+        let mut env = BTreeMap::new();
+        for entry in config.env.iter().flatten().chain(&overrides.env) {
+            let (name, value) = entry
+                .split_once('=')
+                .filter(|(name, _)| !name.is_empty())
+                .ok_or_else(|| ProcessError::InvalidEnv(entry.to_owned()))?;
+            env.insert(name.to_owned(), value.to_owned());
+        }
+
+        let spec = overrides
+            .user
+            .as_deref()
+            .or(config.user.as_deref())
+            .unwrap_or_default();
+        let (user, home) = resolve_user(rootfs, spec)?;
+        env.entry(String::from("PATH"))
+            .or_insert_with(|| String::from(DEFAULT_PATH));
+        env.entry(String::from("HOME")).or_insert(home);
+
+        let working_dir = match overrides
+            .working_dir
+            .as_ref()
+            .or(config.working_dir.as_ref())
+        {
+            Some(dir) if !dir.is_empty() => Path::new("/").join(dir),
+            _ => PathBuf::from("/"),
+        };
+
+        Ok(Process {
+            args,
+            env,
+            user,
+            working_dir,
+        })
+    }
+}
+
+// An entry of `/etc/passwd` or `/etc/group`, with the fields after the id:
+// the group, gecos, home and shell of users, the members of groups.
+struct DbEntry {
+    name: String,
+    id: u32,
+    rest: Vec<String>,
+}
+
+// Resolves `user`, `uid`, `user:group` and mixes of names and ids, returning
+// the user and its home directory.
+fn resolve_user(rootfs: &Path, spec: &str) -> Result<(User, String), ProcessError> {
+    let (user, group) = match spec.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (spec, None),
+    };
+    if user.is_empty() && group.is_some() {
+        return Err(ProcessError::InvalidUser(spec.to_owned()));
+    }
+    let passwd = read_db(rootfs, "etc/passwd")?;
+    let groups = read_db(rootfs, "etc/group")?;
+
+    // Numeric users needn't be in /etc/passwd, and then belong to root's group.
+    let entry = match user.parse::<u32>() {
+        Ok(uid) => passwd.iter().find(|e| e.id == uid),
+        Err(_) if user.is_empty() => passwd.iter().find(|e| e.id == 0),
+        Err(_) => Some(
+            passwd
+                .iter()
+                .find(|e| e.name == user)
+                .ok_or_else(|| ProcessError::UnknownUser(user.to_owned()))?,
+        ),
+    };
+    let uid = match entry {
+        Some(entry) => entry.id,
+        None => user.parse().unwrap_or(0),
+    };
+    let primary = entry
+        .and_then(|e| e.rest.first()?.parse().ok())
+        .unwrap_or(0);
+    let home = entry
+        .and_then(|e| e.rest.get(2).filter(|h| !h.is_empty()).cloned())
+        .unwrap_or_else(|| String::from("/"));
+
+    let gid = match group {
+        None => primary,
+        Some("") => return Err(ProcessError::InvalidUser(spec.to_owned())),
+        Some(group) => match group.parse::<u32>() {
+            Ok(gid) => gid,
+            Err(_) => {
+                groups
+                    .iter()
+                    .find(|e| e.name == group)
+                    .ok_or_else(|| ProcessError::UnknownGroup(group.to_owned()))?
+                    .id
+            }
+        },
+    };
+    // Supplementary groups only apply without an explicit group.
+    let additional_gids = match (group, entry) {
+        (None, Some(entry)) => groups
+            .iter()
+            .filter(|g| g.id != gid)
+            .filter(|g| {
+                g.rest
+                    .first()
+                    .is_some_and(|members| members.split(',').any(|m| m == entry.name))
+            })
+            .map(|g| g.id)
+            .collect(),
+        _ => vec![],
+    };
+
+    Ok((
+        User {
+            uid,
+            gid,
+            additional_gids,
+        },
+        home,
+    ))
+}
+
+// Reads the entries of a passwd or group file of the rootfs, none if it
+// doesn't exist.
+fn read_db(rootfs: &Path, path: &str) -> Result<Vec<DbEntry>, ProcessError> {
+    let path = resolve_in_root(rootfs, Path::new(path), MAX_SYMLINKS).map_err(io::Error::other)?;
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(contents
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut fields = line.split(':');
+            let name = fields.next()?.to_owned();
+            let _password = fields.next()?;
+            let id = fields.next()?.parse().ok()?;
+            Some(DbEntry {
+                name,
+                id,
+                rest: fields.map(String::from).collect(),
+            })
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, fs, os::unix::fs::symlink, path::PathBuf};
+
+    use tempfile::TempDir;
+
+    use crate::{
+        process::{Overrides, Process, ProcessError, User},
+        spec::config::ImageConfig,
+    };
+
+    fn rootfs() -> TempDir {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir_all(dir.path().join("etc")).unwrap();
+        fs::create_dir_all(dir.path().join("usr/lib")).unwrap();
+        fs::write(
+            dir.path().join("usr/lib/passwd"),
+            "root:x:0:0:root:/root:/bin/sh\n# comment\nalice:x:1000:100::/home/alice:/bin/sh\n",
+        )
+        .unwrap();
+        // Absolute links resolve inside the rootfs.
+        symlink("/usr/lib/passwd", dir.path().join("etc/passwd")).unwrap();
+        fs::write(
+            dir.path().join("etc/group"),
+            "root:x:0:\nusers:x:100:\nwheel:x:10:alice,bob\n",
+        )
+        .unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_resolve_process() {
+        let rootfs = rootfs();
+        let config = ImageConfig {
+            entrypoint: Some(vec![String::from("/bin/app")]),
+            cmd: Some(vec![String::from("--serve")]),
+            env: Some(vec![String::from("A=1"), String::from("B=2")]),
+            user: Some(String::from("alice")),
+            working_dir: Some(String::from("srv")),
+            ..ImageConfig::default()
+        };
+
+        let process = Process::resolve(&config, rootfs.path(), &Overrides::default()).unwrap();
+        assert_eq!(process.args, ["/bin/app", "--serve"]);
+        assert_eq!(
+            process.user,
+            User {
+                uid: 1000,
+                gid: 100,
+                additional_gids: vec![10]
+            }
+        );
+        assert_eq!(process.env["HOME"], "/home/alice");
+        assert_eq!(process.working_dir, PathBuf::from("/srv"));
+
+        let overrides = Overrides {
+            entrypoint: Some(vec![String::from("/bin/sh")]),
+            env: vec![String::from("B=3=4"), String::from("PATH=/bin")],
+            user: Some(String::from("1000:wheel")),
+            ..Overrides::default()
+        };
+        let process = Process::resolve(&config, rootfs.path(), &overrides).unwrap();
+        assert_eq!(process.args, ["/bin/sh"]);
+        let env: BTreeMap<&str, &str> = process
+            .env
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        assert_eq!(
+            env,
+            BTreeMap::from([
+                ("A", "1"),
+                ("B", "3=4"),
+                ("HOME", "/home/alice"),
+                ("PATH", "/bin")
+            ])
+        );
+        assert_eq!((process.user.uid, process.user.gid), (1000, 10));
+    }
+
+    #[test]
+    fn test_resolve_user() {
+        let rootfs = rootfs();
+        let resolve = |user: &str| {
+            let config = ImageConfig {
+                cmd: Some(vec![String::from("true")]),
+                user: Some(user.to_owned()),
+                ..ImageConfig::default()
+            };
+            Process::resolve(&config, rootfs.path(), &Overrides::default())
+        };
+        let ids = |user: &str| {
+            let user = resolve(user).unwrap().user;
+            (user.uid, user.gid)
+        };
+
+        assert_eq!(ids(""), (0, 0));
+        assert_eq!(ids("1000"), (1000, 100));
+        assert_eq!(ids("4242"), (4242, 0));
+        assert_eq!(ids("4242:4343"), (4242, 4343));
+        assert_eq!(ids("root:users"), (0, 100));
+        assert_eq!(resolve("4242").unwrap().env["HOME"], "/");
+        assert!(matches!(resolve("mallory"), Err(ProcessError::UnknownUser(u)) if u == "mallory"));
+        assert!(
+            matches!(resolve("alice:staff"), Err(ProcessError::UnknownGroup(g)) if g == "staff")
+        );
+        assert!(matches!(
+            resolve(":users"),
+            Err(ProcessError::InvalidUser(_))
+        ));
+        assert!(matches!(
+            Process::resolve(
+                &ImageConfig::default(),
+                rootfs.path(),
+                &Overrides::default()
+            ),
+            Err(ProcessError::NoCommand)
+        ));
+    }
+}
@@ -0,0 +1,256 @@
+use std::{
+    cell::{Cell, RefCell},
+    fmt,
+    io::{self, Read, Write},
+    path::PathBuf,
+    sync::mpsc::Sender,
+};
+
+use crate::spec::digest::Digest;
+
+/// Something that happened while unpacking an image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// A manifest of the index is about to be unpacked.
+    ManifestSelected { digest: Digest },
+    /// A layer blob of `size` bytes is about to be read.
+    LayerStarted { digest: Digest, size: u64 },
+    /// How much of the current layer was read so far, before and after
+    /// decompression.
+    BytesRead {
+        digest: Digest,
+        compressed: u64,
+        decompressed: u64,
+    },
+    /// An entry was created under the destination.
+    EntryWritten { path: PathBuf },
+    /// The content of a directory from lower layers was removed.
+    WhiteoutApplied { path: PathBuf },
+    /// All the entries of a layer were unpacked.
+    LayerFinished { digest: Digest, decompressed: u64 },
+    /// A manifest, config or layer matched its digest.
+    DigestVerified { digest: Digest },
+}
+
+/// Observer receives the events of an unpack, e.g. to report progress or
+/// log. Closures taking an `&Event` are observers, and so are channel
+/// senders for observing from another thread.
+pub trait Observer {
+    fn event(&self, event: &Event);
+}
+
+impl<F: Fn(&Event)> Observer for F {
+    fn event(&self, event: &Event) {
+        self(event)
+    }
+}
+
+impl Observer for Sender<Event> {
+    fn event(&self, event: &Event) {
+        // The receiving end going away shouldn't stop the unpack.
+        let _ = self.send(event.clone());
+    }
+}
+
+/// Ignores all events.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoProgress;
+
+impl Observer for NoProgress {
+    fn event(&self, _: &Event) {}
+}
+
+impl fmt::Debug for dyn Observer + '_ {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Observer")
+    }
+}
+
+impl fmt::Debug for dyn Observer + Send + Sync + '_ {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Observer")
+    }
+}
+
+const BAR_WIDTH: usize = 30;
+
+#[derive(Debug, Default)]
+struct BarState {
+    layer: Option<(Digest, u64)>,
+    layers: usize,
+    // The last rendered fill, to only redraw when it changes.
+    filled: Option<usize>,
+}
+
+/// ProgressBar draws a line per layer with a bar of how much of the blob
+/// was read, redrawn in place, e.g. on a terminal's stderr.
+#[derive(Debug)]
+pub struct ProgressBar<W: Write> {
+    writer: RefCell<W>,
+    state: RefCell<BarState>,
+}
+
+impl<W: Write> ProgressBar<W> {
+    pub fn new(writer: W) -> Self {
+        ProgressBar {
+            writer: RefCell::new(writer),
+            state: RefCell::new(BarState::default()),
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer.into_inner()
+    }
+
+    fn draw(&self, event: &Event) -> io::Result<()> {
+        let mut state = self.state.borrow_mut();
+        let mut writer = self.writer.borrow_mut();
+        match event {
+            Event::LayerStarted { digest, size } => {
+                state.layers += 1;
+                state.layer = Some((digest.clone(), *size));
+                state.filled = None;
+            }
+            Event::BytesRead {
+                compressed,
+                decompressed,
+                ..
+            } => {
+                let (digest, size) = match &state.layer {
+                    Some(layer) => layer.clone(),
+                    None => return Ok(()),
+                };
+                let filled = match size {
+                    0 => BAR_WIDTH,
+                    size => (BAR_WIDTH as u64 * compressed.min(&size) / size) as usize,
+                };
+                if state.filled != Some(filled) {
+                    state.filled = Some(filled);
+                    write!(
+                        writer,
+                        "\rlayer {} {} [{}{}] {} / {} ({} unpacked)",
+                        state.layers,
+                        short(&digest),
+                        "#".repeat(filled),
+                        " ".repeat(BAR_WIDTH - filled),
+                        human_bytes(*compressed),
+                        human_bytes(size),
+                        human_bytes(*decompressed),
+                    )?;
+                }
+            }
+            Event::LayerFinished {
+                digest,
+                decompressed,
+            } => {
+                writeln!(
+                    writer,
+                    "\rlayer {} {} [{}] {} unpacked",
+                    state.layers,
+                    short(digest),
+                    "#".repeat(BAR_WIDTH),
+                    human_bytes(*decompressed),
+                )?;
+                state.layer = None;
+            }
+            _ => return Ok(()),
+        }
+
+        writer.flush()
+    }
+}
+
+impl<W: Write> Observer for ProgressBar<W> {
+    fn event(&self, event: &Event) {
+        // Failing to draw progress isn't worth failing the unpack for.
+        let _ = self.draw(event);
+    }
+}
+
+fn short(digest: &Digest) -> &str {
+    &digest.encoded[..digest.encoded.len().min(12)]
+}
+
+fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", value, UNITS[unit]),
+    }
+}
+
+/// CountingReader counts the bytes read through it into a shared counter,
+/// which can be read while the reader is borrowed by a decoder.
+pub(crate) struct CountingReader<'c, R> {
+    inner: R,
+    count: &'c Cell<u64>,
+}
+
+impl<'c, R> CountingReader<'c, R> {
+    pub(crate) fn new(inner: R, count: &'c Cell<u64>) -> Self {
+        CountingReader { inner, count }
+    }
+}
+
+impl<R: Read> Read for CountingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.set(self.count.get() + n as u64);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        progress::{Event, Observer, ProgressBar},
+        test_utils::sha256,
+    };
+
+    #[test]
+    fn test_progress_bar() {
+        let digest = sha256(b"layer");
+        let bar = ProgressBar::new(Vec::new());
+        bar.event(&Event::LayerStarted {
+            digest: digest.clone(),
+            size: 4096,
+        });
+        for compressed in [1024, 1025, 4096] {
+            bar.event(&Event::BytesRead {
+                digest: digest.clone(),
+                compressed,
+                decompressed: compressed * 2,
+            });
+        }
+        bar.event(&Event::LayerFinished {
+            digest: digest.clone(),
+            decompressed: 8192,
+        });
+
+        let output = String::from_utf8(bar.into_inner()).unwrap();
+        let lines: Vec<&str> = output.split('\r').skip(1).collect();
+        let short = &digest.encoded[..12];
+        assert_eq!(
+            lines,
+            [
+                format!(
+                    "layer 1 {} [{:<30}] 1.0 KiB / 4.0 KiB (2.0 KiB unpacked)",
+                    short,
+                    "#".repeat(7)
+                ),
+                format!(
+                    "layer 1 {} [{}] 4.0 KiB / 4.0 KiB (8.0 KiB unpacked)",
+                    short,
+                    "#".repeat(30)
+                ),
+                format!("layer 1 {} [{}] 8.0 KiB unpacked\n", short, "#".repeat(30)),
+            ]
+        );
+    }
+}
@@ -0,0 +1,485 @@
+use std::io::Read;
+
+use anyhow::{anyhow, bail};
+
+use crate::hash;
+use crate::registry::{
+    client::{Client, Mount},
+    reference::Reference,
+};
+use crate::spec::descriptor::Descriptor;
+use crate::spec::digest::{Algorithm, Digest};
+use crate::spec::index::Index;
+use crate::spec::manifest::Manifest;
+use crate::spec::media_types::MediaType;
+use crate::store::{BlobStore, DirectoryStore};
+
+/// Splits `<layout>[:tag]` into the layout path and the optional tag.
+pub fn split_image_tag(image: &str) -> (String, Option<String>) {
+    match image.rsplit_once(':') {
+        Some((path, tag)) if !tag.is_empty() && !tag.contains('/') => {
+            (path.to_owned(), Some(tag.to_owned()))
+        }
+        _ => (image.to_owned(), None),
+    }
+}
+
+/// How a blob got into the target repository.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlobPush {
+    /// The repository already had it.
+    Existing,
+    /// Mounted from another repository of the registry.
+    Mounted(String),
+    Uploaded,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PushedBlob {
+    pub digest: Digest,
+    pub size: u64,
+    pub outcome: BlobPush,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PushReport {
+    pub blobs: Vec<PushedBlob>,
+    /// The manifests and indexes put, children first.
+    pub manifests: Vec<Digest>,
+}
+
+/// Pusher uploads the content of a local OCI image layout to a registry.
+#[derive(Debug)]
+pub struct Pusher {
+    store: DirectoryStore,
+    tag: Option<String>,
+    reference: Reference,
+    client: Client,
+    chunk_size: Option<usize>,
+    mount_from: Vec<String>,
+}
+
+impl Pusher {
+    /// `image` is `<layout>[:tag]`, the tag selects a manifest in `index.json`
+    /// by its `org.opencontainers.image.ref.name` annotation.
+    pub fn new(image: String, reference: Reference, insecure: bool) -> Self {
+        let (image_path, tag) = split_image_tag(&image);
+        let client = Client::new(&reference.registry, insecure);
+        Pusher {
+            store: DirectoryStore::new(image_path),
+            tag,
+            reference,
+            client,
+            chunk_size: None,
+            mount_from: vec![],
+        }
+    }
+
+    /// Upload blobs larger than `chunk_size` bytes in chunks.
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = Some(chunk_size);
+        self
+    }
+
+    /// Try to mount blobs from these repositories of the same registry
+    /// before uploading them.
+    pub fn mount_from(mut self, repositories: Vec<String>) -> Self {
+        self.mount_from = repositories;
+        self
+    }
+
+    pub fn push(&self) -> anyhow::Result<PushReport> {
+        let _lock = self.store.lock_shared()?;
+        let mut body = Vec::new();
+        self.store.open_index()?.read_to_end(&mut body)?;
+        let index: Index = serde_json::from_slice(&body)?;
+        let target = self.reference.target();
+        let mut report = PushReport::default();
+
+        match &self.tag {
+            Some(tag) => {
+                let descriptor = index.find_tag(tag).ok_or_else(|| {
+                    anyhow!("tag {} not found in {}", tag, self.store.root().display())
+                })?;
+                self.push_manifest(descriptor, &target, &mut report)?;
+            }
+            None if index.manifests.len() == 1 => {
+                self.push_manifest(&index.manifests[0], &target, &mut report)?;
+            }
+            None => {
+                // Without a tag the whole layout is pushed as an image index.
+                for manifest in &index.manifests {
+                    self.push_manifest(manifest, &manifest.digest.to_string(), &mut report)?;
+                }
+                self.client.put_manifest(
+                    &self.reference.repository,
+                    &target,
+                    &MediaType::ImageIndex,
+                    &body,
+                )?;
+                report
+                    .manifests
+                    .push(hash::digest_bytes(&Algorithm::Sha256, &body)?);
+            }
+        }
+
+        Ok(report)
+    }
+
+    fn push_manifest(
+        &self,
+        descriptor: &Descriptor,
+        reference: &str,
+        report: &mut PushReport,
+    ) -> anyhow::Result<()> {
+        // The blob is pushed byte for byte so its digest is preserved.
+        let mut body = Vec::new();
+        self.store
+            .open(&descriptor.digest)?
+            .read_to_end(&mut body)?;
+
+        match descriptor.media_type {
+            MediaType::ImageIndex => {
+                let index: Index = serde_json::from_slice(&body)?;
+                for manifest in &index.manifests {
+                    self.push_manifest(manifest, &manifest.digest.to_string(), report)?;
+                }
+            }
+            MediaType::ImageManifest => {
+                let manifest: Manifest = serde_json::from_slice(&body)?;
+                report.blobs.push(self.push_blob(&manifest.config)?);
+                for layer in &manifest.layers {
+                    report.blobs.push(self.push_blob(layer)?);
+                }
+            }
+            ref media_type => bail!(
+                "{} has media type {}, expected an index or a manifest",
+                descriptor.digest,
+                media_type
+            ),
+        }
+
+        self.client.put_manifest(
+            &self.reference.repository,
+            reference,
+            &descriptor.media_type,
+            &body,
+        )?;
+        report.manifests.push(descriptor.digest.clone());
+
+        Ok(())
+    }
+
+    fn push_blob(&self, descriptor: &Descriptor) -> anyhow::Result<PushedBlob> {
+        let repository = &self.reference.repository;
+        let digest = &descriptor.digest;
+        let pushed = |outcome| PushedBlob {
+            digest: digest.clone(),
+            size: descriptor.size,
+            outcome,
+        };
+
+        if self.client.blob_exists(repository, digest)? {
+            return Ok(pushed(BlobPush::Existing));
+        }
+
+        let mut location: Option<String> = None;
+        for from in &self.mount_from {
+            let upload = match self.client.mount_blob(repository, digest, from)? {
+                Mount::Mounted => {
+                    if let Some(previous) = location {
+                        self.cancel_upload(&previous);
+                    }
+                    return Ok(pushed(BlobPush::Mounted(from.clone())));
+                }
+                Mount::Upload(upload) => upload,
+            };
+            // Each refused mount opens an upload session, the last one is
+            // used if no mount succeeds.
+            if let Some(previous) = location.replace(upload) {
+                self.cancel_upload(&previous);
+            }
+        }
+
+        let location = match location {
+            Some(location) => location,
+            None => self.client.start_upload(repository)?,
+        };
+
+        let blob = self.store.open(digest)?;
+        match self.chunk_size {
+            Some(chunk_size) if descriptor.size > chunk_size as u64 => self
+                .client
+                .upload_chunked(&location, digest, chunk_size, blob)?,
+            _ => self
+                .client
+                .upload_monolithic(&location, digest, descriptor.size, blob)?,
+        }
+
+        Ok(pushed(BlobPush::Uploaded))
+    }
+
+    // Registries may not support cancelling uploads, unused sessions then
+    // expire on their own.
+    fn cancel_upload(&self, location: &str) {
+        let _ = self.client.cancel_upload(location);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        str::FromStr,
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    use tiny_http::{Header, Method, Response, Server};
+
+    use crate::{
+        pusher::{split_image_tag, BlobPush, Pusher},
+        registry::reference::Reference,
+        test_utils::{tar, TestEntry, TestLayout},
+    };
+
+    #[derive(Default)]
+    struct Registry {
+        blobs: HashMap<(String, String), Vec<u8>>,
+        uploads: HashMap<String, Vec<u8>>,
+        next_upload: usize,
+        manifests: HashMap<(String, String), (String, Vec<u8>)>,
+        requests: Vec<String>,
+    }
+
+    // A bare-bones distribution registry good enough to exercise the push
+    // flow: it supports HEAD, mounts, monolithic and chunked uploads,
+    // cancelled uploads and manifest puts.
+    fn mock_registry() -> (String, Arc<Mutex<Registry>>) {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let address = server.server_addr().to_ip().unwrap().to_string();
+        let state = Arc::new(Mutex::new(Registry::default()));
+        let shared = state.clone();
+
+        thread::spawn(move || {
+            for mut request in server.incoming_requests() {
+                let mut body = Vec::new();
+                request.as_reader().read_to_end(&mut body).unwrap();
+                let url = request.url().to_owned();
+                let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+                let params: HashMap<&str, String> = query
+                    .split('&')
+                    .filter_map(|p| p.split_once('='))
+                    .map(|(k, v)| (k, v.replace("%3A", ":").replace("%2F", "/")))
+                    .collect();
+                let path = path.trim_start_matches("/v2/");
+
+                let mut state = shared.lock().unwrap();
+                state
+                    .requests
+                    .push(format!("{} {}", request.method(), path));
+
+                let response = if let Some((repo, rest)) = path.split_once("/blobs/uploads/") {
+                    match request.method() {
+                        Method::Post => {
+                            let mounted = params.get("mount").and_then(|digest| {
+                                let from = params.get("from")?;
+                                let blob =
+                                    state.blobs.get(&(from.clone(), digest.clone()))?.clone();
+                                state.blobs.insert((repo.to_owned(), digest.clone()), blob);
+                                Some(())
+                            });
+                            if mounted.is_some() {
+                                Response::empty(201)
+                            } else {
+                                let id = state.next_upload.to_string();
+                                state.next_upload += 1;
+                                state.uploads.insert(id.clone(), vec![]);
+                                Response::empty(202).with_header(
+                                    Header::from_bytes(
+                                        "Location",
+                                        format!("/v2/{}/blobs/uploads/{}", repo, id),
+                                    )
+                                    .unwrap(),
+                                )
+                            }
+                        }
+                        Method::Patch => {
+                            state.uploads.get_mut(rest).unwrap().extend(body);
+                            Response::empty(202).with_header(
+                                Header::from_bytes(
+                                    "Location",
+                                    format!("/v2/{}/blobs/uploads/{}", repo, rest),
+                                )
+                                .unwrap(),
+                            )
+                        }
+                        Method::Put => {
+                            let mut blob = state.uploads.remove(rest).unwrap();
+                            blob.extend(body);
+                            let digest = params["digest"].clone();
+                            state.blobs.insert((repo.to_owned(), digest), blob);
+                            Response::empty(201)
+                        }
+                        Method::Delete => match state.uploads.remove(rest) {
+                            Some(_) => Response::empty(204),
+                            None => Response::empty(404),
+                        },
+                        _ => Response::empty(405),
+                    }
+                } else if let Some((repo, digest)) = path.split_once("/blobs/") {
+                    if state
+                        .blobs
+                        .contains_key(&(repo.to_owned(), digest.to_owned()))
+                    {
+                        Response::empty(200)
+                    } else {
+                        Response::empty(404)
+                    }
+                } else if let Some((repo, reference)) = path.split_once("/manifests/") {
+                    let content_type = request
+                        .headers()
+                        .iter()
+                        .find(|h| h.field.equiv("Content-Type"))
+                        .map(|h| h.value.to_string())
+                        .unwrap_or_default();
+                    state.manifests.insert(
+                        (repo.to_owned(), reference.to_owned()),
+                        (content_type, body),
+                    );
+                    Response::empty(201)
+                } else {
+                    Response::empty(404)
+                };
+
+                drop(state);
+                request.respond(response).unwrap();
+            }
+        });
+
+        (address, state)
+    }
+
+    #[test]
+    fn test_split_image_tag() {
+        assert_eq!(
+            split_image_tag("alpine:latest"),
+            (String::from("alpine"), Some(String::from("latest")))
+        );
+        assert_eq!(split_image_tag("alpine"), (String::from("alpine"), None));
+        assert_eq!(
+            split_image_tag("./a:b/alpine"),
+            (String::from("./a:b/alpine"), None)
+        );
+    }
+
+    #[test]
+    fn test_push() {
+        let layout = TestLayout::new();
+        // Compresses to more than the chunk size.
+        let big: Vec<u8> = (0..64 * 1024u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        let manifest = layout.add_image(
+            &[
+                tar(&[TestEntry::File("a", b"a")]),
+                tar(&[TestEntry::File("big", &big)]),
+            ],
+            Some("v1"),
+        );
+        let (address, registry) = mock_registry();
+
+        let reference = Reference::from_str(&format!("{}/test/app:latest", address)).unwrap();
+        Pusher::new(format!("{}:v1", layout.path()), reference, true)
+            .chunk_size(256)
+            .push()
+            .unwrap();
+
+        let state = registry.lock().unwrap();
+        // config and two layers
+        assert_eq!(state.blobs.len(), 3);
+        for ((repo, digest), blob) in &state.blobs {
+            assert_eq!(repo, "test/app");
+            assert_eq!(&crate::test_utils::sha256(blob).to_string(), digest);
+        }
+        assert!(state.requests.iter().any(|r| r.starts_with("PATCH")));
+
+        let (content_type, body) =
+            &state.manifests[&(String::from("test/app"), String::from("latest"))];
+        assert_eq!(content_type, "application/vnd.oci.image.manifest.v1+json");
+        assert_eq!(crate::test_utils::sha256(body), manifest.digest);
+    }
+
+    #[test]
+    fn test_push_skips_existing_and_mounts() {
+        let layout = TestLayout::new();
+        layout.add_image(&[tar(&[TestEntry::File("a", b"a")])], None);
+        let (address, registry) = mock_registry();
+
+        let reference = Reference::from_str(&format!("{}/base:1", address)).unwrap();
+        Pusher::new(layout.path(), reference, true).push().unwrap();
+
+        // Only the last repository has the blobs, the sessions opened by
+        // the refused mounts are cancelled.
+        let reference = Reference::from_str(&format!("{}/app:1", address)).unwrap();
+        let report = Pusher::new(layout.path(), reference, true)
+            .mount_from(vec![
+                String::from("none"),
+                String::from("other"),
+                String::from("base"),
+            ])
+            .push()
+            .unwrap();
+        assert!(report
+            .blobs
+            .iter()
+            .all(|b| b.outcome == BlobPush::Mounted(String::from("base"))));
+        assert_eq!(report.manifests.len(), 1);
+
+        let reference = Reference::from_str(&format!("{}/app:2", address)).unwrap();
+        let report = Pusher::new(layout.path(), reference, true).push().unwrap();
+        assert!(report.blobs.iter().all(|b| b.outcome == BlobPush::Existing));
+
+        let state = registry.lock().unwrap();
+        let uploads = state
+            .requests
+            .iter()
+            .filter(|r| r.starts_with("PUT") && r.contains("/blobs/uploads/"))
+            .count();
+        // Only the first push uploads the config and the layer.
+        assert_eq!(uploads, 2);
+        assert_eq!(
+            state.blobs.keys().filter(|(repo, _)| repo == "app").count(),
+            2
+        );
+        assert!(state
+            .manifests
+            .contains_key(&(String::from("app"), String::from("2"))));
+        assert!(state.uploads.is_empty());
+    }
+
+    #[test]
+    fn test_push_cancels_unused_uploads() {
+        let layout = TestLayout::new();
+        layout.add_image(&[tar(&[TestEntry::File("a", b"a")])], None);
+        let (address, registry) = mock_registry();
+
+        let reference = Reference::from_str(&format!("{}/app:1", address)).unwrap();
+        let report = Pusher::new(layout.path(), reference, true)
+            .mount_from(vec![String::from("base"), String::from("other")])
+            .push()
+            .unwrap();
+        assert!(report.blobs.iter().all(|b| b.outcome == BlobPush::Uploaded));
+
+        let state = registry.lock().unwrap();
+        // One refused mount per blob is superseded by the next one.
+        let cancelled = state
+            .requests
+            .iter()
+            .filter(|r| r.starts_with("DELETE"))
+            .count();
+        assert_eq!(cancelled, 2);
+        assert!(state.uploads.is_empty());
+        assert_eq!(state.blobs.len(), 2);
+    }
+}
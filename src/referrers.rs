@@ -0,0 +1,290 @@
+use std::collections::HashSet;
+
+use crate::lock::LayoutLock;
+use crate::merge::{read_verified, select_manifest};
+use crate::pusher::split_image_tag;
+use crate::spec::annotations::Annotated;
+use crate::spec::descriptor::Descriptor;
+use crate::spec::digest::{Algorithm, Digest};
+use crate::spec::index::Index;
+use crate::spec::manifest::Manifest;
+use crate::spec::media_types::MediaType;
+use crate::store::{BlobStore, DirectoryStore};
+
+/// Returns the tag registries without the referrers API list the referrers
+/// of `digest` under, e.g. `sha256-<hex>`.
+pub fn fallback_tag(digest: &Digest) -> String {
+    format!("{}-{}", digest.algorithm, digest.encoded)
+}
+
+/// Tells whether `tag` is the fallback tag of a digest, or a cosign tag such
+/// as `sha256-<hex>.sig`, rather than the tag of an image.
+pub fn is_referrer_tag(tag: &str) -> bool {
+    let digest = tag.split_once('.').map_or(tag, |(digest, _)| digest);
+    // Only registered algorithms, `v1-2` is a plain tag.
+    digest
+        .replacen('-', ":", 1)
+        .parse::<Digest>()
+        .is_ok_and(|d| !matches!(d.algorithm, Algorithm::Unregistered(_)))
+}
+
+/// Returns the entries of `index` which are images rather than artifacts
+/// attached to one: those without an artifact type nor a referrer tag.
+pub fn images(index: &Index) -> impl Iterator<Item = &Descriptor> {
+    index.manifests.iter().filter(|descriptor| {
+        descriptor.artifact_type.is_none() && !descriptor.ref_name().is_some_and(is_referrer_tag)
+    })
+}
+
+/// Tells whether `manifest` is an artifact attached to an image, such as a
+/// signature, rather than an image.
+pub fn is_artifact(manifest: &Manifest) -> bool {
+    manifest.subject.is_some() || manifest.artifact_type.is_some()
+}
+
+/// Returns the images of `index` an unpack unpacks: the entries of
+/// [`images`] whose manifest isn't an artifact. Manifests larger than
+/// `max_size` aren't read, unpacking refuses them anyway.
+pub fn select_images<'a, S: BlobStore>(
+    store: &S,
+    index: &'a Index,
+    max_size: u64,
+) -> anyhow::Result<Vec<&'a Descriptor>> {
+    let mut selected = vec![];
+    for descriptor in images(index) {
+        if descriptor.media_type == MediaType::ImageManifest && descriptor.size <= max_size {
+            let manifest: Manifest = serde_json::from_slice(&read_verified(store, descriptor)?)?;
+            if is_artifact(&manifest) {
+                continue;
+            }
+        }
+        selected.push(descriptor);
+    }
+
+    Ok(selected)
+}
+
+/// Referrers finds the manifests of a layout attached to an image, such as
+/// signatures, SBOMs and attestations.
+#[derive(Debug)]
+pub struct Referrers {
+    store: DirectoryStore,
+    tag: Option<String>,
+}
+
+impl Referrers {
+    /// `image` is `<layout>[:tag]`, the tag selects a manifest in `index.json`.
+    pub fn new(image: String) -> Self {
+        let (image_path, tag) = split_image_tag(&image);
+        Referrers {
+            store: DirectoryStore::new(image_path),
+            tag,
+        }
+    }
+
+    /// Lists the referrers of the image, see [`find_referrers`].
+    pub fn list(&self) -> anyhow::Result<Vec<Descriptor>> {
+        let _lock = LayoutLock::shared(self.store.root())?;
+        let index: Index = serde_json::from_reader(self.store.open_index()?)?;
+        let subject = select_manifest(&index, self.tag.as_deref(), self.store.root().display())?;
+
+        find_referrers(&self.store, &index, &subject.digest)
+    }
+}
+
+/// Lists the referrers of `subject` like the referrers API of registries
+/// does: descriptors carrying the artifact type and the annotations of the
+/// manifest they point to.
+///
+/// Referrers are the manifests reachable from `index` whose `subject` is the
+/// image, and those listed by the index tagged with the fallback tag of the
+/// image. Manifests tagged `sha256-<hex>.<suffix>`, as cosign tags
+/// signatures and SBOMs, are referrers too.
+pub fn find_referrers<S: BlobStore>(
+    store: &S,
+    index: &Index,
+    subject: &Digest,
+) -> anyhow::Result<Vec<Descriptor>> {
+    let mut referrers = vec![];
+    walk(store, index, subject, &mut referrers, &mut HashSet::new())?;
+
+    let tag = fallback_tag(subject);
+    for descriptor in &index.manifests {
+        match descriptor.ref_name() {
+            Some(name) if name == tag && descriptor.media_type == MediaType::ImageIndex => {
+                let fallback: Index = serde_json::from_slice(&read_verified(store, descriptor)?)?;
+                referrers.extend(fallback.manifests);
+            }
+            Some(name) if name.strip_prefix(&tag).is_some_and(|s| s.starts_with('.')) => {
+                let referrer = match read_document(store, descriptor)? {
+                    Some(document) => document.referrer,
+                    None => descriptor.clone(),
+                };
+                referrers.push(referrer);
+            }
+            _ => {}
+        }
+    }
+
+    let mut found = HashSet::new();
+    referrers.retain(|r| found.insert(r.digest.clone()));
+    Ok(referrers)
+}
+
+// Collects the manifests of `index` and its nested indexes which refer to
+// `subject`.
+fn walk<S: BlobStore>(
+    store: &S,
+    index: &Index,
+    subject: &Digest,
+    referrers: &mut Vec<Descriptor>,
+    visited: &mut HashSet<Digest>,
+) -> anyhow::Result<()> {
+    for descriptor in &index.manifests {
+        if !visited.insert(descriptor.digest.clone()) {
+            continue;
+        }
+        let document = match read_document(store, descriptor)? {
+            Some(document) => document,
+            None => continue,
+        };
+        if document.subject.as_ref() == Some(subject) {
+            referrers.push(document.referrer);
+        }
+        if let Some(nested) = document.nested {
+            walk(store, &nested, subject, referrers, visited)?;
+        }
+    }
+
+    Ok(())
+}
+
+// A manifest or an index, described as the referrers API would.
+struct Document {
+    referrer: Descriptor,
+    subject: Option<Digest>,
+    nested: Option<Index>,
+}
+
+// Reads a manifest or an index, None for other media types.
+fn read_document<S: BlobStore>(
+    store: &S,
+    descriptor: &Descriptor,
+) -> anyhow::Result<Option<Document>> {
+    Ok(Some(match descriptor.media_type {
+        MediaType::ImageIndex => {
+            let index: Index = serde_json::from_slice(&read_verified(store, descriptor)?)?;
+            Document {
+                referrer: Descriptor {
+                    artifact_type: index.artifact_type.clone(),
+                    annotations: index.annotations.clone(),
+                    ..descriptor.clone()
+                },
+                subject: index.subject.as_ref().map(|s| s.digest.clone()),
+                nested: Some(index),
+            }
+        }
+        MediaType::ImageManifest => {
+            let manifest: Manifest = serde_json::from_slice(&read_verified(store, descriptor)?)?;
+            // Manifests without an artifact type are typed by their config.
+            let artifact_type = manifest.artifact_type.unwrap_or(manifest.config.media_type);
+            Document {
+                referrer: Descriptor {
+                    artifact_type: Some(artifact_type),
+                    annotations: manifest.annotations,
+                    ..descriptor.clone()
+                },
+                subject: manifest.subject.map(|s| s.digest),
+                nested: None,
+            }
+        }
+        _ => return Ok(None),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        referrers::{fallback_tag, is_referrer_tag, Referrers},
+        spec::{index::Index, media_types::MediaType},
+        test_utils::{tar, TestEntry, TestLayout},
+    };
+
+    #[test]
+    fn test_referrers() {
+        let layout = TestLayout::new();
+        let image = layout.add_image(&[tar(&[TestEntry::File("a", b"a")])], Some("latest"));
+        let other = layout.add_image(&[tar(&[TestEntry::File("b", b"b")])], Some("other"));
+
+        let signature = layout.write_artifact(
+            "application/vnd.example.signature",
+            b"signed",
+            Some(&image),
+            &[("com.example.key", "value")],
+        );
+        layout.add_to_index(signature.clone(), None);
+        layout.add_to_index(
+            layout.write_artifact(
+                "application/vnd.example.signature",
+                b"other",
+                Some(&other),
+                &[],
+            ),
+            None,
+        );
+        // Copied without the referrers API, listed by the fallback tag.
+        let sbom = layout.write_artifact("application/spdx+json", b"{}", Some(&image), &[]);
+        let fallback = Index {
+            schema_version: 2,
+            media_type: Some(MediaType::ImageIndex),
+            artifact_type: None,
+            manifests: vec![sbom.clone()],
+            subject: None,
+            annotations: None,
+        };
+        let fallback = layout.write_blob(
+            MediaType::ImageIndex,
+            &serde_json::to_vec(&fallback).unwrap(),
+        );
+        layout.add_to_index(fallback, Some(&fallback_tag(&image.digest)));
+        // Tagged like cosign tags signatures, without a subject.
+        let cosign = layout.write_artifact(
+            "application/vnd.dev.cosign.simplesigning.v1+json",
+            b"sig",
+            None,
+            &[],
+        );
+        let cosign_tag = format!("{}.sig", fallback_tag(&image.digest));
+        layout.add_to_index(cosign.clone(), Some(&cosign_tag));
+
+        let referrers = Referrers::new(format!("{}:latest", layout.path()))
+            .list()
+            .unwrap();
+        let digests: Vec<_> = referrers.iter().map(|r| &r.digest).collect();
+        assert_eq!(digests, [&signature.digest, &sbom.digest, &cosign.digest]);
+        assert_eq!(
+            referrers[0].artifact_type,
+            Some(MediaType::Other(String::from(
+                "application/vnd.example.signature"
+            )))
+        );
+        assert_eq!(
+            referrers[0].annotations.as_ref().unwrap()["com.example.key"],
+            "value"
+        );
+        // Annotations are the manifest's, not those of the index entry.
+        assert_eq!(referrers[2].annotations, None);
+
+        assert!(Referrers::new(format!("{}:other", layout.path()))
+            .list()
+            .unwrap()
+            .iter()
+            .all(|r| r.digest != signature.digest));
+
+        assert!(is_referrer_tag(&fallback_tag(&image.digest)));
+        assert!(is_referrer_tag(&cosign_tag));
+        assert!(!is_referrer_tag("latest"));
+        assert!(!is_referrer_tag("v1-2"));
+        assert!(!is_referrer_tag("sha256-1234.sig"));
+    }
+}
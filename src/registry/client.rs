@@ -0,0 +1,236 @@
+use std::io::Read;
+
+use anyhow::{anyhow, bail, Context};
+use ureq::{Agent, AgentBuilder, Response};
+
+use crate::spec::{digest::Digest, media_types::MediaType};
+
+const OCTET_STREAM: &str = "application/octet-stream";
+
+/// The outcome of a cross-repository mount request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mount {
+    /// The registry linked the blob into the target repository.
+    Mounted,
+    /// The registry refused to mount and opened a regular upload session
+    /// at the given location instead.
+    Upload(String),
+}
+
+/// Client is a minimal client for the push side of the OCI distribution API.
+#[derive(Debug, Clone)]
+pub struct Client {
+    base_url: String,
+    agent: Agent,
+}
+
+impl Client {
+    pub fn new(registry: &str, insecure: bool) -> Self {
+        let scheme = if insecure { "http" } else { "https" };
+        Client {
+            base_url: format!("{}://{}", scheme, registry),
+            agent: AgentBuilder::new().build(),
+        }
+    }
+
+    /// Checks whether the repository already holds the blob.
+    pub fn blob_exists(&self, repository: &str, digest: &Digest) -> anyhow::Result<bool> {
+        let url = format!("{}/v2/{}/blobs/{}", self.base_url, repository, digest);
+        match self.agent.head(&url).call() {
+            Ok(_) => Ok(true),
+            Err(ureq::Error::Status(404, _)) => Ok(false),
+            Err(e) => Err(e).with_context(|| format!("HEAD {}", url)),
+        }
+    }
+
+    /// Asks the registry to link a blob from another repository it hosts.
+    pub fn mount_blob(
+        &self,
+        repository: &str,
+        digest: &Digest,
+        from: &str,
+    ) -> anyhow::Result<Mount> {
+        let url = format!("{}/v2/{}/blobs/uploads/", self.base_url, repository);
+        let response = self
+            .agent
+            .post(&url)
+            .query("mount", &digest.to_string())
+            .query("from", from)
+            .call()
+            .with_context(|| format!("POST {}", url))?;
+
+        match response.status() {
+            201 => Ok(Mount::Mounted),
+            202 => Ok(Mount::Upload(self.location(&response)?)),
+            status => bail!(
+                "unexpected status {} mounting {} from {}",
+                status,
+                digest,
+                from
+            ),
+        }
+    }
+
+    /// Opens an upload session, returning its location.
+    pub fn start_upload(&self, repository: &str) -> anyhow::Result<String> {
+        let url = format!("{}/v2/{}/blobs/uploads/", self.base_url, repository);
+        let response = self
+            .agent
+            .post(&url)
+            .call()
+            .with_context(|| format!("POST {}", url))?;
+
+        match response.status() {
+            202 => self.location(&response),
+            status => bail!("unexpected status {} opening upload session", status),
+        }
+    }
+
+    /// Cancels an upload session which won't be used.
+    pub fn cancel_upload(&self, location: &str) -> anyhow::Result<()> {
+        let response = self
+            .agent
+            .delete(location)
+            .call()
+            .with_context(|| format!("DELETE {}", location))?;
+
+        match response.status() {
+            204 => Ok(()),
+            status => bail!("unexpected status {} cancelling upload session", status),
+        }
+    }
+
+    /// Uploads the whole blob in a single `PUT`.
+    pub fn upload_monolithic<R: Read>(
+        &self,
+        location: &str,
+        digest: &Digest,
+        size: u64,
+        reader: R,
+    ) -> anyhow::Result<()> {
+        let response = self
+            .agent
+            .put(location)
+            .query("digest", &digest.to_string())
+            .set("Content-Type", OCTET_STREAM)
+            .set("Content-Length", &size.to_string())
+            .send(reader)
+            .with_context(|| format!("PUT {}", location))?;
+
+        match response.status() {
+            201 => Ok(()),
+            status => bail!("unexpected status {} uploading {}", status, digest),
+        }
+    }
+
+    /// Uploads the blob as a sequence of `PATCH` requests of at most
+    /// `chunk_size` bytes, then closes the session with an empty `PUT`.
+    pub fn upload_chunked<R: Read>(
+        &self,
+        location: &str,
+        digest: &Digest,
+        chunk_size: usize,
+        mut reader: R,
+    ) -> anyhow::Result<()> {
+        let mut location = location.to_owned();
+        let mut offset: u64 = 0;
+        let mut chunk = vec![0; chunk_size];
+
+        loop {
+            let n = read_full(&mut reader, &mut chunk)?;
+            if n == 0 {
+                break;
+            }
+
+            let end = offset + n as u64 - 1;
+            let response = self
+                .agent
+                .request("PATCH", &location)
+                .set("Content-Type", OCTET_STREAM)
+                .set("Content-Range", &format!("{}-{}", offset, end))
+                .send_bytes(&chunk[..n])
+                .with_context(|| format!("PATCH {}", location))?;
+
+            if response.status() != 202 {
+                bail!(
+                    "unexpected status {} uploading chunk {}-{} of {}",
+                    response.status(),
+                    offset,
+                    end,
+                    digest
+                );
+            }
+
+            location = self.location(&response)?;
+            offset = end + 1;
+        }
+
+        let response = self
+            .agent
+            .put(&location)
+            .query("digest", &digest.to_string())
+            .call()
+            .with_context(|| format!("PUT {}", location))?;
+
+        match response.status() {
+            201 => Ok(()),
+            status => bail!("unexpected status {} closing upload of {}", status, digest),
+        }
+    }
+
+    /// Puts a manifest or an index under a tag or digest.
+    pub fn put_manifest(
+        &self,
+        repository: &str,
+        reference: &str,
+        media_type: &MediaType,
+        body: &[u8],
+    ) -> anyhow::Result<()> {
+        let url = format!(
+            "{}/v2/{}/manifests/{}",
+            self.base_url, repository, reference
+        );
+        let response = self
+            .agent
+            .put(&url)
+            .set("Content-Type", media_type.as_str())
+            .send_bytes(body)
+            .with_context(|| format!("PUT {}", url))?;
+
+        match response.status() {
+            201 => Ok(()),
+            status => bail!(
+                "unexpected status {} putting manifest {}",
+                status,
+                reference
+            ),
+        }
+    }
+
+    // Location headers may be absolute or relative to the registry.
+    fn location(&self, response: &Response) -> anyhow::Result<String> {
+        let location = response
+            .header("Location")
+            .ok_or_else(|| anyhow!("registry response is missing a Location header"))?;
+
+        if location.starts_with("http://") || location.starts_with("https://") {
+            Ok(location.to_owned())
+        } else if location.starts_with('/') {
+            Ok(format!("{}{}", self.base_url, location))
+        } else {
+            Ok(format!("{}/{}", self.base_url, location))
+        }
+    }
+}
+
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match reader.read(&mut buf[n..])? {
+            0 => break,
+            read => n += read,
+        }
+    }
+
+    Ok(n)
+}
@@ -0,0 +1,138 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::bail;
+
+use crate::spec::digest::Digest;
+
+/// The registry used when a reference doesn't name one explicitly.
+pub const DEFAULT_REGISTRY: &str = "registry-1.docker.io";
+
+/// The tag used when a reference has neither a tag nor a digest.
+pub const DEFAULT_TAG: &str = "latest";
+
+/// Reference points at a repository inside a distribution registry,
+/// optionally narrowed down to a tag or a digest,
+/// e.g. `localhost:5000/library/alpine:3.14`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reference {
+    pub registry: String,
+    pub repository: String,
+    pub tag: Option<String>,
+    pub digest: Option<Digest>,
+}
+
+impl Reference {
+    /// The reference used when putting the top-level manifest: the digest
+    /// if there is one, the tag otherwise.
+    pub fn target(&self) -> String {
+        match (&self.digest, &self.tag) {
+            (Some(digest), _) => digest.to_string(),
+            (None, Some(tag)) => tag.to_owned(),
+            (None, None) => DEFAULT_TAG.to_owned(),
+        }
+    }
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.registry, self.repository)?;
+        if let Some(tag) = &self.tag {
+            write!(f, ":{}", tag)?;
+        }
+        if let Some(digest) = &self.digest {
+            write!(f, "@{}", digest)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Reference {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, digest) = match s.split_once('@') {
+            Some((name, digest)) => (name, Some(Digest::from_str(digest)?)),
+            None => (s, None),
+        };
+
+        // A colon after the last slash separates the tag, anything before
+        // that may be a registry port.
+        let (name, tag) = match name.rsplit_once(':') {
+            Some((repo, tag)) if !tag.contains('/') => (repo, Some(tag.to_owned())),
+            _ => (name, None),
+        };
+
+        let (registry, repository) = match name.split_once('/') {
+            Some((host, rest))
+                if host.contains('.') || host.contains(':') || host == "localhost" =>
+            {
+                (host.to_owned(), rest.to_owned())
+            }
+            Some(_) => (DEFAULT_REGISTRY.to_owned(), name.to_owned()),
+            None => (DEFAULT_REGISTRY.to_owned(), format!("library/{}", name)),
+        };
+
+        if repository.is_empty() {
+            bail!("missing repository in reference {}", s);
+        }
+
+        if repository.chars().any(|c| c.is_ascii_uppercase()) {
+            bail!("repository name must be lowercase: {}", repository);
+        }
+
+        Ok(Reference {
+            registry,
+            repository,
+            tag,
+            digest,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::{
+        registry::reference::{Reference, DEFAULT_REGISTRY},
+        spec::digest::{Algorithm, Digest},
+    };
+
+    #[test]
+    fn test_parse_reference() {
+        let reference = Reference::from_str("localhost:5000/library/alpine:3.14").unwrap();
+        assert_eq!(
+            reference,
+            Reference {
+                registry: String::from("localhost:5000"),
+                repository: String::from("library/alpine"),
+                tag: Some(String::from("3.14")),
+                digest: None,
+            }
+        );
+        assert_eq!(reference.target(), "3.14");
+
+        let reference = Reference::from_str("alpine").unwrap();
+        assert_eq!(reference.registry, DEFAULT_REGISTRY);
+        assert_eq!(reference.repository, "library/alpine");
+        assert_eq!(reference.target(), "latest");
+
+        let reference = Reference::from_str(
+            "quay.io/org/app@sha256:5b0bcabd1ed22e9fb1310cf6c2dec7cdef19f0ad69efa1f392e94a4333501270",
+        )
+        .unwrap();
+        assert_eq!(reference.registry, "quay.io");
+        assert_eq!(reference.repository, "org/app");
+        assert_eq!(
+            reference.digest,
+            Some(Digest::new(
+                Algorithm::Sha256,
+                String::from("5b0bcabd1ed22e9fb1310cf6c2dec7cdef19f0ad69efa1f392e94a4333501270"),
+            ))
+        );
+
+        assert!(Reference::from_str("localhost:5000/").is_err());
+        assert!(Reference::from_str("localhost:5000/app@sha256:abc").is_err());
+        assert!(Reference::from_str("localhost:5000/Alpine").is_err());
+    }
+}
@@ -0,0 +1,156 @@
+use std::{path::PathBuf, time::Duration};
+
+use serde::{Serialize, Serializer};
+
+use crate::dry_run::DryRunReport;
+use crate::signature::VerifiedSignature;
+use crate::spec::config::Image;
+use crate::spec::descriptor::{Descriptor, Platform};
+use crate::spec::digest::Digest;
+
+/// What an unpack did, for auditing.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UnpackReport {
+    /// The digest of the `index.json` the manifests were selected from.
+    pub index: Digest,
+    pub manifests: Vec<ManifestReport>,
+    pub skipped: Vec<SkippedEntry>,
+    pub warnings: Vec<String>,
+    /// The signatures the images were verified with, if asked to.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub signatures: Vec<VerifiedSignature>,
+    /// What the tree would be, for dry runs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dry_run: Option<DryRunReport>,
+    #[serde(rename = "elapsed_seconds", serialize_with = "seconds")]
+    pub elapsed: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ManifestReport {
+    pub digest: Digest,
+    pub config: Digest,
+    /// The platform of the index entry, or else of the config.
+    pub platform: Option<Platform>,
+    pub layers: Vec<LayerReport>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LayerReport {
+    pub digest: Digest,
+    /// The digest of the decompressed layer.
+    pub diff_id: Digest,
+    pub compressed_bytes: u64,
+    pub uncompressed_bytes: u64,
+    pub entries_written: u64,
+    pub whiteouts_applied: u64,
+}
+
+/// An entry of a layer which wasn't unpacked.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SkippedEntry {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+fn seconds<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+/// PendingManifest builds the report of a manifest as its layers are
+/// applied, checking them against the DiffIDs of its config.
+pub(crate) struct PendingManifest {
+    report: ManifestReport,
+    diff_ids: Option<Vec<Digest>>,
+}
+
+impl PendingManifest {
+    /// `config` holds the verified bytes of the manifest's config.
+    pub(crate) fn new(descriptor: &Descriptor, config: &Descriptor, bytes: &[u8]) -> Self {
+        let image: Option<Image> = serde_json::from_slice(bytes).ok();
+        let platform = descriptor.platform.clone().or_else(|| {
+            image.as_ref().map(|image| Platform {
+                architecture: image.architecture.clone(),
+                os: image.os.clone(),
+                os_version: None,
+                os_features: None,
+                variant: None,
+            })
+        });
+
+        PendingManifest {
+            report: ManifestReport {
+                digest: descriptor.digest.clone(),
+                config: config.digest.clone(),
+                platform,
+                layers: vec![],
+            },
+            diff_ids: image.map(|image| image.rootfs.diff_ids),
+        }
+    }
+
+    pub(crate) fn add_layer(&mut self, layer: LayerReport, warnings: &mut Vec<String>) {
+        let i = self.report.layers.len();
+        match self.diff_ids.as_ref().map(|d| d.get(i)) {
+            None if i == 0 => warnings.push(format!(
+                "config {} is not an image config, DiffIDs aren't checked",
+                self.report.config
+            )),
+            Some(Some(expected)) if *expected != layer.diff_id => warnings.push(format!(
+                "layer {} has DiffID {}, its config says {}",
+                layer.digest, layer.diff_id, expected
+            )),
+            Some(None) => warnings.push(format!(
+                "layer {} has no DiffID in config {}",
+                layer.digest, self.report.config
+            )),
+            _ => {}
+        }
+        self.report.layers.push(layer);
+    }
+
+    pub(crate) fn finish(self) -> ManifestReport {
+        self.report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::Duration};
+
+    use crate::{
+        report::{LayerReport, SkippedEntry, UnpackReport},
+        test_utils::sha256,
+    };
+
+    #[test]
+    fn test_report_json() {
+        let report = UnpackReport {
+            index: sha256(b"index"),
+            manifests: vec![],
+            skipped: vec![SkippedEntry {
+                path: PathBuf::from("dev/null"),
+                reason: String::from("device nodes need privileges"),
+            }],
+            warnings: vec![],
+            signatures: vec![],
+            dry_run: None,
+            elapsed: Duration::from_millis(1500),
+        };
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["elapsed_seconds"], 1.5);
+        assert_eq!(json["skipped"][0]["path"], "dev/null");
+        assert_eq!(json["index"], sha256(b"index").to_string());
+
+        let layer = LayerReport {
+            digest: sha256(b"layer"),
+            diff_id: sha256(b"tar"),
+            compressed_bytes: 5,
+            uncompressed_bytes: 3,
+            entries_written: 1,
+            whiteouts_applied: 0,
+        };
+        let json = serde_json::to_value(&layer).unwrap();
+        assert_eq!(json["diff_id"], sha256(b"tar").to_string());
+    }
+}
@@ -0,0 +1,513 @@
+use std::fmt;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::{
+    rand::SystemRandom,
+    signature::{
+        self as ring_signature, EcdsaKeyPair, Ed25519KeyPair, KeyPair, UnparsedPublicKey,
+        VerificationAlgorithm,
+    },
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::limits::Limits;
+use crate::merge::read_verified;
+use crate::referrers::{self, find_referrers};
+use crate::spec::descriptor::Descriptor;
+use crate::spec::digest::Digest;
+use crate::spec::index::Index;
+use crate::spec::manifest::Manifest;
+use crate::spec::media_types::MediaType;
+use crate::store::BlobStore;
+
+/// The media type of the layers of cosign signatures, holding the payload.
+pub const SIMPLE_SIGNING: &str = "application/vnd.dev.cosign.simplesigning.v1+json";
+/// The annotation of a payload layer holding its base64 signature.
+pub const SIGNATURE_ANNOTATION: &str = "dev.cosignproject.cosign/signature";
+/// The type of the payloads signing container images.
+pub const SIGNATURE_TYPE: &str = "cosign container image signature";
+/// The artifact type of signature manifests attached through their subject.
+pub const SIGNATURE_ARTIFACT_TYPE: &str = "application/vnd.dev.cosign.artifact.sig.v1+json";
+
+// Signature manifests and payloads larger than this are ignored.
+const MAX_SIGNATURE_SIZE: u64 = 1024 * 1024;
+
+// The DER encoded object identifiers of public keys.
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const OID_P256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const OID_P384: &[u8] = &[0x2b, 0x81, 0x04, 0x00, 0x22];
+const OID_ED25519: &[u8] = &[0x2b, 0x65, 0x70];
+
+#[derive(Debug, Error)]
+pub enum SignatureError {
+    #[error("invalid key: {0}")]
+    InvalidKey(&'static str),
+
+    #[error("unsupported public key, expected ECDSA P-256, P-384 or Ed25519")]
+    UnsupportedKey,
+
+    #[error("no signature of {0} verifies with the key")]
+    Unsigned(Digest),
+
+    #[error("a signature of {digest} verifies with the key, but {reason}")]
+    Rejected { digest: Digest, reason: String },
+}
+
+/// A public key signatures are checked against.
+#[derive(Clone)]
+pub struct PublicKey {
+    algorithm: &'static dyn VerificationAlgorithm,
+    bytes: Vec<u8>,
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PublicKey").finish_non_exhaustive()
+    }
+}
+
+impl PublicKey {
+    /// Parses a PEM `PUBLIC KEY`, as written by `cosign generate-key-pair`.
+    pub fn from_pem(pem: &str) -> Result<Self, SignatureError> {
+        PublicKey::from_der(&pem_decode(pem, "PUBLIC KEY")?)
+    }
+
+    /// Parses a DER `SubjectPublicKeyInfo`.
+    pub fn from_der(der: &[u8]) -> Result<Self, SignatureError> {
+        let invalid = || SignatureError::InvalidKey("invalid SubjectPublicKeyInfo");
+        let (spki, _) = der_element(der, 0x30).ok_or_else(invalid)?;
+        let (algorithm, rest) = der_element(spki, 0x30).ok_or_else(invalid)?;
+        let (key, _) = der_element(rest, 0x03).ok_or_else(invalid)?;
+        // Keys are whole bytes, without unused bits.
+        let bytes = match key {
+            [0, bytes @ ..] => bytes.to_vec(),
+            _ => return Err(SignatureError::InvalidKey("invalid key bits")),
+        };
+
+        let (oid, parameters) = der_element(algorithm, 0x06).ok_or_else(invalid)?;
+        let algorithm: &'static dyn VerificationAlgorithm = match oid {
+            OID_EC_PUBLIC_KEY => match der_element(parameters, 0x06) {
+                Some((OID_P256, _)) => &ring_signature::ECDSA_P256_SHA256_ASN1,
+                // cosign hashes payloads with SHA-256 whatever the curve.
+                Some((OID_P384, _)) => &ring_signature::ECDSA_P384_SHA256_ASN1,
+                _ => return Err(SignatureError::UnsupportedKey),
+            },
+            OID_ED25519 => &ring_signature::ED25519,
+            _ => return Err(SignatureError::UnsupportedKey),
+        };
+
+        Ok(PublicKey { algorithm, bytes })
+    }
+
+    /// Checks `signature` of `message`, ECDSA signatures are DER encoded.
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        UnparsedPublicKey::new(self.algorithm, &self.bytes)
+            .verify(message, signature)
+            .is_ok()
+    }
+}
+
+/// A private key images are signed with.
+pub struct PrivateKey(KeyPairKind);
+
+enum KeyPairKind {
+    Ecdsa(EcdsaKeyPair),
+    Ed25519(Ed25519KeyPair),
+}
+
+impl fmt::Debug for PrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PrivateKey").finish_non_exhaustive()
+    }
+}
+
+impl PrivateKey {
+    /// Parses a PEM PKCS#8 `PRIVATE KEY`, as written by `openssl genpkey`.
+    pub fn from_pem(pem: &str) -> Result<Self, SignatureError> {
+        PrivateKey::from_pkcs8(&pem_decode(pem, "PRIVATE KEY")?)
+    }
+
+    /// Parses a DER PKCS#8 ECDSA P-256 or Ed25519 key.
+    pub fn from_pkcs8(der: &[u8]) -> Result<Self, SignatureError> {
+        let invalid = || SignatureError::InvalidKey("invalid PKCS#8 private key");
+        let (info, _) = der_element(der, 0x30).ok_or_else(invalid)?;
+        let (_version, rest) = der_element(info, 0x02).ok_or_else(invalid)?;
+        let (algorithm, _) = der_element(rest, 0x30).ok_or_else(invalid)?;
+        let (oid, parameters) = der_element(algorithm, 0x06).ok_or_else(invalid)?;
+
+        let key = match oid {
+            OID_EC_PUBLIC_KEY => match der_element(parameters, 0x06) {
+                Some((OID_P256, _)) => KeyPairKind::Ecdsa(
+                    EcdsaKeyPair::from_pkcs8(
+                        &ring_signature::ECDSA_P256_SHA256_ASN1_SIGNING,
+                        der,
+                        &SystemRandom::new(),
+                    )
+                    .map_err(|_| invalid())?,
+                ),
+                _ => return Err(SignatureError::UnsupportedKey),
+            },
+            // `openssl genpkey` writes PKCS#8 v1 keys, without the public key.
+            OID_ED25519 => KeyPairKind::Ed25519(
+                Ed25519KeyPair::from_pkcs8_maybe_unchecked(der).map_err(|_| invalid())?,
+            ),
+            _ => return Err(SignatureError::UnsupportedKey),
+        };
+
+        Ok(PrivateKey(key))
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        let (algorithm, bytes): (&'static dyn VerificationAlgorithm, &[u8]) = match &self.0 {
+            KeyPairKind::Ecdsa(key) => (
+                &ring_signature::ECDSA_P256_SHA256_ASN1,
+                key.public_key().as_ref(),
+            ),
+            KeyPairKind::Ed25519(key) => (&ring_signature::ED25519, key.public_key().as_ref()),
+        };
+        PublicKey {
+            algorithm,
+            bytes: bytes.to_vec(),
+        }
+    }
+
+    /// Signs `message`, ECDSA signatures are DER encoded as cosign expects.
+    pub fn sign(&self, message: &[u8]) -> Vec<u8> {
+        match &self.0 {
+            KeyPairKind::Ecdsa(key) => key
+                .sign(&SystemRandom::new(), message)
+                .expect("ECDSA signing failed")
+                .as_ref()
+                .to_vec(),
+            KeyPairKind::Ed25519(key) => key.sign(message).as_ref().to_vec(),
+        }
+    }
+}
+
+// Decodes the PEM block labelled `label`.
+fn pem_decode(pem: &str, label: &str) -> Result<Vec<u8>, SignatureError> {
+    let body: String = pem
+        .trim()
+        .strip_prefix(&format!("-----BEGIN {}-----", label))
+        .and_then(|pem| pem.strip_suffix(&format!("-----END {}-----", label)))
+        .ok_or(SignatureError::InvalidKey("expected a PEM key"))?
+        .split_whitespace()
+        .collect();
+    STANDARD
+        .decode(body)
+        .map_err(|_| SignatureError::InvalidKey("invalid base64"))
+}
+
+// Splits the DER element with `tag` at the start of `input` into its
+// content and what follows it.
+fn der_element(input: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    let (&actual, rest) = input.split_first()?;
+    if actual != tag {
+        return None;
+    }
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = match first {
+        0..=0x7f => (first as usize, rest),
+        // The long form, with the length over the next 1 or 2 bytes.
+        0x81 => (*rest.first()? as usize, &rest[1..]),
+        0x82 => (
+            u16::from_be_bytes([*rest.first()?, *rest.get(1)?]) as usize,
+            rest.get(2..)?,
+        ),
+        _ => return None,
+    };
+    (len <= rest.len()).then(|| rest.split_at(len))
+}
+
+/// The payload cosign signs, in the "simple signing" format.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Payload {
+    pub critical: Critical,
+    pub optional: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Critical {
+    pub identity: Identity,
+    pub image: SignedImage,
+    #[serde(rename = "type")]
+    pub typ: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Identity {
+    #[serde(rename = "docker-reference")]
+    pub docker_reference: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedImage {
+    #[serde(rename = "docker-manifest-digest")]
+    pub docker_manifest_digest: Digest,
+}
+
+/// A signature of an image which verified with the key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct VerifiedSignature {
+    /// The manifest of the signed image.
+    pub image: Digest,
+    /// The manifest holding the signature.
+    pub signature: Digest,
+    /// The reference the image was signed as.
+    pub docker_reference: String,
+}
+
+/// Checks that every image of `index` an unpack would unpack has a cosign
+/// signature verifying with `key`. Signatures are found among the referrers
+/// of the images, either tagged `sha256-<hex>.sig` or through their
+/// `subject`.
+pub fn verify_images<S: BlobStore>(
+    store: &S,
+    index: &Index,
+    key: &PublicKey,
+    limits: &Limits,
+) -> anyhow::Result<Vec<VerifiedSignature>> {
+    referrers::select_images(store, index, limits.max_manifest_size)?
+        .into_iter()
+        .map(|image| verify_image(store, index, &image.digest, key))
+        .collect()
+}
+
+/// Checks that the manifest `image` has a cosign signature verifying with
+/// `key`, and signing that very manifest.
+pub fn verify_image<S: BlobStore>(
+    store: &S,
+    index: &Index,
+    image: &Digest,
+    key: &PublicKey,
+) -> anyhow::Result<VerifiedSignature> {
+    let mut rejected = None;
+    for referrer in find_referrers(store, index, image)? {
+        if referrer.media_type != MediaType::ImageManifest || referrer.size > MAX_SIGNATURE_SIZE {
+            continue;
+        }
+        let manifest: Manifest = serde_json::from_slice(&read_verified(store, &referrer)?)?;
+        for layer in &manifest.layers {
+            match check_layer(store, layer, image, key)? {
+                Checked::Verified(payload) => {
+                    return Ok(VerifiedSignature {
+                        image: image.clone(),
+                        signature: referrer.digest,
+                        docker_reference: payload.critical.identity.docker_reference,
+                    })
+                }
+                Checked::Rejected(reason) => rejected = Some(reason),
+                Checked::Unverified => {}
+            }
+        }
+    }
+
+    Err(match rejected {
+        Some(reason) => SignatureError::Rejected {
+            digest: image.clone(),
+            reason,
+        },
+        None => SignatureError::Unsigned(image.clone()),
+    }
+    .into())
+}
+
+// What checking a layer of a signature manifest found.
+enum Checked {
+    Verified(Payload),
+    // The signature verifies but doesn't sign the image.
+    Rejected(String),
+    // Not a signature, or not one made with the key.
+    Unverified,
+}
+
+fn check_layer<S: BlobStore>(
+    store: &S,
+    layer: &Descriptor,
+    image: &Digest,
+    key: &PublicKey,
+) -> anyhow::Result<Checked> {
+    let signature = layer
+        .annotations
+        .as_ref()
+        .and_then(|a| a.get(SIGNATURE_ANNOTATION));
+    let signature = match signature {
+        Some(signature) if layer.media_type.as_str() == SIMPLE_SIGNING => signature,
+        _ => return Ok(Checked::Unverified),
+    };
+    if layer.size > MAX_SIGNATURE_SIZE {
+        return Ok(Checked::Unverified);
+    }
+
+    let bytes = read_verified(store, layer)?;
+    match STANDARD.decode(signature) {
+        Ok(signature) if key.verify(&bytes, &signature) => {}
+        _ => return Ok(Checked::Unverified),
+    }
+    // The payload is only trusted once its signature is.
+    let payload: Payload = match serde_json::from_slice(&bytes) {
+        Ok(payload) => payload,
+        Err(e) => return Ok(Checked::Rejected(format!("its payload is invalid: {}", e))),
+    };
+    if payload.critical.typ != SIGNATURE_TYPE {
+        return Ok(Checked::Rejected(format!(
+            "its payload has type {:?}",
+            payload.critical.typ
+        )));
+    }
+    if &payload.critical.image.docker_manifest_digest != image {
+        return Ok(Checked::Rejected(format!(
+            "it signs {}",
+            payload.critical.image.docker_manifest_digest
+        )));
+    }
+
+    Ok(Checked::Verified(payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use ring::{
+        rand::SystemRandom,
+        signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING},
+    };
+
+    use crate::{
+        limits::Limits,
+        referrers::fallback_tag,
+        signature::{
+            verify_images, Critical, Identity, Payload, PublicKey, SignatureError, SignedImage,
+            SIGNATURE_ANNOTATION, SIGNATURE_TYPE, SIMPLE_SIGNING,
+        },
+        spec::{
+            descriptor::Descriptor, digest::Digest, manifest::Manifest, media_types::MediaType,
+        },
+        store::DirectoryStore,
+        test_utils::{tar, TestEntry, TestLayout},
+        unpacker::Unpacker,
+    };
+
+    const P256_SPKI: &[u8] = &[
+        0x30, 0x59, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x08,
+        0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07, 0x03, 0x42, 0x00,
+    ];
+    const ED25519_SPKI: &[u8] = &[
+        0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+    ];
+
+    fn pem(prefix: &[u8], key: &[u8]) -> String {
+        let der = [prefix, key].concat();
+        format!(
+            "-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----\n",
+            STANDARD.encode(der)
+        )
+    }
+
+    // Writes a signature manifest of `signed` as cosign does.
+    fn write_signature(
+        layout: &TestLayout,
+        signed: &Digest,
+        subject: Option<&Descriptor>,
+        sign: impl Fn(&[u8]) -> Vec<u8>,
+    ) -> Descriptor {
+        let payload = Payload {
+            critical: Critical {
+                identity: Identity {
+                    docker_reference: String::from("example.com/app"),
+                },
+                image: SignedImage {
+                    docker_manifest_digest: signed.clone(),
+                },
+                typ: String::from(SIGNATURE_TYPE),
+            },
+            optional: None,
+        };
+        let payload = serde_json::to_vec(&payload).unwrap();
+        let mut layer = layout.write_blob(MediaType::Other(String::from(SIMPLE_SIGNING)), &payload);
+        layer.annotations = Some(HashMap::from([(
+            String::from(SIGNATURE_ANNOTATION),
+            STANDARD.encode(sign(&payload)),
+        )]));
+        let manifest = Manifest {
+            schema_version: 2,
+            media_type: Some(MediaType::ImageManifest),
+            artifact_type: None,
+            config: layout.write_blob(MediaType::ImageConfig, b"{}"),
+            layers: vec![layer],
+            subject: subject.cloned(),
+            annotations: None,
+        };
+        layout.write_blob(
+            MediaType::ImageManifest,
+            &serde_json::to_vec(&manifest).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_verify_signatures() {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+        let ecdsa = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng)
+            .unwrap();
+        let ecdsa_key = PublicKey::from_pem(&pem(P256_SPKI, ecdsa.public_key().as_ref())).unwrap();
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let ed25519 = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let ed25519_key =
+            PublicKey::from_pem(&pem(ED25519_SPKI, ed25519.public_key().as_ref())).unwrap();
+
+        let layout = TestLayout::new();
+        let store = DirectoryStore::new(layout.path());
+        let image = layout.add_image(&[tar(&[TestEntry::File("a", b"a")])], None);
+        let error =
+            verify_images(&store, &layout.index(), &ecdsa_key, &Limits::default()).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(SignatureError::Unsigned(digest)) if *digest == image.digest
+        ));
+
+        // Tagged like cosign does, signed with ECDSA.
+        let signature = write_signature(&layout, &image.digest, None, |payload| {
+            ecdsa.sign(&rng, payload).unwrap().as_ref().to_vec()
+        });
+        let tag = format!("{}.sig", fallback_tag(&image.digest));
+        layout.add_to_index(signature.clone(), Some(&tag));
+        // Attached through its subject, signed with Ed25519.
+        let other = write_signature(&layout, &image.digest, Some(&image), |payload| {
+            ed25519.sign(payload).as_ref().to_vec()
+        });
+        layout.add_to_index(other.clone(), None);
+
+        let index = layout.index();
+        let verified = verify_images(&store, &index, &ecdsa_key, &Limits::default()).unwrap();
+        assert_eq!(verified.len(), 1);
+        assert_eq!(verified[0].signature, signature.digest);
+        assert_eq!(verified[0].docker_reference, "example.com/app");
+        let verified = verify_images(&store, &index, &ed25519_key, &Limits::default()).unwrap();
+        assert_eq!(verified[0].signature, other.digest);
+
+        let destination = layout.dir.path().join("rootfs");
+        let report = Unpacker::new(layout.path(), destination.to_str().unwrap().to_owned())
+            .verify_signatures(ecdsa_key.clone())
+            .unpack()
+            .unwrap();
+        assert_eq!(report.manifests.len(), 1);
+        assert_eq!(report.signatures[0].signature, signature.digest);
+
+        // A signature of the first image doesn't sign a second one.
+        let second = layout.add_image(&[tar(&[TestEntry::File("b", b"b")])], None);
+        let replayed = write_signature(&layout, &image.digest, Some(&second), |payload| {
+            ecdsa.sign(&rng, payload).unwrap().as_ref().to_vec()
+        });
+        layout.add_to_index(replayed, None);
+        let error =
+            verify_images(&store, &layout.index(), &ecdsa_key, &Limits::default()).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(SignatureError::Rejected { digest, .. }) if *digest == second.digest
+        ));
+    }
+}
@@ -0,0 +1,277 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use chrono::{DateTime, FixedOffset, SecondsFormat, TimeZone};
+use thiserror::Error;
+
+use super::descriptor::Descriptor;
+use super::digest::{Digest, DigestError};
+use super::index::Index;
+use super::manifest::Manifest;
+
+/// The date and time the image was built, as an RFC 3339 date-time.
+pub const ANNOTATION_CREATED: &str = "org.opencontainers.image.created";
+/// The people or organizations responsible for the image.
+pub const ANNOTATION_AUTHORS: &str = "org.opencontainers.image.authors";
+/// The URL to find more information on the image.
+pub const ANNOTATION_URL: &str = "org.opencontainers.image.url";
+/// The URL to get documentation on the image.
+pub const ANNOTATION_DOCUMENTATION: &str = "org.opencontainers.image.documentation";
+/// The URL to get the source code building the image.
+pub const ANNOTATION_SOURCE: &str = "org.opencontainers.image.source";
+/// The version of the packaged software.
+pub const ANNOTATION_VERSION: &str = "org.opencontainers.image.version";
+/// The source control revision the image was built from.
+pub const ANNOTATION_REVISION: &str = "org.opencontainers.image.revision";
+/// The name of the distributing entity, organization or individual.
+pub const ANNOTATION_VENDOR: &str = "org.opencontainers.image.vendor";
+/// The licenses of the software in the image, as an SPDX expression.
+pub const ANNOTATION_LICENSES: &str = "org.opencontainers.image.licenses";
+/// The annotation key holding the name of a reference for a target,
+/// used in `index.json` to tag manifests.
+pub const ANNOTATION_REF_NAME: &str = "org.opencontainers.image.ref.name";
+/// The human-readable title of the image.
+pub const ANNOTATION_TITLE: &str = "org.opencontainers.image.title";
+/// The human-readable description of the software in the image.
+pub const ANNOTATION_DESCRIPTION: &str = "org.opencontainers.image.description";
+/// The reference of the image the image is based on.
+pub const ANNOTATION_BASE_NAME: &str = "org.opencontainers.image.base.name";
+/// The manifest digest of the image the image is based on.
+pub const ANNOTATION_BASE_DIGEST: &str = "org.opencontainers.image.base.digest";
+
+/// Errors returned when reading or validating annotations.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum AnnotationError {
+    #[error("annotation key {0:?} doesn't follow reverse domain notation")]
+    InvalidKey(String),
+
+    #[error("invalid {ANNOTATION_CREATED} {0:?}, expected an RFC 3339 date-time")]
+    InvalidCreated(String),
+
+    #[error("invalid {ANNOTATION_BASE_DIGEST}: {0}")]
+    InvalidBaseDigest(DigestError),
+}
+
+/// Checks that `key` follows reverse domain notation, e.g. `com.example.myKey`:
+/// at least two lowercase domain labels followed by a name.
+pub fn validate_key(key: &str) -> Result<(), AnnotationError> {
+    let invalid = || AnnotationError::InvalidKey(key.to_owned());
+    let (domain, name) = key.rsplit_once('.').ok_or_else(invalid)?;
+    let labels: Vec<_> = domain.split('.').collect();
+    let valid_label = |label: &&str| {
+        !label.is_empty()
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label
+                .bytes()
+                .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+    };
+    // Names may nest, as in cosign's `dev.cosignproject.cosign/signature`.
+    let valid_name = !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_/".contains(&b));
+    if labels.len() < 2 || !labels.iter().all(valid_label) || !valid_name {
+        return Err(invalid());
+    }
+
+    Ok(())
+}
+
+// Defines a getter and a setter of an annotation holding a plain string.
+macro_rules! string_annotation {
+    ($(#[$meta:meta])* $getter:ident, $setter:ident, $key:ident) => {
+        $(#[$meta])*
+        fn $getter(&self) -> Option<&str> {
+            self.annotation($key)
+        }
+
+        fn $setter(&mut self, value: impl Into<String>) {
+            self.set_annotation($key, value);
+        }
+    };
+}
+
+/// Typed access to the annotations of indexes, manifests and descriptors,
+/// with the keys predefined by the image-spec.
+pub trait Annotated {
+    fn annotations(&self) -> Option<&HashMap<String, String>>;
+
+    fn annotations_mut(&mut self) -> &mut Option<HashMap<String, String>>;
+
+    fn annotation(&self, key: &str) -> Option<&str> {
+        self.annotations()?.get(key).map(String::as_str)
+    }
+
+    fn set_annotation(&mut self, key: &str, value: impl Into<String>) {
+        self.annotations_mut()
+            .get_or_insert_with(HashMap::new)
+            .insert(key.to_owned(), value.into());
+    }
+
+    /// Removes an annotation, dropping the map once empty.
+    fn remove_annotation(&mut self, key: &str) -> Option<String> {
+        let annotations = self.annotations_mut();
+        let value = annotations.as_mut()?.remove(key);
+        if annotations.as_ref().is_some_and(HashMap::is_empty) {
+            *annotations = None;
+        }
+        value
+    }
+
+    /// The creation date of the image, see [`ANNOTATION_CREATED`].
+    fn created(&self) -> Result<Option<DateTime<FixedOffset>>, AnnotationError> {
+        self.annotation(ANNOTATION_CREATED)
+            .map(|created| {
+                DateTime::parse_from_rfc3339(created)
+                    .map_err(|_| AnnotationError::InvalidCreated(created.to_owned()))
+            })
+            .transpose()
+    }
+
+    fn set_created<Tz: TimeZone>(&mut self, created: &DateTime<Tz>)
+    where
+        Tz::Offset: fmt::Display,
+    {
+        let created = created.to_rfc3339_opts(SecondsFormat::AutoSi, true);
+        self.set_annotation(ANNOTATION_CREATED, created);
+    }
+
+    string_annotation!(authors, set_authors, ANNOTATION_AUTHORS);
+    string_annotation!(url, set_url, ANNOTATION_URL);
+    string_annotation!(documentation, set_documentation, ANNOTATION_DOCUMENTATION);
+    string_annotation!(source, set_source, ANNOTATION_SOURCE);
+    string_annotation!(version, set_version, ANNOTATION_VERSION);
+    string_annotation!(revision, set_revision, ANNOTATION_REVISION);
+    string_annotation!(vendor, set_vendor, ANNOTATION_VENDOR);
+    string_annotation!(licenses, set_licenses, ANNOTATION_LICENSES);
+    string_annotation!(
+        /// The tag of a manifest in `index.json`, see [`ANNOTATION_REF_NAME`].
+        ref_name,
+        set_ref_name,
+        ANNOTATION_REF_NAME
+    );
+    string_annotation!(title, set_title, ANNOTATION_TITLE);
+    string_annotation!(description, set_description, ANNOTATION_DESCRIPTION);
+    string_annotation!(base_name, set_base_name, ANNOTATION_BASE_NAME);
+
+    /// The manifest digest of the base image, see [`ANNOTATION_BASE_DIGEST`].
+    fn base_digest(&self) -> Result<Option<Digest>, AnnotationError> {
+        self.annotation(ANNOTATION_BASE_DIGEST)
+            .map(|digest| digest.parse().map_err(AnnotationError::InvalidBaseDigest))
+            .transpose()
+    }
+
+    fn set_base_digest(&mut self, digest: &Digest) {
+        self.set_annotation(ANNOTATION_BASE_DIGEST, digest.to_string());
+    }
+
+    /// Checks that every key follows reverse domain notation, and that the
+    /// typed annotations parse.
+    fn validate_annotations(&self) -> Result<(), AnnotationError> {
+        for key in self.annotations().into_iter().flat_map(HashMap::keys) {
+            validate_key(key)?;
+        }
+        self.created()?;
+        self.base_digest()?;
+
+        Ok(())
+    }
+}
+
+macro_rules! impl_annotated {
+    ($($name:ty),*) => {
+        $(
+            impl Annotated for $name {
+                fn annotations(&self) -> Option<&HashMap<String, String>> {
+                    self.annotations.as_ref()
+                }
+
+                fn annotations_mut(&mut self) -> &mut Option<HashMap<String, String>> {
+                    &mut self.annotations
+                }
+            }
+        )*
+    };
+}
+
+impl_annotated!(Index, Manifest, Descriptor);
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Utc};
+
+    use crate::spec::{
+        annotations::{
+            validate_key, Annotated, AnnotationError, ANNOTATION_BASE_DIGEST, ANNOTATION_CREATED,
+            ANNOTATION_LICENSES, ANNOTATION_REF_NAME,
+        },
+        descriptor::Descriptor,
+        digest::Digest,
+    };
+
+    #[test]
+    fn test_annotations() {
+        let mut descriptor = Descriptor::empty();
+        assert_eq!(descriptor.created(), Ok(None));
+        assert_eq!(descriptor.ref_name(), None);
+
+        let created = DateTime::parse_from_rfc3339("2023-11-14T22:13:20Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        descriptor.set_created(&created);
+        descriptor.set_ref_name("latest");
+        descriptor.set_licenses("Apache-2.0 OR MIT");
+        let digest: Digest =
+            "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a"
+                .parse()
+                .unwrap();
+        descriptor.set_base_digest(&digest);
+        assert_eq!(
+            descriptor.annotation(ANNOTATION_CREATED),
+            Some("2023-11-14T22:13:20Z")
+        );
+        assert_eq!(descriptor.created(), Ok(Some(created.into())));
+        assert_eq!(descriptor.ref_name(), Some("latest"));
+        assert_eq!(descriptor.licenses(), Some("Apache-2.0 OR MIT"));
+        assert_eq!(descriptor.base_digest(), Ok(Some(digest)));
+        assert_eq!(descriptor.validate_annotations(), Ok(()));
+
+        descriptor.set_annotation(ANNOTATION_CREATED, "yesterday");
+        assert_eq!(
+            descriptor.created(),
+            Err(AnnotationError::InvalidCreated(String::from("yesterday")))
+        );
+        assert!(descriptor.validate_annotations().is_err());
+        descriptor.remove_annotation(ANNOTATION_CREATED);
+        descriptor.set_annotation(ANNOTATION_BASE_DIGEST, "sha256:1234");
+        assert!(matches!(
+            descriptor.base_digest(),
+            Err(AnnotationError::InvalidBaseDigest(_))
+        ));
+
+        descriptor.remove_annotation(ANNOTATION_BASE_DIGEST);
+        descriptor.set_annotation("myKey", "value");
+        assert_eq!(
+            descriptor.validate_annotations(),
+            Err(AnnotationError::InvalidKey(String::from("myKey")))
+        );
+        for key in ["com.example.myKey", "dev.cosignproject.cosign/signature"] {
+            assert_eq!(validate_key(key), Ok(()), "{}", key);
+        }
+        for key in [
+            "example.key",
+            "com..key",
+            "Com.example.key",
+            "com.example.",
+            "com.-a.b",
+        ] {
+            assert!(validate_key(key).is_err(), "{}", key);
+        }
+
+        for key in ["myKey", ANNOTATION_REF_NAME, ANNOTATION_LICENSES] {
+            descriptor.remove_annotation(key);
+        }
+        assert_eq!(descriptor.annotations, None);
+    }
+}
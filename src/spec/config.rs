@@ -1,47 +1,370 @@
 use super::digest::Digest;
+use super::platform::{Arch, Os};
+
+use chrono::{DateTime, FixedOffset, Timelike};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{BTreeSet, HashMap};
+use std::{fmt, str::FromStr};
+use thiserror::Error;
+
+/// Errors returned when parsing the fields of an image config.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ConfigError {
+    #[error("invalid port {0:?}, expected `port/tcp`, `port/udp`, `port/sctp` or `port`")]
+    InvalidPort(String),
+
+    #[error("invalid signal {0:?}")]
+    InvalidSignal(String),
+}
 
-use chrono::{DateTime, FixedOffset};
-use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use std::collections::HashMap;
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+    Sctp,
+}
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// An exposed port, e.g. `8080/tcp`. Ports without a protocol are TCP.
+///
+/// Ports are kept as written, e.g. `8080/TCP`, so configs serialize back
+/// to the same bytes. They are ordered like the keys of `ExposedPorts`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Port {
+    raw: String,
+    number: u16,
+    protocol: Option<Protocol>,
+}
+
+impl Port {
+    pub fn new(number: u16, protocol: Option<Protocol>) -> Self {
+        let raw = match protocol {
+            None => number.to_string(),
+            Some(Protocol::Tcp) => format!("{}/tcp", number),
+            Some(Protocol::Udp) => format!("{}/udp", number),
+            Some(Protocol::Sctp) => format!("{}/sctp", number),
+        };
+        Port {
+            raw,
+            number,
+            protocol,
+        }
+    }
+
+    pub fn number(&self) -> u16 {
+        self.number
+    }
+
+    pub fn protocol(&self) -> Option<Protocol> {
+        self.protocol
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.raw
+    }
+}
+
+impl fmt::Display for Port {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.raw)
+    }
+}
+
+impl FromStr for Port {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ConfigError::InvalidPort(s.to_owned());
+        let (number, protocol) = match s.split_once('/') {
+            Some((number, protocol)) => match protocol.to_ascii_lowercase().as_str() {
+                "tcp" => (number, Some(Protocol::Tcp)),
+                "udp" => (number, Some(Protocol::Udp)),
+                "sctp" => (number, Some(Protocol::Sctp)),
+                _ => return Err(invalid()),
+            },
+            None => (s, None),
+        };
+        if !number.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(invalid());
+        }
+
+        Ok(Port {
+            raw: s.to_owned(),
+            number: number.parse().map_err(|_| invalid())?,
+            protocol,
+        })
+    }
+}
+
+/// The signal stopping a container, by name like `SIGTERM` and `SIGRTMIN+3`
+/// or by number.
+///
+/// Signals are kept as written, e.g. `SIGRTMIN3`, so configs serialize back
+/// to the same bytes.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Signal {
+    raw: String,
+    kind: SignalKind,
+}
+
+/// What a [`Signal`] names.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum SignalKind {
+    /// A signal name as written, usually with its `SIG` prefix.
+    Named(String),
+    /// `SIGRTMIN+n`.
+    RtMin(u32),
+    /// `SIGRTMAX-n`.
+    RtMax(u32),
+    Number(u32),
+}
+
+// The numbers of the named signals on Linux.
+const SIGNALS: [(&str, u32); 31] = [
+    ("HUP", 1),
+    ("INT", 2),
+    ("QUIT", 3),
+    ("ILL", 4),
+    ("TRAP", 5),
+    ("ABRT", 6),
+    ("BUS", 7),
+    ("FPE", 8),
+    ("KILL", 9),
+    ("USR1", 10),
+    ("SEGV", 11),
+    ("USR2", 12),
+    ("PIPE", 13),
+    ("ALRM", 14),
+    ("TERM", 15),
+    ("STKFLT", 16),
+    ("CHLD", 17),
+    ("CONT", 18),
+    ("STOP", 19),
+    ("TSTP", 20),
+    ("TTIN", 21),
+    ("TTOU", 22),
+    ("URG", 23),
+    ("XCPU", 24),
+    ("XFSZ", 25),
+    ("VTALRM", 26),
+    ("PROF", 27),
+    ("WINCH", 28),
+    ("IO", 29),
+    ("PWR", 30),
+    ("SYS", 31),
+];
+const SIGRTMIN: u32 = 34;
+const SIGRTMAX: u32 = 64;
+
+impl Signal {
+    pub fn kind(&self) -> &SignalKind {
+        &self.kind
+    }
+
+    /// The number of the signal on Linux, None for unknown names.
+    pub fn number(&self) -> Option<u32> {
+        match &self.kind {
+            SignalKind::Named(name) => {
+                let name = name.to_ascii_uppercase();
+                let name = name.strip_prefix("SIG").unwrap_or(&name);
+                SIGNALS.iter().find(|(n, _)| *n == name).map(|(_, n)| *n)
+            }
+            SignalKind::RtMin(n) => Some(SIGRTMIN + n).filter(|n| *n <= SIGRTMAX),
+            SignalKind::RtMax(n) => SIGRTMAX.checked_sub(*n).filter(|n| *n >= SIGRTMIN),
+            SignalKind::Number(n) => Some(*n),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.raw
+    }
+}
+
+impl From<SignalKind> for Signal {
+    fn from(kind: SignalKind) -> Self {
+        let raw = match &kind {
+            SignalKind::Named(name) => name.clone(),
+            SignalKind::RtMin(0) => String::from("SIGRTMIN"),
+            SignalKind::RtMin(n) => format!("SIGRTMIN+{}", n),
+            SignalKind::RtMax(0) => String::from("SIGRTMAX"),
+            SignalKind::RtMax(n) => format!("SIGRTMAX-{}", n),
+            SignalKind::Number(n) => n.to_string(),
+        };
+        Signal { raw, kind }
+    }
+}
+
+impl fmt::Display for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.raw)
+    }
+}
+
+impl FromStr for Signal {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ConfigError::InvalidSignal(s.to_owned());
+        let offset = |n: &str| match n {
+            "" => Ok(0),
+            n if n.bytes().all(|b| b.is_ascii_digit()) => n.parse().map_err(|_| invalid()),
+            _ => Err(invalid()),
+        };
+
+        // Names are matched like Docker does, in any case and with or
+        // without their `SIG` prefix.
+        let upper = s.to_ascii_uppercase();
+        let name = upper.strip_prefix("SIG").unwrap_or(&upper);
+        let kind = if let Some(n) = name.strip_prefix("RTMIN") {
+            SignalKind::RtMin(offset(n.strip_prefix('+').unwrap_or(n))?)
+        } else if let Some(n) = name.strip_prefix("RTMAX") {
+            SignalKind::RtMax(offset(n.strip_prefix('-').unwrap_or(n))?)
+        } else if !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()) {
+            SignalKind::Number(s.parse().map_err(|_| invalid())?)
+        } else if !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric()) {
+            SignalKind::Named(s.to_owned())
+        } else {
+            return Err(invalid());
+        };
+
+        Ok(Signal {
+            raw: s.to_owned(),
+            kind,
+        })
+    }
+}
+
+impl Serialize for Signal {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Signal {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        Signal::from_str(&s).map_err(de::Error::custom)
+    }
+}
+
+// Dates are written like Go's RFC3339Nano, as most configs are, so parsed
+// configs serialize back to the same bytes.
+fn serialize_date<S>(date: &Option<DateTime<FixedOffset>>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let date = match date {
+        Some(date) => date,
+        None => return serializer.serialize_none(),
+    };
+    let mut formatted = date.format("%Y-%m-%dT%H:%M:%S").to_string();
+    if date.nanosecond() != 0 {
+        let fraction = format!(".{:09}", date.nanosecond());
+        formatted.push_str(fraction.trim_end_matches('0'));
+    }
+    match date.offset().local_minus_utc() {
+        0 => formatted.push('Z'),
+        _ => formatted.push_str(&date.format("%:z").to_string()),
+    }
+    serializer.serialize_str(&formatted)
+}
+
+// Sets are objects with empty values in configs, like `{"8080/tcp": {}}`.
+mod object_set {
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::{fmt, str::FromStr};
+
+    use serde::Serializer;
+    use serde::{de, de::IgnoredAny, ser::SerializeMap, Deserialize, Deserializer, Serialize};
+
+    #[derive(Serialize)]
+    struct Empty {}
+
+    pub fn serialize<T, S>(set: &Option<BTreeSet<T>>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        T: fmt::Display,
+        S: Serializer,
+    {
+        match set {
+            None => serializer.serialize_none(),
+            Some(set) => {
+                let mut map = serializer.serialize_map(Some(set.len()))?;
+                for value in set {
+                    map.serialize_entry(&value.to_string(), &Empty {})?;
+                }
+                map.end()
+            }
+        }
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<Option<BTreeSet<T>>, D::Error>
+    where
+        T: FromStr + Ord,
+        T::Err: fmt::Display,
+        D: Deserializer<'de>,
+    {
+        let map: Option<BTreeMap<String, IgnoredAny>> = Option::deserialize(deserializer)?;
+        map.map(|map| {
+            map.into_keys()
+                .map(|key| key.parse().map_err(de::Error::custom))
+                .collect()
+        })
+        .transpose()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct ImageConfig {
     // User defines the username or UID which the process in the container should run as.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub user: Option<String>,
 
     // ExposedPorts a set of ports to expose from a container running this image.
-    // TODO: change later to normal port key
-    pub exposed_ports: Option<HashMap<String, Value>>,
+    #[serde(default, with = "object_set", skip_serializing_if = "Option::is_none")]
+    pub exposed_ports: Option<BTreeSet<Port>>,
 
     // Env is a list of environment variables to be used in a container.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub env: Option<Vec<String>>,
 
     // Entrypoint defines a list of arguments to use as the command to execute when the container starts.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub entrypoint: Option<Vec<String>>,
 
     // Cmd defines the default arguments to the entrypoint of the container.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub cmd: Option<Vec<String>>,
 
     // Volumes is a set of directories describing where the process is likely write data specific to a container instance.
-    pub volumes: Option<HashMap<String, Value>>,
+    #[serde(default, with = "object_set", skip_serializing_if = "Option::is_none")]
+    pub volumes: Option<BTreeSet<String>>,
 
     // WorkingDir sets the current working directory of the entrypoint process in the container.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub working_dir: Option<String>,
 
     // Labels contains arbitrary metadata for the container.
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "super::document::serialize_sorted"
+    )]
     pub labels: Option<HashMap<String, String>>,
 
     // StopSignal contains the system call signal that will be sent to the container to exit.
-    pub stop_signal: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_signal: Option<Signal>,
 }
 
 // RootFS describes a layer content addresses
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct RootFs {
-    // Type is the type of the rootfs.
-    // TODO: This has to be "layers", treat and validate accordingly
+    // Type is the type of the rootfs, always "layers", see `validate_config`.
     #[serde(rename = "type")]
     pub typ: String,
 
@@ -53,18 +376,26 @@ pub struct RootFs {
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct History {
     // Created is the combined date and time at which the layer was created, formatted as defined by RFC 3339, section 5.6.
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_date"
+    )]
     pub created: Option<DateTime<FixedOffset>>,
 
     // CreatedBy is the command which created the layer.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub created_by: Option<String>,
 
     // Author is the author of the build point.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub author: Option<String>,
 
     // Comment is a custom message set when creating the layer.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub comment: Option<String>,
 
     // EmptyLayer is used to mark if the history item created a filesystem diff.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub empty_layer: Option<bool>,
 }
 
@@ -74,36 +405,236 @@ pub struct History {
 #[serde(rename_all = "camelCase")]
 pub struct Image {
     // Created is the combined date and time at which the image was created, formatted as defined by RFC 3339, section 5.6.
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_date"
+    )]
     pub created: Option<DateTime<FixedOffset>>,
 
     // Author defines the name and/or email address of the person or entity which created and is responsible for maintaining the image.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub author: Option<String>,
 
     // Architecture is the CPU architecture which the binaries in this image are built to run on.
-    pub architecture: String,
+    pub architecture: Arch,
 
     // OS is the name of the operating system which the image is built to run on.
-    pub os: String,
+    pub os: Os,
 
     // Config defines the execution parameters which should be used as a base when running a container using the image.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub config: Option<ImageConfig>,
 
     // RootFS references the layer content addresses used by the image.
     pub rootfs: RootFs,
 
     // History describes the history of each layer.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub history: Option<Vec<History>>,
 }
 
+impl Image {
+    /// Builds the config of an image, its rootfs of type `layers`.
+    pub fn builder(architecture: Arch, os: Os) -> ImageBuilder {
+        ImageBuilder {
+            image: Image {
+                created: None,
+                author: None,
+                architecture,
+                os,
+                config: None,
+                rootfs: RootFs {
+                    typ: String::from("layers"),
+                    diff_ids: vec![],
+                },
+                history: None,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ImageBuilder {
+    image: Image,
+}
+
+impl ImageBuilder {
+    pub fn created(mut self, created: impl Into<DateTime<FixedOffset>>) -> Self {
+        self.image.created = Some(created.into());
+        self
+    }
+
+    pub fn author(mut self, author: impl Into<String>) -> Self {
+        self.image.author = Some(author.into());
+        self
+    }
+
+    pub fn config(mut self, config: ImageConfig) -> Self {
+        self.image.config = Some(config);
+        self
+    }
+
+    /// Appends the DiffID of a layer, in the order of the layers.
+    pub fn diff_id(mut self, diff_id: Digest) -> Self {
+        self.image.rootfs.diff_ids.push(diff_id);
+        self
+    }
+
+    /// Appends a history entry, one per layer besides empty ones.
+    pub fn history(mut self, history: History) -> Self {
+        self.image
+            .history
+            .get_or_insert_with(Vec::new)
+            .push(history);
+        self
+    }
+
+    pub fn build(self) -> Image {
+        self.image
+    }
+}
+
+impl ImageConfig {
+    pub fn builder() -> ImageConfigBuilder {
+        ImageConfigBuilder {
+            config: ImageConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ImageConfigBuilder {
+    config: ImageConfig,
+}
+
+impl ImageConfigBuilder {
+    pub fn user(mut self, user: impl Into<String>) -> Self {
+        self.config.user = Some(user.into());
+        self
+    }
+
+    pub fn exposed_port(mut self, port: Port) -> Self {
+        self.config
+            .exposed_ports
+            .get_or_insert_with(BTreeSet::new)
+            .insert(port);
+        self
+    }
+
+    /// Appends `name=value` to the environment.
+    pub fn env(mut self, name: &str, value: &str) -> Self {
+        self.config
+            .env
+            .get_or_insert_with(Vec::new)
+            .push(format!("{}={}", name, value));
+        self
+    }
+
+    pub fn entrypoint<I: IntoIterator<Item = impl Into<String>>>(mut self, args: I) -> Self {
+        self.config.entrypoint = Some(args.into_iter().map(Into::into).collect());
+        self
+    }
+
+    pub fn cmd<I: IntoIterator<Item = impl Into<String>>>(mut self, args: I) -> Self {
+        self.config.cmd = Some(args.into_iter().map(Into::into).collect());
+        self
+    }
+
+    pub fn volume(mut self, path: impl Into<String>) -> Self {
+        self.config
+            .volumes
+            .get_or_insert_with(BTreeSet::new)
+            .insert(path.into());
+        self
+    }
+
+    pub fn working_dir(mut self, working_dir: impl Into<String>) -> Self {
+        self.config.working_dir = Some(working_dir.into());
+        self
+    }
+
+    pub fn label(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.config
+            .labels
+            .get_or_insert_with(HashMap::new)
+            .insert(key.into(), value.into());
+        self
+    }
+
+    pub fn stop_signal(mut self, signal: Signal) -> Self {
+        self.config.stop_signal = Some(signal);
+        self
+    }
+
+    pub fn build(self) -> ImageConfig {
+        self.config
+    }
+}
+
+impl History {
+    pub fn builder() -> HistoryBuilder {
+        HistoryBuilder {
+            history: History {
+                created: None,
+                created_by: None,
+                author: None,
+                comment: None,
+                empty_layer: None,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HistoryBuilder {
+    history: History,
+}
+
+impl HistoryBuilder {
+    pub fn created(mut self, created: impl Into<DateTime<FixedOffset>>) -> Self {
+        self.history.created = Some(created.into());
+        self
+    }
+
+    pub fn created_by(mut self, created_by: impl Into<String>) -> Self {
+        self.history.created_by = Some(created_by.into());
+        self
+    }
+
+    pub fn author(mut self, author: impl Into<String>) -> Self {
+        self.history.author = Some(author.into());
+        self
+    }
+
+    pub fn comment(mut self, comment: impl Into<String>) -> Self {
+        self.history.comment = Some(comment.into());
+        self
+    }
+
+    /// Marks entries which didn't create a layer, e.g. `ENV` instructions.
+    pub fn empty_layer(mut self, empty_layer: bool) -> Self {
+        self.history.empty_layer = Some(empty_layer);
+        self
+    }
+
+    pub fn build(self) -> History {
+        self.history
+    }
+}
+
+#[cfg(test)]
 mod tests {
-    use std::collections::HashMap;
+    use std::collections::{BTreeSet, HashMap};
 
     use chrono::DateTime;
-    use serde_json::json;
+    use proptest::prelude::*;
 
     use crate::spec::{
-        config::{History, Image, ImageConfig, RootFs},
+        config::{
+            ConfigError, History, Image, ImageConfig, Port, Protocol, RootFs, Signal, SignalKind,
+        },
         digest::{Algorithm, Digest},
+        platform::{Arch, Os},
     };
 
     #[test]
@@ -137,6 +668,7 @@ mod tests {
 					"/var/log/my-app-logs": {}
 				},
 				"WorkingDir": "/home/alice",
+				"StopSignal": "SIGRTMIN+3",
 				"Labels": {
 					"com.example.project.git.url": "https://example.com/project.git",
 					"com.example.project.git.commit": "45a939b2999782a3f005621a8d0f29aa387e1d6b"
@@ -162,12 +694,11 @@ mod tests {
 			]
 		}"#;
         let image: Image = serde_json::from_str(IMAGE_JSON).unwrap();
-        let mut exposed_ports = HashMap::new();
-        exposed_ports.insert(String::from("8080/tcp"), json!({}));
-
-        let mut volumes = HashMap::new();
-        volumes.insert(String::from("/var/job-result-data"), json!({}));
-        volumes.insert(String::from("/var/log/my-app-logs"), json!({}));
+        let exposed_ports = BTreeSet::from([Port::new(8080, Some(Protocol::Tcp))]);
+        let volumes = BTreeSet::from([
+            String::from("/var/job-result-data"),
+            String::from("/var/log/my-app-logs"),
+        ]);
 
         let mut labels = HashMap::new();
         labels.insert(
@@ -196,7 +727,7 @@ mod tests {
             volumes: Some(volumes),
             working_dir: Some(String::from("/home/alice")),
             labels: Some(labels),
-            stop_signal: None,
+            stop_signal: Some(Signal::from(SignalKind::RtMin(3))),
         };
         let rootfs = RootFs {
             typ: String::from("layers"),
@@ -236,13 +767,63 @@ mod tests {
         let expected = Image {
             created: Some(DateTime::parse_from_rfc3339("2015-10-31T22:22:56.015925234Z").unwrap()),
             author: Some(String::from("Alyssa P. Hacker <alyspdev@example.com>")),
-            architecture: String::from("amd64"),
-            os: String::from("linux"),
+            architecture: Arch::Amd64,
+            os: Os::Linux,
             config: Some(config),
             rootfs,
             history: Some(histories),
         };
 
         assert_eq!(expected, image);
+
+        let json = serde_json::to_value(&image).unwrap();
+        let original: serde_json::Value = serde_json::from_str(IMAGE_JSON).unwrap();
+        for field in ["ExposedPorts", "Volumes", "StopSignal"] {
+            assert_eq!(json["config"][field], original["config"][field]);
+        }
+        assert_eq!(serde_json::from_value::<Image>(json).unwrap(), image);
+    }
+
+    #[test]
+    fn test_ports_and_signals() {
+        let port = |s: &str| s.parse::<Port>();
+        assert_eq!(port("53/udp"), Ok(Port::new(53, Some(Protocol::Udp))));
+        assert_eq!(port("80").unwrap().to_string(), "80");
+        let tcp = port("8080/TCP").unwrap();
+        assert_eq!(tcp.protocol(), Some(Protocol::Tcp));
+        assert_eq!(tcp.to_string(), "8080/TCP");
+        for invalid in ["", "80/icmp", "/tcp", "+80", "65536"] {
+            assert_eq!(
+                port(invalid),
+                Err(ConfigError::InvalidPort(invalid.to_owned()))
+            );
+        }
+
+        let signal = |s: &str| s.parse::<Signal>().unwrap();
+        assert_eq!(signal("SIGTERM").number(), Some(15));
+        assert_eq!(signal("kill").number(), Some(9));
+        assert_eq!(signal("SIGRTMAX-2"), Signal::from(SignalKind::RtMax(2)));
+        assert_eq!(signal("SIGRTMAX-2").number(), Some(62));
+        assert_eq!(signal("SIGRTMIN").to_string(), "SIGRTMIN");
+        assert_eq!(signal("SIGRTMIN3").kind(), &SignalKind::RtMin(3));
+        assert_eq!(signal("rtmin+03").number(), Some(37));
+        assert_eq!(signal("SIGRTMIN+03").to_string(), "SIGRTMIN+03");
+        assert_eq!(signal("3"), Signal::from(SignalKind::Number(3)));
+        assert_eq!(signal("SIGNOPE").number(), None);
+        assert!("SIG TERM".parse::<Signal>().is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn test_ports_and_signals_round_trip(
+            ports in prop::collection::btree_set("[0-9]{1,4}(/(tcp|TCP|Udp|sctp))?", 0..4),
+            signal in "(SIG|sig)?(RTMIN(\\+?0?[0-9])?|RTMAX(-?0?[0-9])?|[A-Z]{2,6})|[0-9]{1,2}",
+        ) {
+            let ports: serde_json::Map<_, _> =
+                ports.into_iter().map(|p| (p, serde_json::json!({}))).collect();
+            let json = serde_json::json!({"ExposedPorts": ports, "StopSignal": signal}).to_string();
+            let config: ImageConfig = serde_json::from_str(&json).unwrap();
+            prop_assert_eq!(serde_json::to_string(&config).unwrap(), json);
+        }
     }
 }
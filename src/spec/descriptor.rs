@@ -1,8 +1,12 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-use super::digest::Digest;
+use super::annotations::Annotated;
+use super::digest::{Algorithm, Digest};
+use super::document;
 use super::media_types::MediaType;
+use super::platform::{Arch, Os, Variant};
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -10,42 +14,138 @@ pub struct Descriptor {
     pub media_type: MediaType,
     pub digest: Digest,
     pub size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub urls: Option<Vec<String>>,
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "super::document::serialize_sorted"
+    )]
     pub annotations: Option<HashMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub platform: Option<Platform>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub data: Option<String>,
+    /// The type of the artifact a manifest descriptor points to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artifact_type: Option<MediaType>,
+}
+
+impl Descriptor {
+    /// The descriptor of the `{}` blob, with its content embedded, which
+    /// artifacts without a config use as theirs.
+    pub fn empty() -> Self {
+        Descriptor {
+            media_type: MediaType::EmptyJson,
+            digest: Digest::new(
+                Algorithm::Sha256,
+                String::from("44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a"),
+            ),
+            size: 2,
+            urls: None,
+            annotations: None,
+            platform: None,
+            data: Some(String::from("e30=")),
+            artifact_type: None,
+        }
+    }
+
+    pub fn builder(media_type: MediaType, digest: Digest, size: u64) -> DescriptorBuilder {
+        DescriptorBuilder {
+            descriptor: Descriptor {
+                media_type,
+                digest,
+                size,
+                urls: None,
+                annotations: None,
+                platform: None,
+                data: None,
+                artifact_type: None,
+            },
+        }
+    }
+}
+
+/// Builds descriptors, see [`super::document::Document::to_blob`] for those
+/// of documents.
+#[derive(Debug, Clone)]
+pub struct DescriptorBuilder {
+    descriptor: Descriptor,
+}
+
+impl DescriptorBuilder {
+    /// Describes `bytes`, computing their sha256 digest and size.
+    pub fn for_bytes(media_type: MediaType, bytes: &[u8]) -> Self {
+        Descriptor::builder(media_type, document::digest(bytes), bytes.len() as u64)
+    }
+
+    pub fn url(mut self, url: impl Into<String>) -> Self {
+        self.descriptor
+            .urls
+            .get_or_insert_with(Vec::new)
+            .push(url.into());
+        self
+    }
+
+    pub fn annotation(mut self, key: &str, value: impl Into<String>) -> Self {
+        self.descriptor.set_annotation(key, value);
+        self
+    }
+
+    pub fn platform(mut self, platform: Platform) -> Self {
+        self.descriptor.platform = Some(platform);
+        self
+    }
+
+    /// Embeds the content described, which must be `bytes`.
+    pub fn data(mut self, bytes: &[u8]) -> Self {
+        self.descriptor.data = Some(STANDARD.encode(bytes));
+        self
+    }
+
+    pub fn artifact_type(mut self, artifact_type: MediaType) -> Self {
+        self.descriptor.artifact_type = Some(artifact_type);
+        self
+    }
+
+    pub fn build(self) -> Descriptor {
+        self.descriptor
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Platform {
     // Architecture field specifies the CPU architecture, for example
     // `amd64` or `ppc64`.
-    // TODO: use enum
-    pub architecture: String,
+    pub architecture: Arch,
 
     // OS specifies the operating system, for example `linux` or `windows`.
-    // TODO: use enum
-    pub os: String,
+    pub os: Os,
 
     // OSVersion is an optional field specifying the operating system
     // version, for example on Windows `10.0.14393.1066`.
+    #[serde(rename = "os.version", skip_serializing_if = "Option::is_none")]
     pub os_version: Option<String>,
 
     // OSFeatures is an optional field specifying an array of strings,
     // each listing a required OS feature (for example on Windows `win32k`).
+    #[serde(rename = "os.features", skip_serializing_if = "Option::is_none")]
     pub os_features: Option<Vec<String>>,
 
     // Variant is an optional field specifying a variant of the CPU, for
     // example `v7` to specify ARMv7 when architecture is `arm`.
-    // TODO: use enum
-    pub variant: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variant: Option<Variant>,
 }
 
+#[cfg(test)]
 mod tests {
-    use crate::spec::{
-        descriptor::Descriptor,
-        digest::{Algorithm, Digest},
-        media_types::MediaType,
+    use crate::{
+        spec::{
+            descriptor::Descriptor,
+            digest::{Algorithm, Digest},
+            media_types::MediaType,
+        },
+        test_utils::sha256,
     };
 
     #[test]
@@ -72,8 +172,30 @@ mod tests {
             urls: Some(vec![String::from("https://example.com/example-manifest")]),
             platform: None,
             data: None,
+            artifact_type: None,
         };
 
         assert_eq!(descriptor, expected)
     }
+
+    #[test]
+    fn test_empty_descriptor() {
+        const EMPTY_JSON: &str = r#"
+        {
+            "mediaType": "application/vnd.oci.empty.v1+json",
+            "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a",
+            "size": 2,
+            "data": "e30="
+        }"#;
+
+        let descriptor: Descriptor = serde_json::from_str(EMPTY_JSON).unwrap();
+        assert_eq!(descriptor, Descriptor::empty());
+        assert_eq!(descriptor.digest, sha256(b"{}"));
+
+        let json = serde_json::to_string(&descriptor).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Descriptor>(&json).unwrap(),
+            descriptor
+        );
+    }
 }
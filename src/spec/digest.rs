@@ -1,38 +1,101 @@
 use std::{fmt, str::FromStr};
 
-use anyhow::Result;
-use serde::{Deserialize, Deserializer, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error;
+
+/// Errors returned when parsing or validating a digest.
+///
+/// [image-spec]: https://github.com/opencontainers/image-spec/blob/v1.0.1/descriptor.md#digests
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum DigestError {
+    #[error("digest {0:?} is missing the ':' separator")]
+    MissingSeparator(String),
+
+    #[error("invalid digest algorithm {0:?}")]
+    InvalidAlgorithm(String),
+
+    #[error("invalid encoded digest {0:?}")]
+    InvalidEncoded(String),
+
+    #[error("{algorithm} digest must be {expected} characters long, got {actual}")]
+    InvalidLength {
+        algorithm: Algorithm,
+        expected: usize,
+        actual: usize,
+    },
+
+    #[error("{algorithm} digest must be lowercase hex, got {encoded:?}")]
+    InvalidHex {
+        algorithm: Algorithm,
+        encoded: String,
+    },
+}
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Algorithm {
     Sha256,
     Sha512,
+    Blake3,
     Unregistered(String),
 }
 
+impl Algorithm {
+    /// The length of the hex encoded digest for registered algorithms.
+    pub fn encoded_len(&self) -> Option<usize> {
+        match self {
+            Algorithm::Sha256 => Some(64),
+            Algorithm::Sha512 => Some(128),
+            Algorithm::Blake3 => Some(64),
+            Algorithm::Unregistered(_) => None,
+        }
+    }
+}
+
 impl fmt::Display for Algorithm {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Algorithm::Sha256 => write!(f, "sha256"),
             Algorithm::Sha512 => write!(f, "sha512"),
+            Algorithm::Blake3 => write!(f, "blake3"),
             Algorithm::Unregistered(s) => write!(f, "{}", s),
         }
     }
 }
 
+// algorithm ::= algorithm-component (algorithm-separator algorithm-component)*
+// algorithm-component ::= [a-z0-9]+
+// algorithm-separator ::= [+._-]
+fn is_valid_algorithm(s: &str) -> bool {
+    s.split(['+', '.', '_', '-']).all(|component| {
+        !component.is_empty()
+            && component
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+    })
+}
+
+// encoded ::= [a-zA-Z0-9=_-]+
+fn is_valid_encoded(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '=' | '_' | '-'))
+}
+
 impl FromStr for Algorithm {
-    type Err = ();
+    type Err = DigestError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "sha256" => Ok(Algorithm::Sha256),
             "sha512" => Ok(Algorithm::Sha512),
-            _ => Ok(Algorithm::Unregistered(s.to_owned())),
+            "blake3" => Ok(Algorithm::Blake3),
+            _ if is_valid_algorithm(s) => Ok(Algorithm::Unregistered(s.to_owned())),
+            _ => Err(DigestError::InvalidAlgorithm(s.to_owned())),
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Digest {
     pub algorithm: Algorithm,
     pub encoded: String,
@@ -43,8 +106,41 @@ impl Digest {
         Self { algorithm, encoded }
     }
 
-    pub fn validate(&self) -> Result<()> {
-        todo!()
+    /// Checks the digest against the grammar of the image-spec, and the
+    /// length and charset of the encoded part for registered algorithms.
+    pub fn validate(&self) -> Result<(), DigestError> {
+        if let Algorithm::Unregistered(algorithm) = &self.algorithm {
+            if !is_valid_algorithm(algorithm) {
+                return Err(DigestError::InvalidAlgorithm(algorithm.to_owned()));
+            }
+        }
+
+        if !is_valid_encoded(&self.encoded) {
+            return Err(DigestError::InvalidEncoded(self.encoded.to_owned()));
+        }
+
+        if let Some(expected) = self.algorithm.encoded_len() {
+            if self.encoded.len() != expected {
+                return Err(DigestError::InvalidLength {
+                    algorithm: self.algorithm.clone(),
+                    expected,
+                    actual: self.encoded.len(),
+                });
+            }
+
+            if !self
+                .encoded
+                .chars()
+                .all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+            {
+                return Err(DigestError::InvalidHex {
+                    algorithm: self.algorithm.clone(),
+                    encoded: self.encoded.to_owned(),
+                });
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -54,17 +150,148 @@ impl fmt::Display for Digest {
     }
 }
 
+impl FromStr for Digest {
+    type Err = DigestError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (algorithm, encoded) = s
+            .split_once(':')
+            .ok_or_else(|| DigestError::MissingSeparator(s.to_owned()))?;
+        let digest = Digest::new(Algorithm::from_str(algorithm)?, encoded.to_owned());
+        digest.validate()?;
+
+        Ok(digest)
+    }
+}
+
+impl TryFrom<&str> for Digest {
+    type Error = DigestError;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        Digest::from_str(s)
+    }
+}
+
+impl Serialize for Digest {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
 impl<'de> Deserialize<'de> for Digest {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: Deserializer<'de>,
     {
         let s = String::deserialize(deserializer)?;
-        let split: Vec<&str> = s.split(':').collect();
+        Digest::from_str(&s).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use proptest::prelude::*;
+
+    use crate::spec::digest::{Algorithm, Digest, DigestError};
 
-        return Ok(Digest::new(
-            Algorithm::from_str(split[0]).unwrap(),
-            split[1].to_owned(),
+    const SHA256: &str = "5b0bcabd1ed22e9fb1310cf6c2dec7cdef19f0ad69efa1f392e94a4333501270";
+
+    #[test]
+    fn test_parse_digest() {
+        assert_eq!(
+            Digest::from_str(&format!("sha256:{}", SHA256)).unwrap(),
+            Digest::new(Algorithm::Sha256, String::from(SHA256))
+        );
+        assert_eq!(
+            Digest::try_from("multihash+base58:QmRZxt2b1FVZPNqd8hsiykDL3TdBDeTSPX9Kv46HmX4Gx8")
+                .unwrap()
+                .algorithm,
+            Algorithm::Unregistered(String::from("multihash+base58"))
+        );
+
+        assert_eq!(
+            Digest::from_str("sha256"),
+            Err(DigestError::MissingSeparator(String::from("sha256")))
+        );
+        assert_eq!(
+            Digest::from_str("sha256:abc"),
+            Err(DigestError::InvalidLength {
+                algorithm: Algorithm::Sha256,
+                expected: 64,
+                actual: 3
+            })
+        );
+        assert!(matches!(
+            Digest::from_str(&format!("sha256:{}", SHA256.to_uppercase())),
+            Err(DigestError::InvalidHex { .. })
+        ));
+        assert!(matches!(
+            Digest::from_str("SHA256:abc"),
+            Err(DigestError::InvalidAlgorithm(_))
         ));
+        assert!(matches!(
+            Digest::from_str("sha+:abc"),
+            Err(DigestError::InvalidAlgorithm(_))
+        ));
+        assert!(matches!(
+            Digest::from_str("foo:"),
+            Err(DigestError::InvalidEncoded(_))
+        ));
+        assert!(matches!(
+            Digest::from_str("foo:a/b"),
+            Err(DigestError::InvalidEncoded(_))
+        ));
+    }
+
+    #[test]
+    fn test_deserialize_invalid_digest() {
+        assert!(serde_json::from_str::<Digest>(r#""sha256""#).is_err());
+        assert!(serde_json::from_str::<Digest>(r#""sha256:zz""#).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn parse_never_panics(s in "\\PC*") {
+            let _ = Digest::from_str(&s);
+        }
+
+        #[test]
+        fn registered_digests_round_trip(
+            encoded in "[a-f0-9]{64}",
+            encoded512 in "[a-f0-9]{128}",
+        ) {
+            for s in [format!("sha256:{}", encoded), format!("sha512:{}", encoded512)] {
+                let digest = Digest::from_str(&s).unwrap();
+                prop_assert_eq!(digest.to_string(), s.clone());
+                let json = serde_json::to_string(&digest).unwrap();
+                prop_assert_eq!(serde_json::from_str::<Digest>(&json).unwrap(), digest);
+            }
+        }
+
+        #[test]
+        fn unregistered_digests_round_trip(
+            algorithm in "[a-z0-9]+([+._-][a-z0-9]+)*",
+            encoded in "[a-zA-Z0-9=_-]+",
+        ) {
+            prop_assume!(!["sha256", "sha512", "blake3"].contains(&algorithm.as_str()));
+            let s = format!("{}:{}", algorithm, encoded);
+            prop_assert_eq!(Digest::from_str(&s).unwrap().to_string(), s);
+        }
+
+        #[test]
+        fn wrong_length_is_rejected(encoded in "[a-f0-9]{0,63}") {
+            prop_assert!(
+                matches!(
+                    Digest::from_str(&format!("sha256:{}", encoded)),
+                    Err(DigestError::InvalidLength { .. }) | Err(DigestError::InvalidEncoded(_))
+                ),
+                "unexpected result"
+            );
+        }
     }
 }
@@ -0,0 +1,193 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Serialize, Serializer};
+
+use super::config::Image;
+use super::descriptor::Descriptor;
+use super::digest::{Algorithm, Digest};
+use super::index::Index;
+use super::manifest::Manifest;
+use super::media_types::MediaType;
+use crate::hash;
+
+/// A document serialized as canonical JSON, with the descriptor of the blob
+/// holding it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Blob {
+    pub descriptor: Descriptor,
+    pub bytes: Vec<u8>,
+}
+
+/// Indexes, manifests and image configs, the documents stored as blobs.
+pub trait Document: Serialize {
+    /// The media type of the blob holding the document.
+    fn media_type(&self) -> MediaType;
+
+    /// The artifact type the descriptor of the document carries, if any.
+    fn artifact_type(&self) -> Option<MediaType> {
+        None
+    }
+
+    /// Serializes the document as canonical JSON: compact, with fields in
+    /// the order of the spec, maps sorted by key and absent fields omitted.
+    ///
+    /// Documents parsed from canonical JSON serialize back to the same bytes.
+    /// Others may not, e.g. unknown fields are dropped and Go escapes `&` as
+    /// `\u0026`, so keep their bytes to keep their digests.
+    fn to_canonical_json(&self) -> serde_json::Result<Vec<u8>> {
+        serde_json::to_vec(self)
+    }
+
+    /// Serializes the document, computing the sha256 digest and the size of
+    /// its descriptor.
+    fn to_blob(&self) -> serde_json::Result<Blob> {
+        let bytes = self.to_canonical_json()?;
+        let descriptor = Descriptor {
+            media_type: self.media_type(),
+            digest: digest(&bytes),
+            size: bytes.len() as u64,
+            urls: None,
+            annotations: None,
+            platform: None,
+            data: None,
+            artifact_type: self.artifact_type(),
+        };
+
+        Ok(Blob { descriptor, bytes })
+    }
+}
+
+impl Document for Index {
+    fn media_type(&self) -> MediaType {
+        MediaType::ImageIndex
+    }
+
+    fn artifact_type(&self) -> Option<MediaType> {
+        self.artifact_type.clone()
+    }
+}
+
+impl Document for Manifest {
+    fn media_type(&self) -> MediaType {
+        MediaType::ImageManifest
+    }
+
+    fn artifact_type(&self) -> Option<MediaType> {
+        self.artifact_type.clone()
+    }
+}
+
+impl Document for Image {
+    fn media_type(&self) -> MediaType {
+        MediaType::ImageConfig
+    }
+}
+
+pub(crate) fn digest(bytes: &[u8]) -> Digest {
+    // sha256 is always registered.
+    hash::digest_bytes(&Algorithm::Sha256, bytes).unwrap()
+}
+
+// Maps are serialized sorted by key, HashMap iterates in random order.
+pub(super) fn serialize_sorted<S>(
+    map: &Option<HashMap<String, String>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    map.as_ref()
+        .map(|map| map.iter().collect::<BTreeMap<_, _>>())
+        .serialize(serializer)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+
+    use crate::spec::{
+        config::{History, Image, ImageConfig},
+        descriptor::DescriptorBuilder,
+        document::Document,
+        index::Index,
+        manifest::Manifest,
+        media_types::MediaType,
+        platform::{Arch, Os},
+    };
+    use crate::test_utils::sha256;
+
+    #[test]
+    fn test_build_canonical_documents() {
+        let layer = DescriptorBuilder::for_bytes(MediaType::ImageLayerTar, b"layer").build();
+        assert_eq!(layer.digest, sha256(b"layer"));
+        assert_eq!(layer.size, 5);
+
+        let created = DateTime::parse_from_rfc3339("2023-11-14T22:13:20.5Z").unwrap();
+        let image = Image::builder(Arch::Amd64, Os::Linux)
+            .created(created)
+            .config(
+                ImageConfig::builder()
+                    .env("PATH", "/bin")
+                    .cmd(["sh"])
+                    .label("b", "2")
+                    .label("a", "1")
+                    .build(),
+            )
+            .diff_id(sha256(b"layer"))
+            .history(
+                History::builder()
+                    .created(created)
+                    .created_by("ADD layer")
+                    .build(),
+            )
+            .history(
+                History::builder()
+                    .comment("metadata")
+                    .empty_layer(true)
+                    .build(),
+            )
+            .build();
+        let config = image.to_blob().unwrap();
+        assert_eq!(
+            String::from_utf8(config.bytes.clone()).unwrap(),
+            format!(
+                concat!(
+                    r#"{{"created":"2023-11-14T22:13:20.5Z","architecture":"amd64","os":"linux","#,
+                    r#""config":{{"Env":["PATH=/bin"],"Cmd":["sh"],"Labels":{{"a":"1","b":"2"}}}},"#,
+                    r#""rootfs":{{"type":"layers","diff_ids":["{}"]}},"#,
+                    r#""history":[{{"created":"2023-11-14T22:13:20.5Z","created_by":"ADD layer"}},"#,
+                    r#"{{"comment":"metadata","empty_layer":true}}]}}"#
+                ),
+                sha256(b"layer")
+            )
+        );
+        assert_eq!(config.descriptor.media_type, MediaType::ImageConfig);
+        assert_eq!(config.descriptor.digest, sha256(&config.bytes));
+
+        let manifest = Manifest::builder(config.descriptor.clone())
+            .layer(layer)
+            .annotation("org.opencontainers.image.created", created.to_rfc3339())
+            .build();
+        let index = Index::builder()
+            .manifest(manifest.to_blob().unwrap().descriptor)
+            .build();
+
+        // Parsing and serializing again doesn't change digests.
+        let parsed: Image = serde_json::from_slice(&config.bytes).unwrap();
+        assert_eq!(parsed, image);
+        assert_eq!(parsed.to_blob().unwrap(), config);
+        let manifest = manifest.to_blob().unwrap();
+        let parsed: Manifest = serde_json::from_slice(&manifest.bytes).unwrap();
+        assert_eq!(parsed.to_blob().unwrap(), manifest);
+        let index = index.to_blob().unwrap();
+        assert_eq!(index.descriptor.media_type, MediaType::ImageIndex);
+        for blob in [&manifest, &index] {
+            let json = String::from_utf8(blob.bytes.clone()).unwrap();
+            assert!(!json.contains("null"), "{}", json);
+        }
+
+        let go = r#"{"created":"2015-10-31T22:22:56.015925234Z","architecture":"amd64","os":"linux","rootfs":{"type":"layers","diff_ids":[]}}"#;
+        let parsed: Image = serde_json::from_str(go).unwrap();
+        assert_eq!(parsed.to_canonical_json().unwrap(), go.as_bytes());
+    }
+}
@@ -1,7 +1,9 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
+use super::annotations::Annotated;
 use super::descriptor::Descriptor;
+use super::media_types::MediaType;
 
 pub const INDEX_FILE_NAME: &str = "index.json";
 
@@ -10,13 +12,81 @@ pub const INDEX_FILE_NAME: &str = "index.json";
 pub struct Index {
     pub schema_version: u32,
 
+    // MediaType is the media type of the index itself.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<MediaType>,
+
+    // ArtifactType is the type of the artifact when the index is used for one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artifact_type: Option<MediaType>,
+
     // Manifests references platform specific manifests.
     pub manifests: Vec<Descriptor>,
 
+    // Subject is the manifest this index refers to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subject: Option<Descriptor>,
+
     // Annotations contains arbitrary metadata for the image index.
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "super::document::serialize_sorted"
+    )]
     pub annotations: Option<HashMap<String, String>>,
 }
 
+impl Index {
+    pub fn builder() -> IndexBuilder {
+        IndexBuilder {
+            index: Index {
+                schema_version: 2,
+                media_type: Some(MediaType::ImageIndex),
+                artifact_type: None,
+                manifests: vec![],
+                subject: None,
+                annotations: None,
+            },
+        }
+    }
+
+    /// Finds the manifest tagged `tag` through its ref name annotation.
+    pub fn find_tag(&self, tag: &str) -> Option<&Descriptor> {
+        self.manifests.iter().find(|m| m.ref_name() == Some(tag))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IndexBuilder {
+    index: Index,
+}
+
+impl IndexBuilder {
+    pub fn artifact_type(mut self, artifact_type: MediaType) -> Self {
+        self.index.artifact_type = Some(artifact_type);
+        self
+    }
+
+    pub fn manifest(mut self, manifest: Descriptor) -> Self {
+        self.index.manifests.push(manifest);
+        self
+    }
+
+    pub fn subject(mut self, subject: Descriptor) -> Self {
+        self.index.subject = Some(subject);
+        self
+    }
+
+    pub fn annotation(mut self, key: &str, value: impl Into<String>) -> Self {
+        self.index.set_annotation(key, value);
+        self
+    }
+
+    pub fn build(self) -> Index {
+        self.index
+    }
+}
+
+#[cfg(test)]
 mod tests {
     use std::collections::HashMap;
 
@@ -25,6 +95,7 @@ mod tests {
         digest::{Algorithm, Digest},
         index::Index,
         media_types::MediaType,
+        platform::{Arch, Os},
     };
 
     #[test]
@@ -72,13 +143,14 @@ mod tests {
             size: 7143,
             urls: None,
             platform: Some(Platform {
-                architecture: String::from("ppc64le"),
-                os: String::from("linux"),
+                architecture: Arch::Ppc64le,
+                os: Os::Linux,
                 os_features: None,
                 os_version: None,
                 variant: None,
             }),
             data: None,
+            artifact_type: None,
         };
 
         let descriptor2 = Descriptor {
@@ -91,22 +163,59 @@ mod tests {
             size: 7682,
             urls: None,
             platform: Some(Platform {
-                architecture: String::from("amd64"),
-                os: String::from("linux"),
+                architecture: Arch::Amd64,
+                os: Os::Linux,
                 os_features: None,
                 os_version: None,
                 variant: None,
             }),
             data: None,
+            artifact_type: None,
         };
 
         let index: Index = serde_json::from_str(INDEX_JSON).unwrap();
         let expected = Index {
             schema_version: 2,
+            media_type: None,
+            artifact_type: None,
             manifests: vec![descriptor1, descriptor2],
+            subject: None,
             annotations: Some(annotations),
         };
 
         assert_eq!(index, expected)
     }
+
+    #[test]
+    fn test_round_trip_artifact_index() {
+        const INDEX_JSON: &str = r#"
+        {
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.index.v1+json",
+            "artifactType": "application/vnd.example.sbom.v1",
+            "manifests": [
+              {
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "artifactType": "application/vnd.example.sbom.v1",
+                "size": 7143,
+                "digest": "sha256:e692418e4cbaf90ca69d05a66403747baa33ee08806650b51fab815ad7fc331f"
+              }
+            ],
+            "subject": {
+              "mediaType": "application/vnd.oci.image.manifest.v1+json",
+              "size": 7682,
+              "digest": "sha256:5b0bcabd1ed22e9fb1310cf6c2dec7cdef19f0ad69efa1f392e94a4333501270"
+            }
+        }"#;
+
+        let index: Index = serde_json::from_str(INDEX_JSON).unwrap();
+        let sbom = MediaType::Other(String::from("application/vnd.example.sbom.v1"));
+        assert_eq!(index.media_type, Some(MediaType::ImageIndex));
+        assert_eq!(index.artifact_type.as_ref(), Some(&sbom));
+        assert_eq!(index.manifests[0].artifact_type.as_ref(), Some(&sbom));
+        assert_eq!(index.subject.as_ref().map(|s| s.size), Some(7682));
+
+        let json = serde_json::to_string(&index).unwrap();
+        assert_eq!(serde_json::from_str::<Index>(&json).unwrap(), index);
+    }
 }
@@ -1,3 +1,5 @@
+use serde::{Deserialize, Serialize};
+
 /// The file name of oci image layout file
 pub const IMAGE_LAYOUT: &str = "oci-layout";
 
@@ -6,7 +8,8 @@ pub const IMAGE_LAYOUT_VERSION: &str = "1.0.0";
 
 /// The directory storing the blobs
 pub const BLOBS: &str = "blobs";
-#[derive(Debug, Clone, PartialEq, Eq)]
-struct ImageLayout {
-    image_layout_version: String,
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageLayout {
+    pub image_layout_version: String,
 }
@@ -1,6 +1,8 @@
 use std::collections::HashMap;
 
+use super::annotations::Annotated;
 use super::descriptor::Descriptor;
+use super::media_types::MediaType;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -8,6 +10,15 @@ use serde::{Deserialize, Serialize};
 pub struct Manifest {
     pub schema_version: u32,
 
+    // MediaType is the media type of the manifest itself.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<MediaType>,
+
+    // ArtifactType is the type of the artifact when the manifest is used
+    // for one, and its config is the empty descriptor.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artifact_type: Option<MediaType>,
+
     // Config references a configuration object for a container, by digest.
     // The referenced configuration object is a JSON blob that the runtime uses to set up the container.
     pub config: Descriptor,
@@ -15,10 +26,68 @@ pub struct Manifest {
     // Layers is an indexed list of layers referenced by the manifest.
     pub layers: Vec<Descriptor>,
 
+    // Subject is the manifest this one refers to, e.g. the image it signs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subject: Option<Descriptor>,
+
     // Annotations contains arbitrary metadata for the image manifest.
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "super::document::serialize_sorted"
+    )]
     pub annotations: Option<HashMap<String, String>>,
 }
 
+impl Manifest {
+    /// Builds an image manifest, or an artifact one with an artifact type.
+    pub fn builder(config: Descriptor) -> ManifestBuilder {
+        ManifestBuilder {
+            manifest: Manifest {
+                schema_version: 2,
+                media_type: Some(MediaType::ImageManifest),
+                artifact_type: None,
+                config,
+                layers: vec![],
+                subject: None,
+                annotations: None,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ManifestBuilder {
+    manifest: Manifest,
+}
+
+impl ManifestBuilder {
+    pub fn artifact_type(mut self, artifact_type: MediaType) -> Self {
+        self.manifest.artifact_type = Some(artifact_type);
+        self
+    }
+
+    /// Appends a layer, the first one is the base layer.
+    pub fn layer(mut self, layer: Descriptor) -> Self {
+        self.manifest.layers.push(layer);
+        self
+    }
+
+    pub fn subject(mut self, subject: Descriptor) -> Self {
+        self.manifest.subject = Some(subject);
+        self
+    }
+
+    pub fn annotation(mut self, key: &str, value: impl Into<String>) -> Self {
+        self.manifest.set_annotation(key, value);
+        self
+    }
+
+    pub fn build(self) -> Manifest {
+        self.manifest
+    }
+}
+
+#[cfg(test)]
 mod tests {
     use std::collections::HashMap;
 
@@ -72,6 +141,7 @@ mod tests {
             urls: None,
             platform: None,
             data: None,
+            artifact_type: None,
         };
         let layers = vec![
             Descriptor {
@@ -87,6 +157,7 @@ mod tests {
                 urls: None,
                 platform: None,
                 data: None,
+                artifact_type: None,
             },
             Descriptor {
                 media_type: MediaType::ImageLayerTarGzip,
@@ -101,6 +172,7 @@ mod tests {
                 urls: None,
                 platform: None,
                 data: None,
+                artifact_type: None,
             },
             Descriptor {
                 media_type: MediaType::ImageLayerTarGzip,
@@ -115,6 +187,7 @@ mod tests {
                 urls: None,
                 platform: None,
                 data: None,
+                artifact_type: None,
             },
         ];
         let mut annotations: HashMap<String, String> = HashMap::new();
@@ -124,11 +197,72 @@ mod tests {
         let manifest: Manifest = serde_json::from_str(MANIFEST_JSON).unwrap();
         let expected = Manifest {
             schema_version: 2,
+            media_type: None,
+            artifact_type: None,
             config,
             layers,
+            subject: None,
             annotations: Some(annotations),
         };
 
         assert_eq!(manifest, expected);
     }
+
+    #[test]
+    fn test_round_trip_artifact_manifest() {
+        const MANIFEST_JSON: &str = r#"
+        {
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "artifactType": "application/vnd.example+type",
+            "config": {
+              "mediaType": "application/vnd.oci.empty.v1+json",
+              "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a",
+              "size": 2
+            },
+            "layers": [
+              {
+                "mediaType": "application/vnd.oci.empty.v1+json",
+                "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a",
+                "size": 2
+              }
+            ],
+            "subject": {
+              "mediaType": "application/vnd.oci.image.manifest.v1+json",
+              "digest": "sha256:5b0bcabd1ed22e9fb1310cf6c2dec7cdef19f0ad69efa1f392e94a4333501270",
+              "size": 7682
+            },
+            "annotations": {
+              "oci.opencontainers.image.created": "2023-01-02T03:04:05Z",
+              "com.example.data": "payload"
+            }
+        }"#;
+
+        let manifest: Manifest = serde_json::from_str(MANIFEST_JSON).unwrap();
+        assert_eq!(manifest.media_type, Some(MediaType::ImageManifest));
+        assert_eq!(
+            manifest.artifact_type,
+            Some(MediaType::Other(String::from(
+                "application/vnd.example+type"
+            )))
+        );
+        assert_eq!(
+            manifest.config,
+            Descriptor {
+                data: None,
+                ..Descriptor::empty()
+            }
+        );
+        let subject = manifest.subject.as_ref().unwrap();
+        assert_eq!(
+            subject.digest,
+            Digest::new(
+                Algorithm::Sha256,
+                String::from("5b0bcabd1ed22e9fb1310cf6c2dec7cdef19f0ad69efa1f392e94a4333501270")
+            )
+        );
+
+        let json = serde_json::to_string(&manifest).unwrap();
+        assert_eq!(serde_json::from_str::<Manifest>(&json).unwrap(), manifest);
+    }
 }
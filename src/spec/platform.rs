@@ -0,0 +1,85 @@
+string_enum! {
+    /// An operating system, as named by Go's `GOOS`.
+    Os {
+        Aix => "aix",
+        Android => "android",
+        Darwin => "darwin",
+        Dragonfly => "dragonfly",
+        FreeBsd => "freebsd",
+        Illumos => "illumos",
+        Ios => "ios",
+        Js => "js",
+        Linux => "linux",
+        NetBsd => "netbsd",
+        OpenBsd => "openbsd",
+        Plan9 => "plan9",
+        Solaris => "solaris",
+        Wasip1 => "wasip1",
+        Windows => "windows",
+    }
+}
+
+string_enum! {
+    /// A CPU architecture, as named by Go's `GOARCH`.
+    Arch {
+        I386 => "386",
+        Amd64 => "amd64",
+        Arm => "arm",
+        Arm64 => "arm64",
+        Loong64 => "loong64",
+        Mips => "mips",
+        Mipsle => "mipsle",
+        Mips64 => "mips64",
+        Mips64le => "mips64le",
+        Ppc64 => "ppc64",
+        Ppc64le => "ppc64le",
+        Riscv64 => "riscv64",
+        S390x => "s390x",
+        Wasm => "wasm",
+    }
+}
+
+string_enum! {
+    /// A variant of a CPU architecture, e.g. `v7` for ARMv7 or `v3` for the
+    /// x86-64-v3 level of `amd64`.
+    Variant {
+        V1 => "v1",
+        V2 => "v2",
+        V3 => "v3",
+        V4 => "v4",
+        V5 => "v5",
+        V6 => "v6",
+        V7 => "v7",
+        V8 => "v8",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use crate::spec::platform::{Arch, Os, Variant};
+
+    #[test]
+    fn test_platform_enums() {
+        assert_eq!(
+            serde_json::from_str::<Arch>(r#""386""#).unwrap(),
+            Arch::I386
+        );
+        assert_eq!(Os::from("linux"), Os::Linux);
+        assert_eq!(
+            serde_json::from_str::<Variant>(r#""v9""#).unwrap(),
+            Variant::Other(String::from("v9"))
+        );
+        assert_eq!(serde_json::to_string(&Arch::Arm64).unwrap(), r#""arm64""#);
+    }
+
+    proptest! {
+        #[test]
+        fn test_platform_enums_round_trip(s in "[a-z0-9]{1,12}") {
+            let os: Os = serde_json::from_str(&serde_json::to_string(&s).unwrap()).unwrap();
+            prop_assert_eq!(os.as_str(), s.as_str());
+            prop_assert_eq!(Arch::from(s.as_str()).to_string(), s);
+        }
+    }
+}
@@ -0,0 +1,465 @@
+use std::fmt;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::Serialize;
+use thiserror::Error;
+
+use super::annotations::{Annotated, ANNOTATION_BASE_DIGEST, ANNOTATION_CREATED};
+use super::config::Image;
+use super::descriptor::Descriptor;
+use super::index::Index;
+use super::layout::{ImageLayout, IMAGE_LAYOUT_VERSION};
+use super::manifest::Manifest;
+use super::media_types::MediaType;
+use crate::hash;
+
+/// A MUST rule of the image-spec a document breaks, at a JSON path such as
+/// `$.manifests[0].mediaType`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Violation {
+    pub path: String,
+    pub message: String,
+}
+
+impl Violation {
+    pub fn new(path: impl Into<String>, message: impl Into<String>) -> Self {
+        Violation {
+            path: path.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// Returned when a document breaks the image-spec, e.g. by strict unpacks.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("{document} violates the image-spec: {}", join(.violations))]
+pub struct ValidationError {
+    pub document: String,
+    pub violations: Vec<Violation>,
+}
+
+impl ValidationError {
+    /// Fails when `violations` isn't empty.
+    pub fn check(document: impl fmt::Display, violations: Vec<Violation>) -> Result<(), Self> {
+        if violations.is_empty() {
+            return Ok(());
+        }
+        Err(ValidationError {
+            document: document.to_string(),
+            violations,
+        })
+    }
+}
+
+fn join(violations: &[Violation]) -> String {
+    let violations: Vec<_> = violations.iter().map(Violation::to_string).collect();
+    violations.join("; ")
+}
+
+/// Validates the `oci-layout` file.
+pub fn validate_layout(layout: &ImageLayout) -> Vec<Violation> {
+    let mut violations = vec![];
+    if layout.image_layout_version != IMAGE_LAYOUT_VERSION {
+        violations.push(Violation::new(
+            "$.imageLayoutVersion",
+            format!(
+                "is {}, expected {}",
+                layout.image_layout_version, IMAGE_LAYOUT_VERSION
+            ),
+        ));
+    }
+    violations
+}
+
+/// Validates an image index, `index.json` included, and its descriptors.
+pub fn validate_index(index: &Index) -> Vec<Violation> {
+    let mut violations = vec![];
+    check_schema_version(index.schema_version, &mut violations);
+    check_own_media_type(&index.media_type, MediaType::ImageIndex, &mut violations);
+    if let Some(artifact_type) = &index.artifact_type {
+        check_media_type("$.artifactType", artifact_type, &mut violations);
+    }
+    for (i, manifest) in index.manifests.iter().enumerate() {
+        check_descriptor(&format!("$.manifests[{}]", i), manifest, &mut violations);
+    }
+    if let Some(subject) = &index.subject {
+        check_descriptor("$.subject", subject, &mut violations);
+    }
+    check_annotations("$", index, &mut violations);
+    violations
+}
+
+/// Validates an image manifest and its descriptors. Configs of any media
+/// type are allowed, as artifacts like Helm charts carry their own.
+pub fn validate_manifest(manifest: &Manifest) -> Vec<Violation> {
+    let mut violations = vec![];
+    check_schema_version(manifest.schema_version, &mut violations);
+    check_own_media_type(
+        &manifest.media_type,
+        MediaType::ImageManifest,
+        &mut violations,
+    );
+    match &manifest.artifact_type {
+        Some(artifact_type) => check_media_type("$.artifactType", artifact_type, &mut violations),
+        None if manifest.config.media_type == MediaType::EmptyJson => violations.push(
+            Violation::new("$.artifactType", "must be set when the config is empty"),
+        ),
+        None => {}
+    }
+    check_descriptor("$.config", &manifest.config, &mut violations);
+    for (i, layer) in manifest.layers.iter().enumerate() {
+        check_descriptor(&format!("$.layers[{}]", i), layer, &mut violations);
+    }
+    if let Some(subject) = &manifest.subject {
+        check_descriptor("$.subject", subject, &mut violations);
+    }
+    check_annotations("$", manifest, &mut violations);
+    violations
+}
+
+/// Validates the config of an image against the manifest referencing it:
+/// a DiffID per layer, and a history entry per layer besides empty ones.
+pub fn validate_config(image: &Image, manifest: &Manifest) -> Vec<Violation> {
+    let mut violations = vec![];
+    let rootfs = &image.rootfs;
+    if rootfs.typ != "layers" {
+        violations.push(Violation::new(
+            "$.rootfs.type",
+            format!("is {:?}, expected \"layers\"", rootfs.typ),
+        ));
+    }
+    if rootfs.diff_ids.len() != manifest.layers.len() {
+        violations.push(Violation::new(
+            "$.rootfs.diff_ids",
+            format!(
+                "has {} DiffIDs but the manifest has {} layers",
+                rootfs.diff_ids.len(),
+                manifest.layers.len()
+            ),
+        ));
+    }
+    if let Some(history) = &image.history {
+        let layers = history
+            .iter()
+            .filter(|h| h.empty_layer != Some(true))
+            .count();
+        if layers != rootfs.diff_ids.len() {
+            violations.push(Violation::new(
+                "$.history",
+                format!(
+                    "has {} entries which aren't empty_layer but there are {} DiffIDs",
+                    layers,
+                    rootfs.diff_ids.len()
+                ),
+            ));
+        }
+    }
+    let env = image.config.as_ref().and_then(|c| c.env.as_ref());
+    for (i, variable) in env.into_iter().flatten().enumerate() {
+        if variable
+            .split_once('=')
+            .is_none_or(|(name, _)| name.is_empty())
+        {
+            violations.push(Violation::new(
+                format!("$.config.Env[{}]", i),
+                format!("{:?} isn't VARNAME=VARVALUE", variable),
+            ));
+        }
+    }
+    violations
+}
+
+fn check_schema_version(schema_version: u32, violations: &mut Vec<Violation>) {
+    if schema_version != 2 {
+        violations.push(Violation::new(
+            "$.schemaVersion",
+            format!("is {}, expected 2", schema_version),
+        ));
+    }
+}
+
+// Documents which set their own media type must set the right one.
+fn check_own_media_type(
+    media_type: &Option<MediaType>,
+    expected: MediaType,
+    violations: &mut Vec<Violation>,
+) {
+    match media_type {
+        Some(media_type) if *media_type != expected => violations.push(Violation::new(
+            "$.mediaType",
+            format!("is {}, expected {}", media_type, expected),
+        )),
+        _ => {}
+    }
+}
+
+fn check_descriptor(path: &str, descriptor: &Descriptor, violations: &mut Vec<Violation>) {
+    check_media_type(
+        &format!("{}.mediaType", path),
+        &descriptor.media_type,
+        violations,
+    );
+    if let Some(artifact_type) = &descriptor.artifact_type {
+        check_media_type(&format!("{}.artifactType", path), artifact_type, violations);
+    }
+    if descriptor.size > i64::MAX as u64 {
+        violations.push(Violation::new(
+            format!("{}.size", path),
+            "doesn't fit in an int64",
+        ));
+    }
+    for (i, url) in descriptor.urls.iter().flatten().enumerate() {
+        if !is_uri(url) {
+            violations.push(Violation::new(
+                format!("{}.urls[{}]", path, i),
+                format!("{:?} isn't a URI", url),
+            ));
+        }
+    }
+    if let Some(data) = &descriptor.data {
+        check_data(path, descriptor, data, violations);
+    }
+    check_annotations(path, descriptor, violations);
+}
+
+// Embedded data must be the content the descriptor describes.
+fn check_data(path: &str, descriptor: &Descriptor, data: &str, violations: &mut Vec<Violation>) {
+    let bytes = match STANDARD.decode(data) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            let message = format!("isn't base64: {}", e);
+            return violations.push(Violation::new(format!("{}.data", path), message));
+        }
+    };
+    if bytes.len() as u64 != descriptor.size {
+        violations.push(Violation::new(
+            format!("{}.data", path),
+            format!("holds {} bytes, expected {}", bytes.len(), descriptor.size),
+        ));
+        return;
+    }
+    // Data under unsupported algorithms can't be checked.
+    if let Ok(actual) = hash::digest_bytes(&descriptor.digest.algorithm, &bytes) {
+        if actual != descriptor.digest {
+            violations.push(Violation::new(
+                format!("{}.data", path),
+                format!("has digest {}, expected {}", actual, descriptor.digest),
+            ));
+        }
+    }
+}
+
+fn check_annotations(path: &str, annotated: &impl Annotated, violations: &mut Vec<Violation>) {
+    let key = |key| format!("{}.annotations['{}']", path, key);
+    if let Err(e) = annotated.created() {
+        violations.push(Violation::new(key(ANNOTATION_CREATED), e.to_string()));
+    }
+    if let Err(e) = annotated.base_digest() {
+        violations.push(Violation::new(key(ANNOTATION_BASE_DIGEST), e.to_string()));
+    }
+}
+
+fn check_media_type(path: &str, media_type: &MediaType, violations: &mut Vec<Violation>) {
+    if !is_media_type(media_type.as_str()) {
+        violations.push(Violation::new(
+            path,
+            format!(
+                "{:?} isn't a media type as RFC 6838 defines them",
+                media_type.as_str()
+            ),
+        ));
+    }
+}
+
+// `type/subtype`, both restricted names as RFC 6838 defines them.
+fn is_media_type(s: &str) -> bool {
+    let restricted_name = |name: &str| {
+        name.len() <= 127
+            && name
+                .bytes()
+                .next()
+                .is_some_and(|b| b.is_ascii_alphanumeric())
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"!#$&-^_.+".contains(&b))
+    };
+    s.split_once('/')
+        .is_some_and(|(typ, subtype)| restricted_name(typ) && restricted_name(subtype))
+}
+
+// An RFC 3986 URI starts with its scheme.
+fn is_uri(s: &str) -> bool {
+    s.split_once(':').is_some_and(|(scheme, rest)| {
+        scheme
+            .bytes()
+            .next()
+            .is_some_and(|b| b.is_ascii_alphabetic())
+            && scheme
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"+-.".contains(&b))
+            && !rest.is_empty()
+            && !rest.contains(char::is_whitespace)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::spec::{
+        config::{History, Image, ImageConfig, RootFs},
+        descriptor::Descriptor,
+        index::Index,
+        manifest::Manifest,
+        media_types::MediaType,
+        platform::{Arch, Os},
+        validation::{validate_config, validate_index, validate_manifest, Violation},
+    };
+    use crate::test_utils::{descriptor, sha256};
+
+    fn paths(violations: &[Violation]) -> Vec<&str> {
+        violations.iter().map(|v| v.path.as_str()).collect()
+    }
+
+    #[test]
+    fn test_validate_documents() {
+        let layer = descriptor(MediaType::ImageLayerTarGzip, b"layer");
+        let mut manifest = Manifest {
+            schema_version: 2,
+            media_type: Some(MediaType::ImageManifest),
+            artifact_type: None,
+            config: descriptor(MediaType::ImageConfig, b"{}"),
+            layers: vec![layer.clone()],
+            subject: None,
+            annotations: None,
+        };
+        assert_eq!(validate_manifest(&manifest), vec![]);
+
+        manifest.schema_version = 1;
+        manifest.media_type = Some(MediaType::ImageIndex);
+        manifest.config.media_type = MediaType::Other(String::from("application/json"));
+        manifest.layers[0].media_type = MediaType::Other(String::from("tar"));
+        manifest.layers[0].urls = Some(vec![String::from("https://example.com/layer")]);
+        manifest.layers[0].annotations = Some(HashMap::from([(
+            String::from("org.opencontainers.image.created"),
+            String::from("yesterday"),
+        )]));
+        assert_eq!(
+            paths(&validate_manifest(&manifest)),
+            [
+                "$.schemaVersion",
+                "$.mediaType",
+                "$.layers[0].mediaType",
+                "$.layers[0].annotations['org.opencontainers.image.created']",
+            ]
+        );
+
+        // Artifacts predating `artifactType` are typed by their config.
+        let chart = Manifest {
+            schema_version: 2,
+            media_type: Some(MediaType::ImageManifest),
+            artifact_type: None,
+            config: descriptor(
+                MediaType::Other(String::from("application/vnd.cncf.helm.config.v1+json")),
+                b"{}",
+            ),
+            layers: vec![descriptor(
+                MediaType::Other(String::from(
+                    "application/vnd.cncf.helm.chart.content.v1.tar+gzip",
+                )),
+                b"chart",
+            )],
+            subject: None,
+            annotations: None,
+        };
+        assert_eq!(validate_manifest(&chart), vec![]);
+
+        // Artifacts need a type with an empty config, whose data must match.
+        let mut artifact = Manifest {
+            schema_version: 2,
+            media_type: None,
+            artifact_type: None,
+            config: Descriptor::empty(),
+            layers: vec![],
+            subject: None,
+            annotations: None,
+        };
+        artifact.config.data = Some(String::from("e30K"));
+        artifact
+            .layers
+            .push(descriptor(MediaType::EmptyJson, b"{}"));
+        artifact.layers[0].data = Some(String::from("not base64"));
+        assert_eq!(
+            paths(&validate_manifest(&artifact)),
+            ["$.artifactType", "$.config.data", "$.layers[0].data"]
+        );
+
+        let mut index = Index {
+            schema_version: 2,
+            media_type: None,
+            artifact_type: None,
+            manifests: vec![descriptor(MediaType::ImageManifest, b"{}")],
+            subject: None,
+            annotations: None,
+        };
+        assert_eq!(validate_index(&index), vec![]);
+        index.manifests[0].urls = Some(vec![String::from("not a url")]);
+        index.annotations = Some(HashMap::from([(
+            String::from("org.opencontainers.image.base.digest"),
+            String::from("sha256:1234"),
+        )]));
+        assert_eq!(
+            paths(&validate_index(&index)),
+            [
+                "$.manifests[0].urls[0]",
+                "$.annotations['org.opencontainers.image.base.digest']",
+            ]
+        );
+
+        let history = |empty_layer| History {
+            created: None,
+            created_by: None,
+            author: None,
+            comment: None,
+            empty_layer,
+        };
+        let mut image = Image {
+            created: None,
+            author: None,
+            architecture: Arch::Amd64,
+            os: Os::Linux,
+            config: Some(ImageConfig {
+                env: Some(vec![String::from("PATH=/bin")]),
+                ..ImageConfig::default()
+            }),
+            rootfs: RootFs {
+                typ: String::from("layers"),
+                diff_ids: vec![sha256(b"layer")],
+            },
+            history: Some(vec![history(None), history(Some(true))]),
+        };
+        manifest.layers = vec![layer];
+        assert_eq!(validate_config(&image, &manifest), vec![]);
+
+        image.rootfs.typ = String::from("tar");
+        image.history = Some(vec![history(Some(false)), history(None)]);
+        image.config.as_mut().unwrap().env =
+            Some(vec![String::from("PATH=/bin"), String::from("=x")]);
+        manifest.layers.clear();
+        assert_eq!(
+            paths(&validate_config(&image, &manifest)),
+            [
+                "$.rootfs.type",
+                "$.rootfs.diff_ids",
+                "$.history",
+                "$.config.Env[1]"
+            ]
+        );
+    }
+}
@@ -0,0 +1,435 @@
+use std::{fs, ops::Range};
+
+use anyhow::{anyhow, bail, Context};
+use tar::Builder;
+
+use crate::compression::{Compression, Compressor};
+use crate::hash::{self, HashingWriter};
+use crate::lock::LayoutLock;
+use crate::merge::{read_verified, select_manifest, MergedLayers};
+use crate::pusher::split_image_tag;
+use crate::spec::annotations::Annotated;
+use crate::spec::config::{History, Image};
+use crate::spec::descriptor::Descriptor;
+use crate::spec::digest::{Algorithm, Digest};
+use crate::spec::index::Index;
+use crate::spec::manifest::Manifest;
+use crate::store::{BlobStore, DirectoryStore};
+
+/// Parses a layer range such as `1..3`, `2..` or `..4`.
+pub fn parse_layer_range(s: &str) -> anyhow::Result<Range<usize>> {
+    let (start, end) = s
+        .split_once("..")
+        .ok_or_else(|| anyhow!("invalid layer range {:?}, expected N..M", s))?;
+    let start = match start {
+        "" => 0,
+        start => start.parse()?,
+    };
+    let end = match end {
+        "" => usize::MAX,
+        end => end.parse()?,
+    };
+
+    Ok(start..end)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SquashReport {
+    /// The manifest of the squashed image.
+    pub manifest: Descriptor,
+    /// The layer replacing the squashed ones.
+    pub layer: Descriptor,
+    pub squashed_layers: usize,
+}
+
+/// Squasher merges the layers of an image into a single layer, writing a new
+/// image into the same or another layout. Entries are streamed from the
+/// original layers into the new one, nothing is extracted on disk.
+#[derive(Debug)]
+pub struct Squasher {
+    store: DirectoryStore,
+    tag: Option<String>,
+    output: Option<(DirectoryStore, Option<String>)>,
+    layers: Option<Range<usize>>,
+}
+
+impl Squasher {
+    /// `image` is `<layout>[:tag]`, the tag selects a manifest in `index.json`.
+    pub fn new(image: String) -> Self {
+        let (image_path, tag) = split_image_tag(&image);
+        Squasher {
+            store: DirectoryStore::new(image_path),
+            tag,
+            output: None,
+            layers: None,
+        }
+    }
+
+    /// Writes the squashed image to `<layout>[:tag]` instead of replacing
+    /// the original one.
+    pub fn output(mut self, image: String) -> Self {
+        let (image_path, tag) = split_image_tag(&image);
+        self.output = Some((DirectoryStore::new(image_path), tag));
+        self
+    }
+
+    /// Only squashes the layers in `range`, keeping the others as they are.
+    pub fn layers(mut self, range: Range<usize>) -> Self {
+        self.layers = Some(range);
+        self
+    }
+
+    pub fn squash(&self) -> anyhow::Result<SquashReport> {
+        let (output, output_tag) = match &self.output {
+            Some((store, tag)) => (store, tag.clone()),
+            None => (&self.store, self.tag.clone()),
+        };
+        output.init()?;
+        let _output_lock = LayoutLock::exclusive(output.root())?;
+        let _lock = match output.root() == self.store.root() {
+            true => None,
+            false => Some(LayoutLock::shared(self.store.root())?),
+        };
+
+        let index: Index = serde_json::from_reader(self.store.open_index()?)?;
+        let source = select_manifest(&index, self.tag.as_deref(), self.store.root().display())?;
+        let mut manifest: Manifest = serde_json::from_slice(&read_verified(&self.store, source)?)?;
+        let mut image: Image =
+            serde_json::from_slice(&read_verified(&self.store, &manifest.config)?)?;
+
+        let count = manifest.layers.len();
+        let range = match &self.layers {
+            Some(range) => range.start..range.end.min(count),
+            None => 0..count,
+        };
+        if range.is_empty() {
+            bail!(
+                "no layers to squash in {:?}, the image has {}",
+                range,
+                count
+            );
+        }
+        if image.rootfs.diff_ids.len() != count {
+            bail!(
+                "config has {} DiffIDs for {} layers",
+                image.rootfs.diff_ids.len(),
+                count
+            );
+        }
+
+        let (layer, diff_id) = {
+            // Whiteouts only need to be kept when there are layers below.
+            let layers = &manifest.layers[range.clone()];
+            let merged = MergedLayers::new(&self.store, layers, range.start > 0)?;
+            self.write_layer(output, &merged)?
+        };
+
+        for kept in manifest.layers[..range.start]
+            .iter()
+            .chain(&manifest.layers[range.end..])
+        {
+            self.copy_blob(output, &kept.digest)?;
+        }
+        manifest.layers.splice(range.clone(), [layer.clone()]);
+        image.rootfs.diff_ids.splice(range.clone(), [diff_id]);
+        image.history = image
+            .history
+            .map(|history| collapse_history(history, &range));
+
+        let config = serde_json::to_vec(&image)?;
+        manifest.config = Descriptor {
+            size: config.len() as u64,
+            digest: output.write_blob(&config)?,
+            ..manifest.config
+        };
+        let bytes = serde_json::to_vec(&manifest)?;
+        let mut descriptor = Descriptor {
+            size: bytes.len() as u64,
+            digest: output.write_blob(&bytes)?,
+            annotations: None,
+            ..source.clone()
+        };
+
+        let mut output_index: Index = serde_json::from_reader(output.open_index()?)?;
+        match &output_tag {
+            Some(tag) => {
+                output_index.manifests.retain(|m| m.ref_name() != Some(tag));
+                descriptor.set_ref_name(tag);
+            }
+            None if output.root() == self.store.root() => {
+                output_index.manifests.retain(|m| m.digest != source.digest);
+            }
+            None => {}
+        }
+        output_index.manifests.push(descriptor.clone());
+        output.write_index(&output_index)?;
+
+        Ok(SquashReport {
+            manifest: descriptor,
+            layer,
+            squashed_layers: range.len(),
+        })
+    }
+
+    fn copy_blob(&self, output: &DirectoryStore, digest: &Digest) -> anyhow::Result<()> {
+        let destination = output.blob_path(digest);
+        if !destination.exists() {
+            fs::create_dir_all(destination.parent().unwrap())?;
+            fs::copy(self.store.blob_path(digest), destination)
+                .with_context(|| format!("copying blob {}", digest))?;
+        }
+
+        Ok(())
+    }
+
+    // Writes the entries which survived the merge into a new gzipped layer,
+    // returning its descriptor and DiffID.
+    fn write_layer(
+        &self,
+        output: &DirectoryStore,
+        merged: &MergedLayers<DirectoryStore>,
+    ) -> anyhow::Result<(Descriptor, Digest)> {
+        let blob = Compressor::new(Compression::Gzip, output.blob_writer()?)?;
+        let tar = HashingWriter::new(blob, hash::hasher(&Algorithm::Sha256)?);
+        let mut builder = Builder::new(tar);
+        merged.write(&mut builder)?;
+
+        let tar = builder.into_inner()?;
+        let (blob, diff_id, _) = tar.finish();
+        let (digest, size) = blob.finish()?.commit()?;
+        let descriptor = Descriptor {
+            media_type: Compression::Gzip.layer_media_type(),
+            digest,
+            size,
+            urls: None,
+            annotations: None,
+            platform: None,
+            data: None,
+            artifact_type: None,
+        };
+
+        Ok((descriptor, diff_id))
+    }
+}
+
+// Replaces the history of the squashed layers, and of the empty layers
+// between them, with a single entry. It is dated like the newest of them,
+// so squashing the same image twice gives the same config.
+fn collapse_history(history: Vec<History>, range: &Range<usize>) -> Vec<History> {
+    let mut layer = 0;
+    let squashed: Vec<bool> = history
+        .iter()
+        .map(|entry| {
+            let empty = entry.empty_layer == Some(true);
+            let squashed = if empty {
+                layer > range.start && layer < range.end
+            } else {
+                range.contains(&layer)
+            };
+            if !empty {
+                layer += 1;
+            }
+            squashed
+        })
+        .collect();
+    let created = history
+        .iter()
+        .zip(&squashed)
+        .filter(|(_, squashed)| **squashed)
+        .filter_map(|(entry, _)| entry.created)
+        .max();
+
+    let mut collapsed = Vec::new();
+    let mut replaced = false;
+    for (entry, squashed) in history.into_iter().zip(squashed) {
+        if !squashed {
+            collapsed.push(entry);
+        } else if !replaced {
+            collapsed.push(History {
+                created,
+                created_by: Some(String::from("oci-extractor squash")),
+                author: None,
+                comment: Some(format!("squashed layers {}..{}", range.start, range.end)),
+                empty_layer: None,
+            });
+            replaced = true;
+        }
+    }
+
+    collapsed
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, fs, io::Read};
+
+    use flate2::read::GzDecoder;
+    use tar::Archive;
+
+    use chrono::DateTime;
+
+    use crate::{
+        spec::{
+            config::{History, Image},
+            digest::Digest,
+            manifest::Manifest,
+        },
+        squash::{collapse_history, parse_layer_range, Squasher},
+        store::{BlobStore, DirectoryStore},
+        test_utils::{sha256, tar, TestEntry, TestLayout},
+        unpacker::Unpacker,
+    };
+
+    fn read_json<T: serde::de::DeserializeOwned>(store: &DirectoryStore, digest: &Digest) -> T {
+        serde_json::from_reader(store.open(digest).unwrap()).unwrap()
+    }
+
+    fn layer_entries(
+        store: &DirectoryStore,
+        manifest: &Manifest,
+        layer: usize,
+    ) -> HashMap<String, Vec<u8>> {
+        let blob = store.open(&manifest.layers[layer].digest).unwrap();
+        let mut archive = Archive::new(GzDecoder::new(blob));
+        archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let mut contents = Vec::new();
+                entry.read_to_end(&mut contents).unwrap();
+                (entry.path().unwrap().to_str().unwrap().to_owned(), contents)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_layer_range() {
+        assert_eq!(parse_layer_range("1..3").unwrap(), 1..3);
+        assert_eq!(parse_layer_range("2..").unwrap(), 2..usize::MAX);
+        assert_eq!(parse_layer_range("..4").unwrap(), 0..4);
+        assert!(parse_layer_range("4").is_err());
+    }
+
+    #[test]
+    fn test_squash() {
+        let layout = TestLayout::new();
+        let layers = [
+            tar(&[
+                TestEntry::File("etc/passwd", b"root"),
+                TestEntry::File("tmp/cache", b"cache"),
+                TestEntry::File("usr/a", b"a"),
+            ]),
+            tar(&[
+                TestEntry::File("etc/passwd", b"root\nuser"),
+                TestEntry::File("tmp/.wh..wh..opq", b""),
+                TestEntry::File("usr/.wh.a", b""),
+            ]),
+            tar(&[TestEntry::Symlink("bin", "usr/bin")]),
+        ];
+        layout.add_image(&layers, Some("latest"));
+
+        let output = TestLayout::new();
+        let report = Squasher::new(format!("{}:latest", layout.path()))
+            .output(format!("{}:squashed", output.path()))
+            .squash()
+            .unwrap();
+        assert_eq!(report.squashed_layers, 3);
+
+        let index = output.index();
+        assert_eq!(index.find_tag("squashed"), Some(&report.manifest));
+        let store = DirectoryStore::new(output.path());
+        let manifest: Manifest = read_json(&store, &report.manifest.digest);
+        assert_eq!(manifest.layers, vec![report.layer.clone()]);
+        let image: Image = read_json(&store, &manifest.config.digest);
+        assert_eq!(image.rootfs.diff_ids.len(), 1);
+
+        let entries = layer_entries(&store, &manifest, 0);
+        let mut paths: Vec<&str> = entries.keys().map(String::as_str).collect();
+        paths.sort_unstable();
+        assert_eq!(paths, ["bin", "etc/passwd"]);
+        assert_eq!(entries["etc/passwd"], b"root\nuser");
+
+        // The squashed image unpacks to the same tree as the original one.
+        let rootfs = output.dir.path().join("rootfs");
+        Unpacker::new(output.path(), rootfs.to_str().unwrap().to_owned())
+            .unpack()
+            .unwrap();
+        assert_eq!(fs::read(rootfs.join("etc/passwd")).unwrap(), b"root\nuser");
+        assert_eq!(
+            fs::read_link(rootfs.join("bin")).unwrap().to_str(),
+            Some("usr/bin")
+        );
+    }
+
+    #[test]
+    fn test_squash_layer_range() {
+        let layout = TestLayout::new();
+        let layers = [
+            tar(&[TestEntry::File("a", b"a")]),
+            tar(&[TestEntry::File("b", b"b")]),
+            tar(&[TestEntry::File(".wh.a", b""), TestEntry::File("c", b"c")]),
+            tar(&[TestEntry::File("d", b"d")]),
+        ];
+        let original = layout.add_image(&layers, Some("latest"));
+
+        let report = Squasher::new(format!("{}:latest", layout.path()))
+            .layers(1..3)
+            .squash()
+            .unwrap();
+        assert_eq!(report.squashed_layers, 2);
+
+        // The tag now points to the squashed image.
+        let index = layout.index();
+        assert_eq!(index.manifests, vec![report.manifest.clone()]);
+        assert_ne!(report.manifest.digest, original.digest);
+
+        let store = DirectoryStore::new(layout.path());
+        let manifest: Manifest = read_json(&store, &report.manifest.digest);
+        let image: Image = read_json(&store, &manifest.config.digest);
+        assert_eq!(
+            image.rootfs.diff_ids,
+            [
+                sha256(&layers[0]),
+                sha256(&tar(&[
+                    TestEntry::File("b", b"b"),
+                    TestEntry::File(".wh.a", b""),
+                    TestEntry::File("c", b"c"),
+                ])),
+                sha256(&layers[3])
+            ]
+        );
+        // The whiteout still hides `a` from the first layer.
+        let entries = layer_entries(&store, &manifest, 1);
+        assert!(entries.contains_key(".wh.a"));
+    }
+
+    #[test]
+    fn test_collapse_history() {
+        let entry = |created: &str, empty_layer| History {
+            created: Some(DateTime::parse_from_rfc3339(created).unwrap()),
+            created_by: None,
+            author: None,
+            comment: None,
+            empty_layer,
+        };
+        let history = vec![
+            entry("2024-01-01T00:00:00Z", None),
+            entry("2024-01-02T00:00:00Z", None),
+            entry("2024-01-04T00:00:00Z", Some(true)),
+            entry("2024-01-03T00:00:00Z", None),
+            entry("2024-01-05T00:00:00Z", None),
+        ];
+
+        let collapsed = collapse_history(history.clone(), &(1..3));
+        assert_eq!(collapsed.len(), 3);
+        assert_eq!(collapsed[0], history[0]);
+        assert_eq!(collapsed[1].created, history[2].created);
+        assert_eq!(
+            collapsed[1].comment.as_deref(),
+            Some("squashed layers 1..3")
+        );
+        assert_eq!(collapsed[2], history[4]);
+        assert_eq!(collapse_history(history, &(1..3)), collapsed);
+    }
+}
@@ -0,0 +1,1132 @@
+use std::{
+    cmp,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::unix::ffi::OsStrExt,
+    path::Path,
+};
+
+use anyhow::{anyhow, bail};
+use flate2::write::ZlibEncoder;
+use tar::EntryType;
+
+use crate::compression::Compression;
+use crate::merge::MergedEntry;
+
+// See https://dr-emann.github.io/squashfs/ for a description of the format.
+const MAGIC: u32 = 0x7371_7368;
+const SUPERBLOCK_SIZE: u64 = 96;
+const BLOCK_SIZE: usize = 128 * 1024;
+const BLOCK_LOG: u16 = 17;
+const METADATA_SIZE: usize = 8192;
+// Images are padded to a multiple of this, as mksquashfs does.
+const DEVICE_BLOCK_SIZE: u64 = 4096;
+
+// Compression ids
+const GZIP: u16 = 1;
+const ZSTD: u16 = 6;
+
+// Superblock flags
+const UNCOMPRESSED_INODES: u16 = 0x0001;
+const UNCOMPRESSED_DATA: u16 = 0x0002;
+const UNCOMPRESSED_FRAGMENTS: u16 = 0x0008;
+const NO_FRAGMENTS: u16 = 0x0010;
+const UNCOMPRESSED_XATTRS: u16 = 0x0100;
+const NO_XATTRS: u16 = 0x0200;
+const UNCOMPRESSED_IDS: u16 = 0x0800;
+
+// Set in the header of metadata blocks, and in the size of data blocks,
+// stored uncompressed.
+const METADATA_UNCOMPRESSED: u16 = 0x8000;
+const BLOCK_UNCOMPRESSED: u32 = 1 << 24;
+
+const INVALID_TABLE: u64 = u64::MAX;
+const NO_FRAGMENT: u32 = u32::MAX;
+const NO_XATTR: u32 = u32::MAX;
+
+// Inode types, the extended ones are the basic ones plus 7.
+const DIR: u16 = 1;
+const FILE: u16 = 2;
+const SYMLINK: u16 = 3;
+const BLOCK_DEV: u16 = 4;
+const CHAR_DEV: u16 = 5;
+const FIFO: u16 = 6;
+const EXTENDED: u16 = 7;
+
+// Directory listings can only group this many entries under one header.
+const DIR_HEADER_COUNT: usize = 256;
+
+// Xattr name prefixes and their types.
+const XATTR_PREFIXES: [(&str, u16); 3] = [("user.", 0), ("trusted.", 1), ("security.", 2)];
+const PAX_XATTR: &str = "SCHILY.xattr.";
+
+type NodeId = usize;
+type Xattrs = Vec<(u16, Vec<u8>, Vec<u8>)>;
+
+#[derive(Debug)]
+enum Kind {
+    Dir(BTreeMap<Vec<u8>, NodeId>),
+    File {
+        blocks_start: u64,
+        blocks: Vec<u32>,
+        size: u64,
+    },
+    Symlink(Vec<u8>),
+    BlockDev(u32),
+    CharDev(u32),
+    Fifo,
+}
+
+#[derive(Debug)]
+struct Node {
+    kind: Kind,
+    mode: u16,
+    uid: u32,
+    gid: u32,
+    mtime: u32,
+    xattrs: Xattrs,
+    // The number of directory entries pointing to a non-directory.
+    nlink: u32,
+}
+
+impl Node {
+    fn dir(mtime: u32) -> Self {
+        Node {
+            kind: Kind::Dir(BTreeMap::new()),
+            mode: 0o755,
+            uid: 0,
+            gid: 0,
+            mtime,
+            xattrs: vec![],
+            nlink: 1,
+        }
+    }
+
+    fn children(&self) -> Vec<(Vec<u8>, NodeId)> {
+        match &self.kind {
+            Kind::Dir(children) => children.iter().map(|(n, c)| (n.clone(), *c)).collect(),
+            _ => vec![],
+        }
+    }
+
+    fn basic_type(&self) -> u16 {
+        match self.kind {
+            Kind::Dir(_) => DIR,
+            Kind::File { .. } => FILE,
+            Kind::Symlink(_) => SYMLINK,
+            Kind::BlockDev(_) => BLOCK_DEV,
+            Kind::CharDev(_) => CHAR_DEV,
+            Kind::Fifo => FIFO,
+        }
+    }
+}
+
+/// SquashFsWriter builds a SquashFS 4.0 image from the entries of a merged
+/// tree. File data is written as entries arrive, inodes and directories
+/// are kept in memory and written by `finish`. The output only depends on
+/// the entries and the modification time, so it is reproducible.
+pub struct SquashFsWriter<W: Write + Seek> {
+    writer: W,
+    compression: Compression,
+    mtime: u32,
+    position: u64,
+    nodes: Vec<Node>,
+}
+
+impl<W: Write + Seek> SquashFsWriter<W> {
+    /// `mtime` is the image's modification time, in seconds since the epoch.
+    pub fn new(mut writer: W, compression: Compression, mtime: u32) -> io::Result<Self> {
+        // The superblock is written once the tables are known.
+        writer.write_all(&[0; SUPERBLOCK_SIZE as usize])?;
+        Ok(SquashFsWriter {
+            writer,
+            compression,
+            mtime,
+            position: SUPERBLOCK_SIZE,
+            nodes: vec![Node::dir(mtime)],
+        })
+    }
+
+    pub fn add(&mut self, entry: MergedEntry) -> anyhow::Result<()> {
+        let (parent, name) = match (entry.path.parent(), entry.path.file_name()) {
+            (Some(parent), Some(name)) => (self.directory(parent)?, name.as_bytes().to_vec()),
+            _ => return Ok(()),
+        };
+        let header = &entry.header;
+
+        let kind = match header.entry_type() {
+            EntryType::Directory => Kind::Dir(BTreeMap::new()),
+            EntryType::Regular | EntryType::Continuous => {
+                let (blocks_start, blocks, size) = self.write_data(entry.data)?;
+                Kind::File {
+                    blocks_start,
+                    blocks,
+                    size,
+                }
+            }
+            EntryType::Symlink => Kind::Symlink(link_name(&entry)?.as_os_str().as_bytes().to_vec()),
+            EntryType::Block => Kind::BlockDev(device(header)?),
+            EntryType::Char => Kind::CharDev(device(header)?),
+            EntryType::Fifo => Kind::Fifo,
+            EntryType::Link => {
+                let target = link_name(&entry)?;
+                let target = self
+                    .lookup(target)
+                    .filter(|id| !matches!(self.nodes[*id].kind, Kind::Dir(_)))
+                    .ok_or_else(|| anyhow!("hardlink {:?} to missing {:?}", entry.path, target))?;
+                self.nodes[target].nlink += 1;
+                self.insert(parent, name, target);
+                return Ok(());
+            }
+            _ => return Ok(()),
+        };
+
+        let mut node = Node {
+            kind,
+            mode: (header.mode()? & 0o7777) as u16,
+            uid: pax_number(&entry, "uid").unwrap_or(header.uid()?) as u32,
+            gid: pax_number(&entry, "gid").unwrap_or(header.gid()?) as u32,
+            mtime: cmp::min(header.mtime()?, u32::MAX as u64) as u32,
+            xattrs: xattrs(&entry),
+            nlink: 1,
+        };
+
+        // A directory seen before, implicitly or in a lower layer, keeps its
+        // children.
+        let existing = self.child(parent, &name);
+        if let (Some(id), Kind::Dir(_)) = (existing, &node.kind) {
+            if let Kind::Dir(children) = &mut self.nodes[id].kind {
+                node.kind = Kind::Dir(std::mem::take(children));
+                self.nodes[id] = node;
+                return Ok(());
+            }
+        }
+
+        self.nodes.push(node);
+        self.insert(parent, name, self.nodes.len() - 1);
+        Ok(())
+    }
+
+    /// Writes the inode, directory and lookup tables and the superblock,
+    /// returning the writer.
+    pub fn finish(mut self) -> anyhow::Result<W> {
+        let ids: BTreeSet<u32> = self.nodes.iter().flat_map(|n| [n.uid, n.gid]).collect();
+        if ids.len() > u16::MAX as usize + 1 {
+            bail!(
+                "{} distinct uids and gids, at most 65536 are supported",
+                ids.len()
+            );
+        }
+        let ids: Vec<u32> = ids.into_iter().collect();
+
+        let mut numbers = vec![0; self.nodes.len()];
+        let mut next = 1;
+        self.number(0, &mut numbers, &mut next);
+
+        let mut tables = Tables {
+            ids: ids
+                .iter()
+                .enumerate()
+                .map(|(i, id)| (*id, i as u16))
+                .collect(),
+            numbers,
+            refs: vec![None; self.nodes.len()],
+            inodes: MetadataWriter::new(self.compression),
+            directories: MetadataWriter::new(self.compression),
+            xattr_ids: HashMap::new(),
+            xattr_entries: MetadataWriter::new(self.compression),
+            xattrs: MetadataWriter::new(self.compression),
+        };
+        let root_parent = next;
+        self.write_dir(0, root_parent, &mut tables)?;
+        let root_inode = tables.refs[0].unwrap();
+
+        let inode_table_start = self.position;
+        self.write_all(&tables.inodes.finish()?)?;
+        let directory_table_start = self.position;
+        // Even an empty directory table must come before the next table.
+        self.write_all(&tables.directories.finish_non_empty()?)?;
+        let fragment_table_start = self.position;
+
+        let mut id_table = MetadataWriter::new(self.compression);
+        for id in &ids {
+            id_table.write(&id.to_le_bytes())?;
+        }
+        let id_table_start = self.write_lookup_table(id_table)?;
+
+        let xattr_id_table_start = if tables.xattr_ids.is_empty() {
+            INVALID_TABLE
+        } else {
+            let xattr_table_start = self.position;
+            self.write_all(&tables.xattrs.finish()?)?;
+            let count = tables.xattr_ids.len() as u32;
+            let mut pointers = Vec::new();
+            for start in self.write_blocks(tables.xattr_entries)? {
+                pointers.extend_from_slice(&start.to_le_bytes());
+            }
+            let xattr_id_table_start = self.position;
+            self.write_all(&xattr_table_start.to_le_bytes())?;
+            self.write_all(&count.to_le_bytes())?;
+            self.write_all(&0u32.to_le_bytes())?;
+            self.write_all(&pointers)?;
+            xattr_id_table_start
+        };
+
+        let bytes_used = self.position;
+        let padding = (DEVICE_BLOCK_SIZE - bytes_used % DEVICE_BLOCK_SIZE) % DEVICE_BLOCK_SIZE;
+        self.write_all(&vec![0; padding as usize])?;
+
+        let mut flags = NO_FRAGMENTS;
+        if xattr_id_table_start == INVALID_TABLE {
+            flags |= NO_XATTRS;
+        }
+        if self.compression == Compression::None {
+            flags |= UNCOMPRESSED_INODES
+                | UNCOMPRESSED_DATA
+                | UNCOMPRESSED_FRAGMENTS
+                | UNCOMPRESSED_XATTRS
+                | UNCOMPRESSED_IDS;
+        }
+
+        let mut superblock = Vec::with_capacity(SUPERBLOCK_SIZE as usize);
+        superblock.extend_from_slice(&MAGIC.to_le_bytes());
+        superblock.extend_from_slice(&(next - 1).to_le_bytes());
+        superblock.extend_from_slice(&self.mtime.to_le_bytes());
+        superblock.extend_from_slice(&(BLOCK_SIZE as u32).to_le_bytes());
+        superblock.extend_from_slice(&0u32.to_le_bytes());
+        let compression_id = match self.compression {
+            Compression::Zstd => ZSTD,
+            Compression::Gzip | Compression::None => GZIP,
+        };
+        superblock.extend_from_slice(&compression_id.to_le_bytes());
+        superblock.extend_from_slice(&BLOCK_LOG.to_le_bytes());
+        superblock.extend_from_slice(&flags.to_le_bytes());
+        superblock.extend_from_slice(&(ids.len() as u16).to_le_bytes());
+        superblock.extend_from_slice(&4u16.to_le_bytes());
+        superblock.extend_from_slice(&0u16.to_le_bytes());
+        for field in [
+            root_inode,
+            bytes_used,
+            id_table_start,
+            xattr_id_table_start,
+            inode_table_start,
+            directory_table_start,
+            fragment_table_start,
+            INVALID_TABLE,
+        ] {
+            superblock.extend_from_slice(&field.to_le_bytes());
+        }
+
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.writer.write_all(&superblock)?;
+        self.writer.seek(SeekFrom::End(0))?;
+        Ok(self.writer)
+    }
+
+    fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.writer.write_all(bytes)?;
+        self.position += bytes.len() as u64;
+        Ok(())
+    }
+
+    // Writes a file's data in blocks, returning where they start, their
+    // sizes and the file size.
+    fn write_data(&mut self, reader: &mut dyn Read) -> anyhow::Result<(u64, Vec<u32>, u64)> {
+        let blocks_start = self.position;
+        let mut blocks = Vec::new();
+        let mut size = 0;
+        let mut block = vec![0; BLOCK_SIZE];
+
+        loop {
+            let len = read_full(reader, &mut block)?;
+            if len == 0 {
+                break;
+            }
+            size += len as u64;
+            match compress(self.compression, &block[..len])? {
+                Some(compressed) => {
+                    self.write_all(&compressed)?;
+                    blocks.push(compressed.len() as u32);
+                }
+                None => {
+                    self.write_all(&block[..len])?;
+                    blocks.push(len as u32 | BLOCK_UNCOMPRESSED);
+                }
+            }
+            if len < BLOCK_SIZE {
+                break;
+            }
+        }
+
+        // Empty files point nowhere, as with mksquashfs.
+        let blocks_start = if blocks.is_empty() { 0 } else { blocks_start };
+        Ok((blocks_start, blocks, size))
+    }
+
+    // Writes the blocks of a metadata stream, returning their positions.
+    fn write_blocks(&mut self, table: MetadataWriter) -> io::Result<Vec<u64>> {
+        let start = self.position;
+        let (bytes, block_starts) = table.finish_with_blocks()?;
+        self.write_all(&bytes)?;
+        Ok(block_starts.into_iter().map(|b| start + b).collect())
+    }
+
+    // Writes a lookup table, the metadata blocks followed by pointers to
+    // them, and returns the position of the pointers.
+    fn write_lookup_table(&mut self, table: MetadataWriter) -> io::Result<u64> {
+        let pointers = self.write_blocks(table)?;
+        let start = self.position;
+        for pointer in pointers {
+            self.write_all(&pointer.to_le_bytes())?;
+        }
+        Ok(start)
+    }
+
+    // Returns the directory at `path`, creating missing ones.
+    fn directory(&mut self, path: &Path) -> anyhow::Result<NodeId> {
+        let mut id = 0;
+        for component in path.iter() {
+            let name = component.as_bytes().to_vec();
+            id = match self.child(id, &name) {
+                Some(child) if matches!(self.nodes[child].kind, Kind::Dir(_)) => child,
+                Some(_) => bail!("{:?} is not a directory", path),
+                None => {
+                    self.nodes.push(Node::dir(self.mtime));
+                    let child = self.nodes.len() - 1;
+                    self.insert(id, name, child);
+                    child
+                }
+            };
+        }
+
+        Ok(id)
+    }
+
+    fn lookup(&self, path: &Path) -> Option<NodeId> {
+        path.iter()
+            .try_fold(0, |id, name| self.child(id, name.as_bytes()))
+    }
+
+    fn child(&self, dir: NodeId, name: &[u8]) -> Option<NodeId> {
+        match &self.nodes[dir].kind {
+            Kind::Dir(children) => children.get(name).copied(),
+            _ => None,
+        }
+    }
+
+    fn insert(&mut self, dir: NodeId, name: Vec<u8>, child: NodeId) {
+        if let Kind::Dir(children) = &mut self.nodes[dir].kind {
+            children.insert(name, child);
+        }
+    }
+
+    // Numbers inodes in the order they are written: children before their
+    // directory, so the root comes last.
+    fn number(&self, dir: NodeId, numbers: &mut [u32], next: &mut u32) {
+        for (_, child) in self.nodes[dir].children() {
+            if matches!(self.nodes[child].kind, Kind::Dir(_)) {
+                self.number(child, numbers, next);
+            } else if numbers[child] == 0 {
+                numbers[child] = *next;
+                *next += 1;
+            }
+        }
+        numbers[dir] = *next;
+        *next += 1;
+    }
+
+    fn write_dir(&self, dir: NodeId, parent: u32, tables: &mut Tables) -> anyhow::Result<()> {
+        let children = self.nodes[dir].children();
+        let mut subdirectories = 0u32;
+        for (_, child) in &children {
+            if matches!(self.nodes[*child].kind, Kind::Dir(_)) {
+                self.write_dir(*child, tables.numbers[dir], tables)?;
+                subdirectories += 1;
+            } else if tables.refs[*child].is_none() {
+                self.write_inode(*child, &[], tables)?;
+            }
+        }
+
+        // Entries are grouped under headers sharing an inode metadata block
+        // and a base inode number.
+        let mut listing = Vec::new();
+        let mut group: Vec<(&[u8], NodeId)> = Vec::new();
+        for (name, child) in &children {
+            if let Some((_, first)) = group.first() {
+                let (first, next) = (tables.inode(*first), tables.inode(*child));
+                let offset = next.1 as i64 - first.1 as i64;
+                if group.len() == DIR_HEADER_COUNT
+                    || first.0 >> 16 != next.0 >> 16
+                    || offset < i16::MIN as i64
+                    || offset > i16::MAX as i64
+                {
+                    self.write_dir_group(&mut listing, &group, tables);
+                    group.clear();
+                }
+            }
+            group.push((name, *child));
+        }
+        if !group.is_empty() {
+            self.write_dir_group(&mut listing, &group, tables);
+        }
+
+        let (block, offset) = tables.directories.position();
+        tables.directories.write(&listing)?;
+
+        let node = &self.nodes[dir];
+        let file_size = listing.len() as u32 + 3;
+        let nlink = 2 + subdirectories;
+        let mut inode = Vec::new();
+        if file_size <= u16::MAX as u32 && node.xattrs.is_empty() {
+            inode.extend_from_slice(&block.to_le_bytes());
+            inode.extend_from_slice(&nlink.to_le_bytes());
+            inode.extend_from_slice(&(file_size as u16).to_le_bytes());
+            inode.extend_from_slice(&offset.to_le_bytes());
+            inode.extend_from_slice(&parent.to_le_bytes());
+            self.write_inode(dir, &inode, tables)
+        } else {
+            inode.extend_from_slice(&nlink.to_le_bytes());
+            inode.extend_from_slice(&file_size.to_le_bytes());
+            inode.extend_from_slice(&block.to_le_bytes());
+            inode.extend_from_slice(&parent.to_le_bytes());
+            // No directory index, lookups scan the listing.
+            inode.extend_from_slice(&0u16.to_le_bytes());
+            inode.extend_from_slice(&offset.to_le_bytes());
+            inode.extend_from_slice(&tables.xattr_index(&node.xattrs)?.to_le_bytes());
+            self.write_inode(dir, &inode, tables)
+        }
+    }
+
+    fn write_dir_group(&self, listing: &mut Vec<u8>, group: &[(&[u8], NodeId)], tables: &Tables) {
+        let (first_ref, base) = tables.inode(group[0].1);
+        listing.extend_from_slice(&(group.len() as u32 - 1).to_le_bytes());
+        listing.extend_from_slice(&((first_ref >> 16) as u32).to_le_bytes());
+        listing.extend_from_slice(&base.to_le_bytes());
+        for (name, child) in group {
+            let (inode_ref, number) = tables.inode(*child);
+            listing.extend_from_slice(&(inode_ref as u16).to_le_bytes());
+            listing.extend_from_slice(&((number as i64 - base as i64) as i16).to_le_bytes());
+            listing.extend_from_slice(&self.nodes[*child].basic_type().to_le_bytes());
+            listing.extend_from_slice(&(name.len() as u16 - 1).to_le_bytes());
+            listing.extend_from_slice(name);
+        }
+    }
+
+    // Writes the inode of a node, `dir_inode` holds the already encoded
+    // type specific part of directory inodes.
+    fn write_inode(&self, id: NodeId, dir_inode: &[u8], tables: &mut Tables) -> anyhow::Result<()> {
+        let node = &self.nodes[id];
+        let xattr = tables.xattr_index(&node.xattrs)?;
+        let extended = xattr != NO_XATTR;
+
+        let mut body = Vec::new();
+        let typ = match &node.kind {
+            Kind::Dir(_) => {
+                body.extend_from_slice(dir_inode);
+                // Directory inodes with a 32 bits size are the extended kind.
+                if dir_inode.len() == 16 {
+                    DIR
+                } else {
+                    DIR + EXTENDED
+                }
+            }
+            Kind::File {
+                blocks_start,
+                blocks,
+                size,
+            } => {
+                if extended
+                    || node.nlink > 1
+                    || *blocks_start > u32::MAX as u64
+                    || *size > u32::MAX as u64
+                {
+                    body.extend_from_slice(&blocks_start.to_le_bytes());
+                    body.extend_from_slice(&size.to_le_bytes());
+                    // Sparse bytes, none since all blocks are stored.
+                    body.extend_from_slice(&0u64.to_le_bytes());
+                    body.extend_from_slice(&node.nlink.to_le_bytes());
+                    body.extend_from_slice(&NO_FRAGMENT.to_le_bytes());
+                    body.extend_from_slice(&0u32.to_le_bytes());
+                    body.extend_from_slice(&xattr.to_le_bytes());
+                    for block in blocks {
+                        body.extend_from_slice(&block.to_le_bytes());
+                    }
+                    FILE + EXTENDED
+                } else {
+                    body.extend_from_slice(&(*blocks_start as u32).to_le_bytes());
+                    body.extend_from_slice(&NO_FRAGMENT.to_le_bytes());
+                    body.extend_from_slice(&0u32.to_le_bytes());
+                    body.extend_from_slice(&(*size as u32).to_le_bytes());
+                    for block in blocks {
+                        body.extend_from_slice(&block.to_le_bytes());
+                    }
+                    FILE
+                }
+            }
+            Kind::Symlink(target) => {
+                body.extend_from_slice(&node.nlink.to_le_bytes());
+                body.extend_from_slice(&(target.len() as u32).to_le_bytes());
+                body.extend_from_slice(target);
+                if extended {
+                    body.extend_from_slice(&xattr.to_le_bytes());
+                    SYMLINK + EXTENDED
+                } else {
+                    SYMLINK
+                }
+            }
+            Kind::BlockDev(device) | Kind::CharDev(device) => {
+                body.extend_from_slice(&node.nlink.to_le_bytes());
+                body.extend_from_slice(&device.to_le_bytes());
+                let typ = node.basic_type();
+                if extended {
+                    body.extend_from_slice(&xattr.to_le_bytes());
+                    typ + EXTENDED
+                } else {
+                    typ
+                }
+            }
+            Kind::Fifo => {
+                body.extend_from_slice(&node.nlink.to_le_bytes());
+                if extended {
+                    body.extend_from_slice(&xattr.to_le_bytes());
+                    FIFO + EXTENDED
+                } else {
+                    FIFO
+                }
+            }
+        };
+
+        let (block, offset) = tables.inodes.position();
+        tables.refs[id] = Some(((block as u64) << 16) | offset as u64);
+
+        let mut inode = Vec::with_capacity(16 + body.len());
+        inode.extend_from_slice(&typ.to_le_bytes());
+        inode.extend_from_slice(&node.mode.to_le_bytes());
+        inode.extend_from_slice(&tables.ids[&node.uid].to_le_bytes());
+        inode.extend_from_slice(&tables.ids[&node.gid].to_le_bytes());
+        inode.extend_from_slice(&node.mtime.to_le_bytes());
+        inode.extend_from_slice(&tables.numbers[id].to_le_bytes());
+        inode.extend_from_slice(&body);
+        tables.inodes.write(&inode)?;
+
+        Ok(())
+    }
+}
+
+// The state built while writing inodes and directories.
+struct Tables {
+    ids: HashMap<u32, u16>,
+    numbers: Vec<u32>,
+    refs: Vec<Option<u64>>,
+    inodes: MetadataWriter,
+    directories: MetadataWriter,
+    // Identical xattr sets are stored once.
+    xattr_ids: HashMap<Xattrs, u32>,
+    xattr_entries: MetadataWriter,
+    xattrs: MetadataWriter,
+}
+
+impl Tables {
+    fn inode(&self, id: NodeId) -> (u64, u32) {
+        (self.refs[id].unwrap(), self.numbers[id])
+    }
+
+    fn xattr_index(&mut self, xattrs: &Xattrs) -> io::Result<u32> {
+        if xattrs.is_empty() {
+            return Ok(NO_XATTR);
+        }
+        if let Some(index) = self.xattr_ids.get(xattrs) {
+            return Ok(*index);
+        }
+
+        let (block, offset) = self.xattrs.position();
+        let mut size = 0;
+        for (typ, name, value) in xattrs {
+            self.xattrs.write(&typ.to_le_bytes())?;
+            self.xattrs.write(&(name.len() as u16).to_le_bytes())?;
+            self.xattrs.write(name)?;
+            self.xattrs.write(&(value.len() as u32).to_le_bytes())?;
+            self.xattrs.write(value)?;
+            // As listed by listxattr, with the prefix and a NUL.
+            let prefix = XATTR_PREFIXES.iter().find(|(_, t)| t == typ).unwrap().0;
+            size += prefix.len() + name.len() + 1 + value.len();
+        }
+
+        let index = self.xattr_ids.len() as u32;
+        let xattr_ref = ((block as u64) << 16) | offset as u64;
+        self.xattr_entries.write(&xattr_ref.to_le_bytes())?;
+        self.xattr_entries
+            .write(&(xattrs.len() as u32).to_le_bytes())?;
+        self.xattr_entries.write(&(size as u32).to_le_bytes())?;
+        self.xattr_ids.insert(xattrs.clone(), index);
+        Ok(index)
+    }
+}
+
+// MetadataWriter packs a stream into 8 KiB metadata blocks, each compressed
+// separately.
+struct MetadataWriter {
+    compression: Compression,
+    block: Vec<u8>,
+    out: Vec<u8>,
+    block_starts: Vec<u64>,
+}
+
+impl MetadataWriter {
+    fn new(compression: Compression) -> Self {
+        MetadataWriter {
+            compression,
+            block: Vec::with_capacity(METADATA_SIZE),
+            out: Vec::new(),
+            block_starts: Vec::new(),
+        }
+    }
+
+    // The position of the next byte, as the offset of its block in the
+    // output and its offset in the uncompressed block.
+    fn position(&self) -> (u32, u16) {
+        (self.out.len() as u32, self.block.len() as u16)
+    }
+
+    fn write(&mut self, mut bytes: &[u8]) -> io::Result<()> {
+        while !bytes.is_empty() {
+            let n = cmp::min(METADATA_SIZE - self.block.len(), bytes.len());
+            self.block.extend_from_slice(&bytes[..n]);
+            bytes = &bytes[n..];
+            if self.block.len() == METADATA_SIZE {
+                self.flush()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+
+        self.block_starts.push(self.out.len() as u64);
+        match compress(self.compression, &self.block)? {
+            Some(compressed) => {
+                self.out
+                    .extend_from_slice(&(compressed.len() as u16).to_le_bytes());
+                self.out.extend_from_slice(&compressed);
+            }
+            None => {
+                let header = self.block.len() as u16 | METADATA_UNCOMPRESSED;
+                self.out.extend_from_slice(&header.to_le_bytes());
+                self.out.extend_from_slice(&self.block);
+            }
+        }
+        self.block.clear();
+        Ok(())
+    }
+
+    fn finish(self) -> io::Result<Vec<u8>> {
+        Ok(self.finish_with_blocks()?.0)
+    }
+
+    // An empty uncompressed block is written if nothing else was.
+    fn finish_non_empty(mut self) -> io::Result<Vec<u8>> {
+        self.flush()?;
+        if self.out.is_empty() {
+            self.out
+                .extend_from_slice(&METADATA_UNCOMPRESSED.to_le_bytes());
+        }
+        Ok(self.out)
+    }
+
+    fn finish_with_blocks(mut self) -> io::Result<(Vec<u8>, Vec<u64>)> {
+        self.flush()?;
+        Ok((self.out, self.block_starts))
+    }
+}
+
+// Compresses a block, returning `None` if it should be stored as is.
+fn compress(compression: Compression, bytes: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    // The default levels of mksquashfs.
+    let compressed = match compression {
+        Compression::None => return Ok(None),
+        Compression::Gzip => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::best());
+            encoder.write_all(bytes)?;
+            encoder.finish()?
+        }
+        Compression::Zstd => zstd::stream::encode_all(bytes, 15)?,
+    };
+
+    Ok(Some(compressed).filter(|c| c.len() < bytes.len()))
+}
+
+// Reads until `buf` is full or the reader is exhausted.
+fn read_full(reader: &mut dyn Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(len)
+}
+
+fn link_name<'a>(entry: &'a MergedEntry) -> anyhow::Result<&'a Path> {
+    entry
+        .link_name
+        .as_deref()
+        .ok_or_else(|| anyhow!("link {:?} has no target", entry.path))
+}
+
+// Encodes a device number the way the kernel's `new_encode_dev` does.
+fn device(header: &tar::Header) -> io::Result<u32> {
+    let major = header.device_major()?.unwrap_or(0);
+    let minor = header.device_minor()?.unwrap_or(0);
+    Ok((minor & 0xff) | (major << 8) | ((minor & !0xff) << 12))
+}
+
+// Numeric PAX records override the header, which has limited room.
+fn pax_number(entry: &MergedEntry, key: &str) -> Option<u64> {
+    entry
+        .pax_extensions
+        .iter()
+        .find(|(k, _)| k == key)
+        .and_then(|(_, v)| std::str::from_utf8(v).ok()?.parse().ok())
+}
+
+// Xattrs of the supported namespaces, sorted so images are reproducible.
+fn xattrs(entry: &MergedEntry) -> Xattrs {
+    let mut xattrs: Xattrs = entry
+        .pax_extensions
+        .iter()
+        .filter_map(|(key, value)| {
+            let name = key.strip_prefix(PAX_XATTR)?;
+            let (prefix, typ) = XATTR_PREFIXES.iter().find(|(p, _)| name.starts_with(p))?;
+            Some((
+                *typ,
+                name.as_bytes()[prefix.len()..].to_vec(),
+                value.clone(),
+            ))
+        })
+        .collect();
+    xattrs.sort();
+    xattrs
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        io::{Cursor, Read},
+    };
+
+    use flate2::read::ZlibDecoder;
+    use tar::{EntryType, Header};
+
+    use crate::{
+        compression::Compression,
+        export::Exporter,
+        test_utils::{tar, TestEntry, TestLayout},
+    };
+
+    fn u16_at(bytes: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap())
+    }
+
+    fn u32_at(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    fn u64_at(bytes: &[u8], at: usize) -> u64 {
+        u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+    }
+
+    fn decompress(bytes: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        ZlibDecoder::new(bytes).read_to_end(&mut out).unwrap();
+        out
+    }
+
+    // Decodes the metadata blocks in image[start..end], returning the
+    // stream and where each block starts in it.
+    fn metadata(image: &[u8], start: u64, end: u64) -> (Vec<u8>, HashMap<u64, usize>) {
+        let (mut stream, mut blocks) = (Vec::new(), HashMap::new());
+        let mut at = start as usize;
+        while at < end as usize {
+            let header = u16_at(image, at);
+            let len = (header & 0x7fff) as usize;
+            let data = &image[at + 2..at + 2 + len];
+            blocks.insert(at as u64 - start, stream.len());
+            match header & 0x8000 {
+                0 => stream.extend(decompress(data)),
+                _ => stream.extend_from_slice(data),
+            }
+            at += 2 + len;
+        }
+        (stream, blocks)
+    }
+
+    #[derive(Debug)]
+    struct Inode {
+        typ: u16,
+        mode: u16,
+        uid: u32,
+        number: u32,
+        nlink: u32,
+        data: Vec<u8>,
+        xattrs: Vec<(u16, String, Vec<u8>)>,
+    }
+
+    // A minimal reader, just enough to check what the writer produces.
+    struct Reader {
+        image: Vec<u8>,
+        inodes: (Vec<u8>, HashMap<u64, usize>),
+        directories: (Vec<u8>, HashMap<u64, usize>),
+        ids: Vec<u32>,
+    }
+
+    impl Reader {
+        fn new(image: Vec<u8>) -> Self {
+            assert_eq!(u32_at(&image, 0), 0x7371_7368);
+            let id_count = u16_at(&image, 26) as usize;
+            let id_table = u64_at(&image, 48);
+            let inode_table = u64_at(&image, 64);
+            let directory_table = u64_at(&image, 72);
+            let fragment_table = u64_at(&image, 80);
+            let inodes = metadata(&image, inode_table, directory_table);
+            let directories = metadata(&image, directory_table, fragment_table);
+            let ids_start = u64_at(&image, id_table as usize);
+            let (ids, _) = metadata(&image, ids_start, id_table);
+            let ids = (0..id_count).map(|i| u32_at(&ids, i * 4)).collect();
+            Reader {
+                image,
+                inodes,
+                directories,
+                ids,
+            }
+        }
+
+        fn inode(&self, inode_ref: u64) -> (Inode, usize) {
+            let (stream, blocks) = &self.inodes;
+            let at = blocks[&(inode_ref >> 16)] + (inode_ref & 0xffff) as usize;
+            let mut inode = Inode {
+                typ: u16_at(stream, at),
+                mode: u16_at(stream, at + 2),
+                uid: self.ids[u16_at(stream, at + 4) as usize],
+                number: u32_at(stream, at + 12),
+                nlink: 1,
+                data: vec![],
+                xattrs: vec![],
+            };
+            let body = at + 16;
+            match inode.typ {
+                // Basic file
+                2 => {
+                    let start = u32_at(stream, body) as u64;
+                    let size = u32_at(stream, body + 12) as usize;
+                    inode.data = self.file_data(start, size, &stream[body + 16..]);
+                }
+                // Extended file
+                9 => {
+                    let start = u64_at(stream, body);
+                    let size = u64_at(stream, body + 8) as usize;
+                    inode.nlink = u32_at(stream, body + 24);
+                    inode.data = self.file_data(start, size, &stream[body + 40..]);
+                    inode.xattrs = self.xattrs(u32_at(stream, body + 36));
+                }
+                // Symlink
+                3 => {
+                    let len = u32_at(stream, body + 4) as usize;
+                    inode.data = stream[body + 8..body + 8 + len].to_vec();
+                }
+                _ => {}
+            }
+            (inode, body)
+        }
+
+        fn file_data(&self, start: u64, size: usize, blocks: &[u8]) -> Vec<u8> {
+            let mut data = Vec::new();
+            let mut at = start as usize;
+            for i in 0..size.div_ceil(128 << 10) {
+                let block = u32_at(blocks, i * 4);
+                let len = (block & !(1 << 24)) as usize;
+                let bytes = &self.image[at..at + len];
+                match block & (1 << 24) {
+                    0 => data.extend(decompress(bytes)),
+                    _ => data.extend_from_slice(bytes),
+                }
+                at += len;
+            }
+            data
+        }
+
+        fn xattrs(&self, index: u32) -> Vec<(u16, String, Vec<u8>)> {
+            if index == u32::MAX {
+                return vec![];
+            }
+            let table = u64_at(&self.image, 56) as usize;
+            let kv_start = u64_at(&self.image, table);
+            let ids_start = u64_at(&self.image, table + 16);
+            let (ids, _) = metadata(&self.image, ids_start, table as u64);
+            let (kv, blocks) = metadata(&self.image, kv_start, ids_start);
+
+            let entry = index as usize * 16;
+            let xattr_ref = u64_at(&ids, entry);
+            let mut at = blocks[&(xattr_ref >> 16)] + (xattr_ref & 0xffff) as usize;
+            let mut xattrs = Vec::new();
+            for _ in 0..u32_at(&ids, entry + 8) {
+                let typ = u16_at(&kv, at);
+                let len = u16_at(&kv, at + 2) as usize;
+                let name = String::from_utf8(kv[at + 4..at + 4 + len].to_vec()).unwrap();
+                at += 4 + len;
+                let value_len = u32_at(&kv, at) as usize;
+                xattrs.push((typ, name, kv[at + 4..at + 4 + value_len].to_vec()));
+                at += 4 + value_len;
+            }
+            xattrs
+        }
+
+        // Lists the whole tree, by path.
+        fn tree(&self) -> HashMap<String, Inode> {
+            let root = u64_at(&self.image, 32);
+            let mut tree = HashMap::new();
+            self.walk(root, "", &mut tree);
+            tree
+        }
+
+        fn walk(&self, dir_ref: u64, path: &str, tree: &mut HashMap<String, Inode>) {
+            let (dir, body) = self.inode(dir_ref);
+            assert_eq!(dir.typ, 1);
+            let stream = &self.inodes.0;
+            let block = u32_at(stream, body) as u64;
+            let size = u16_at(stream, body + 8) as usize - 3;
+            let offset = u16_at(stream, body + 10) as usize;
+            let (listing, blocks) = &self.directories;
+            let mut at = blocks[&block] + offset;
+            let end = at + size;
+            while at < end {
+                let count = u32_at(listing, at) + 1;
+                let start = u32_at(listing, at + 4) as u64;
+                let base = u32_at(listing, at + 8);
+                at += 12;
+                for _ in 0..count {
+                    let child_ref = (start << 16) | u16_at(listing, at) as u64;
+                    let number = (base as i64 + u16_at(listing, at + 2) as i16 as i64) as u32;
+                    let len = u16_at(listing, at + 6) as usize + 1;
+                    let name = String::from_utf8(listing[at + 8..at + 8 + len].to_vec()).unwrap();
+                    at += 8 + len;
+
+                    let child_path = format!("{}/{}", path, name);
+                    let (child, _) = self.inode(child_ref);
+                    assert_eq!(child.number, number);
+                    if child.typ == 1 {
+                        self.walk(child_ref, &child_path, tree);
+                    }
+                    tree.insert(child_path, child);
+                }
+            }
+        }
+    }
+
+    fn squashfs(layout: &TestLayout, compression: Compression) -> Vec<u8> {
+        Exporter::new(layout.path())
+            .compression(compression)
+            .export_squashfs(Cursor::new(Vec::new()))
+            .unwrap()
+            .into_inner()
+    }
+
+    #[test]
+    fn test_squashfs() {
+        let mut builder = tar::Builder::new(Vec::new());
+        builder
+            .append_pax_extensions([("SCHILY.xattr.security.capability", &b"cap"[..])])
+            .unwrap();
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Regular);
+        header.set_mode(0o4755);
+        header.set_uid(1000);
+        header.set_gid(0);
+        header.set_mtime(0);
+        header.set_size(4);
+        builder
+            .append_data(&mut header, "usr/bin/ping", &b"ping"[..])
+            .unwrap();
+        let base = builder.into_inner().unwrap();
+
+        // Compressible and incompressible blocks, and a partial block.
+        let mut state = 1u32;
+        let big: Vec<u8> = (0..300_000u32)
+            .map(|i| {
+                // xorshift, which zlib cannot compress.
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                match i < 150_000 {
+                    true => (i % 251) as u8,
+                    false => state as u8,
+                }
+            })
+            .collect();
+        let layout = TestLayout::new();
+        layout.add_image(
+            &[
+                base,
+                tar(&[
+                    TestEntry::File("etc/passwd", b"root"),
+                    TestEntry::Link("etc/passwd-", "etc/passwd"),
+                    TestEntry::Symlink("bin", "usr/bin"),
+                    TestEntry::File("big", &big),
+                    TestEntry::File("empty", b""),
+                ]),
+            ],
+            None,
+        );
+
+        let image = squashfs(&layout, Compression::Gzip);
+        assert_eq!(image.len() % 4096, 0);
+        assert!(image.len() > 128 << 10);
+        // Byte for byte reproducible.
+        assert_eq!(image, squashfs(&layout, Compression::Gzip));
+
+        let tree = Reader::new(image).tree();
+        let mut paths: Vec<&str> = tree.keys().map(String::as_str).collect();
+        paths.sort_unstable();
+        assert_eq!(
+            paths,
+            [
+                "/big",
+                "/bin",
+                "/empty",
+                "/etc",
+                "/etc/passwd",
+                "/etc/passwd-",
+                "/usr",
+                "/usr/bin",
+                "/usr/bin/ping"
+            ]
+        );
+
+        let ping = &tree["/usr/bin/ping"];
+        assert_eq!((ping.mode, ping.uid), (0o4755, 1000));
+        assert_eq!(ping.data, b"ping");
+        assert_eq!(
+            ping.xattrs,
+            [(2, String::from("capability"), b"cap".to_vec())]
+        );
+
+        let (passwd, link) = (&tree["/etc/passwd"], &tree["/etc/passwd-"]);
+        assert_eq!(passwd.number, link.number);
+        assert_eq!((passwd.nlink, passwd.data.as_slice()), (2, &b"root"[..]));
+        assert_eq!(tree["/bin"].data, b"usr/bin");
+        assert_eq!(tree["/big"].data, big);
+        assert!(tree["/empty"].data.is_empty());
+
+        let uncompressed = squashfs(&layout, Compression::None);
+        let tree = Reader::new(uncompressed).tree();
+        assert_eq!(tree["/big"].data, big);
+    }
+}
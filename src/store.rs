@@ -0,0 +1,382 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Cursor, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use tar::Archive;
+
+use crate::hash::{self, HashingWriter};
+use crate::lock::LayoutLock;
+use crate::spec::digest::{Algorithm, Digest};
+use crate::spec::index::{Index, INDEX_FILE_NAME};
+use crate::spec::layout::{ImageLayout, BLOBS, IMAGE_LAYOUT, IMAGE_LAYOUT_VERSION};
+use crate::spec::media_types::MediaType;
+
+/// Metadata about a stored blob.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlobStat {
+    pub size: u64,
+}
+
+/// BlobStore gives access to the content of an OCI image layout,
+/// regardless of where it is kept.
+pub trait BlobStore {
+    /// Opens the blob with the given digest for reading.
+    fn open(&self, digest: &Digest) -> io::Result<Box<dyn Read + '_>>;
+
+    /// Returns metadata about the blob without reading it.
+    fn stat(&self, digest: &Digest) -> io::Result<BlobStat>;
+
+    /// Opens the `index.json` of the layout.
+    fn open_index(&self) -> io::Result<Box<dyn Read + '_>>;
+
+    fn exists(&self, digest: &Digest) -> bool {
+        self.stat(digest).is_ok()
+    }
+
+    /// Locks the layout for reading, so garbage collection can't delete
+    /// blobs while they are read. Stores which can't change aren't locked.
+    fn lock_shared(&self) -> io::Result<Option<LayoutLock>> {
+        Ok(None)
+    }
+}
+
+fn not_found(what: &dyn std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{} not found", what))
+}
+
+/// DirectoryStore reads an OCI image layout from a directory,
+/// with blobs under `blobs/<algorithm>/<encoded>`.
+#[derive(Debug, Clone)]
+pub struct DirectoryStore {
+    root: PathBuf,
+}
+
+impl DirectoryStore {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        DirectoryStore { root: root.into() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn blob_path(&self, digest: &Digest) -> PathBuf {
+        self.root
+            .join(BLOBS)
+            .join(digest.algorithm.to_string())
+            .join(&digest.encoded)
+    }
+
+    /// Lists the blobs in the layout sorted by digest, files whose path
+    /// doesn't form a valid digest are skipped.
+    pub fn blobs(&self) -> io::Result<Vec<(Digest, BlobStat)>> {
+        let mut blobs = Vec::new();
+        for algorithm in fs::read_dir(self.root.join(BLOBS))? {
+            let algorithm = algorithm?;
+            if !algorithm.file_type()?.is_dir() {
+                continue;
+            }
+            let name = algorithm.file_name();
+            let name = name.to_string_lossy();
+
+            for blob in fs::read_dir(algorithm.path())? {
+                let blob = blob?;
+                let metadata = blob.metadata()?;
+                if !metadata.is_file() {
+                    continue;
+                }
+                let digest = format!("{}:{}", name, blob.file_name().to_string_lossy());
+                if let Ok(digest) = Digest::from_str(&digest) {
+                    blobs.push((
+                        digest,
+                        BlobStat {
+                            size: metadata.len(),
+                        },
+                    ));
+                }
+            }
+        }
+
+        blobs.sort_by_key(|(digest, _)| digest.to_string());
+        Ok(blobs)
+    }
+
+    /// Creates the layout if it doesn't exist yet, with an empty index.
+    pub fn init(&self) -> io::Result<()> {
+        fs::create_dir_all(self.root.join(BLOBS).join(Algorithm::Sha256.to_string()))?;
+        let layout_path = self.root.join(IMAGE_LAYOUT);
+        if !layout_path.exists() {
+            let layout = ImageLayout {
+                image_layout_version: String::from(IMAGE_LAYOUT_VERSION),
+            };
+            self.write_atomically(&layout_path, &serde_json::to_vec(&layout)?)?;
+        }
+        if !self.root.join(INDEX_FILE_NAME).exists() {
+            self.write_index(&Index {
+                schema_version: 2,
+                media_type: Some(MediaType::ImageIndex),
+                artifact_type: None,
+                manifests: vec![],
+                subject: None,
+                annotations: None,
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Starts writing a blob, which is stored under its sha256 digest once
+    /// committed.
+    pub fn blob_writer(&self) -> io::Result<BlobWriter> {
+        let dir = self.root.join(BLOBS).join(Algorithm::Sha256.to_string());
+        let temp_path = temp_path(&dir);
+        let file = File::create(&temp_path)?;
+        Ok(BlobWriter {
+            store: self.clone(),
+            temp_path,
+            writer: HashingWriter::new(file, hash::hasher(&Algorithm::Sha256).unwrap()),
+        })
+    }
+
+    pub fn write_blob(&self, bytes: &[u8]) -> io::Result<Digest> {
+        let mut writer = self.blob_writer()?;
+        writer.write_all(bytes)?;
+        let (digest, _) = writer.commit()?;
+        Ok(digest)
+    }
+
+    /// Replaces the layout's `index.json`.
+    pub fn write_index(&self, index: &Index) -> io::Result<()> {
+        self.write_atomically(
+            &self.root.join(INDEX_FILE_NAME),
+            &serde_json::to_vec(index)?,
+        )
+    }
+
+    fn write_atomically(&self, path: &Path, bytes: &[u8]) -> io::Result<()> {
+        let temp_path = temp_path(&self.root);
+        fs::write(&temp_path, bytes)?;
+        fs::rename(temp_path, path)
+    }
+}
+
+// Temporary files are hidden so they are never mistaken for blobs.
+pub(crate) fn temp_path(dir: &Path) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    dir.join(format!(".tmp-{}-{}", process::id(), n))
+}
+
+/// BlobWriter streams a new blob into a `DirectoryStore`.
+pub struct BlobWriter {
+    store: DirectoryStore,
+    temp_path: PathBuf,
+    writer: HashingWriter<File>,
+}
+
+impl BlobWriter {
+    /// Moves the blob in place, returning its digest and size.
+    pub fn commit(self) -> io::Result<(Digest, u64)> {
+        let (file, digest, size) = self.writer.finish();
+        file.sync_all()?;
+        fs::rename(&self.temp_path, self.store.blob_path(&digest))?;
+        Ok((digest, size))
+    }
+}
+
+impl Write for BlobWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl BlobStore for DirectoryStore {
+    fn open(&self, digest: &Digest) -> io::Result<Box<dyn Read + '_>> {
+        Ok(Box::new(File::open(self.blob_path(digest))?))
+    }
+
+    fn stat(&self, digest: &Digest) -> io::Result<BlobStat> {
+        let metadata = fs::metadata(self.blob_path(digest))?;
+        Ok(BlobStat {
+            size: metadata.len(),
+        })
+    }
+
+    fn open_index(&self) -> io::Result<Box<dyn Read + '_>> {
+        Ok(Box::new(File::open(self.root.join(INDEX_FILE_NAME))?))
+    }
+
+    fn lock_shared(&self) -> io::Result<Option<LayoutLock>> {
+        LayoutLock::shared(&self.root).map(Some)
+    }
+}
+
+/// MemoryStore keeps blobs in memory, it is mostly useful for tests.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore {
+    blobs: HashMap<Digest, Vec<u8>>,
+    index: Vec<u8>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+
+    /// Stores the blob under its sha256 digest and returns the digest.
+    pub fn insert(&mut self, blob: Vec<u8>) -> Digest {
+        let digest = hash::digest_bytes(&Algorithm::Sha256, &blob).unwrap();
+        self.blobs.insert(digest.clone(), blob);
+        digest
+    }
+
+    pub fn set_index(&mut self, index: Vec<u8>) {
+        self.index = index;
+    }
+}
+
+impl BlobStore for MemoryStore {
+    fn open(&self, digest: &Digest) -> io::Result<Box<dyn Read + '_>> {
+        let blob = self.blobs.get(digest).ok_or_else(|| not_found(digest))?;
+        Ok(Box::new(Cursor::new(blob.as_slice())))
+    }
+
+    fn stat(&self, digest: &Digest) -> io::Result<BlobStat> {
+        let blob = self.blobs.get(digest).ok_or_else(|| not_found(digest))?;
+        Ok(BlobStat {
+            size: blob.len() as u64,
+        })
+    }
+
+    fn open_index(&self) -> io::Result<Box<dyn Read + '_>> {
+        Ok(Box::new(Cursor::new(self.index.as_slice())))
+    }
+}
+
+/// TarStore reads an OCI image layout packed in an uncompressed tar archive,
+/// such as the ones produced by `skopeo copy ... oci-archive:`.
+///
+/// The archive is scanned once when the store is opened, blobs are then read
+/// straight from their offset in the archive.
+#[derive(Debug, Clone)]
+pub struct TarStore {
+    path: PathBuf,
+    // Maps paths inside the archive to the offset and size of their data.
+    entries: HashMap<String, (u64, u64)>,
+}
+
+impl TarStore {
+    pub fn open<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
+        let path = path.into();
+        let mut archive = Archive::new(File::open(&path)?);
+        let mut entries = HashMap::new();
+
+        for entry in archive.entries()? {
+            let entry = entry?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let name = entry.path()?.to_string_lossy().into_owned();
+            let name = name.trim_start_matches("./").to_owned();
+            entries.insert(name, (entry.raw_file_position(), entry.size()));
+        }
+
+        Ok(TarStore { path, entries })
+    }
+
+    fn open_entry(&self, name: &str) -> io::Result<Box<dyn Read + '_>> {
+        let (offset, size) = self.entries.get(name).ok_or_else(|| not_found(&name))?;
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(*offset))?;
+        Ok(Box::new(file.take(*size)))
+    }
+
+    fn blob_name(digest: &Digest) -> String {
+        format!("{}/{}/{}", BLOBS, digest.algorithm, digest.encoded)
+    }
+}
+
+impl BlobStore for TarStore {
+    fn open(&self, digest: &Digest) -> io::Result<Box<dyn Read + '_>> {
+        self.open_entry(&TarStore::blob_name(digest))
+    }
+
+    fn stat(&self, digest: &Digest) -> io::Result<BlobStat> {
+        let name = TarStore::blob_name(digest);
+        let (_, size) = self.entries.get(&name).ok_or_else(|| not_found(digest))?;
+        Ok(BlobStat { size: *size })
+    }
+
+    fn open_index(&self) -> io::Result<Box<dyn Read + '_>> {
+        self.open_entry(INDEX_FILE_NAME)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use crate::{
+        spec::{digest::Digest, media_types::MediaType},
+        store::{BlobStat, BlobStore, DirectoryStore, MemoryStore, TarStore},
+        test_utils::{sha256, TestLayout},
+    };
+
+    fn read_all(store: &dyn BlobStore, digest: &Digest) -> Vec<u8> {
+        let mut buf = Vec::new();
+        store.open(digest).unwrap().read_to_end(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_directory_and_tar_store() {
+        let layout = TestLayout::new();
+        let descriptor = layout.write_blob(MediaType::ImageConfig, b"{}");
+        let missing = sha256(b"missing");
+
+        let directory = DirectoryStore::new(layout.path());
+        assert_eq!(read_all(&directory, &descriptor.digest), b"{}");
+        assert_eq!(
+            directory.stat(&descriptor.digest).unwrap(),
+            BlobStat { size: 2 }
+        );
+        assert!(!directory.exists(&missing));
+
+        let archive = layout.dir.path().with_extension("tar");
+        let mut builder = tar::Builder::new(std::fs::File::create(&archive).unwrap());
+        builder.append_dir_all(".", layout.path()).unwrap();
+        builder.finish().unwrap();
+
+        let tar = TarStore::open(&archive).unwrap();
+        assert_eq!(read_all(&tar, &descriptor.digest), b"{}");
+        assert_eq!(tar.stat(&descriptor.digest).unwrap(), BlobStat { size: 2 });
+        assert!(!tar.exists(&missing));
+        let mut index = String::new();
+        tar.open_index()
+            .unwrap()
+            .read_to_string(&mut index)
+            .unwrap();
+        assert!(index.contains("\"schemaVersion\":2"));
+        std::fs::remove_file(archive).unwrap();
+    }
+
+    #[test]
+    fn test_memory_store() {
+        let mut store = MemoryStore::new();
+        let digest = store.insert(b"hello".to_vec());
+        assert_eq!(digest, sha256(b"hello"));
+        assert_eq!(read_all(&store, &digest), b"hello");
+        assert_eq!(store.stat(&digest).unwrap(), BlobStat { size: 5 });
+        assert!(store.open(&sha256(b"missing")).is_err());
+    }
+}
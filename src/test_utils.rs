@@ -0,0 +1,256 @@
+//! Helpers for building OCI image layouts on disk in tests.
+
+use std::{fs, io::Write};
+
+use flate2::{write::GzEncoder, Compression};
+use tempfile::TempDir;
+
+use crate::hash;
+use crate::spec::{
+    annotations::Annotated,
+    config::{Image, RootFs},
+    descriptor::Descriptor,
+    digest::{Algorithm, Digest},
+    index::{Index, INDEX_FILE_NAME},
+    layout::{ImageLayout, BLOBS, IMAGE_LAYOUT, IMAGE_LAYOUT_VERSION},
+    manifest::Manifest,
+    media_types::MediaType,
+    platform::{Arch, Os},
+};
+
+/// An entry of a test layer.
+pub enum TestEntry<'a> {
+    Dir(&'a str),
+    File(&'a str, &'a [u8]),
+    Symlink(&'a str, &'a str),
+    Link(&'a str, &'a str),
+    CharDevice(&'a str, u32, u32),
+}
+
+pub fn sha256(bytes: &[u8]) -> Digest {
+    hash::digest_bytes(&Algorithm::Sha256, bytes).unwrap()
+}
+
+/// Builds an uncompressed layer tarball.
+pub fn tar(entries: &[TestEntry]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(Vec::new());
+    for entry in entries {
+        let mut header = tar::Header::new_gnu();
+        header.set_mtime(0);
+        header.set_uid(0);
+        header.set_gid(0);
+        match entry {
+            TestEntry::Dir(path) => {
+                header.set_entry_type(tar::EntryType::Directory);
+                header.set_mode(0o755);
+                header.set_size(0);
+                header.set_cksum();
+                builder.append_data(&mut header, path, &[][..]).unwrap();
+            }
+            TestEntry::File(path, contents) => {
+                header.set_entry_type(tar::EntryType::Regular);
+                header.set_mode(0o644);
+                header.set_size(contents.len() as u64);
+                header.set_cksum();
+                builder.append_data(&mut header, path, *contents).unwrap();
+            }
+            TestEntry::Symlink(path, target) => {
+                header.set_entry_type(tar::EntryType::Symlink);
+                header.set_mode(0o777);
+                header.set_size(0);
+                builder.append_link(&mut header, path, target).unwrap();
+            }
+            TestEntry::Link(path, target) => {
+                header.set_entry_type(tar::EntryType::Link);
+                header.set_mode(0o644);
+                header.set_size(0);
+                builder.append_link(&mut header, path, target).unwrap();
+            }
+            TestEntry::CharDevice(path, major, minor) => {
+                header.set_entry_type(tar::EntryType::Char);
+                header.set_mode(0o666);
+                header.set_size(0);
+                header.set_device_major(*major).unwrap();
+                header.set_device_minor(*minor).unwrap();
+                header.set_cksum();
+                builder.append_data(&mut header, path, &[][..]).unwrap();
+            }
+        }
+    }
+
+    builder.into_inner().unwrap()
+}
+
+pub fn gzip(bytes: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(bytes).unwrap();
+    encoder.finish().unwrap()
+}
+
+pub fn descriptor(media_type: MediaType, bytes: &[u8]) -> Descriptor {
+    Descriptor {
+        media_type,
+        digest: sha256(bytes),
+        size: bytes.len() as u64,
+        urls: None,
+        annotations: None,
+        platform: None,
+        data: None,
+        artifact_type: None,
+    }
+}
+
+/// An OCI image layout in a temporary directory.
+pub struct TestLayout {
+    pub dir: TempDir,
+}
+
+impl TestLayout {
+    pub fn new() -> Self {
+        let dir = TempDir::new().unwrap();
+        let layout = ImageLayout {
+            image_layout_version: String::from(IMAGE_LAYOUT_VERSION),
+        };
+        fs::write(
+            dir.path().join(IMAGE_LAYOUT),
+            serde_json::to_vec(&layout).unwrap(),
+        )
+        .unwrap();
+        fs::create_dir_all(dir.path().join(BLOBS).join("sha256")).unwrap();
+
+        let test_layout = TestLayout { dir };
+        test_layout.write_index(&Index {
+            schema_version: 2,
+            media_type: Some(MediaType::ImageIndex),
+            artifact_type: None,
+            manifests: vec![],
+            subject: None,
+            annotations: None,
+        });
+
+        test_layout
+    }
+
+    pub fn path(&self) -> String {
+        self.dir.path().to_str().unwrap().to_owned()
+    }
+
+    pub fn index(&self) -> Index {
+        let bytes = fs::read(self.dir.path().join(INDEX_FILE_NAME)).unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    pub fn write_index(&self, index: &Index) {
+        fs::write(
+            self.dir.path().join(INDEX_FILE_NAME),
+            serde_json::to_vec(index).unwrap(),
+        )
+        .unwrap();
+    }
+
+    pub fn write_blob(&self, media_type: MediaType, bytes: &[u8]) -> Descriptor {
+        let descriptor = descriptor(media_type, bytes);
+        fs::write(
+            self.dir
+                .path()
+                .join(BLOBS)
+                .join("sha256")
+                .join(&descriptor.digest.encoded),
+            bytes,
+        )
+        .unwrap();
+
+        descriptor
+    }
+
+    /// Writes gzipped layers built from `tars`, a config and a manifest
+    /// referencing them, and returns the manifest descriptor without adding
+    /// it to `index.json`.
+    pub fn write_image(&self, tars: &[Vec<u8>]) -> Descriptor {
+        let layers: Vec<Descriptor> = tars
+            .iter()
+            .map(|tar| self.write_blob(MediaType::ImageLayerTarGzip, &gzip(tar)))
+            .collect();
+        let image = Image {
+            created: None,
+            author: None,
+            architecture: Arch::Amd64,
+            os: Os::Linux,
+            config: None,
+            rootfs: RootFs {
+                typ: String::from("layers"),
+                diff_ids: tars.iter().map(|tar| sha256(tar)).collect(),
+            },
+            history: None,
+        };
+        let config = self.write_blob(MediaType::ImageConfig, &serde_json::to_vec(&image).unwrap());
+        let manifest = Manifest {
+            schema_version: 2,
+            media_type: Some(MediaType::ImageManifest),
+            artifact_type: None,
+            config,
+            layers,
+            subject: None,
+            annotations: None,
+        };
+
+        self.write_blob(
+            MediaType::ImageManifest,
+            &serde_json::to_vec(&manifest).unwrap(),
+        )
+    }
+
+    /// Writes an artifact manifest of type `artifact_type` with an empty
+    /// config and `payload` as its only layer, attached to `subject`.
+    pub fn write_artifact(
+        &self,
+        artifact_type: &str,
+        payload: &[u8],
+        subject: Option<&Descriptor>,
+        annotations: &[(&str, &str)],
+    ) -> Descriptor {
+        self.write_blob(MediaType::EmptyJson, b"{}");
+        let layer = self.write_blob(MediaType::Other(String::from(artifact_type)), payload);
+        let manifest = Manifest {
+            schema_version: 2,
+            media_type: Some(MediaType::ImageManifest),
+            artifact_type: Some(MediaType::Other(String::from(artifact_type))),
+            config: Descriptor::empty(),
+            layers: vec![layer],
+            subject: subject.cloned(),
+            annotations: match annotations {
+                [] => None,
+                annotations => Some(
+                    annotations
+                        .iter()
+                        .map(|(k, v)| (k.to_string(), v.to_string()))
+                        .collect(),
+                ),
+            },
+        };
+
+        self.write_blob(
+            MediaType::ImageManifest,
+            &serde_json::to_vec(&manifest).unwrap(),
+        )
+    }
+
+    /// Appends a descriptor to `index.json`, tagged with `tag` if given.
+    pub fn add_to_index(&self, mut descriptor: Descriptor, tag: Option<&str>) -> Descriptor {
+        if let Some(tag) = tag {
+            descriptor.set_ref_name(tag);
+        }
+
+        let mut index = self.index();
+        index.manifests.push(descriptor.clone());
+        self.write_index(&index);
+
+        descriptor
+    }
+
+    /// Writes an image and tags it in `index.json`.
+    pub fn add_image(&self, tars: &[Vec<u8>], tag: Option<&str>) -> Descriptor {
+        let manifest = self.write_image(tars);
+        self.add_to_index(manifest, tag)
+    }
+}
@@ -1,120 +1,1348 @@
 use std::{
-    fs::{self, File},
-    io::BufReader,
-    path::{Path, PathBuf},
+    cell::{Cell, RefCell},
+    collections::{BTreeSet, HashMap},
+    ffi::CString,
+    fs,
+    io::{self, Read},
+    os::unix::{ffi::OsStrExt, fs::PermissionsExt},
+    path::{Component, Path, PathBuf},
+    time::Instant,
 };
 
-use flate2::read::GzDecoder;
+use anyhow::bail;
+use filetime::FileTime;
 use tar::Archive;
 use tar::Entry;
+use tar::EntryType;
 
+use crate::cache::FileCache;
+use crate::compression;
+use crate::dry_run::{Simulated, Simulation};
+use crate::hash::{self, HashingReader};
+use crate::limits::{LimitError, LimitedReader, Limits};
+use crate::merge::{normalize, Whiteout};
+use crate::progress::{CountingReader, Event, NoProgress, Observer};
+use crate::referrers;
+use crate::report::{LayerReport, ManifestReport, PendingManifest, SkippedEntry, UnpackReport};
+use crate::signature::{self, PublicKey};
+use crate::spec::config::Image;
+use crate::spec::descriptor::Descriptor;
 use crate::spec::digest::Algorithm;
-use crate::spec::layout::BLOBS;
+use crate::spec::index::INDEX_FILE_NAME;
 use crate::spec::manifest::Manifest;
+use crate::spec::media_types::MediaType;
+use crate::spec::validation::{
+    validate_config, validate_index, validate_manifest, ValidationError, Violation,
+};
+use crate::store::{BlobStore, DirectoryStore};
+
+use super::spec::index::Index;
 
-use super::spec::index::{Index, INDEX_FILE_NAME};
+/// How the mtimes recorded in layers are applied to unpacked files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timestamps {
+    /// Keep the mtimes of the layers.
+    Preserve,
+    /// Set mtimes later than the epoch to the epoch, as `SOURCE_DATE_EPOCH`
+    /// asks for.
+    Clamp(u64),
+    /// Set every mtime to the epoch.
+    Override(u64),
+}
 
-const WHITEOUT_PREFIX: &str = ".wh.";
-const WHITEOUT_OPAQUE: &str = ".wh..wh..opq";
+impl Timestamps {
+    fn apply(self, mtime: u64) -> u64 {
+        match self {
+            Timestamps::Preserve => mtime,
+            Timestamps::Clamp(epoch) => mtime.min(epoch),
+            Timestamps::Override(epoch) => epoch,
+        }
+    }
+
+    fn epoch(self) -> Option<u64> {
+        match self {
+            Timestamps::Preserve => None,
+            Timestamps::Clamp(epoch) | Timestamps::Override(epoch) => Some(epoch),
+        }
+    }
+}
 
 #[derive(Debug)]
-pub struct Unpacker {
-    image_name: String,
+pub struct Unpacker<S: BlobStore = DirectoryStore> {
+    store: S,
     destination: String,
+    limits: Limits,
+    cache: Option<FileCache>,
+    restore_dir_mtimes: bool,
+    timestamps: Timestamps,
+    dry_run: bool,
+    signature_key: Option<PublicKey>,
+    strict: bool,
+    observer: Box<dyn Observer>,
 }
 
 impl Unpacker {
     pub fn new(image_name: String, destination: String) -> Self {
+        Unpacker::with_store(DirectoryStore::new(image_name), destination)
+    }
+}
+
+impl<S: BlobStore> Unpacker<S> {
+    /// Creates an unpacker reading the image from any blob store.
+    pub fn with_store(store: S, destination: String) -> Self {
         Unpacker {
-            image_name,
+            store,
             destination,
+            limits: Limits::default(),
+            cache: None,
+            restore_dir_mtimes: false,
+            timestamps: Timestamps::Preserve,
+            dry_run: false,
+            signature_key: None,
+            strict: false,
+            observer: Box::new(NoProgress),
         }
     }
 
-    pub fn unpack(&self) {
-        let engine = Engine::new(self.image_name.to_owned(), self.destination.to_owned());
-        engine.parse().unwrap();
+    /// Overrides the default resource limits.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Creates regular files from a cache shared across unpacks, adding
+    /// those it doesn't have yet.
+    pub fn cache(mut self, cache: FileCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Sets the mtimes and modes of directories from their entries once
+    /// everything is unpacked, since adding children updates them.
+    pub fn restore_dir_mtimes(mut self, restore: bool) -> Self {
+        self.restore_dir_mtimes = restore;
+        self
+    }
+
+    /// Clamps or overrides mtimes, directories created for entries without
+    /// their own get the epoch. Directory mtimes are restored first.
+    pub fn timestamps(mut self, timestamps: Timestamps) -> Self {
+        self.timestamps = timestamps;
+        self
+    }
+
+    /// Walks the layers without writing anything, the report then tells what
+    /// the unpacked tree would be.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Refuses images without a cosign signature verifying with `key`.
+    pub fn verify_signatures(mut self, key: PublicKey) -> Self {
+        self.signature_key = Some(key);
+        self
+    }
+
+    /// Refuses indexes, manifests and configs breaking a rule of the
+    /// image-spec, see [`crate::spec::validation`].
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Reports the progress of the unpack to `observer`.
+    pub fn observer(mut self, observer: impl Observer + 'static) -> Self {
+        self.observer = Box::new(observer);
+        self
+    }
+
+    /// Unpacks the image, returning a report of what was done. Limits,
+    /// digests, signatures and strict validation fail with their own errors,
+    /// e.g. [`LimitError`] or [`ValidationError`].
+    pub fn unpack(&self) -> anyhow::Result<UnpackReport> {
+        let _lock = self.store.lock_shared()?;
+        let _cache_lock = self.cache.as_ref().map(|c| c.lock()).transpose()?;
+        let engine = Engine::new(&self.store, self.destination.to_owned(), self.limits)
+            .cache(self.cache.as_ref())
+            .timestamps(self.restore_dir_mtimes, self.timestamps)
+            .dry_run(self.dry_run)
+            .signature_key(self.signature_key.as_ref())
+            .strict(self.strict)
+            .observer(self.observer.as_ref());
+        engine.parse()
     }
 }
 
-struct Engine {
-    image_path: String,
-    destination: String,
+struct Engine<'a, S: BlobStore> {
+    store: &'a S,
+    signature_key: Option<&'a PublicKey>,
+    strict: bool,
+    applier: Applier<'a>,
 }
 
-impl Engine {
-    pub fn new(image_path: String, destination: String) -> Self {
+impl<'a, S: BlobStore> Engine<'a, S> {
+    pub fn new(store: &'a S, destination: String, limits: Limits) -> Self {
         Engine {
-            image_path,
-            destination,
+            store,
+            signature_key: None,
+            strict: false,
+            applier: Applier::new(destination, limits),
         }
     }
 
-    pub fn parse(&self) -> anyhow::Result<Index> {
+    fn observer(mut self, observer: &'a dyn Observer) -> Self {
+        self.applier.observer = observer;
+        self
+    }
+
+    fn cache(mut self, cache: Option<&'a FileCache>) -> Self {
+        self.applier.cache = cache;
+        self
+    }
+
+    fn timestamps(mut self, restore_dir_mtimes: bool, timestamps: Timestamps) -> Self {
+        self.applier.restore_dir_mtimes = restore_dir_mtimes;
+        self.applier.timestamps = timestamps;
+        self
+    }
+
+    fn dry_run(mut self, dry_run: bool) -> Self {
+        self.applier.simulation = dry_run.then(|| RefCell::new(Simulation::default()));
+        self
+    }
+
+    fn signature_key(mut self, key: Option<&'a PublicKey>) -> Self {
+        self.signature_key = key;
+        self
+    }
+
+    fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    pub fn parse(&self) -> anyhow::Result<UnpackReport> {
+        let start = Instant::now();
+        let limits = &self.applier.limits;
         // TODO: add validation for layout file
-        let path = format!("{}/{}", self.image_path.as_str(), INDEX_FILE_NAME);
-        let file = File::open(path)?;
-        let reader = BufReader::new(file);
-        let index: Index = serde_json::from_reader(reader)?;
+        let mut bytes = Vec::new();
+        LimitedReader::new(
+            self.store.open_index()?,
+            limits.max_manifest_size,
+            LimitError::DocumentTooLarge {
+                what: String::from("index.json"),
+                limit: limits.max_manifest_size,
+            },
+        )
+        .read_to_end(&mut bytes)
+        .map_err(LimitError::from_io)?;
+        let index_digest = hash::digest_bytes(&Algorithm::Sha256, &bytes)?;
+        let index: Index = serde_json::from_slice(&bytes)?;
+        if self.strict {
+            ValidationError::check(INDEX_FILE_NAME, validate_index(&index))?;
+        }
+        // Nothing is written before every image is known to be signed.
+        let signatures = match self.signature_key {
+            Some(key) => signature::verify_images(self.store, &index, key, limits)?,
+            None => vec![],
+        };
 
         // TODO: find a sane place for this
-        fs::create_dir(&self.destination)?;
+        if self.applier.simulation.is_none() {
+            fs::create_dir(&self.applier.destination)?;
+        }
 
-        for manifest in &index.manifests {
-            self.parse_digest(&manifest.digest.algorithm, &manifest.digest.encoded)?;
+        let mut manifests = Vec::new();
+        for manifest in referrers::select_images(self.store, &index, limits.max_manifest_size)? {
+            manifests.push(self.parse_manifest(manifest)?);
         }
+        let (skipped, warnings) = self.applier.finish()?;
 
-        Ok(index)
+        Ok(UnpackReport {
+            index: index_digest,
+            manifests,
+            skipped,
+            warnings,
+            signatures,
+            dry_run: self
+                .applier
+                .simulation
+                .as_ref()
+                .map(|s| s.borrow().report()),
+            elapsed: start.elapsed(),
+        })
     }
 
-    fn parse_digest(&self, algorithm: &Algorithm, encoded: &str) -> anyhow::Result<()> {
-        let blob_path = format!("{}/{}/{}", self.image_path.as_str(), BLOBS, algorithm);
-        let path = format!("{}/{}", &blob_path, &encoded);
-        let file = File::open(&path)?;
-        let reader = BufReader::new(file);
-        let manifest: Manifest = serde_json::from_reader(reader)?;
+    fn parse_manifest(&self, descriptor: &Descriptor) -> anyhow::Result<ManifestReport> {
+        self.applier.observer.event(&Event::ManifestSelected {
+            digest: descriptor.digest.clone(),
+        });
+        let limits = &self.applier.limits;
+        let bytes = self.read_document(descriptor, "manifest", limits.max_manifest_size)?;
+        let manifest: Manifest = serde_json::from_slice(&bytes)?;
+        let config = self.read_document(&manifest.config, "config", limits.max_config_size)?;
+        if self.strict {
+            // Artifacts may carry configs of their own types, images can't.
+            let mut violations = validate_manifest(&manifest);
+            if manifest.config.media_type != MediaType::ImageConfig {
+                violations.push(Violation::new(
+                    "$.config.mediaType",
+                    format!(
+                        "is {}, images use {}",
+                        manifest.config.media_type,
+                        MediaType::ImageConfig
+                    ),
+                ));
+            }
+            ValidationError::check(&descriptor.digest, violations)?;
+            let image: Image = serde_json::from_slice(&config)?;
+            ValidationError::check(&manifest.config.digest, validate_config(&image, &manifest))?;
+        }
 
-        for layer in manifest.layers {
-            println!("upacking layer: {:?}", &layer.digest.encoded);
-            self.unpack_layer(&blob_path, &layer.digest.encoded)?;
+        let mut report = PendingManifest::new(descriptor, &manifest.config, &config);
+        for layer in &manifest.layers {
+            let layer = self.unpack_layer(layer)?;
+            report.add_layer(layer, &mut self.applier.warnings.borrow_mut());
         }
 
-        Ok(())
+        Ok(report.finish())
+    }
+
+    // Reads a manifest or config, refusing more than `limit` bytes.
+    fn read_document(
+        &self,
+        descriptor: &Descriptor,
+        what: &str,
+        limit: u64,
+    ) -> anyhow::Result<Vec<u8>> {
+        let digest = &descriptor.digest;
+        check_descriptor_size(descriptor, what, limit)?;
+
+        let mut bytes = Vec::new();
+        LimitedReader::for_blob(self.store.open(digest)?, digest, descriptor.size)
+            .read_to_end(&mut bytes)
+            .map_err(LimitError::from_io)?;
+        let actual = hash::digest_bytes(&digest.algorithm, &bytes)?;
+        if &actual != digest {
+            bail!("{} {} has digest {}", what, digest, actual);
+        }
+        self.applier.observer.event(&Event::DigestVerified {
+            digest: digest.clone(),
+        });
+
+        Ok(bytes)
+    }
+
+    fn unpack_layer(&self, layer: &Descriptor) -> anyhow::Result<LayerReport> {
+        self.applier.observer.event(&Event::LayerStarted {
+            digest: layer.digest.clone(),
+            size: layer.size,
+        });
+        let compressed = Cell::new(0);
+        let hasher = hash::hasher(&layer.digest.algorithm)?;
+        let mut blob = HashingReader::new(
+            CountingReader::new(
+                LimitedReader::for_blob(self.store.open(&layer.digest)?, &layer.digest, layer.size),
+                &compressed,
+            ),
+            hasher,
+        );
+        let decompressed = compression::decompress(&layer.media_type, &mut blob)?;
+        let mut report = self
+            .applier
+            .apply_layer(layer, decompressed, &|| compressed.get())?;
+
+        // Drain what the decoder didn't need so the whole blob is hashed.
+        io::copy(&mut blob, &mut io::sink()).map_err(LimitError::from_io)?;
+        let (digest, size) = blob.finish();
+        if digest != layer.digest {
+            bail!("layer {} has digest {}", layer.digest, digest);
+        }
+        self.applier
+            .observer
+            .event(&Event::DigestVerified { digest });
+        report.compressed_bytes = size;
+
+        Ok(report)
     }
+}
 
-    fn unpack_layer(&self, layer_path: &str, layer: &str) -> anyhow::Result<()> {
-        let path = format!("{}/{}", &layer_path, layer);
-        let file = File::open(&path)?;
+/// Applier applies decompressed layers to the destination, regardless of
+/// how their blobs are read.
+pub(crate) struct Applier<'a> {
+    destination: String,
+    limits: Limits,
+    // Decompressed bytes and entries unpacked so far, across layers.
+    unpacked_bytes: Cell<u64>,
+    unpacked_entries: Cell<u64>,
+    pub(crate) cache: Option<&'a FileCache>,
+    pub(crate) restore_dir_mtimes: bool,
+    pub(crate) timestamps: Timestamps,
+    // The mode and mtime of directory entries, restored at the end.
+    directories: RefCell<HashMap<PathBuf, (u32, u64)>>,
+    // The paths written by the current layer, which its whiteouts don't hide.
+    written: RefCell<BTreeSet<PathBuf>>,
+    skipped: RefCell<Vec<SkippedEntry>>,
+    pub(crate) warnings: RefCell<Vec<String>>,
+    // Set for dry runs, which apply entries to it instead of the destination.
+    pub(crate) simulation: Option<RefCell<Simulation>>,
+    pub(crate) observer: &'a dyn Observer,
+}
 
-        // TODO: GzDecoder is not necessarily correct, a robust solution
-        // would be to read the layer's media type
-        let mut archive = Archive::new(GzDecoder::new(file));
+// What was unpacked from the current layer.
+#[derive(Default)]
+struct LayerCounts {
+    entries: u64,
+    whiteouts: u64,
+}
+
+impl<'a> Applier<'a> {
+    pub(crate) fn new(destination: String, limits: Limits) -> Self {
+        Applier {
+            destination,
+            limits,
+            unpacked_bytes: Cell::new(0),
+            unpacked_entries: Cell::new(0),
+            cache: None,
+            restore_dir_mtimes: false,
+            timestamps: Timestamps::Preserve,
+            directories: RefCell::new(HashMap::new()),
+            written: RefCell::new(BTreeSet::new()),
+            skipped: RefCell::new(vec![]),
+            warnings: RefCell::new(vec![]),
+            simulation: None,
+            observer: &NoProgress,
+        }
+    }
+
+    /// Unpacks the entries of a decompressed layer, `compressed` tells how
+    /// much of the blob was read so far. The whole stream is read to compute
+    /// the DiffID.
+    pub(crate) fn apply_layer(
+        &self,
+        layer: &Descriptor,
+        reader: impl Read,
+        compressed: &dyn Fn() -> u64,
+    ) -> anyhow::Result<LayerReport> {
+        // Bound the decompressed stream by whichever of the layer and total
+        // limits is reached first.
+        let remaining = self.limits.max_total_size - self.unpacked_bytes.get();
+        let (limit, error) = if self.limits.max_layer_size <= remaining {
+            let limit = self.limits.max_layer_size;
+            let digest = layer.digest.clone();
+            (limit, LimitError::LayerTooLarge { digest, limit })
+        } else {
+            let limit = self.limits.max_total_size;
+            (remaining, LimitError::TotalSizeExceeded { limit })
+        };
+        let decompressed = Cell::new(0);
+        let reader = HashingReader::new(
+            CountingReader::new(reader, &decompressed),
+            hash::hasher(&Algorithm::Sha256)?,
+        );
+        let mut archive = Archive::new(LimitedReader::new(reader, limit, error));
         let destination = Path::new(&self.destination);
 
-        archive.entries()?.filter_map(|e| e.ok()).for_each(|entry| {
-            self.unpack_entry(destination, entry);
+        if let Some(simulation) = &self.simulation {
+            simulation.borrow_mut().start_layer(&layer.digest);
+        }
+        self.written.borrow_mut().clear();
+        let mut counts = LayerCounts::default();
+        let mut reported = 0;
+        for entry in archive.entries().map_err(LimitError::from_io)? {
+            let entry = entry.map_err(LimitError::from_io)?;
+            self.check_entry(&entry)?;
+            match &self.simulation {
+                Some(simulation) => match simulation.borrow_mut().entry(&entry)? {
+                    Simulated::Written => counts.entries += 1,
+                    Simulated::WhiteoutApplied => counts.whiteouts += 1,
+                    Simulated::Refused => {}
+                },
+                None => self.unpack_entry(destination, entry, &mut counts)?,
+            }
+            if compressed() != reported {
+                reported = compressed();
+                self.observer.event(&Event::BytesRead {
+                    digest: layer.digest.clone(),
+                    compressed: reported,
+                    decompressed: decompressed.get(),
+                });
+            }
+        }
+        // Padding after the end of the archive is part of the DiffID.
+        let mut rest = archive.into_inner();
+        io::copy(&mut rest, &mut io::sink()).map_err(LimitError::from_io)?;
+        let consumed = rest.consumed();
+        let (diff_id, _) = rest.into_inner().finish();
+        self.unpacked_bytes
+            .set(self.unpacked_bytes.get() + consumed);
+        self.observer.event(&Event::LayerFinished {
+            digest: layer.digest.clone(),
+            decompressed: consumed,
         });
 
+        Ok(LayerReport {
+            digest: layer.digest.clone(),
+            diff_id,
+            compressed_bytes: compressed(),
+            uncompressed_bytes: consumed,
+            entries_written: counts.entries,
+            whiteouts_applied: counts.whiteouts,
+        })
+    }
+
+    /// Restores directory metadata once all layers are applied, and returns
+    /// the skipped entries and warnings.
+    pub(crate) fn finish(&self) -> anyhow::Result<(Vec<SkippedEntry>, Vec<String>)> {
+        let restore = self.restore_dir_mtimes || self.timestamps != Timestamps::Preserve;
+        if restore && self.simulation.is_none() {
+            self.restore_directories(Path::new(&self.destination), Path::new(""))?;
+        }
+
+        Ok((self.skipped.take(), self.warnings.take()))
+    }
+
+    fn skip(&self, path: PathBuf, reason: &str) {
+        self.skipped.borrow_mut().push(SkippedEntry {
+            path,
+            reason: String::from(reason),
+        });
+    }
+
+    fn check_entry<T: Read>(&self, entry: &Entry<T>) -> anyhow::Result<()> {
+        let entries = self.unpacked_entries.get() + 1;
+        if entries > self.limits.max_entries {
+            bail!(LimitError::TooManyEntries {
+                limit: self.limits.max_entries
+            });
+        }
+        self.unpacked_entries.set(entries);
+        self.limits.check_path(&entry.path()?)?;
+
+        Ok(())
+    }
+
+    fn unpack_entry<T: std::io::Read>(
+        &self,
+        destination: &Path,
+        mut entry: Entry<T>,
+        counts: &mut LayerCounts,
+    ) -> anyhow::Result<()> {
+        let path: PathBuf = entry.path()?.to_path_buf();
+        if let Some(whiteout) = Whiteout::of(&normalize(&path)) {
+            let applied = !path.components().any(|c| c == Component::ParentDir)
+                && apply_whiteout(destination, &whiteout, &self.written.borrow())?;
+            if !applied {
+                self.skip(path, "outside of the destination");
+                return Ok(());
+            }
+            counts.whiteouts += 1;
+            self.observer.event(&Event::WhiteoutApplied {
+                path: whiteout.path().to_path_buf(),
+            });
+        } else if entry.header().entry_type().is_dir() {
+            // The root itself only gets its metadata recorded.
+            if !normalize(&path).as_os_str().is_empty() {
+                match entry_file(destination, &path)? {
+                    Some(dir) => match fs::create_dir(dir) {
+                        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e.into()),
+                        _ => {}
+                    },
+                    None => {
+                        self.skip(path, "outside of the destination");
+                        return Ok(());
+                    }
+                }
+            }
+            let metadata = (entry.header().mode()?, entry.header().mtime()?);
+            self.directories
+                .borrow_mut()
+                .insert(normalize(&path), metadata);
+            self.written.borrow_mut().insert(normalize(&path));
+            counts.entries += 1;
+            self.observer.event(&Event::EntryWritten { path });
+        } else {
+            // Like `Entry::unpack_in`, which avoids a zero mtime.
+            let mtime = self.timestamps.apply(entry.header().mtime()?.max(1));
+            let unpacked = match (self.cache, entry.header().entry_type()) {
+                (Some(cache), EntryType::Regular | EntryType::Continuous) => {
+                    unpack_cached(cache, destination, &path, &mut entry, mtime)?
+                }
+                (_, EntryType::Char | EntryType::Block | EntryType::Fifo) => {
+                    match unpack_node(destination, &path, &entry, mtime) {
+                        Err(e) if is_permission_denied(&e) => {
+                            self.skip(path.clone(), "device nodes need privileges");
+                            return Ok(());
+                        }
+                        result => result?,
+                    }
+                }
+                _ => {
+                    let unpacked = entry.unpack_in(destination).map_err(LimitError::from_io)?;
+                    if unpacked && self.timestamps != Timestamps::Preserve {
+                        let mtime = FileTime::from_unix_time(mtime as i64, 0);
+                        let file = destination.join(normalize(&path));
+                        filetime::set_symlink_file_times(file, mtime, mtime)?;
+                    }
+                    unpacked
+                }
+            };
+            if entry.header().entry_type().is_symlink() {
+                self.limits.check_symlink_chain(destination, &path)?;
+            }
+            if unpacked {
+                self.written.borrow_mut().insert(normalize(&path));
+                counts.entries += 1;
+                self.observer.event(&Event::EntryWritten { path });
+            } else {
+                self.skip(path, "outside of the destination");
+            }
+        }
+
         Ok(())
     }
 
-    fn unpack_entry<T: std::io::Read>(&self, destination: &Path, mut entry: Entry<T>) {
-        let path: PathBuf = entry.path().unwrap().to_path_buf();
-        let last_component = path
-            .components()
-            .last()
+    // Applies the recorded modes and mtimes of the directories under `dir`,
+    // children first.
+    fn restore_directories(&self, dir: &Path, relative: &Path) -> anyhow::Result<()> {
+        for child in fs::read_dir(dir)? {
+            let child = child?;
+            if child.file_type()?.is_dir() {
+                let child_relative = relative.join(child.file_name());
+                self.restore_directories(&child.path(), &child_relative)?;
+            }
+        }
+
+        let recorded = self.directories.borrow().get(relative).copied();
+        let mtime = match recorded {
+            Some((mode, mtime)) => {
+                fs::set_permissions(dir, fs::Permissions::from_mode(mode & 0o777))?;
+                Some(self.timestamps.apply(mtime))
+            }
+            None => self.timestamps.epoch(),
+        };
+        if let Some(mtime) = mtime {
+            let mtime = FileTime::from_unix_time(mtime as i64, 0);
+            filetime::set_file_times(dir, mtime, mtime)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Unpacks a regular file through the cache, with the same checks as
+// `Entry::unpack_in` so files can't be written outside of `destination`.
+// Returns false for skipped entries, as `unpack_in` does.
+fn unpack_cached<T: Read>(
+    cache: &FileCache,
+    destination: &Path,
+    path: &Path,
+    entry: &mut Entry<T>,
+    mtime: u64,
+) -> anyhow::Result<bool> {
+    let file = match entry_file(destination, path)? {
+        Some(file) => file,
+        None => return Ok(false),
+    };
+
+    let digest = cache.insert(entry).map_err(LimitError::from_io)?;
+    if fs::symlink_metadata(&file).is_ok_and(|m| !m.is_dir()) {
+        fs::remove_file(&file)?;
+    }
+    cache.materialize(&digest, &file, entry.header().mode()?, mtime)?;
+
+    Ok(true)
+}
+
+// Creates a device node or fifo, which `Entry::unpack_in` would write as an
+// empty regular file. Creating devices fails unless privileged.
+fn unpack_node<T: Read>(
+    destination: &Path,
+    path: &Path,
+    entry: &Entry<T>,
+    mtime: u64,
+) -> anyhow::Result<bool> {
+    let file = match entry_file(destination, path)? {
+        Some(file) => file,
+        None => return Ok(false),
+    };
+    if fs::symlink_metadata(&file).is_ok_and(|m| !m.is_dir()) {
+        fs::remove_file(&file)?;
+    }
+
+    let header = entry.header();
+    let kind = match header.entry_type() {
+        EntryType::Char => libc::S_IFCHR,
+        EntryType::Block => libc::S_IFBLK,
+        _ => libc::S_IFIFO,
+    };
+    let device = libc::makedev(
+        header.device_major()?.unwrap_or(0),
+        header.device_minor()?.unwrap_or(0),
+    );
+    let name = CString::new(file.as_os_str().as_bytes())?;
+    // SAFETY: `name` is a valid NUL-terminated path.
+    let result = unsafe { libc::mknod(name.as_ptr(), kind | (header.mode()? & 0o777), device) };
+    if result != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let mtime = FileTime::from_unix_time(mtime as i64, 0);
+    filetime::set_file_times(&file, mtime, mtime)?;
+
+    Ok(true)
+}
+
+// Where an entry goes under `destination`, with its parents created. None
+// for paths escaping it through `..`, as `Entry::unpack_in` skips them.
+// Parents are created one level at a time, after checking the level above
+// doesn't lead out of `destination` through a symlink.
+fn entry_file(destination: &Path, path: &Path) -> anyhow::Result<Option<PathBuf>> {
+    if path.components().any(|c| c == Component::ParentDir) {
+        return Ok(None);
+    }
+    let root = destination.canonicalize()?;
+    let path = normalize(path);
+    let mut parent = root.clone();
+    for component in path.parent().into_iter().flat_map(Path::components) {
+        let next = parent.join(component);
+        match fs::symlink_metadata(&next) {
+            Ok(_) => parent = next.canonicalize()?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                fs::create_dir(&next)?;
+                parent = next;
+            }
+            Err(e) => return Err(e.into()),
+        }
+        if !parent.starts_with(&root) {
+            bail!("{:?} is outside of {:?}", path, destination);
+        }
+    }
+
+    Ok(path.file_name().map(|name| parent.join(name)))
+}
+
+// Applies a whiteout to `destination`, removing what lower layers left
+// there but not what the current layer `written`. Returns false without
+// removing anything for whiteouts leading out of it through a symlink, e.g.
+// one from an earlier layer pointing to `/etc`.
+fn apply_whiteout(
+    destination: &Path,
+    whiteout: &Whiteout,
+    written: &BTreeSet<PathBuf>,
+) -> anyhow::Result<bool> {
+    let root = destination.canonicalize()?;
+    // A hidden symlink is removed, not followed, an opaque one is followed.
+    let resolved = match whiteout {
+        Whiteout::Path(path) => match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => root.join(parent).canonicalize().map(|p| p.join(name)),
+            _ => return Ok(false),
+        },
+        Whiteout::Children(dir) => root.join(dir).canonicalize(),
+    };
+    let target = match resolved {
+        Ok(target) if target.starts_with(&root) => target,
+        Ok(_) => return Ok(false),
+        // Nothing is there to hide.
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+        Err(e) => return Err(e.into()),
+    };
+    let metadata = match fs::symlink_metadata(&target) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+        Err(e) => return Err(e.into()),
+    };
+
+    let relative = target.strip_prefix(&root)?;
+    if matches!(whiteout, Whiteout::Path(_)) && !has_written(written, relative) {
+        remove(&target, metadata.is_dir())?;
+    } else if metadata.is_dir() {
+        remove_lower_in(&target, relative, written)?;
+    }
+
+    Ok(true)
+}
+
+// Removes what `dir`, at `relative` in the destination, has that the
+// current layer didn't write.
+fn remove_lower_in(dir: &Path, relative: &Path, written: &BTreeSet<PathBuf>) -> io::Result<()> {
+    for child in fs::read_dir(dir)? {
+        let child = child?;
+        let relative = relative.join(child.file_name());
+        let is_dir = child.file_type()?.is_dir();
+        if !has_written(written, &relative) {
+            remove(&child.path(), is_dir)?;
+        } else if is_dir {
+            remove_lower_in(&child.path(), &relative, written)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Tells whether the current layer wrote `path` or something under it.
+fn has_written(written: &BTreeSet<PathBuf>, path: &Path) -> bool {
+    // Paths compare component by component, so descendants directly follow.
+    written
+        .range(path.to_path_buf()..)
+        .next()
+        .is_some_and(|p| p.starts_with(path))
+}
+
+fn remove(path: &Path, is_dir: bool) -> io::Result<()> {
+    if is_dir {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+fn is_permission_denied(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<io::Error>()
+        .and_then(io::Error::raw_os_error)
+        .is_some_and(|code| code == libc::EPERM)
+}
+
+// Refuses descriptors claiming more than the limit before anything is read.
+pub(crate) fn check_descriptor_size(
+    descriptor: &Descriptor,
+    what: &str,
+    limit: u64,
+) -> anyhow::Result<()> {
+    if descriptor.size > limit {
+        bail!(LimitError::DocumentTooLarge {
+            what: format!("{} {}", what, descriptor.digest),
+            limit,
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        os::unix::fs::{FileTypeExt, MetadataExt},
+        path::PathBuf,
+        sync::mpsc,
+    };
+
+    use tempfile::TempDir;
+
+    use crate::{
+        cache::{CacheMode, FileCache},
+        limits::{LimitError, Limits},
+        progress::Event,
+        spec::{
+            index::Index, manifest::Manifest, media_types::MediaType, platform::Os,
+            validation::ValidationError,
+        },
+        store::{DirectoryStore, MemoryStore},
+        test_utils::{descriptor, gzip, sha256, tar, TestEntry, TestLayout},
+        unpacker::{Timestamps, Unpacker},
+    };
+
+    #[test]
+    fn test_unpack_from_memory_store() {
+        let mut store = MemoryStore::new();
+        let layer = gzip(&tar(&[TestEntry::File("hello", b"world")]));
+        let config = b"{}".to_vec();
+        let manifest = Manifest {
+            schema_version: 2,
+            media_type: Some(MediaType::ImageManifest),
+            artifact_type: None,
+            config: descriptor(MediaType::ImageConfig, &config),
+            layers: vec![descriptor(MediaType::ImageLayerTarGzip, &layer)],
+            subject: None,
+            annotations: None,
+        };
+        let manifest = serde_json::to_vec(&manifest).unwrap();
+        let index = Index {
+            schema_version: 2,
+            media_type: Some(MediaType::ImageIndex),
+            artifact_type: None,
+            manifests: vec![descriptor(MediaType::ImageManifest, &manifest)],
+            subject: None,
+            annotations: None,
+        };
+        store.insert(layer);
+        store.insert(config);
+        store.insert(manifest);
+        store.set_index(serde_json::to_vec(&index).unwrap());
+
+        let dir = TempDir::new().unwrap();
+        let destination = dir.path().join("rootfs");
+        Unpacker::with_store(store, destination.to_str().unwrap().to_owned())
+            .unpack()
+            .unwrap();
+
+        assert_eq!(fs::read(destination.join("hello")).unwrap(), b"world");
+    }
+
+    #[test]
+    fn test_unpack_with_cache() {
+        let layout = TestLayout::new();
+        layout.add_image(
+            &[tar(&[
+                TestEntry::File("etc/hostname", b"localhost"),
+                TestEntry::File(".wh.missing", b""),
+            ])],
+            None,
+        );
+        let cache = FileCache::open(layout.dir.path().join("cache"))
             .unwrap()
-            .as_os_str()
-            .to_str()
+            .mode(CacheMode::Hardlink);
+
+        let mut inodes = Vec::new();
+        for rootfs in ["a", "b"] {
+            let destination = layout.dir.path().join(rootfs);
+            Unpacker::new(layout.path(), destination.to_str().unwrap().to_owned())
+                .cache(cache.clone())
+                .unpack()
+                .unwrap();
+            let hostname = destination.join("etc/hostname");
+            assert_eq!(fs::read(&hostname).unwrap(), b"localhost");
+            assert!(!destination.join(".wh.missing").exists());
+            inodes.push(fs::metadata(hostname).unwrap().ino());
+        }
+        assert_eq!(inodes[0], inodes[1]);
+    }
+
+    #[test]
+    fn test_unpack_reproducible_timestamps() {
+        let layout = TestLayout::new();
+        layout.add_image(
+            &[
+                tar(&[TestEntry::Dir("etc/"), TestEntry::File("usr/bin/sh", b"sh")]),
+                tar(&[TestEntry::File("etc/hostname", b"localhost")]),
+            ],
+            None,
+        );
+        let mtime = |path: PathBuf| fs::symlink_metadata(path).unwrap().mtime();
+
+        let destination = layout.dir.path().join("restored");
+        Unpacker::new(layout.path(), destination.to_str().unwrap().to_owned())
+            .restore_dir_mtimes(true)
+            .unpack()
             .unwrap();
-        if entry.header().entry_type().is_dir() {
-            if !destination.join(&path).exists() {
-                fs::create_dir(destination.join(&path)).unwrap();
-            }
-        } else if !last_component.starts_with(WHITEOUT_PREFIX) {
-            entry.unpack_in(destination).unwrap();
-        } else if last_component.starts_with(WHITEOUT_OPAQUE) {
-            fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        assert_eq!(mtime(destination.join("etc")), 0);
+
+        let destination = layout.dir.path().join("clamped");
+        Unpacker::new(layout.path(), destination.to_str().unwrap().to_owned())
+            .timestamps(Timestamps::Clamp(100))
+            .unpack()
+            .unwrap();
+        assert_eq!(mtime(destination.join("etc")), 0);
+        assert_eq!(mtime(destination.join("etc/hostname")), 1);
+        assert_eq!(mtime(destination.join("usr/bin")), 100);
+        assert_eq!(mtime(destination), 100);
+
+        let destination = layout.dir.path().join("overridden");
+        Unpacker::new(layout.path(), destination.to_str().unwrap().to_owned())
+            .timestamps(Timestamps::Override(5))
+            .unpack()
+            .unwrap();
+        for path in ["", "etc", "etc/hostname", "usr/bin/sh"] {
+            assert_eq!(mtime(destination.join(path)), 5);
         }
     }
+
+    #[test]
+    fn test_unpack_events() {
+        let layout = TestLayout::new();
+        let layer = tar(&[
+            TestEntry::Dir("etc/"),
+            TestEntry::File("etc/hostname", b"host"),
+        ]);
+        let manifest = layout.add_image(std::slice::from_ref(&layer), None);
+        let (layer, size) = (sha256(&gzip(&layer)), gzip(&layer).len() as u64);
+
+        let (sender, receiver) = mpsc::channel();
+        let destination = layout.dir.path().join("rootfs");
+        Unpacker::new(layout.path(), destination.to_str().unwrap().to_owned())
+            .observer(sender)
+            .unpack()
+            .unwrap();
+
+        let events: Vec<Event> = receiver.try_iter().collect();
+        let bytes = fs::read(DirectoryStore::new(layout.path()).blob_path(&manifest.digest));
+        let config = serde_json::from_slice::<Manifest>(&bytes.unwrap())
+            .unwrap()
+            .config;
+        assert_eq!(
+            events[..5],
+            [
+                Event::ManifestSelected {
+                    digest: manifest.digest.clone()
+                },
+                Event::DigestVerified {
+                    digest: manifest.digest
+                },
+                Event::DigestVerified {
+                    digest: config.digest
+                },
+                Event::LayerStarted {
+                    digest: layer.clone(),
+                    size,
+                },
+                Event::EntryWritten {
+                    path: PathBuf::from("etc/")
+                },
+            ]
+        );
+        assert!(events.contains(&Event::EntryWritten {
+            path: PathBuf::from("etc/hostname")
+        }));
+        assert!(events.iter().any(|e| matches!(e, Event::BytesRead { .. })));
+        assert!(matches!(
+            &events[events.len() - 2..],
+            [
+                Event::LayerFinished { digest: finished, .. },
+                Event::DigestVerified { digest: verified },
+            ] if *finished == layer && *verified == layer
+        ));
+    }
+
+    #[test]
+    fn test_unpack_report() {
+        let layout = TestLayout::new();
+        let base = tar(&[
+            TestEntry::Dir("etc/"),
+            TestEntry::File("etc/hostname", b"localhost"),
+            TestEntry::CharDevice("null", 1, 3),
+        ]);
+        let top = tar(&[TestEntry::File("etc/.wh..wh..opq", b"")]);
+        let manifest = layout.add_image(&[base.clone(), top.clone()], None);
+
+        let destination = layout.dir.path().join("rootfs");
+        let report = Unpacker::new(layout.path(), destination.to_str().unwrap().to_owned())
+            .unpack()
+            .unwrap();
+        let index = fs::read(layout.dir.path().join("index.json")).unwrap();
+        assert_eq!(report.index, sha256(&index));
+        assert_eq!(report.manifests.len(), 1);
+        let manifest_report = &report.manifests[0];
+        assert_eq!(manifest_report.digest, manifest.digest);
+        assert_eq!(manifest_report.platform.as_ref().unwrap().os, Os::Linux);
+        assert_eq!(report.warnings, Vec::<String>::new());
+
+        // Device nodes are only created when privileged.
+        let created = fs::symlink_metadata(destination.join("null"))
+            .is_ok_and(|m| m.file_type().is_char_device());
+        assert_eq!(created, report.skipped.is_empty());
+        let layers = &manifest_report.layers;
+        assert_eq!(
+            (&layers[0].digest, &layers[0].diff_id),
+            (&sha256(&gzip(&base)), &sha256(&base))
+        );
+        assert_eq!(layers[0].compressed_bytes, gzip(&base).len() as u64);
+        assert_eq!(layers[0].uncompressed_bytes, base.len() as u64);
+        assert_eq!(layers[0].entries_written, if created { 3 } else { 2 });
+        assert_eq!(
+            (layers[1].diff_id.clone(), layers[1].whiteouts_applied),
+            (sha256(&top), 1)
+        );
+    }
+
+    #[test]
+    fn test_unpack_whiteouts() {
+        let base = tar(&[
+            TestEntry::File("etc/hostname", b"localhost"),
+            TestEntry::File("etc/passwd", b"root"),
+            TestEntry::File("tmp/a/b", b"b"),
+            TestEntry::File("kept", b"kept"),
+        ]);
+        // Whiteouts hide what lower layers have, not what their own has.
+        let top = tar(&[
+            TestEntry::File("etc/passwd", b"user"),
+            TestEntry::File("etc/.wh..wh..opq", b""),
+            TestEntry::File(".wh.tmp", b""),
+            TestEntry::File("new", b"new"),
+            TestEntry::File(".wh.new", b""),
+        ]);
+        let unpack = |layers: &[Vec<u8>]| {
+            let layout = TestLayout::new();
+            layout.add_image(layers, None);
+            let destination = layout.dir.path().join("rootfs");
+            let report = Unpacker::new(layout.path(), destination.to_str().unwrap().to_owned())
+                .unpack()
+                .unwrap();
+            (layout, destination, report)
+        };
+
+        let (_layout, destination, report) = unpack(&[base.clone(), top.clone()]);
+        assert!(!destination.join("etc/hostname").exists());
+        assert_eq!(fs::read(destination.join("etc/passwd")).unwrap(), b"user");
+        assert!(!destination.join("tmp").exists());
+        assert_eq!(fs::read(destination.join("new")).unwrap(), b"new");
+        assert_eq!(fs::read(destination.join("kept")).unwrap(), b"kept");
+        assert_eq!(report.manifests[0].layers[1].whiteouts_applied, 3);
+
+        // An opaque root keeps the destination itself.
+        let root = tar(&[
+            TestEntry::File("fresh", b"fresh"),
+            TestEntry::File(".wh..wh..opq", b""),
+        ]);
+        let (_layout, destination, _) = unpack(&[base, top, root]);
+        let names: Vec<_> = fs::read_dir(&destination)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["fresh"]);
+    }
+
+    #[test]
+    fn test_unpack_contains_whiteouts() {
+        // Next to the destination, so `..` reaches it.
+        let layout = TestLayout::new();
+        let victim = layout.dir.path().join("victim");
+        fs::create_dir_all(victim.join("sub")).unwrap();
+        fs::write(victim.join("sub/file"), b"kept").unwrap();
+
+        let mut escaping = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        let name = b"../victim/sub/.wh..wh..opq";
+        header.as_gnu_mut().unwrap().name[..name.len()].copy_from_slice(name);
+        header.set_size(0);
+        header.set_cksum();
+        escaping.append(&header, &[][..]).unwrap();
+
+        layout.add_image(
+            &[
+                tar(&[TestEntry::Symlink("link", victim.to_str().unwrap())]),
+                tar(&[TestEntry::File("link/sub/.wh..wh..opq", b"")]),
+                escaping.into_inner().unwrap(),
+            ],
+            None,
+        );
+        let destination = layout.dir.path().join("rootfs");
+        let report = Unpacker::new(layout.path(), destination.to_str().unwrap().to_owned())
+            .unpack()
+            .unwrap();
+
+        assert_eq!(fs::read(victim.join("sub/file")).unwrap(), b"kept");
+        let skipped: Vec<_> = report.skipped.iter().map(|s| s.path.clone()).collect();
+        assert_eq!(
+            skipped,
+            [
+                PathBuf::from("link/sub/.wh..wh..opq"),
+                PathBuf::from("../victim/sub/.wh..wh..opq")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unpack_cached_stays_in_destination() {
+        let layout = TestLayout::new();
+        let victim = layout.dir.path().join("victim");
+        fs::create_dir(&victim).unwrap();
+        layout.add_image(
+            &[
+                tar(&[TestEntry::Symlink("link", victim.to_str().unwrap())]),
+                tar(&[TestEntry::File("link/new/file", b"escaped")]),
+            ],
+            None,
+        );
+        let cache = FileCache::open(layout.dir.path().join("cache")).unwrap();
+
+        let destination = layout.dir.path().join("rootfs");
+        let err = Unpacker::new(layout.path(), destination.to_str().unwrap().to_owned())
+            .cache(cache)
+            .unpack()
+            .unwrap_err();
+        assert!(err.to_string().contains("is outside of"), "{}", err);
+        assert!(!victim.join("new").exists());
+    }
+
+    #[test]
+    fn test_unpack_directories_stay_in_destination() {
+        // Next to the destination, so `..` reaches it.
+        let layout = TestLayout::new();
+        let mut escaping = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        let name = b"../escaped/";
+        header.as_gnu_mut().unwrap().name[..name.len()].copy_from_slice(name);
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_mode(0o755);
+        header.set_size(0);
+        header.set_cksum();
+        escaping.append(&header, &[][..]).unwrap();
+
+        // Parents missing from the tar are created.
+        layout.add_image(
+            &[
+                escaping.into_inner().unwrap(),
+                tar(&[TestEntry::Dir("a/b")]),
+            ],
+            None,
+        );
+        let destination = layout.dir.path().join("rootfs");
+        let report = Unpacker::new(layout.path(), destination.to_str().unwrap().to_owned())
+            .unpack()
+            .unwrap();
+        assert!(!layout.dir.path().join("escaped").exists());
+        assert_eq!(report.skipped[0].path, PathBuf::from("../escaped/"));
+        assert!(destination.join("a/b").is_dir());
+
+        let layout = TestLayout::new();
+        let victim = layout.dir.path().join("victim");
+        fs::create_dir(&victim).unwrap();
+        layout.add_image(
+            &[
+                tar(&[TestEntry::Symlink("link", victim.to_str().unwrap())]),
+                tar(&[TestEntry::Dir("link/new")]),
+            ],
+            None,
+        );
+        let destination = layout.dir.path().join("rootfs");
+        let err = Unpacker::new(layout.path(), destination.to_str().unwrap().to_owned())
+            .unpack()
+            .unwrap_err();
+        assert!(err.to_string().contains("is outside of"), "{}", err);
+        assert!(!victim.join("new").exists());
+    }
+
+    #[test]
+    fn test_unpack_verifies_layer_digest() {
+        let layout = TestLayout::new();
+        let layer = tar(&[TestEntry::File("hello", b"world")]);
+        layout.add_image(std::slice::from_ref(&layer), None);
+        let store = DirectoryStore::new(layout.path());
+        fs::write(
+            store.blob_path(&sha256(&gzip(&layer))),
+            gzip(&tar(&[TestEntry::File("hello", b"there")])),
+        )
+        .unwrap();
+
+        let destination = layout.dir.path().join("rootfs");
+        let err = Unpacker::new(layout.path(), destination.to_str().unwrap().to_owned())
+            .unpack()
+            .unwrap_err();
+        assert!(err.to_string().contains("has digest"), "{}", err);
+    }
+
+    #[test]
+    fn test_strict_unpack() {
+        let layout = TestLayout::new();
+        layout.add_image(&[tar(&[TestEntry::File("hello", b"world")])], None);
+        let mut index = layout.index();
+        index.schema_version = 1;
+        layout.write_index(&index);
+
+        let destination = layout.dir.path().join("rootfs");
+        let unpacker =
+            Unpacker::new(layout.path(), destination.to_str().unwrap().to_owned()).dry_run(true);
+        unpacker.unpack().unwrap();
+
+        let err = unpacker.strict(true).unpack().unwrap_err();
+        let err = err.downcast::<ValidationError>().unwrap();
+        assert_eq!(err.document, "index.json");
+        assert_eq!(err.violations[0].path, "$.schemaVersion");
+        assert!(!destination.exists());
+
+        // Artifacts typed by their config, like Helm charts, are valid
+        // manifests but not images.
+        let layout = TestLayout::new();
+        let chart = Manifest {
+            schema_version: 2,
+            media_type: Some(MediaType::ImageManifest),
+            artifact_type: None,
+            config: layout.write_blob(
+                MediaType::Other(String::from("application/vnd.cncf.helm.config.v1+json")),
+                b"{}",
+            ),
+            layers: vec![],
+            subject: None,
+            annotations: None,
+        };
+        let manifest = layout.write_blob(
+            MediaType::ImageManifest,
+            &serde_json::to_vec(&chart).unwrap(),
+        );
+        layout.add_to_index(manifest.clone(), None);
+        let err = Unpacker::new(layout.path(), destination.to_str().unwrap().to_owned())
+            .strict(true)
+            .unpack()
+            .unwrap_err();
+        let err = err.downcast::<ValidationError>().unwrap();
+        assert_eq!(err.document, manifest.digest.to_string());
+        assert_eq!(err.violations[0].path, "$.config.mediaType");
+    }
+
+    fn unpack_with_limits(layers: &[Vec<u8>], limits: Limits) -> LimitError {
+        let layout = TestLayout::new();
+        layout.add_image(layers, None);
+        let destination = layout.dir.path().join("rootfs");
+        Unpacker::new(layout.path(), destination.to_str().unwrap().to_owned())
+            .limits(limits)
+            .unpack()
+            .unwrap_err()
+            .downcast::<LimitError>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_unpack_refuses_zip_bomb() {
+        let zeroes = vec![0; 1 << 20];
+        let layer = tar(&[TestEntry::File("zeroes", &zeroes)]);
+        let limits = Limits {
+            max_layer_size: 1 << 16,
+            ..Limits::default()
+        };
+        assert!(matches!(
+            unpack_with_limits(std::slice::from_ref(&layer), limits),
+            LimitError::LayerTooLarge { limit, .. } if limit == 1 << 16
+        ));
+
+        let limits = Limits {
+            max_total_size: (1 << 20) + (1 << 16),
+            ..Limits::default()
+        };
+        assert_eq!(
+            unpack_with_limits(&[layer.clone(), layer], limits),
+            LimitError::TotalSizeExceeded {
+                limit: (1 << 20) + (1 << 16)
+            }
+        );
+    }
+
+    #[test]
+    fn test_unpack_enforces_entry_limits() {
+        let layer = tar(&[
+            TestEntry::File("a", b"a"),
+            TestEntry::File("b", b"b"),
+            TestEntry::Symlink("c", "b"),
+            TestEntry::Symlink("d", "c"),
+        ]);
+        let limits = Limits {
+            max_entries: 3,
+            ..Limits::default()
+        };
+        assert_eq!(
+            unpack_with_limits(std::slice::from_ref(&layer), limits),
+            LimitError::TooManyEntries { limit: 3 }
+        );
+
+        let limits = Limits {
+            max_symlink_chain: 1,
+            ..Limits::default()
+        };
+        assert!(matches!(
+            unpack_with_limits(&[layer], limits),
+            LimitError::SymlinkChainTooLong { .. }
+        ));
+    }
+
+    #[test]
+    fn test_unpack_refuses_oversized_manifest() {
+        let limits = Limits {
+            max_manifest_size: 16,
+            ..Limits::default()
+        };
+        assert!(matches!(
+            unpack_with_limits(&[tar(&[])], limits),
+            LimitError::DocumentTooLarge { .. }
+        ));
+    }
 }
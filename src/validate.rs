@@ -0,0 +1,266 @@
+use std::{collections::HashSet, fs, io};
+
+use serde::Serialize;
+
+use crate::lock::LayoutLock;
+use crate::merge::{read_verified, select_manifest};
+use crate::pusher::split_image_tag;
+use crate::spec::config::Image;
+use crate::spec::descriptor::Descriptor;
+use crate::spec::digest::Digest;
+use crate::spec::index::{Index, INDEX_FILE_NAME};
+use crate::spec::layout::{ImageLayout, IMAGE_LAYOUT};
+use crate::spec::manifest::Manifest;
+use crate::spec::media_types::MediaType;
+use crate::spec::validation::{
+    validate_config, validate_index, validate_layout, validate_manifest, Violation,
+};
+use crate::store::{BlobStore, DirectoryStore};
+
+/// A violation found in a document of the layout, named `oci-layout`,
+/// `index.json` or by its digest.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DocumentViolation {
+    pub document: String,
+    #[serde(flatten)]
+    pub violation: Violation,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ValidationReport {
+    pub validated_documents: usize,
+    pub violations: Vec<DocumentViolation>,
+}
+
+impl ValidationReport {
+    pub fn is_ok(&self) -> bool {
+        self.violations.is_empty()
+    }
+
+    fn add(&mut self, document: impl ToString, violations: Vec<Violation>) {
+        self.validated_documents += 1;
+        let document = document.to_string();
+        self.violations
+            .extend(violations.into_iter().map(|violation| DocumentViolation {
+                document: document.clone(),
+                violation,
+            }));
+    }
+}
+
+/// Validator checks the documents of a layout against the MUST rules of the
+/// image-spec: `oci-layout`, `index.json`, and the indexes, manifests and
+/// image configs reachable from it. Unlike fsck, layers aren't read.
+#[derive(Debug)]
+pub struct Validator {
+    store: DirectoryStore,
+    tag: Option<String>,
+}
+
+impl Validator {
+    /// `image` is `<layout>[:tag]`, the tag restricts the validation to one
+    /// manifest of `index.json`.
+    pub fn new(image: String) -> Self {
+        let (image_path, tag) = split_image_tag(&image);
+        Validator {
+            store: DirectoryStore::new(image_path),
+            tag,
+        }
+    }
+
+    pub fn validate(&self) -> anyhow::Result<ValidationReport> {
+        let _lock = LayoutLock::shared(self.store.root())?;
+        let mut report = ValidationReport::default();
+
+        // Like fsck, a missing `oci-layout` or `index.json` is a violation.
+        match fs::read(self.store.root().join(IMAGE_LAYOUT)) {
+            Ok(layout) => match serde_json::from_slice::<ImageLayout>(&layout) {
+                Ok(layout) => report.add(IMAGE_LAYOUT, validate_layout(&layout)),
+                Err(e) => report.add(IMAGE_LAYOUT, vec![unparsable(e)]),
+            },
+            Err(e) => report.add(IMAGE_LAYOUT, vec![unreadable(e)]),
+        }
+
+        let index = match self.store.open_index() {
+            Ok(index) => serde_json::from_reader::<_, Index>(index),
+            Err(e) => {
+                report.add(INDEX_FILE_NAME, vec![unreadable(e)]);
+                return Ok(report);
+            }
+        };
+        let index = match index {
+            Ok(index) => index,
+            Err(e) => {
+                report.add(INDEX_FILE_NAME, vec![unparsable(e)]);
+                return Ok(report);
+            }
+        };
+        report.add(INDEX_FILE_NAME, validate_index(&index));
+
+        let descriptors = match &self.tag {
+            Some(tag) => vec![select_manifest(
+                &index,
+                Some(tag),
+                self.store.root().display(),
+            )?],
+            None => index.manifests.iter().collect(),
+        };
+        let mut visited = HashSet::new();
+        for descriptor in descriptors {
+            self.validate_descriptor(descriptor, &mut visited, &mut report)?;
+        }
+
+        Ok(report)
+    }
+
+    fn validate_descriptor(
+        &self,
+        descriptor: &Descriptor,
+        visited: &mut HashSet<Digest>,
+        report: &mut ValidationReport,
+    ) -> anyhow::Result<()> {
+        let digest = &descriptor.digest;
+        if !visited.insert(digest.clone()) {
+            return Ok(());
+        }
+
+        match descriptor.media_type {
+            MediaType::ImageIndex => {
+                let bytes = read_verified(&self.store, descriptor)?;
+                match serde_json::from_slice::<Index>(&bytes) {
+                    Ok(index) => {
+                        report.add(digest, validate_index(&index));
+                        for manifest in &index.manifests {
+                            self.validate_descriptor(manifest, visited, report)?;
+                        }
+                    }
+                    Err(e) => report.add(digest, vec![unparsable(e)]),
+                }
+            }
+            MediaType::ImageManifest => {
+                let bytes = read_verified(&self.store, descriptor)?;
+                match serde_json::from_slice::<Manifest>(&bytes) {
+                    Ok(manifest) => {
+                        report.add(digest, validate_manifest(&manifest));
+                        self.validate_config(&manifest, report)?;
+                    }
+                    Err(e) => report.add(digest, vec![unparsable(e)]),
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    // Image configs are checked against their manifest, artifacts have
+    // configs of their own types.
+    fn validate_config(
+        &self,
+        manifest: &Manifest,
+        report: &mut ValidationReport,
+    ) -> anyhow::Result<()> {
+        let config = &manifest.config;
+        if config.media_type != MediaType::ImageConfig {
+            return Ok(());
+        }
+        let bytes = read_verified(&self.store, config)?;
+        match serde_json::from_slice::<Image>(&bytes) {
+            Ok(image) => report.add(&config.digest, validate_config(&image, manifest)),
+            Err(e) => report.add(&config.digest, vec![unparsable(e)]),
+        }
+
+        Ok(())
+    }
+}
+
+// Documents which don't parse violate the rules of their types, e.g. a
+// missing required field.
+fn unparsable(e: serde_json::Error) -> Violation {
+    Violation::new("$", e.to_string())
+}
+
+fn unreadable(e: io::Error) -> Violation {
+    Violation::new("$", format!("cannot be read: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::{
+        spec::{
+            config::{Image, RootFs},
+            layout::IMAGE_LAYOUT,
+            manifest::Manifest,
+            media_types::MediaType,
+            platform::{Arch, Os},
+        },
+        test_utils::{gzip, sha256, tar, TestEntry, TestLayout},
+        validate::Validator,
+    };
+
+    #[test]
+    fn test_validate_layout() {
+        let layout = TestLayout::new();
+        layout.add_image(&[tar(&[TestEntry::File("a", b"a")])], Some("latest"));
+        let report = Validator::new(layout.path()).validate().unwrap();
+        assert!(report.is_ok(), "{:?}", report.violations);
+        // oci-layout, index.json, manifest and config
+        assert_eq!(report.validated_documents, 4);
+
+        let layer = tar(&[TestEntry::File("b", b"b")]);
+        let image = Image {
+            created: None,
+            author: None,
+            architecture: Arch::Amd64,
+            os: Os::Linux,
+            config: None,
+            rootfs: RootFs {
+                typ: String::from("tar"),
+                diff_ids: vec![sha256(&layer)],
+            },
+            history: None,
+        };
+        let config =
+            layout.write_blob(MediaType::ImageConfig, &serde_json::to_vec(&image).unwrap());
+        let manifest = Manifest {
+            schema_version: 1,
+            media_type: Some(MediaType::ImageManifest),
+            artifact_type: None,
+            config: config.clone(),
+            layers: vec![layout.write_blob(MediaType::ImageLayerTarGzip, &gzip(&layer))],
+            subject: None,
+            annotations: None,
+        };
+        let manifest = layout.write_blob(
+            MediaType::ImageManifest,
+            &serde_json::to_vec(&manifest).unwrap(),
+        );
+        layout.add_to_index(manifest.clone(), Some("invalid"));
+
+        let report = Validator::new(format!("{}:invalid", layout.path()))
+            .validate()
+            .unwrap();
+        let violations: Vec<_> = report
+            .violations
+            .iter()
+            .map(|v| (v.document.as_str(), v.violation.path.as_str()))
+            .collect();
+        assert_eq!(
+            violations,
+            [
+                (manifest.digest.to_string().as_str(), "$.schemaVersion"),
+                (config.digest.to_string().as_str(), "$.rootfs.type"),
+            ]
+        );
+
+        fs::remove_file(layout.dir.path().join(IMAGE_LAYOUT)).unwrap();
+        let report = Validator::new(layout.path()).validate().unwrap();
+        assert_eq!(report.violations[0].document, IMAGE_LAYOUT);
+        assert!(report.violations[0]
+            .violation
+            .message
+            .starts_with("cannot be read"));
+    }
+}